@@ -1,26 +1,294 @@
-use crate::{bsp, cpu};
+use crate::{bsp, console, cpu, exception, exception::ExceptionContext, print::fmt::Hex};
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use cortex_a::{asm, regs::*};
 
-#[naked]
+#[cfg(target_arch = "aarch64")]
+global_asm!(include_str!("cpu/boot.S"));
+#[cfg(target_arch = "aarch64")]
+global_asm!(include_str!("cpu/chainload.S"));
+
+extern "C" {
+    /// Sets up the stack and calls `_start_rust`, see cpu/boot.S
+    pub fn _start() -> !;
+}
+
 #[no_mangle]
-pub unsafe extern "C" fn _start() -> ! {
+pub unsafe extern "C" fn _start_rust(dtb_addr: usize) -> ! {
     use crate::runtime_init;
 
-    if bsp::cpu::BOOT_CORE_ID == cpu::smp::core_id() {
-        SP.set(bsp::cpu::BOOT_CORE_STACK_START);
-        runtime_init::runtime_init()
+    if current_el() == 2 {
+        el2_to_el1(dtb_addr)
+    }
+
+    if cpu::smp::is_boot_core() {
+        runtime_init::runtime_init(dtb_addr)
+    } else {
+        let (entry, stack_top) = cpu::smp::wait_for_release();
+
+        // In one block, so nothing runs on the new stack expecting its locals
+        // on the old one
+        #[cfg(target_arch = "aarch64")]
+        asm!("mov sp, {}", "br {}", in(reg) stack_top, in(reg) entry, options(noreturn));
+        #[cfg(not(target_arch = "aarch64"))]
+        unimplemented!("{:?} {}", entry as *const (), stack_top)
+    }
+}
+
+// SCTLR_EL1's reset value is UNKNOWN. This is just its RES1 bits (29, 28,
+// 23, 22, 20 and 11), so the MMU, caches and alignment checks start off.
+const SCTLR_EL1_AT_BOOT: u64 = 0x30d0_0800;
+
+// CPACR_EL1.FPEN, set so FP and SIMD instructions don't trap at EL1 or EL0
+const CPACR_EL1_FPEN: u64 = 0b11 << 20;
+
+/// Drops to EL1h with every exception masked and starts `_start` over there,
+/// which sets the stack up again, keeping the device tree address. EL1 keeps
+/// the physical timer and counter, with no virtual offset.
+#[inline(always)]
+unsafe fn el2_to_el1(dtb_addr: usize) -> ! {
+    CNTHCTL_EL2.write(CNTHCTL_EL2::EL1PCEN::SET + CNTHCTL_EL2::EL1PCTEN::SET);
+    CNTVOFF_EL2.set(0);
+    // The target has NEON, and the compiler uses it well before the vectors
+    // are installed
+    #[cfg(target_arch = "aarch64")]
+    asm!("msr CPACR_EL1, {}", in(reg) CPACR_EL1_FPEN, options(nomem, nostack));
+    SCTLR_EL1.set(SCTLR_EL1_AT_BOOT);
+    HCR_EL2.write(HCR_EL2::RW::EL1IsAarch64);
+    SPSR_EL2.write(
+        SPSR_EL2::D::Masked
+            + SPSR_EL2::A::Masked
+            + SPSR_EL2::I::Masked
+            + SPSR_EL2::F::Masked
+            + SPSR_EL2::M::EL1h,
+    );
+    ELR_EL2.set(_start as *const () as u64);
+
+    #[cfg(target_arch = "aarch64")]
+    asm!("eret", in("x0") dtb_addr, options(noreturn));
+    #[cfg(not(target_arch = "aarch64"))]
+    unimplemented!("{}", dtb_addr)
+}
+
+/// The EL field of a raw `CurrentEL` value
+pub const fn el_from_current_el(raw: u64) -> u8 {
+    (raw >> 2 & 0b11) as u8
+}
+
+/// The exception level this core is running at
+#[inline(always)]
+pub fn current_el() -> u8 {
+    el_from_current_el(CurrentEL.get())
+}
+
+#[cfg(target_arch = "aarch64")]
+pub use asm::{nop, sev, wfe, wfi};
+
+// Host tests run the waiting loops on threads, where sleeping is spinning
+#[cfg(not(target_arch = "aarch64"))]
+pub use core::hint::spin_loop as nop;
+#[cfg(not(target_arch = "aarch64"))]
+pub use core::hint::spin_loop as wfe;
+#[cfg(not(target_arch = "aarch64"))]
+pub use core::hint::spin_loop as wfi;
+#[cfg(not(target_arch = "aarch64"))]
+pub fn sev() {}
+
+// CNTKCTL_EL1 event stream enable and trigger bit select
+const CNTKCTL_EVNTEN: u64 = 1 << 2;
+const CNTKCTL_EVNTI_MASK: u64 = 0xf << 4;
+
+// The event fires when bit 7 of the counter goes from 0 to 1, every 256 ticks:
+// 13 µs on the Pi 3's 19.2 MHz counter, under 5 µs on the Pi 4's 54 MHz one.
+const EVENT_STREAM_BIT: u64 = 7;
+
+/// Has the generic timer send this core a periodic event, which bounds how
+/// long `spin_hint` sleeps. Every core enables its own.
+pub fn enable_event_stream() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        let mut cntkctl: u64;
+        asm!("mrs {}, CNTKCTL_EL1", out(reg) cntkctl, options(nomem, nostack));
+        cntkctl = cntkctl & !CNTKCTL_EVNTI_MASK | EVENT_STREAM_BIT << 4 | CNTKCTL_EVNTEN;
+        asm!("msr CNTKCTL_EL1, {}", "isb", in(reg) cntkctl, options(nomem, nostack));
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    unimplemented!()
+}
+
+fn event_stream_enabled() -> bool {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let cntkctl: u64;
+        unsafe { asm!("mrs {}, CNTKCTL_EL1", out(reg) cntkctl, options(nomem, nostack)) };
+        cntkctl & CNTKCTL_EVNTEN != 0
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    unimplemented!()
+}
+
+/// The body of a polling loop. Sleeps in `wfe` until the next event stream
+/// tick, interrupt or `sev`, and is a `nop` until the event stream is on so
+/// it can't sleep for good.
+#[inline(always)]
+pub fn spin_hint() {
+    #[cfg(target_arch = "aarch64")]
+    if event_stream_enabled() {
+        wfe();
     } else {
-        wait_forever()
+        nop();
     }
+
+    // Host tests poll fake registers that another thread updates
+    #[cfg(not(target_arch = "aarch64"))]
+    core::hint::spin_loop()
 }
 
-pub use asm::nop;
+#[inline(always)]
+pub fn main_id() -> u64 {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let midr: u64;
+        unsafe { asm!("mrs {}, MIDR_EL1", out(reg) midr) };
+        midr
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    unimplemented!()
+}
 
+#[inline(always)]
+pub fn stack_pointer() -> usize {
+    SP.get() as usize
+}
+
+/// Retires at least `n` `nop`s. The loop lives in a single asm block, so the
+/// compiler can neither unroll nor drop it.
+///
+/// This is not a timer: how long a `nop` takes depends on the core clock and
+/// the pipeline, so only use it where a lower bound on the delay is enough.
+/// Use the system timer for anything measured in real time.
 #[inline(always)]
 pub fn spin_for_cycles(n: usize) {
+    if n == 0 {
+        return;
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        asm!(
+            "1: nop",
+            "subs {0}, {0}, #1",
+            "b.ne 1b",
+            inout(reg) n => _,
+            options(nomem, nostack)
+        )
+    };
+
+    // Counts the iterations instead, for the tests
+    #[cfg(not(target_arch = "aarch64"))]
     for _ in 0..n {
-        asm::nop();
+        SPUN_CYCLES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+static SPUN_CYCLES: AtomicUsize = AtomicUsize::new(0);
+
+/// Dumps the exception return state, SP and x0-x30 from `e`, the frame an
+/// exception handler was given or `ExceptionContext::capture()`. Writes only
+/// to `w`, so it is safe to call with the panic console.
+pub fn dump_registers(w: &mut dyn fmt::Write, e: &ExceptionContext) -> fmt::Result {
+    writeln!(
+        w,
+        "ELR_EL1: {:#}  SPSR_EL1: {:#}  SP: {:#}",
+        Hex(e.elr_el1),
+        Hex(e.spsr_el1),
+        Hex(e.sp)
+    )?;
+    let regs = e.gpr.iter().chain(core::iter::once(&e.lr));
+    for (i, &reg) in regs.enumerate() {
+        write!(w, "x{:02}: {:#}  ", i, Hex(reg))?;
+        if i % 4 == 3 {
+            writeln!(w)?;
+        }
     }
+    writeln!(w)
+}
+
+// A plain store rather than a `synchronization::Once`: it is set with the MMU
+// still off, where the exclusives `Once` is built on aren't usable
+static DTB_ADDR: AtomicUsize = AtomicUsize::new(0);
+
+/// Set once by `runtime_init`, after .bss is zeroed
+pub unsafe fn set_dtb_addr(addr: usize) {
+    DTB_ADDR.store(addr, Ordering::Relaxed);
+}
+
+/// Device tree address the firmware booted us with
+pub fn dtb_addr() -> usize {
+    DTB_ADDR.load(Ordering::Relaxed)
+}
+
+/// SCTLR_EL1 as the boot protocol expects it on entry to a new image: MMU,
+/// data and instruction caches off, everything else untouched.
+pub fn chainload_sctlr(sctlr: u64) -> u64 {
+    const M: u64 = 1 << 0;
+    const C: u64 = 1 << 2;
+    const I: u64 = 1 << 12;
+
+    sctlr & !(M | C | I)
+}
+
+/// Copies the `kernel_size` byte image at `load_addr` to the kernel load
+/// address and jumps to it with interrupts masked, the MMU and caches off, and
+/// x0 holding the DTB address.
+///
+/// The drivers are shut down first, and the copy runs from a trampoline
+/// outside the image. Secondary cores must be parked somewhere the new image
+/// doesn't overwrite.
+pub unsafe fn chainload(load_addr: usize, kernel_size: usize) -> ! {
+    use crate::driver::interface::DriverManager;
+    use exception::{DAIF_A, DAIF_D, DAIF_F, DAIF_I};
+
+    extern "C" {
+        static __chainload_trampoline_start: u8;
+        static __chainload_trampoline_end: u8;
+    }
+
+    // Before anything is torn down, so the panic can still be seen and the
+    // board stays usable
+    let dst = bsp::cpu::KERNEL_LOAD_ADDR;
+    if load_addr < dst && load_addr + kernel_size > dst {
+        panic!("Chainload image at {:#x} overlaps the load address", load_addr);
+    }
+
+    // While the drivers can still take their locks and print. Not every
+    // console drains on shutdown.
+    console::console().flush();
+    bsp::driver::driver_manager().shutdown_all();
+    exception::set_daif(DAIF_D | DAIF_A | DAIF_I | DAIF_F);
+
+    let start = &__chainload_trampoline_start as *const u8;
+    let len = &__chainload_trampoline_end as *const u8 as usize - start as usize;
+    let trampoline = bsp::cpu::CHAINLOAD_TRAMPOLINE_ADDR;
+    core::ptr::copy_nonoverlapping(start, trampoline as *mut u8, len);
+
+    // Once the caches are off nothing may be left dirty in them: not the new
+    // image, not the trampoline, and not the old kernel it replaces
+    cpu::cache::clean_and_invalidate_range(trampoline, len);
+    cpu::cache::clean_and_invalidate_range(load_addr, kernel_size);
+    cpu::cache::clean_and_invalidate_range(dst, kernel_size);
+    cpu::cache::icache::invalidate_all();
+
+    let trampoline: extern "C" fn(usize, usize, usize, usize, u64) -> ! =
+        core::mem::transmute(trampoline);
+    let sctlr = chainload_sctlr(SCTLR_EL1.get());
+    trampoline(dst, load_addr, kernel_size, dtb_addr(), sctlr)
 }
 
 #[inline(always)]
@@ -28,4 +296,100 @@ pub fn wait_forever() -> ! {
     loop {
         asm::wfe();
     }
-}
\ No newline at end of file
+}
+
+/// Masks IRQs and FIQs and then parks the core for good. Unlike
+/// `wait_forever`, no interrupt wakes it up or gets handled meanwhile.
+#[inline(always)]
+pub fn halt() -> ! {
+    mask_for_halt();
+    wait_forever()
+}
+
+// IRQs and FIQs off for good, the rest of DAIF as it was
+#[inline(always)]
+fn mask_for_halt() {
+    unsafe { exception::local_irq_save() };
+    // Masked before the first `wfe`
+    cpu::barrier::isb();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{string::String, vec::Vec};
+
+    #[test]
+    fn el_from_current_el_reads_bits_3_2() {
+        assert_eq!(el_from_current_el(0b0000), 0);
+        assert_eq!(el_from_current_el(0b0100), 1);
+        assert_eq!(el_from_current_el(0b1000), 2);
+        assert_eq!(el_from_current_el(0b1100), 3);
+        // The rest of the register is RES0, and doesn't count
+        assert_eq!(el_from_current_el(0xffff_fff3), 0);
+        assert_eq!(el_from_current_el(0xffff_ffff), 3);
+    }
+
+    #[test]
+    fn halt_masks_irqs_and_fiqs_only() {
+        use exception::{daif, set_daif, DAIF_D, DAIF_F, DAIF_I};
+
+        unsafe { set_daif(DAIF_D) };
+        mask_for_halt();
+        assert_eq!(daif(), DAIF_D | DAIF_I | DAIF_F);
+
+        unsafe { set_daif(DAIF_I) };
+        mask_for_halt();
+        assert_eq!(daif(), DAIF_I | DAIF_F);
+    }
+
+    #[test]
+    fn spin_for_cycles_runs_every_iteration() {
+        let before = SPUN_CYCLES.load(Ordering::Relaxed);
+        spin_for_cycles(0);
+        assert_eq!(SPUN_CYCLES.load(Ordering::Relaxed), before);
+        spin_for_cycles(1000);
+        assert_eq!(SPUN_CYCLES.load(Ordering::Relaxed), before + 1000);
+    }
+
+    #[test]
+    fn dump_registers_prints_the_frame() {
+        let mut e = ExceptionContext {
+            gpr: [0; 30],
+            lr: 0x8_1234,
+            elr_el1: 0x8_0800,
+            spsr_el1: 0x3c5,
+            sp: 0x7_fff0,
+        };
+        e.gpr[5] = 0xdead_beef;
+        let mut out = String::new();
+
+        dump_registers(&mut out, &e).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(
+            lines[0],
+            "ELR_EL1: 0x0000000000080800  SPSR_EL1: 0x00000000000003c5  SP: 0x000000000007fff0"
+        );
+        assert!(lines[2].starts_with("x04: 0x0000000000000000  x05: 0x00000000deadbeef  "));
+        assert!(lines[8].starts_with("x28: "));
+        assert!(lines[8].ends_with("x30: 0x0000000000081234  "));
+        assert_eq!(lines.len(), 9);
+    }
+
+    #[test]
+    fn chainload_sctlr_clears_only_mmu_and_caches() {
+        // What the kernel runs with: M, C, SA, I and the RES1 bits
+        let running = 0x30d0_180d;
+
+        assert_eq!(chainload_sctlr(running), 0x30d0_0808);
+        assert_eq!(chainload_sctlr(0), 0);
+        assert_eq!(chainload_sctlr(!0), !0x1005);
+    }
+
+    #[test]
+    fn el1_boots_with_only_the_sctlr_res1_bits() {
+        // The running value above, less M, C, SA and I
+        assert_eq!(SCTLR_EL1_AT_BOOT, 0x30d0_180d & !0x100d);
+        assert_eq!(chainload_sctlr(SCTLR_EL1_AT_BOOT), SCTLR_EL1_AT_BOOT);
+    }
+}
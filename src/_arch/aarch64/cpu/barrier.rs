@@ -0,0 +1,62 @@
+// Without `nomem`, so the compiler doesn't move memory accesses across them
+// either
+macro_rules! barrier {
+    ($(#[$doc:meta])* $name:ident, $insn:literal) => {
+        $(#[$doc])*
+        #[inline(always)]
+        pub fn $name() {
+            #[cfg(target_arch = "aarch64")]
+            unsafe {
+                asm!($insn, options(nostack, preserves_flags))
+            };
+
+            #[cfg(not(target_arch = "aarch64"))]
+            LAST_BARRIER.with(|last| last.set($insn));
+        }
+    };
+}
+
+// Host tests have no barriers to run, so they note the last instruction
+// instead, per thread
+#[cfg(not(target_arch = "aarch64"))]
+std::thread_local! {
+    static LAST_BARRIER: core::cell::Cell<&'static str> = core::cell::Cell::new("");
+}
+
+barrier!(
+    /// `dmb sy`: memory accesses before it are observed before those after it
+    dmb,
+    "dmb sy"
+);
+
+barrier!(
+    /// `dsb sy`: waits for every memory access before it to complete
+    dsb,
+    "dsb sy"
+);
+
+barrier!(
+    /// `isb sy`: flushes the pipeline, so later instructions see the effects of
+    /// system register writes before it
+    isb,
+    "isb sy"
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last_barrier() -> &'static str {
+        LAST_BARRIER.with(|last| last.get())
+    }
+
+    #[test]
+    fn each_wrapper_runs_its_full_system_barrier() {
+        dmb();
+        assert_eq!(last_barrier(), "dmb sy");
+        dsb();
+        assert_eq!(last_barrier(), "dsb sy");
+        isb();
+        assert_eq!(last_barrier(), "isb sy");
+    }
+}
@@ -0,0 +1,94 @@
+use crate::cpu::features;
+use cortex_a::barrier;
+
+/// The first and one-past-last cache line addresses covering
+/// `addr..addr + len`, for a power of two `line` size. Empty when `len` is 0.
+pub const fn line_bounds(addr: usize, len: usize, line: usize) -> (usize, usize) {
+    let start = addr & !(line - 1);
+    if len == 0 {
+        return (start, start);
+    }
+
+    (start, (addr + len + line - 1) & !(line - 1))
+}
+
+/// The smallest data cache line size in bytes, from CTR_EL0.DminLine
+pub fn dcache_line_size() -> usize {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let ctr: u64;
+        unsafe { asm!("mrs {}, ctr_el0", out(reg) ctr, options(nomem, nostack)) };
+        features::ctr_dcache_line(ctr)
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    unimplemented!()
+}
+
+macro_rules! dc_range {
+    ($op:literal, $addr:expr, $len:expr) => {{
+        let line = dcache_line_size();
+        let (mut va, end) = line_bounds($addr, $len, line);
+        while va < end {
+            #[cfg(target_arch = "aarch64")]
+            unsafe { asm!(concat!("dc ", $op, ", {}"), in(reg) va, options(nostack)) };
+            va += line;
+        }
+        unsafe { barrier::dsb(barrier::SY) };
+    }};
+}
+
+/// Writes the dirty data cache lines covering `addr..addr + len` back to the
+/// point of coherency, before a device reads the memory.
+pub fn clean_range(addr: usize, len: usize) {
+    dc_range!("cvac", addr, len)
+}
+
+/// Discards the data cache lines covering `addr..addr + len`, after a device
+/// wrote the memory. Whole lines go, so the caller must own the first and
+/// last of them too, or dirty data sharing them is lost.
+pub fn invalidate_range(addr: usize, len: usize) {
+    dc_range!("ivac", addr, len)
+}
+
+/// Cleans and then invalidates the data cache lines covering
+/// `addr..addr + len`, for memory shared with the VideoCore or with cores
+/// running with their caches off.
+pub fn clean_and_invalidate_range(addr: usize, len: usize) {
+    dc_range!("civac", addr, len)
+}
+
+pub mod icache {
+    use cortex_a::barrier;
+
+    /// Invalidates the whole instruction cache of the calling core, after
+    /// new code was written to memory.
+    pub fn invalidate_all() {
+        unsafe {
+            #[cfg(target_arch = "aarch64")]
+            asm!("ic iallu", options(nostack));
+            barrier::dsb(barrier::SY);
+            barrier::isb(barrier::SY);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_bounds_of_an_aligned_line() {
+        assert_eq!(line_bounds(0x1000, 64, 64), (0x1000, 0x1040));
+    }
+
+    #[test]
+    fn line_bounds_straddling_two_lines() {
+        assert_eq!(line_bounds(0x103F, 2, 64), (0x1000, 0x1080));
+    }
+
+    #[test]
+    fn line_bounds_of_nothing_is_empty() {
+        assert_eq!(line_bounds(0x1010, 0, 64), (0x1000, 0x1000));
+    }
+}
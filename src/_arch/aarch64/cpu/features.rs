@@ -0,0 +1,117 @@
+use cortex_a::regs::*;
+
+/// What the core implements, as far as the kernel cares
+#[derive(Copy, Clone)]
+pub struct CpuFeatures {
+    pub granule_4k: bool,
+    pub granule_16k: bool,
+    pub granule_64k: bool,
+    /// ID_AA64MMFR0_EL1.PARange as is, which TCR_EL1.IPS encodes the same way
+    pub pa_range: u8,
+    pub fp: bool,
+    pub advsimd: bool,
+    pub el2: bool,
+    pub el3: bool,
+    /// Smallest cache lines, in bytes
+    pub dcache_line: usize,
+    pub icache_line: usize,
+}
+
+const fn field(reg: u64, offset: u32) -> u8 {
+    ((reg >> offset) & 0xF) as u8
+}
+
+/// Physical address bits for a PARange value, `None` for reserved ones
+pub const fn pa_bits(pa_range: u8) -> Option<u8> {
+    match pa_range {
+        0b0000 => Some(32),
+        0b0001 => Some(36),
+        0b0010 => Some(40),
+        0b0011 => Some(42),
+        0b0100 => Some(44),
+        0b0101 => Some(48),
+        0b0110 => Some(52),
+        _ => None,
+    }
+}
+
+/// CTR_EL0.DminLine, log2 of the line size in words, as bytes
+pub const fn ctr_dcache_line(ctr: u64) -> usize {
+    4 << field(ctr, 16)
+}
+
+/// Decodes raw ID_AA64MMFR0_EL1, ID_AA64PFR0_EL1 and CTR_EL0 values
+pub const fn decode(mmfr0: u64, pfr0: u64, ctr: u64) -> CpuFeatures {
+    CpuFeatures {
+        // 0b1111 is "not implemented" for these two, but 0b0000 for 16K
+        granule_4k: field(mmfr0, 28) != 0b1111,
+        granule_16k: field(mmfr0, 20) != 0b0000,
+        granule_64k: field(mmfr0, 24) != 0b1111,
+        pa_range: field(mmfr0, 0),
+        fp: field(pfr0, 16) != 0b1111,
+        advsimd: field(pfr0, 20) != 0b1111,
+        el2: field(pfr0, 8) != 0b0000,
+        el3: field(pfr0, 12) != 0b0000,
+        dcache_line: ctr_dcache_line(ctr),
+        icache_line: 4 << field(ctr, 0),
+    }
+}
+
+/// The calling core's features. All cores on these boards are the same.
+pub fn features() -> CpuFeatures {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let (pfr0, ctr): (u64, u64);
+        unsafe {
+            asm!("mrs {}, id_aa64pfr0_el1", out(reg) pfr0, options(nomem, nostack));
+            asm!("mrs {}, ctr_el0", out(reg) ctr, options(nomem, nostack));
+        }
+        decode(ID_AA64MMFR0_EL1.get(), pfr0, ctr)
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    unimplemented!()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_a53() {
+        // Cortex-A53 (Pi 3): 40-bit PAs, no 16K granule, 64-byte lines
+        let a53 = decode(0x1122, 0x2222, 0x8444_C004);
+
+        assert!(a53.granule_4k && !a53.granule_16k && a53.granule_64k);
+        assert_eq!(pa_bits(a53.pa_range), Some(40));
+        assert!(a53.fp && a53.advsimd && a53.el2 && a53.el3);
+        assert_eq!(a53.dcache_line, 64);
+        assert_eq!(a53.icache_line, 64);
+    }
+
+    #[test]
+    fn decode_a72() {
+        // Cortex-A72 (Pi 4): 44-bit PAs
+        let a72 = decode(0x1124, 0x2222, 0x8444_C004);
+
+        assert_eq!(pa_bits(a72.pa_range), Some(44));
+    }
+
+    #[test]
+    fn decode_missing_features() {
+        // No FP or SIMD, no EL3, 16K granules only
+        let bare = decode(0xFF10_0000, 0x00FF_0222, 0x8444_C003);
+
+        assert!(!bare.granule_4k && bare.granule_16k && !bare.granule_64k);
+        assert!(!bare.fp && !bare.advsimd && bare.el2 && !bare.el3);
+        assert_eq!(bare.icache_line, 32);
+    }
+
+    #[test]
+    fn pa_bits_rejects_reserved_values() {
+        assert_eq!(pa_bits(0b0000), Some(32));
+        assert_eq!(pa_bits(0b0110), Some(52));
+        assert_eq!(pa_bits(0b0111), None);
+        assert_eq!(pa_bits(0b1111), None);
+    }
+}
@@ -1,10 +1,112 @@
-use cortex_a::regs::*;
+use crate::{bsp, cpu};
+use core::{
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use cortex_a::{asm, barrier, regs::*};
+
+/// The core number in an MPIDR_EL1 value. On these clusters of up to four
+/// cores it's affinity level 0, the low byte, of which only 2 bits are used.
+pub const fn core_id_from_mpidr(mpidr: u64) -> u8 {
+    const CORE_MASK: u64 = 0b11;
+    (mpidr & CORE_MASK) as u8
+}
+
+/// Whether an MPIDR_EL1 value belongs to `bsp::cpu::boot_core_id()`.
+pub fn is_boot_core_mpidr(mpidr: u64) -> bool {
+    core_id_from_mpidr(mpidr) as usize == bsp::cpu::boot_core_id()
+}
+
+#[inline(always)]
+pub fn is_boot_core() -> bool {
+    is_boot_core_mpidr(MPIDR_EL1.get())
+}
 
 #[inline(always)]
 pub fn core_id<T>() -> T
 where
     T: From<u8>
 {
-    const CORE_MASK: u64 = 0b11;
-    T::from((MPIDR_EL1.get() & CORE_MASK) as u8)
+    T::from(core_id_from_mpidr(MPIDR_EL1.get()))
+}
+
+// Spin-table release addresses used by the firmware's armstub
+const RELEASE_ADDR: [usize; 4] = [0xD8, 0xE0, 0xE8, 0xF0];
+
+static CORE_ENTRY: [AtomicUsize; 4] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+static CORE_STACK: [AtomicUsize; 4] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+/// Releases a parked core to run `entry` on the stack ending at `stack_top`.
+///
+/// The release mailbox gets the address of `_start` rather than `entry`, so a
+/// core still held by the armstub also goes through the stack setup there.
+///
+/// # Safety
+///
+/// `stack_top` must be the top of memory nothing else uses, and each core may
+/// only be started once.
+pub unsafe fn start_core(core_id: u8, entry: fn() -> !, stack_top: usize) {
+    let id = core_id as usize;
+    assert!(id < bsp::cpu::CORE_COUNT && id != bsp::cpu::boot_core_id());
+
+    CORE_ENTRY[id].store(entry as usize, Ordering::Relaxed);
+    CORE_STACK[id].store(stack_top, Ordering::Relaxed);
+
+    // The parked core runs with its caches off, so everything it reads has to
+    // be pushed out of ours
+    cpu::cache::clean_and_invalidate_range(&CORE_ENTRY[id] as *const _ as usize, 8);
+    cpu::cache::clean_and_invalidate_range(&CORE_STACK[id] as *const _ as usize, 8);
+
+    ptr::write_volatile(RELEASE_ADDR[id] as *mut u64, cpu::_start as *const () as u64);
+    cpu::cache::clean_and_invalidate_range(RELEASE_ADDR[id], 8);
+    asm::sev();
+}
+
+/// Parks the calling core until `start_core` releases it, then returns the
+/// entry point and stack it was given.
+#[inline(always)]
+pub unsafe fn wait_for_release() -> (fn() -> !, usize) {
+    let id: usize = core_id::<u8>() as usize;
+
+    while ptr::read_volatile(RELEASE_ADDR[id] as *const u64) == 0 {
+        asm::wfe();
+    }
+    barrier::dsb(barrier::SY);
+
+    let entry = CORE_ENTRY[id].load(Ordering::Relaxed);
+    let stack_top = CORE_STACK[id].load(Ordering::Relaxed);
+    (core::mem::transmute::<usize, fn() -> !>(entry), stack_top)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn core_id_from_mpidr_reads_aff0() {
+        // Core 3 of a Cortex-A72 with the multiprocessor bit (31) set
+        assert_eq!(core_id_from_mpidr(0x8000_0003), 3);
+        // Aff1 is the cluster, not the core
+        assert_eq!(core_id_from_mpidr(0x8000_0100), 0);
+    }
+
+    #[test]
+    fn exactly_one_core_is_the_boot_core() {
+        // The multiprocessor bit and the higher affinity levels don't matter
+        for upper in [0, 0x8000_0000, 0x8000_0100] {
+            let boot: Vec<usize> =
+                (0..4).filter(|&core| is_boot_core_mpidr(upper | core as u64)).collect();
+            assert_eq!(boot, [bsp::cpu::boot_core_id()]);
+        }
+    }
 }
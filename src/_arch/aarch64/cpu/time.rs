@@ -0,0 +1,146 @@
+use crate::{bsp, cpu, cpu::time::interface};
+use cortex_a::{barrier, regs::*};
+
+// CNTP_TVAL_EL0 is a signed 32-bit down-counter
+const MAX_TVAL: u64 = i32::MAX as u64;
+
+pub struct GenericTimer;
+
+static TIME_MANAGER: GenericTimer = GenericTimer;
+
+pub fn time_manager() -> &'static impl interface::TimeManager {
+    &TIME_MANAGER
+}
+
+pub fn generic_timer() -> &'static GenericTimer {
+    &TIME_MANAGER
+}
+
+impl GenericTimer {
+    fn checked_frequency(&self) -> u64 {
+        // Only the low 32 bits of the register hold the frequency
+        match CNTFRQ_EL0.get() as u32 {
+            0 => panic!("CNTFRQ_EL0 is zero, the generic timer frequency was not set by firmware"),
+            freq => freq as u64,
+        }
+    }
+
+    #[inline(always)]
+    fn ticks(&self) -> u64 {
+        // Keep the counter read from being speculated ahead of earlier instructions
+        unsafe { barrier::isb(barrier::SY) };
+        CNTPCT_EL0.get()
+    }
+}
+
+/// Counter ticks in `ms` milliseconds at `freq` Hz
+pub const fn ms_to_ticks(freq: u64, ms: u32) -> u64 {
+    freq * ms as u64 / 1000
+}
+
+/// Counter ticks in `ns` nanoseconds at `freq` Hz, rounded up so a delay is
+/// never short. The product is taken in 128 bits, so it can't overflow, and
+/// a result past `u64::MAX` saturates.
+pub const fn ns_to_ticks(freq: u64, ns: u64) -> u64 {
+    let scaled = ns as u128 * freq as u128;
+    let rem = scaled % 1_000_000_000;
+    let ticks = scaled / 1_000_000_000 + (rem != 0) as u128;
+    if ticks > u64::MAX as u128 {
+        u64::MAX
+    } else {
+        ticks as u64
+    }
+}
+
+/// Busy-waits for at least `ns` nanoseconds on the counter, for short
+/// delays where `sleep_ms` is too coarse.
+///
+/// The wait starts part way through a tick, so it runs one tick past the
+/// rounded-up count to be sure.
+pub fn delay_ns(ns: u64) {
+    if ns == 0 {
+        return;
+    }
+
+    let delta = ns_to_ticks(TIME_MANAGER.checked_frequency(), ns);
+    let start = TIME_MANAGER.ticks();
+    // Not `spin_hint`, whose `wfe` could overshoot by an event stream period
+    while TIME_MANAGER.ticks().wrapping_sub(start) <= delta {
+        cpu::nop();
+    }
+}
+
+/// Sleeps in `wfi` for at least `ms` milliseconds, woken by this core's
+/// physical timer.
+///
+/// Call it with IRQs unmasked. The timer wakes the core either way, but with
+/// them masked every other interrupt, like UART input, waits out the sleep.
+pub fn sleep_ms(ms: u32) {
+    let mut ticks = ms_to_ticks(TIME_MANAGER.checked_frequency(), ms);
+
+    bsp::exception::enable_local_timer_irq();
+    while ticks > 0 {
+        let chunk = ticks.min(MAX_TVAL);
+        ticks -= chunk;
+
+        CNTP_TVAL_EL0.set(chunk);
+        CNTP_CTL_EL0.write(CNTP_CTL_EL0::ENABLE::SET + CNTP_CTL_EL0::IMASK::CLEAR);
+
+        // `handle_timer_irq` turns the timer off if it gets the IRQ first
+        while CNTP_CTL_EL0.is_set(CNTP_CTL_EL0::ENABLE)
+            && !CNTP_CTL_EL0.is_set(CNTP_CTL_EL0::ISTATUS)
+        {
+            cpu::wfi();
+        }
+    }
+    CNTP_CTL_EL0.set(0);
+}
+
+/// Turns the timer off if it's what raised the IRQ. It is core-local, so the
+/// IRQ manager never sees it.
+pub fn handle_timer_irq() {
+    if CNTP_CTL_EL0.matches_all(CNTP_CTL_EL0::ENABLE::SET + CNTP_CTL_EL0::ISTATUS::SET) {
+        CNTP_CTL_EL0.set(0);
+    }
+}
+
+impl interface::TimeManager for GenericTimer {
+    fn frequency(&self) -> Option<u32> {
+        // Only the low 32 bits of the register hold the frequency
+        match CNTFRQ_EL0.get() as u32 {
+            0 => None,
+            freq => Some(freq),
+        }
+    }
+
+    fn uptime_nanos(&self) -> u64 {
+        let freq = self.checked_frequency();
+        (self.ticks() as u128 * 1_000_000_000 / freq as u128) as u64
+    }
+
+    fn spin_for_micros(&self, us: u64) {
+        let freq = self.checked_frequency();
+        let delta = (us as u128 * freq as u128 / 1_000_000) as u64;
+        let start = self.ticks();
+
+        while self.ticks().wrapping_sub(start) < delta {}
+    }
+}
+
+// Reads 0 until the firmware has set the frequency, rather than panicking
+impl interface::Timer for GenericTimer {
+    fn now_nanos(&self) -> u64 {
+        use interface::TimeManager;
+
+        match self.frequency() {
+            Some(_) => self.uptime_nanos(),
+            None => 0,
+        }
+    }
+
+    fn spin_until(&self, deadline_nanos: u64) {
+        while self.now_nanos() < deadline_nanos {
+            cpu::spin_hint();
+        }
+    }
+}
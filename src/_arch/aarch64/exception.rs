@@ -0,0 +1,258 @@
+use crate::{bsp, cpu, exception, print::fmt::{Bin, Hex}};
+use core::fmt;
+use cortex_a::{barrier, regs::*};
+
+#[cfg(target_arch = "aarch64")]
+global_asm!(include_str!("exception.S"));
+
+/// Layout must match CALL_WITH_CONTEXT in exception.S
+#[repr(C)]
+pub struct ExceptionContext {
+    pub gpr: [u64; 30],
+    pub lr: u64,
+    pub elr_el1: u64,
+    pub spsr_el1: u64,
+    /// SP from before the context was pushed, so not restored
+    pub sp: u64,
+}
+
+// The 16 * 17 bytes CALL_WITH_CONTEXT reserves
+const _: [(); 16 * 17] = [(); core::mem::size_of::<ExceptionContext>()];
+
+impl ExceptionContext {
+    /// The registers at the point of the call, with ELR_EL1 and SPSR_EL1 as
+    /// they stand, for a dump from outside an exception handler
+    #[inline(always)]
+    pub fn capture() -> Self {
+        #[cfg_attr(not(target_arch = "aarch64"), allow(unused_mut))]
+        let mut e = Self {
+            gpr: [0; 30],
+            lr: 0,
+            elr_el1: ELR_EL1.get(),
+            spsr_el1: SPSR_EL1.get(),
+            sp: SP.get(),
+        };
+
+        #[cfg(target_arch = "aarch64")]
+        unsafe {
+            asm!(
+                "stp x0, x1, [{0}, #0]",
+                "stp x2, x3, [{0}, #16]",
+                "stp x4, x5, [{0}, #32]",
+                "stp x6, x7, [{0}, #48]",
+                "stp x8, x9, [{0}, #64]",
+                "stp x10, x11, [{0}, #80]",
+                "stp x12, x13, [{0}, #96]",
+                "stp x14, x15, [{0}, #112]",
+                "stp x16, x17, [{0}, #128]",
+                "stp x18, x19, [{0}, #144]",
+                "stp x20, x21, [{0}, #160]",
+                "stp x22, x23, [{0}, #176]",
+                "stp x24, x25, [{0}, #192]",
+                "stp x26, x27, [{0}, #208]",
+                "stp x28, x29, [{0}, #224]",
+                "str x30, [{0}, #240]",
+                in(reg) &mut e as *mut Self,
+                options(nostack)
+            );
+        }
+
+        e
+    }
+}
+
+fn exception_class_name(ec: u32) -> &'static str {
+    match ec {
+        0b00_0000 => "Unknown reason",
+        0b00_0001 => "Trapped WFI/WFE",
+        0b00_1110 => "Illegal execution state",
+        0b01_0101 => "SVC from AArch64",
+        0b01_1000 => "Trapped MSR/MRS",
+        0b10_0000 => "Instruction abort, lower EL",
+        0b10_0001 => "Instruction abort, current EL",
+        0b10_0010 => "PC alignment fault",
+        0b10_0100 => "Data abort, lower EL",
+        0b10_0101 => "Data abort, current EL",
+        0b10_0110 => "SP alignment fault",
+        0b11_1100 => "BRK instruction",
+        _ => "N/A",
+    }
+}
+
+fn report(w: &mut dyn fmt::Write, name: &str, e: &ExceptionContext) -> fmt::Result {
+    let esr = ESR_EL1.get();
+    let ec = ((esr >> 26) & 0x3F) as u32;
+
+    writeln!(w, "\nUnhandled exception: {}", name)?;
+    // EC in binary, the way the architecture lists the classes
+    writeln!(w, "ESR_EL1: {:#} (EC {:#}: {})", Hex(esr), Bin(ec as u8), exception_class_name(ec))?;
+    writeln!(w, "FAR_EL1: {:#}", Hex(FAR_EL1.get()))?;
+    cpu::dump_registers(w, e)?;
+
+    if let Some((sp, bounds)) = cpu::stack::check_overflow() {
+        writeln!(w, "Stack overflow: SP {:#x} outside {:#x}..{:#x}", sp, bounds.end, bounds.start)?;
+    }
+
+    Ok(())
+}
+
+// Goes through the panic console so a fault while the console is held still reports
+fn default_exception_handler(name: &str, e: &ExceptionContext) -> ! {
+    let mut out = unsafe { bsp::console::panic_console_out() };
+    let _ = report(&mut out, name, e);
+
+    cpu::halt()
+}
+
+#[no_mangle]
+unsafe extern "C" fn current_el0_synchronous(e: &mut ExceptionContext) {
+    default_exception_handler("current EL0, synchronous", e)
+}
+
+#[no_mangle]
+unsafe extern "C" fn current_el0_irq(e: &mut ExceptionContext) {
+    default_exception_handler("current EL0, IRQ", e)
+}
+
+#[no_mangle]
+unsafe extern "C" fn current_el0_fiq(e: &mut ExceptionContext) {
+    default_exception_handler("current EL0, FIQ", e)
+}
+
+#[no_mangle]
+unsafe extern "C" fn current_el0_serror(e: &mut ExceptionContext) {
+    default_exception_handler("current EL0, SError", e)
+}
+
+#[no_mangle]
+unsafe extern "C" fn current_elx_synchronous(e: &mut ExceptionContext) {
+    default_exception_handler("current ELx, synchronous", e)
+}
+
+#[no_mangle]
+unsafe extern "C" fn current_elx_irq(_e: &mut ExceptionContext) {
+    use exception::interface::IRQManager;
+
+    cpu::time::handle_timer_irq();
+    bsp::exception::irq_manager().dispatch();
+}
+
+#[no_mangle]
+unsafe extern "C" fn current_elx_fiq(e: &mut ExceptionContext) {
+    default_exception_handler("current ELx, FIQ", e)
+}
+
+#[no_mangle]
+unsafe extern "C" fn current_elx_serror(e: &mut ExceptionContext) {
+    default_exception_handler("current ELx, SError", e)
+}
+
+#[no_mangle]
+unsafe extern "C" fn lower_aarch64_synchronous(e: &mut ExceptionContext) {
+    default_exception_handler("lower EL, AArch64, synchronous", e)
+}
+
+#[no_mangle]
+unsafe extern "C" fn lower_aarch64_irq(e: &mut ExceptionContext) {
+    default_exception_handler("lower EL, AArch64, IRQ", e)
+}
+
+#[no_mangle]
+unsafe extern "C" fn lower_aarch64_fiq(e: &mut ExceptionContext) {
+    default_exception_handler("lower EL, AArch64, FIQ", e)
+}
+
+#[no_mangle]
+unsafe extern "C" fn lower_aarch64_serror(e: &mut ExceptionContext) {
+    default_exception_handler("lower EL, AArch64, SError", e)
+}
+
+#[no_mangle]
+unsafe extern "C" fn lower_aarch32_synchronous(e: &mut ExceptionContext) {
+    default_exception_handler("lower EL, AArch32, synchronous", e)
+}
+
+#[no_mangle]
+unsafe extern "C" fn lower_aarch32_irq(e: &mut ExceptionContext) {
+    default_exception_handler("lower EL, AArch32, IRQ", e)
+}
+
+#[no_mangle]
+unsafe extern "C" fn lower_aarch32_fiq(e: &mut ExceptionContext) {
+    default_exception_handler("lower EL, AArch32, FIQ", e)
+}
+
+#[no_mangle]
+unsafe extern "C" fn lower_aarch32_serror(e: &mut ExceptionContext) {
+    default_exception_handler("lower EL, AArch32, SError", e)
+}
+
+// The DAIF mask bits
+pub const DAIF_D: u64 = 1 << 9;
+pub const DAIF_A: u64 = 1 << 8;
+pub const DAIF_I: u64 = 1 << 7;
+pub const DAIF_F: u64 = 1 << 6;
+
+// Host tests have no DAIF, so each thread, standing in for a core, gets a
+// plain value instead
+#[cfg(not(target_arch = "aarch64"))]
+std::thread_local! {
+    static HOST_DAIF: core::cell::Cell<u64> = core::cell::Cell::new(0);
+}
+
+/// This core's DAIF
+pub fn daif() -> u64 {
+    #[cfg(target_arch = "aarch64")]
+    {
+        DAIF.get()
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    HOST_DAIF.with(|d| d.get())
+}
+
+pub unsafe fn set_daif(daif: u64) {
+    #[cfg(target_arch = "aarch64")]
+    DAIF.set(daif);
+
+    #[cfg(not(target_arch = "aarch64"))]
+    HOST_DAIF.with(|d| d.set(daif));
+}
+
+pub unsafe fn local_irq_unmask() {
+    set_daif(daif() & !DAIF_I);
+}
+
+pub unsafe fn local_irq_mask() {
+    set_daif(daif() | DAIF_I);
+}
+
+/// Masks IRQs and FIQs, returning the DAIF value from before for
+/// `local_irq_restore`
+pub unsafe fn local_irq_save() -> u64 {
+    let daif = daif();
+    set_daif(daif | DAIF_I | DAIF_F);
+    daif
+}
+
+pub unsafe fn local_irq_restore(daif: u64) {
+    set_daif(daif);
+}
+
+pub unsafe fn init() {
+    extern "C" {
+        static __exception_vector_start: u64;
+    }
+
+    VBAR_EL1.set(&__exception_vector_start as *const _ as u64);
+
+    // Make sure the new vectors are used from the next instruction on
+    barrier::isb(barrier::SY);
+}
+
+/// Reads an address outside the physical address space to check that the
+/// synchronous exception handler fires.
+pub unsafe fn trigger_synchronous_exception() {
+    let bogus = 0xFFFF_FFFF_FFFF_F000 as *const u64;
+    core::ptr::read_volatile(bogus);
+}
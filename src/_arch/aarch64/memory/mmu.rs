@@ -0,0 +1,254 @@
+use crate::{bsp, cpu, memory::mmu::MemAttributes};
+use core::{ops::Range, ptr};
+use cortex_a::{barrier, regs::*};
+use register::register_bitfields;
+
+register_bitfields! {
+    u64,
+
+    // Level 1 descriptor pointing to a level 2 table
+    TABLE_DESCRIPTOR [
+        NEXT_LEVEL_TABLE_ADDR OFFSET(12) NUMBITS(36) [],
+        TYPE OFFSET(1) NUMBITS(1) [
+            Block = 0,
+            Table = 1
+        ],
+        VALID OFFSET(0) NUMBITS(1) []
+    ],
+
+    // Level 2 descriptor mapping a 2 MiB block
+    BLOCK_DESCRIPTOR [
+        UXN OFFSET(54) NUMBITS(1) [],
+        PXN OFFSET(53) NUMBITS(1) [],
+        OUTPUT_ADDR OFFSET(21) NUMBITS(27) [],
+        // Access flag
+        AF OFFSET(10) NUMBITS(1) [],
+        SH OFFSET(8) NUMBITS(2) [
+            OuterShareable = 0b10,
+            InnerShareable = 0b11
+        ],
+        // EL1 read-write, no EL0 access
+        AP OFFSET(6) NUMBITS(2) [
+            RW_EL1 = 0b00
+        ],
+        // Index into MAIR_EL1
+        ATTR_INDEX OFFSET(2) NUMBITS(3) [],
+        TYPE OFFSET(1) NUMBITS(1) [
+            Block = 0,
+            Table = 1
+        ],
+        VALID OFFSET(0) NUMBITS(1) []
+    ]
+}
+
+const MAIR_DEVICE: u64 = 0;
+const MAIR_NORMAL: u64 = 1;
+const MAIR_NORMAL_NON_CACHEABLE: u64 = 2;
+
+const BLOCK_SHIFT: usize = 21;
+/// The granularity of the mapping: attributes apply to whole blocks
+pub const BLOCK_SIZE: usize = 1 << BLOCK_SHIFT;
+const ENTRIES_PER_TABLE: usize = 512;
+
+// 4 GiB of address space is enough for the RAM and peripherals of both
+// boards: 4 level 1 entries of 1 GiB, each split into 2 MiB blocks.
+const L1_ENTRIES: usize = 4;
+const ADDRESS_SPACE_BITS: u64 = 32;
+
+#[repr(C, align(4096))]
+struct TranslationTables {
+    l2: [[u64; ENTRIES_PER_TABLE]; L1_ENTRIES],
+    l1: [u64; ENTRIES_PER_TABLE],
+}
+
+static mut TABLES: TranslationTables = TranslationTables {
+    l2: [[0; ENTRIES_PER_TABLE]; L1_ENTRIES],
+    l1: [0; ENTRIES_PER_TABLE],
+};
+
+fn table_descriptor(next_level_table: usize) -> u64 {
+    (TABLE_DESCRIPTOR::NEXT_LEVEL_TABLE_ADDR.val(next_level_table as u64 >> 12)
+        + TABLE_DESCRIPTOR::TYPE::Table
+        + TABLE_DESCRIPTOR::VALID::SET)
+        .value
+}
+
+fn block_descriptor(output_addr: usize, attributes: MemAttributes) -> u64 {
+    let common = BLOCK_DESCRIPTOR::OUTPUT_ADDR.val(output_addr as u64 >> BLOCK_SHIFT)
+        + BLOCK_DESCRIPTOR::AF::SET
+        + BLOCK_DESCRIPTOR::AP::RW_EL1
+        + BLOCK_DESCRIPTOR::TYPE::Block
+        + BLOCK_DESCRIPTOR::VALID::SET;
+
+    let attributes = match attributes {
+        MemAttributes::Device => {
+            BLOCK_DESCRIPTOR::SH::OuterShareable
+                + BLOCK_DESCRIPTOR::ATTR_INDEX.val(MAIR_DEVICE)
+                + BLOCK_DESCRIPTOR::PXN::SET
+                + BLOCK_DESCRIPTOR::UXN::SET
+        }
+        MemAttributes::Normal => {
+            BLOCK_DESCRIPTOR::SH::InnerShareable
+                + BLOCK_DESCRIPTOR::ATTR_INDEX.val(MAIR_NORMAL)
+                + BLOCK_DESCRIPTOR::UXN::SET
+        }
+        MemAttributes::NormalNonCacheable => {
+            BLOCK_DESCRIPTOR::SH::OuterShareable
+                + BLOCK_DESCRIPTOR::ATTR_INDEX.val(MAIR_NORMAL_NON_CACHEABLE)
+                + BLOCK_DESCRIPTOR::PXN::SET
+                + BLOCK_DESCRIPTOR::UXN::SET
+        }
+    };
+
+    (common + attributes).value
+}
+
+fn block_entry(addr: usize) -> u64 {
+    match bsp::memory::memory_attributes(addr) {
+        Some(attributes) => block_descriptor(addr, attributes),
+        None => 0,
+    }
+}
+
+unsafe fn populate_tables() {
+    let tables = &mut *ptr::addr_of_mut!(TABLES);
+
+    for (i, l2) in tables.l2.iter_mut().enumerate() {
+        for (j, entry) in l2.iter_mut().enumerate() {
+            *entry = block_entry((i * ENTRIES_PER_TABLE + j) << BLOCK_SHIFT);
+        }
+        tables.l1[i] = table_descriptor(l2 as *const _ as usize);
+    }
+}
+
+/// Rebuilds the blocks covering `range` from the BSP's attributes, for memory
+/// whose attributes are only known once the system is up. The range is
+/// written back out of the caches first, in case it turns uncached.
+///
+/// The blocks are unmapped while they change, so nothing may access them
+/// meanwhile, from any core.
+pub unsafe fn remap(range: Range<usize>) {
+    if range.start >= range.end {
+        return;
+    }
+    cpu::cache::clean_and_invalidate_range(range.start, range.end - range.start);
+
+    let blocks = (range.start >> BLOCK_SHIFT)..((range.end - 1) >> BLOCK_SHIFT) + 1;
+    let blocks = blocks.start..blocks.end.min(L1_ENTRIES * ENTRIES_PER_TABLE);
+    let entry = |block: usize| -> *mut u64 {
+        ptr::addr_of_mut!(TABLES.l2[block / ENTRIES_PER_TABLE][block % ENTRIES_PER_TABLE])
+    };
+
+    // Break before make, so no core ever sees both the old and new attributes
+    for block in blocks.clone() {
+        ptr::write_volatile(entry(block), 0);
+    }
+    barrier::dsb(barrier::ISHST);
+    #[cfg(target_arch = "aarch64")]
+    asm!("tlbi vmalle1is", options(nostack));
+    barrier::dsb(barrier::ISH);
+
+    for block in blocks {
+        ptr::write_volatile(entry(block), block_entry(block << BLOCK_SHIFT));
+    }
+    barrier::dsb(barrier::ISHST);
+    barrier::isb(barrier::SY);
+}
+
+fn set_up_mair() {
+    MAIR_EL1.write(
+        MAIR_EL1::Attr2_Normal_Outer::NonCacheable
+            + MAIR_EL1::Attr2_Normal_Inner::NonCacheable
+            + MAIR_EL1::Attr1_Normal_Outer::WriteBack_NonTransient_ReadWriteAlloc
+            + MAIR_EL1::Attr1_Normal_Inner::WriteBack_NonTransient_ReadWriteAlloc
+            + MAIR_EL1::Attr0_Device::nonGathering_nonReordering_EarlyWriteAck,
+    );
+}
+
+// The tables are laid out for 4 KiB granules, and outputs may be as wide as
+// the core's physical addresses
+fn configure_translation_control() {
+    let features = cpu::features::features();
+    if !features.granule_4k {
+        panic!("MMU: CPU lacks the 4 KiB translation granule");
+    }
+    if cpu::features::pa_bits(features.pa_range).is_none() {
+        panic!("MMU: reserved PARange {:#x}", features.pa_range);
+    }
+
+    TCR_EL1.write(
+        TCR_EL1::TBI0::Ignored
+            + TCR_EL1::IPS.val(features.pa_range as u64)
+            + TCR_EL1::TG0::KiB_4
+            + TCR_EL1::SH0::Inner
+            + TCR_EL1::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+            + TCR_EL1::IRGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+            + TCR_EL1::EPD0::EnableTTBR0Walks
+            + TCR_EL1::EPD1::DisableTTBR1Walks
+            + TCR_EL1::T0SZ.val(64 - ADDRESS_SPACE_BITS),
+    );
+}
+
+/// Identity-maps RAM as cacheable Normal memory, apart from the BSP's DMA
+/// region, and the peripherals as Device-nGnRE, then turns on the MMU and
+/// caches for the calling core.
+///
+/// The boot core builds the tables and must call this before releasing the
+/// secondary cores, which then only program their own registers.
+pub unsafe fn enable() {
+    if cpu::smp::is_boot_core() {
+        populate_tables();
+    }
+
+    set_up_mair();
+    TTBR0_EL1.set_baddr(ptr::addr_of!(TABLES.l1) as u64);
+    configure_translation_control();
+
+    // Drop any stale translations before the tables go live
+    #[cfg(target_arch = "aarch64")]
+    asm!("tlbi vmalle1", options(nostack));
+    barrier::dsb(barrier::ISH);
+    barrier::isb(barrier::SY);
+
+    SCTLR_EL1.modify(SCTLR_EL1::M::Enable + SCTLR_EL1::C::Cacheable + SCTLR_EL1::I::Cacheable);
+
+    barrier::isb(barrier::SY);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_descriptor_points_at_the_table() {
+        assert_eq!(table_descriptor(0x9_3000), 0x9_3000 | 0b11);
+    }
+
+    #[test]
+    fn block_descriptor_attributes() {
+        // AF, block, valid
+        const COMMON: u64 = (1 << 10) | 0b01;
+        const XN: u64 = (1 << 54) | (1 << 53);
+
+        assert_eq!(
+            block_descriptor(0x3F20_0000, MemAttributes::Device),
+            0x3F20_0000 | COMMON | XN | (0b10 << 8) | (MAIR_DEVICE << 2)
+        );
+        assert_eq!(
+            block_descriptor(0x20_0000, MemAttributes::Normal),
+            0x20_0000 | COMMON | (1 << 54) | (0b11 << 8) | (MAIR_NORMAL << 2)
+        );
+        assert_eq!(
+            block_descriptor(0x40_0000, MemAttributes::NormalNonCacheable),
+            0x40_0000 | COMMON | XN | (0b10 << 8) | (MAIR_NORMAL_NON_CACHEABLE << 2)
+        );
+    }
+
+    #[test]
+    fn block_descriptor_drops_the_offset_into_the_block() {
+        assert_eq!(
+            block_descriptor(0x20_1234, MemAttributes::Normal),
+            block_descriptor(0x20_0000, MemAttributes::Normal)
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use crate::{bsp, console, println, synchronization, synchronization::NullLock};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+// Stages reached before there was a console to print them on
+const MAX_PENDING: usize = 8;
+
+struct Pending {
+    stages: [(&'static str, Elapsed); MAX_PENDING],
+    count: usize,
+}
+
+static LAST_STAGE_NANOS: AtomicU64 = AtomicU64::new(0);
+static PENDING: NullLock<Pending> = NullLock::new(Pending {
+    stages: [("", Elapsed(0)); MAX_PENDING],
+    count: 0,
+});
+
+/// A time span in nanoseconds, shown as "+2.3ms" or, from a second up,
+/// "+1.250s"
+#[derive(Copy, Clone)]
+pub struct Elapsed(pub u64);
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ns = self.0;
+        if ns < 1_000_000_000 {
+            write!(f, "+{}.{}ms", ns / 1_000_000, ns % 1_000_000 / 100_000)
+        } else {
+            write!(
+                f,
+                "+{}.{:03}s",
+                ns / 1_000_000_000,
+                ns % 1_000_000_000 / 1_000_000
+            )
+        }
+    }
+}
+
+use synchronization::interface::Mutex;
+
+/// Marks the end of a boot phase and prints how long it took since the last
+/// one, or since reset for the first. Stages reached before the console is up
+/// are held back and printed with the first one after. Boot runs on one core,
+/// so this is not for the secondaries.
+pub fn stage(name: &'static str) {
+    let now = bsp::time::timer().now_nanos();
+    let elapsed = Elapsed(now.saturating_sub(LAST_STAGE_NANOS.swap(now, Ordering::Relaxed)));
+
+    let mut r = &PENDING;
+    r.lock(|pending| {
+        if !console::is_live() {
+            // Dropped once full, the timestamps stay right either way
+            if pending.count < MAX_PENDING {
+                pending.stages[pending.count] = (name, elapsed);
+                pending.count += 1;
+            }
+            return;
+        }
+
+        for &(name, elapsed) in pending.stages[..pending.count].iter() {
+            println!("[{}] {}", elapsed, name);
+        }
+        pending.count = 0;
+
+        println!("[{}] {}", elapsed, name);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::format;
+
+    #[test]
+    fn elapsed_under_a_second_in_tenths_of_a_millisecond() {
+        assert_eq!(format!("{}", Elapsed(0)), "+0.0ms");
+        assert_eq!(format!("{}", Elapsed(99_999)), "+0.0ms");
+        assert_eq!(format!("{}", Elapsed(450_000)), "+0.4ms");
+        assert_eq!(format!("{}", Elapsed(2_345_678)), "+2.3ms");
+        assert_eq!(format!("{}", Elapsed(999_999_999)), "+999.9ms");
+    }
+
+    #[test]
+    fn elapsed_from_a_second_in_milliseconds() {
+        assert_eq!(format!("{}", Elapsed(1_000_000_000)), "+1.000s");
+        assert_eq!(format!("{}", Elapsed(1_250_000_000)), "+1.250s");
+        assert_eq!(format!("{}", Elapsed(62_007_900_000)), "+62.007s");
+    }
+}
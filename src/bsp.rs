@@ -1,4 +1,5 @@
 mod device_driver;
+pub mod fdt;
 
 #[cfg(any(feature = "bsp_rpi3", feature = "bsp_rpi4"))]
 mod raspberrypi;
@@ -1,5 +1,32 @@
+mod bcm2xxx_activity_led;
+mod bcm2xxx_bsc;
+mod bcm2xxx_dma;
+mod bcm2xxx_emmc;
+mod bcm2xxx_framebuffer;
 mod bcm2xxx_gpio;
+mod bcm2xxx_interrupt_controller;
+mod bcm2xxx_mailbox;
+mod bcm2xxx_mini_uart;
 mod bcm2xxx_pl011_uart;
+mod bcm2xxx_pwm;
+mod bcm2xxx_reset_button;
+mod bcm2xxx_spi;
+mod bcm2xxx_system_timer;
+mod bcm2xxx_watchdog;
+mod font8x8;
 
+pub use bcm2xxx_activity_led::*;
+pub use bcm2xxx_bsc::*;
+pub use bcm2xxx_dma::*;
+pub use bcm2xxx_emmc::*;
+pub use bcm2xxx_framebuffer::*;
 pub use bcm2xxx_gpio::*;
-pub use bcm2xxx_pl011_uart::*;
\ No newline at end of file
+pub use bcm2xxx_interrupt_controller::*;
+pub use bcm2xxx_mailbox::*;
+pub use bcm2xxx_mini_uart::*;
+pub use bcm2xxx_pl011_uart::*;
+pub use bcm2xxx_pwm::*;
+pub use bcm2xxx_reset_button::*;
+pub use bcm2xxx_spi::*;
+pub use bcm2xxx_system_timer::*;
+pub use bcm2xxx_watchdog::*;
\ No newline at end of file
@@ -0,0 +1,143 @@
+use super::{Function, SystemTimer, GPIO};
+use crate::{cpu, driver, driver::DriverError, exception};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// The ARM's free system timer channels are 1 and 3
+const TIMER_CHANNEL: usize = 1;
+const BLINK_PERIOD_US: u32 = 1_000_000;
+
+/// A GPIO-driven, active-high LED. As the handler of its system timer
+/// channel's match IRQ it blinks itself, once the first match is armed with
+/// `start_heartbeat`.
+pub struct ActivityLed {
+    pin: u32,
+    on: AtomicBool,
+    gpio: &'static GPIO,
+    timer: &'static SystemTimer,
+}
+
+impl ActivityLed {
+    pub const unsafe fn new(pin: u32, gpio: &'static GPIO, timer: &'static SystemTimer) -> Self {
+        Self {
+            pin,
+            on: AtomicBool::new(false),
+            gpio,
+            timer,
+        }
+    }
+
+    fn set(&self, on: bool) {
+        let ret = if on {
+            self.gpio.set_high(self.pin)
+        } else {
+            self.gpio.set_low(self.pin)
+        };
+        // The pin was checked by `init`
+        if ret.is_ok() {
+            self.on.store(on, Ordering::Relaxed);
+        }
+    }
+
+    pub fn on(&self) {
+        self.set(true);
+    }
+
+    pub fn off(&self) {
+        self.set(false);
+    }
+
+    pub fn toggle(&self) {
+        self.set(!self.is_on());
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on.load(Ordering::Relaxed)
+    }
+
+    fn arm(&self) {
+        let now = self.timer.now_micros() as u32;
+        self.timer.set_compare(TIMER_CHANNEL, now.wrapping_add(BLINK_PERIOD_US));
+    }
+
+    /// Arms the first timer match. The IRQ has to be routed to `self` by then.
+    pub fn start_heartbeat(&self) {
+        self.arm();
+    }
+}
+
+impl driver::interface::DeviceDriver for ActivityLed {
+    fn name(&self) -> &str {
+        "BCM Activity LED"
+    }
+
+    fn compatible(&self) -> &str {
+        "gpio-leds"
+    }
+
+    fn dependencies(&self) -> &[&str] {
+        &["brcm,bcm2835-gpio", "brcm,bcm2835-system-timer"]
+    }
+
+    fn init(&self) -> Result<(), DriverError> {
+        self.gpio.set_function(self.pin, Function::Output)?;
+        self.set(false);
+
+        Ok(())
+    }
+}
+
+impl exception::interface::IRQHandler for ActivityLed {
+    // Toggles on every match, so the LED spends a period on, then one off
+    fn handle(&self) {
+        if self.timer.ack(TIMER_CHANNEL) {
+            // The LED is on GPIO, between two system timer accesses
+            cpu::barrier::dsb();
+            self.toggle();
+            cpu::barrier::dsb();
+            self.arm();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::device_driver::mmio::fake_registers;
+    use core::sync::atomic::AtomicU32;
+    use std::boxed::Box;
+
+    fn fake_led(pin: u32) -> (&'static [AtomicU32], ActivityLed) {
+        // GPIO's register block, up to GPIO_PUP_PDN_CNTRL
+        let regs = fake_registers::<[u32; 0xF4 / 4]>();
+        let timer = Box::leak(Box::new(unsafe { SystemTimer::new(0) }));
+        let gpio = Box::leak(Box::new(unsafe { GPIO::new(regs.as_ptr() as usize, timer) }));
+
+        (regs, unsafe { ActivityLed::new(pin, gpio, timer) })
+    }
+
+    #[test]
+    fn tracks_what_it_last_drove_the_pin_to() {
+        let (regs, led) = fake_led(29);
+
+        assert!(!led.is_on());
+        led.on();
+        assert!(led.is_on());
+        // GPSET0
+        assert_eq!(regs[0x1C / 4].load(Ordering::Relaxed), 1 << 29);
+        led.toggle();
+        assert!(!led.is_on());
+        // GPCLR0
+        assert_eq!(regs[0x28 / 4].load(Ordering::Relaxed), 1 << 29);
+        led.toggle();
+        led.off();
+        assert!(!led.is_on());
+    }
+
+    #[test]
+    fn a_bad_pin_stays_off() {
+        let (_, led) = fake_led(99);
+
+        led.on();
+        assert!(!led.is_on());
+    }
+}
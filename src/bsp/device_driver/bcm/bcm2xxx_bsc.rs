@@ -0,0 +1,324 @@
+use super::{clock, Function, Mailbox, SystemTimer, GPIO};
+use crate::{
+    cpu,
+    cpu::time::deadline_passed,
+    driver,
+    driver::DriverError,
+    synchronization,
+    synchronization::NullLock,
+};
+use core::{
+    ops,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use register::{mmio::*, register_bitfields, register_structs};
+
+register_bitfields! {
+    u32,
+
+    // Control
+    C [
+        I2CEN OFFSET(15) NUMBITS(1) [],
+        // Start transfer
+        ST OFFSET(7) NUMBITS(1) [],
+        CLEAR OFFSET(4) NUMBITS(2) [
+            ClearFifo = 0b01
+        ],
+        READ OFFSET(0) NUMBITS(1) [
+            Write = 0,
+            Read = 1
+        ]
+    ],
+
+    // Status, the error and DONE bits are write 1 to clear
+    S [
+        // Slave held SCL stretched for too long
+        CLKT OFFSET(9) NUMBITS(1) [],
+        // Slave didn't acknowledge its address or data
+        ERR OFFSET(8) NUMBITS(1) [],
+        // FIFO has data to read
+        RXD OFFSET(5) NUMBITS(1) [],
+        // FIFO has room to write
+        TXD OFFSET(4) NUMBITS(1) [],
+        DONE OFFSET(1) NUMBITS(1) [],
+        // Transfer active
+        TA OFFSET(0) NUMBITS(1) []
+    ]
+}
+
+register_structs! {
+    #[allow(non_snake_case)]
+    RegisterBlock {
+        (0x00 => C: ReadWrite<u32, C::Register>),
+        (0x04 => S: ReadWrite<u32, S::Register>),
+        (0x08 => DLEN: ReadWrite<u32>),
+        (0x0C => A: ReadWrite<u32>),
+        (0x10 => FIFO: ReadWrite<u32>),
+        (0x14 => DIV: ReadWrite<u32>),
+        (0x18 => _reserved1),
+        (0x20 => @END),
+    }
+}
+
+const MAX_TRANSFER_LEN: usize = 0xffff;
+
+// Generous for 64 KiB at 100 kHz, and only hit if the bus is wedged
+const TRANSFER_TIMEOUT_US: u64 = 10_000_000;
+
+/// The clock divider for SCL at no more than `bus_hz`. Unlike SPI's CDIV,
+/// DIV divides by odd values as written.
+pub fn bsc_divider(core_clk_hz: u32, bus_hz: u32) -> Result<u32, DriverError> {
+    if bus_hz == 0 {
+        return Err(DriverError::InvalidConfig);
+    }
+
+    // Rounded up, without an add that could overflow
+    let rem = core_clk_hz % bus_hz;
+    let div = core_clk_hz / bus_hz + (rem != 0) as u32;
+
+    // 0 would mean 32768, keep to what the register holds as written
+    if !(2..=0xffff).contains(&div) {
+        return Err(DriverError::InvalidConfig);
+    }
+
+    Ok(div)
+}
+
+struct BSCInner {
+    base_addr: usize,
+}
+
+/// An I2C master. Transfers are polled, one at a time.
+pub struct BSC {
+    inner: NullLock<BSCInner>,
+    sda: u32,
+    scl: u32,
+    bus_hz: u32,
+    gpio: &'static GPIO,
+    mailbox: &'static Mailbox,
+    timer: &'static SystemTimer,
+    enabled: AtomicBool,
+}
+
+impl ops::Deref for BSCInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl BSCInner {
+    const fn new(base_addr: usize) -> Self {
+        Self { base_addr }
+    }
+
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    fn start(&mut self, addr: u8, len: usize, read: bool) -> Result<(), DriverError> {
+        if addr > 0x7f || len == 0 || len > MAX_TRANSFER_LEN {
+            return Err(DriverError::InvalidConfig);
+        }
+
+        self.C.write(C::CLEAR::ClearFifo);
+        self.S.write(S::CLKT::SET + S::ERR::SET + S::DONE::SET);
+        self.A.set(addr as u32);
+        self.DLEN.set(len as u32);
+
+        let direction = if read { C::READ::Read } else { C::READ::Write };
+        self.C.write(C::I2CEN::SET + C::ST::SET + direction);
+
+        Ok(())
+    }
+
+    // Errors abort the transfer, so they also mean it's over
+    fn status(&self) -> Result<bool, DriverError> {
+        let s = self.S.extract();
+        if s.is_set(S::ERR) {
+            return Err(DriverError::Nack);
+        }
+        if s.is_set(S::CLKT) {
+            return Err(DriverError::HardwareTimeout);
+        }
+
+        Ok(s.is_set(S::DONE))
+    }
+
+    fn finish(&mut self, ret: Result<(), DriverError>) -> Result<(), DriverError> {
+        self.S.write(S::CLKT::SET + S::ERR::SET + S::DONE::SET);
+        self.C.write(C::I2CEN::SET + C::CLEAR::ClearFifo);
+
+        ret
+    }
+
+    fn write(&mut self, timer: &SystemTimer, addr: u8, data: &[u8]) -> Result<(), DriverError> {
+        self.start(addr, data.len(), false)?;
+
+        let start = timer.now_micros();
+        let mut sent = 0;
+        let ret = loop {
+            while sent < data.len() && self.S.is_set(S::TXD) {
+                self.FIFO.set(data[sent] as u32);
+                sent += 1;
+            }
+
+            match self.status() {
+                Ok(true) => break Ok(()),
+                Ok(false) => {}
+                Err(e) => break Err(e),
+            }
+            if deadline_passed(start, timer.now_micros(), TRANSFER_TIMEOUT_US) {
+                break Err(DriverError::HardwareTimeout);
+            }
+            cpu::spin_hint();
+        };
+
+        self.finish(ret)
+    }
+
+    fn read(&mut self, timer: &SystemTimer, addr: u8, buf: &mut [u8]) -> Result<(), DriverError> {
+        self.start(addr, buf.len(), true)?;
+
+        let start = timer.now_micros();
+        let mut received = 0;
+        let ret = loop {
+            while received < buf.len() && self.S.is_set(S::RXD) {
+                buf[received] = self.FIFO.get() as u8;
+                received += 1;
+            }
+
+            match self.status() {
+                // The last bytes can still be in the FIFO when DONE is set
+                Ok(true) if received == buf.len() => break Ok(()),
+                Ok(_) => {}
+                Err(e) => break Err(e),
+            }
+            if deadline_passed(start, timer.now_micros(), TRANSFER_TIMEOUT_US) {
+                break Err(DriverError::HardwareTimeout);
+            }
+            cpu::spin_hint();
+        };
+
+        self.finish(ret)
+    }
+}
+
+impl BSC {
+    /// A BSC master on pins `sda`/`scl`, which must carry it as `Alt0`
+    pub const unsafe fn new(
+        base_addr: usize,
+        (sda, scl): (u32, u32),
+        bus_hz: u32,
+        gpio: &'static GPIO,
+        mailbox: &'static Mailbox,
+        timer: &'static SystemTimer,
+    ) -> Self {
+        Self {
+            inner: NullLock::new(BSCInner::new(base_addr)),
+            sda,
+            scl,
+            bus_hz,
+            gpio,
+            mailbox,
+            timer,
+            enabled: AtomicBool::new(true),
+        }
+    }
+
+    /// Whether the driver manager should initialize it, and so take over
+    /// the SDA and SCL pins. Set before `init`.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub unsafe fn set_base_addr(&self, base_addr: usize) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.base_addr = base_addr);
+    }
+
+    /// Writes `data` to the 7-bit address `addr`. A missing or unwilling
+    /// device shows up as `DriverError::Nack`.
+    pub fn write(&self, addr: u8, data: &[u8]) -> Result<(), DriverError> {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return Err(DriverError::Unsupported);
+        }
+
+        let mut r = &self.inner;
+        r.lock(|inner| inner.write(self.timer, addr, data))
+    }
+
+    /// Fills `buf` from the 7-bit address `addr`
+    pub fn read(&self, addr: u8, buf: &mut [u8]) -> Result<(), DriverError> {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return Err(DriverError::Unsupported);
+        }
+
+        let mut r = &self.inner;
+        r.lock(|inner| inner.read(self.timer, addr, buf))
+    }
+}
+
+use synchronization::interface::Mutex;
+
+impl driver::interface::DeviceDriver for BSC {
+    fn name(&self) -> &str {
+        "BCM BSC"
+    }
+
+    fn compatible(&self) -> &str {
+        "brcm,bcm2835-i2c"
+    }
+
+    // The divider runs off the core clock, which the mailbox reports
+    fn dependencies(&self) -> &[&str] {
+        &["brcm,bcm2835-gpio", "brcm,bcm2835-mbox", "brcm,bcm2835-system-timer"]
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    fn init(&self) -> Result<(), DriverError> {
+        let core_clk_hz = self
+            .mailbox
+            .get_clock_rate(clock::CORE)
+            .map_err(|_| DriverError::HardwareTimeout)?;
+        let div = bsc_divider(core_clk_hz, self.bus_hz)?;
+
+        self.gpio.set_function(self.sda, Function::Alt0)?;
+        self.gpio.set_function(self.scl, Function::Alt0)?;
+
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.DIV.set(div);
+            inner.C.write(C::I2CEN::SET + C::CLEAR::ClearFifo);
+            inner.S.write(S::CLKT::SET + S::ERR::SET + S::DONE::SET);
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bsc_divider_keeps_odd_divisors() {
+        assert_eq!(bsc_divider(250_000_000, 100_000), Ok(2500));
+        assert_eq!(bsc_divider(150_000_000, 400_000), Ok(375));
+        // Rounded up, so SCL stays at or under the requested rate
+        assert_eq!(bsc_divider(250_000_000, 400_000), Ok(625));
+        assert_eq!(bsc_divider(250_000_000, 3_000_000), Ok(84));
+    }
+
+    #[test]
+    fn bsc_divider_out_of_range() {
+        assert_eq!(bsc_divider(250_000_000, 0), Err(DriverError::InvalidConfig));
+        assert_eq!(bsc_divider(250_000_000, 250_000_000), Err(DriverError::InvalidConfig));
+        assert_eq!(bsc_divider(250_000_000, 1_000), Err(DriverError::InvalidConfig));
+        assert_eq!(bsc_divider(u32::MAX, u32::MAX - 1), Ok(2));
+    }
+}
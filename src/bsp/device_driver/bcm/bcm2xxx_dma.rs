@@ -0,0 +1,328 @@
+use super::SystemTimer;
+use crate::{
+    cpu,
+    cpu::time::deadline_passed,
+    driver,
+    driver::DriverError,
+    memory::VirtualAddress,
+    synchronization,
+    synchronization::NullLock,
+};
+use core::{
+    mem, ops,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use register::{mmio::*, register_bitfields, register_structs};
+
+register_bitfields! {
+    u32,
+
+    // Control and status
+    CS [
+        RESET OFFSET(31) NUMBITS(1) [],
+        ABORT OFFSET(30) NUMBITS(1) [],
+        // Wait for the last write to be acknowledged before signalling END
+        WAIT_FOR_OUTSTANDING_WRITES OFFSET(28) NUMBITS(1) [],
+        PANIC_PRIORITY OFFSET(20) NUMBITS(4) [],
+        PRIORITY OFFSET(16) NUMBITS(4) [],
+        ERROR OFFSET(8) NUMBITS(1) [],
+        // Interrupt status, write 1 to clear
+        INT OFFSET(2) NUMBITS(1) [],
+        // Transfer complete, write 1 to clear
+        END OFFSET(1) NUMBITS(1) [],
+        ACTIVE OFFSET(0) NUMBITS(1) []
+    ],
+
+    // Transfer information, the first word of a control block
+    TI [
+        // Peripheral whose DREQ paces the transfer
+        PERMAP OFFSET(16) NUMBITS(5) [],
+        SRC_INC OFFSET(8) NUMBITS(1) [],
+        DEST_DREQ OFFSET(6) NUMBITS(1) [],
+        DEST_INC OFFSET(4) NUMBITS(1) [],
+        // Wait for a write response before the next write
+        WAIT_RESP OFFSET(3) NUMBITS(1) [],
+        INTEN OFFSET(0) NUMBITS(1) []
+    ]
+}
+
+const CHANNELS: usize = 15;
+
+register_structs! {
+    #[allow(non_snake_case)]
+    ChannelRegisterBlock {
+        (0x00 => CS: ReadWrite<u32, CS::Register>),
+        (0x04 => CONBLK_AD: ReadWrite<u32>),
+        (0x08 => _reserved1),
+        (0x100 => @END),
+    },
+
+    #[allow(non_snake_case)]
+    RegisterBlock {
+        (0x000 => CHANNEL: [ChannelRegisterBlock; CHANNELS]),
+        (0xF00 => _reserved1),
+        (0xFF0 => ENABLE: ReadWrite<u32>),
+        (0xFF4 => @END),
+    }
+}
+
+/// DREQ number of the PL011 transmitter, for `TI::PERMAP`
+pub const DREQ_UART_TX: u32 = 12;
+
+// The DMA engine sees RAM through the uncached bus alias and the peripherals
+// at their VideoCore bus addresses.
+const BUS_RAM_ALIAS: u32 = 0xC000_0000;
+pub const BUS_PERIPHERAL_BASE: u32 = 0x7E00_0000;
+
+// A full transmit buffer takes under 50 ms at 230400 baud. The rest is for
+// flow control holding the line.
+const TRANSFER_TIMEOUT_US: u64 = 1_000_000;
+
+/// Bus address of `addr` in RAM, as the DMA engine sees it. The engine
+/// doesn't go through the MMU.
+pub fn ram_bus_addr(addr: VirtualAddress) -> u32 {
+    addr.to_physical().as_usize() as u32 | BUS_RAM_ALIAS
+}
+
+/// One transfer as read by the DMA engine. It must be 32-byte aligned and,
+/// since the engine reads it behind the ARM caches, live in uncached memory.
+#[repr(C, align(32))]
+pub struct ControlBlock {
+    pub ti: u32,
+    pub source_ad: u32,
+    pub dest_ad: u32,
+    pub txfr_len: u32,
+    pub stride: u32,
+    pub nextconbk: u32,
+    _reserved: [u32; 2],
+}
+
+// The engine expects eight words and nothing else
+const _: [(); 32] = [(); mem::size_of::<ControlBlock>()];
+
+impl ControlBlock {
+    pub const fn new() -> Self {
+        Self {
+            ti: 0,
+            source_ad: 0,
+            dest_ad: 0,
+            txfr_len: 0,
+            stride: 0,
+            nextconbk: 0,
+            _reserved: [0; 2],
+        }
+    }
+
+    /// Sets the block up to copy `len` bytes from `src` to a fixed peripheral
+    /// register at bus address `dest`, one word per DREQ from peripheral `dreq`
+    pub fn set_to_peripheral(&mut self, src: usize, dest: u32, len: usize, dreq: u32) {
+        let ti = TI::PERMAP.val(dreq) + TI::SRC_INC::SET + TI::DEST_DREQ::SET + TI::WAIT_RESP::SET;
+        self.ti = ti.value;
+        self.source_ad = ram_bus_addr(VirtualAddress::new(src));
+        self.dest_ad = dest;
+        self.txfr_len = len as u32;
+        self.stride = 0;
+        self.nextconbk = 0;
+    }
+}
+
+struct DMAInner {
+    base_addr: usize,
+}
+
+pub struct DMA {
+    channel: usize,
+    inner: NullLock<DMAInner>,
+    timer: &'static SystemTimer,
+    // Between a successful `init` and `shutdown`
+    ready: AtomicBool,
+}
+
+impl ops::Deref for DMAInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl DMAInner {
+    const fn new(base_addr: usize) -> Self {
+        Self { base_addr }
+    }
+
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    fn init(&mut self, channel: usize) {
+        self.ENABLE.set(self.ENABLE.get() | 1 << channel);
+
+        let regs = &self.CHANNEL[channel];
+        regs.CS.write(CS::RESET::SET);
+        while regs.CS.is_set(CS::RESET) {
+            cpu::nop();
+        }
+    }
+
+    // Transfers run to completion under the lock, so this only catches one a
+    // fault cut short
+    fn shutdown(&mut self, channel: usize) {
+        let regs = &self.CHANNEL[channel];
+        if regs.CS.is_set(CS::ACTIVE) {
+            regs.CS.write(CS::ABORT::SET);
+        }
+        regs.CS.write(CS::RESET::SET);
+        while regs.CS.is_set(CS::RESET) {
+            cpu::nop();
+        }
+        self.ENABLE.set(self.ENABLE.get() & !(1 << channel));
+    }
+
+    fn transfer(
+        &mut self,
+        timer: &SystemTimer,
+        channel: usize,
+        cb: &ControlBlock,
+    ) -> Result<(), DriverError> {
+        let regs = &self.CHANNEL[channel];
+
+        // The control block and the data must be in memory before the engine
+        // fetches them
+        cpu::barrier::dsb();
+
+        regs.CONBLK_AD.set(ram_bus_addr(VirtualAddress::new(cb as *const _ as usize)));
+        regs.CS.write(
+            CS::WAIT_FOR_OUTSTANDING_WRITES::SET
+                + CS::PANIC_PRIORITY.val(15)
+                + CS::PRIORITY.val(8)
+                + CS::ACTIVE::SET,
+        );
+
+        let start = timer.now_micros();
+        while !regs.CS.is_set(CS::END) {
+            if regs.CS.is_set(CS::ERROR) {
+                regs.CS.write(CS::ABORT::SET);
+                return Err(DriverError::BusError);
+            }
+            if deadline_passed(start, timer.now_micros(), TRANSFER_TIMEOUT_US) {
+                regs.CS.write(CS::ABORT::SET);
+                return Err(DriverError::HardwareTimeout);
+            }
+            cpu::spin_hint();
+        }
+        regs.CS.write(CS::END::SET + CS::INT::SET);
+
+        // Whatever the engine wrote to RAM is there before we read it
+        cpu::barrier::dsb();
+
+        Ok(())
+    }
+}
+
+impl DMA {
+    /// Drives one channel of the controller at `base_addr`. The firmware
+    /// keeps some channels for the VideoCore, so pick one it leaves free.
+    pub const unsafe fn new(base_addr: usize, channel: usize, timer: &'static SystemTimer) -> Self {
+        Self {
+            channel,
+            inner: NullLock::new(DMAInner::new(base_addr)),
+            timer,
+            ready: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether the channel is up, so transfers can be handed to it
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    pub unsafe fn set_base_addr(&self, base_addr: usize) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.base_addr = base_addr);
+    }
+
+    /// Runs the transfer described by `cb` and waits for it to finish
+    pub fn transfer(&self, cb: &ControlBlock) -> Result<(), DriverError> {
+        if !self.is_ready() {
+            return Err(DriverError::Unsupported);
+        }
+
+        let mut r = &self.inner;
+        r.lock(|inner| inner.transfer(self.timer, self.channel, cb))
+    }
+}
+
+use synchronization::interface::Mutex;
+
+impl driver::interface::DeviceDriver for DMA {
+    fn name(&self) -> &str {
+        "BCM DMA"
+    }
+
+    fn compatible(&self) -> &str {
+        "brcm,bcm2835-dma"
+    }
+
+    // `transfer` times out on the system timer
+    fn dependencies(&self) -> &[&str] {
+        &["brcm,bcm2835-system-timer"]
+    }
+
+    fn init(&self) -> Result<(), DriverError> {
+        if self.channel >= CHANNELS {
+            return Err(DriverError::InvalidConfig);
+        }
+
+        let mut r = &self.inner;
+        r.lock(|inner| inner.init(self.channel));
+        self.ready.store(true, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        // `init` refused it, there is nothing to stop
+        if self.channel >= CHANNELS {
+            return;
+        }
+
+        self.ready.store(false, Ordering::Relaxed);
+        let mut r = &self.inner;
+        r.lock(|inner| inner.shutdown(self.channel));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_block_layout() {
+        let cb = ControlBlock::new();
+        let base = &cb as *const _ as usize;
+        let offset = |field: &u32| field as *const _ as usize - base;
+
+        assert_eq!(mem::size_of::<ControlBlock>(), 32);
+        assert_eq!(mem::align_of::<ControlBlock>(), 32);
+        assert_eq!(offset(&cb.ti), 0x00);
+        assert_eq!(offset(&cb.source_ad), 0x04);
+        assert_eq!(offset(&cb.dest_ad), 0x08);
+        assert_eq!(offset(&cb.txfr_len), 0x0c);
+        assert_eq!(offset(&cb.stride), 0x10);
+        assert_eq!(offset(&cb.nextconbk), 0x14);
+    }
+
+    #[test]
+    fn set_to_peripheral_paces_on_the_dreq() {
+        let mut cb = ControlBlock::new();
+
+        cb.set_to_peripheral(0x10_0000, 0x7E20_1000, 64, DREQ_UART_TX);
+        // PERMAP 12, SRC_INC, DEST_DREQ, WAIT_RESP
+        assert_eq!(cb.ti, 12 << 16 | 1 << 8 | 1 << 6 | 1 << 3);
+        assert_eq!(cb.source_ad, 0xC010_0000);
+        assert_eq!(cb.dest_ad, 0x7E20_1000);
+        assert_eq!(cb.txfr_len, 64);
+        assert_eq!(cb.nextconbk, 0);
+    }
+}
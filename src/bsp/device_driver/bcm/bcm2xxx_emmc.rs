@@ -0,0 +1,589 @@
+use super::{Function, Mailbox, Pull, SystemTimer, GPIO};
+use crate::{
+    cpu,
+    cpu::time::deadline_passed,
+    driver,
+    driver::{DriverError, ProbeInfo},
+    fs, synchronization,
+    synchronization::Spinlock,
+};
+use core::ops;
+use register::{mmio::*, register_bitfields, register_structs};
+
+register_bitfields! {
+    u32,
+
+    // Present state
+    STATUS [
+        // A data command or a busy response is still running
+        DAT_INHIBIT OFFSET(1) NUMBITS(1) [],
+        CMD_INHIBIT OFFSET(0) NUMBITS(1) []
+    ],
+
+    // Host configuration
+    CONTROL0 [
+        // Bus voltage, 3.3V. Only EMMC2 switches the card's power.
+        SD_BUS_VOLTAGE OFFSET(9) NUMBITS(3) [
+            V3_3 = 0b111
+        ],
+        SD_BUS_POWER OFFSET(8) NUMBITS(1) [],
+        // 4-bit data bus
+        HCTL_DWIDTH OFFSET(1) NUMBITS(1) []
+    ],
+
+    // Clock and reset
+    CONTROL1 [
+        SRST_DATA OFFSET(26) NUMBITS(1) [],
+        SRST_CMD OFFSET(25) NUMBITS(1) [],
+        SRST_HC OFFSET(24) NUMBITS(1) [],
+        // Data timeout of 2^(13 + unit) clocks
+        DATA_TOUNIT OFFSET(16) NUMBITS(4) [],
+        // Low 8 and high 2 bits of the 10-bit divider
+        CLK_FREQ8 OFFSET(8) NUMBITS(8) [],
+        CLK_FREQ_MS2 OFFSET(6) NUMBITS(2) [],
+        // SD clock to the card
+        CLK_EN OFFSET(2) NUMBITS(1) [],
+        CLK_STABLE OFFSET(1) NUMBITS(1) [],
+        CLK_INTLEN OFFSET(0) NUMBITS(1) []
+    ],
+
+    // Interrupt flags, write 1 to clear
+    INTERRUPT [
+        DTO_ERR OFFSET(20) NUMBITS(1) [],
+        CTO_ERR OFFSET(16) NUMBITS(1) [],
+        // Any error, with the details in bits 16-24
+        ERR OFFSET(15) NUMBITS(1) [],
+        READ_RDY OFFSET(5) NUMBITS(1) [],
+        WRITE_RDY OFFSET(4) NUMBITS(1) [],
+        DATA_DONE OFFSET(1) NUMBITS(1) [],
+        CMD_DONE OFFSET(0) NUMBITS(1) []
+    ]
+}
+
+register_structs! {
+    #[allow(non_snake_case)]
+    RegisterBlock {
+        (0x00 => _reserved1),
+        (0x04 => BLKSIZECNT: ReadWrite<u32>),
+        (0x08 => ARG1: ReadWrite<u32>),
+        (0x0C => CMDTM: ReadWrite<u32>),
+        (0x10 => RESP: [ReadOnly<u32>; 4]),
+        (0x20 => DATA: ReadWrite<u32>),
+        (0x24 => STATUS: ReadOnly<u32, STATUS::Register>),
+        (0x28 => CONTROL0: ReadWrite<u32, CONTROL0::Register>),
+        (0x2C => CONTROL1: ReadWrite<u32, CONTROL1::Register>),
+        (0x30 => INTERRUPT: ReadWrite<u32, INTERRUPT::Register>),
+        (0x34 => IRPT_MASK: ReadWrite<u32>),
+        (0x38 => IRPT_EN: ReadWrite<u32>),
+        (0x3C => @END),
+    }
+}
+
+pub const BLOCK_SIZE: usize = 512;
+
+const IDENTIFICATION_HZ: u32 = 400_000;
+const TRANSFER_HZ: u32 = 25_000_000;
+
+const COMMAND_TIMEOUT_US: u64 = 100_000;
+const RESET_TIMEOUT_US: u64 = 100_000;
+// The card gets a second to leave its power-up busy state
+const OP_COND_TIMEOUT_US: u64 = 1_000_000;
+const OP_COND_RETRY_US: u64 = 10_000;
+
+/// What a command expects back, which also decides the CRC and index checks
+#[derive(Copy, Clone, PartialEq)]
+pub enum Response {
+    None,
+    /// R2, the CID or CSD
+    R136,
+    /// R1, R6 and R7
+    R48,
+    /// R1b, holding DAT0 low while the card is busy
+    R48Busy,
+    /// R3, the OCR, which has neither a CRC nor the command index
+    R48NoCrc,
+}
+
+/// Which way a command's data block goes, if it has one
+#[derive(Copy, Clone, PartialEq)]
+pub enum Data {
+    None,
+    Read,
+    Write,
+}
+
+/// The CMDTM value issuing command `index`
+pub const fn command(index: u32, response: Response, data: Data) -> u32 {
+    // CMD_RSPNS_TYPE, CMD_CRCCHK_EN and CMD_IXCHK_EN
+    let response = match response {
+        Response::None => 0,
+        Response::R136 => 1 << 16 | 1 << 19,
+        Response::R48 => 2 << 16 | 1 << 19 | 1 << 20,
+        Response::R48Busy => 3 << 16 | 1 << 19 | 1 << 20,
+        Response::R48NoCrc => 2 << 16,
+    };
+    // CMD_ISDATA and TM_DAT_DIR, set for card to host
+    let data = match data {
+        Data::None => 0,
+        Data::Read => 1 << 21 | 1 << 4,
+        Data::Write => 1 << 21,
+    };
+
+    index << 24 | response | data
+}
+
+const GO_IDLE_STATE: u32 = command(0, Response::None, Data::None);
+const ALL_SEND_CID: u32 = command(2, Response::R136, Data::None);
+const SEND_RELATIVE_ADDR: u32 = command(3, Response::R48, Data::None);
+const SELECT_CARD: u32 = command(7, Response::R48Busy, Data::None);
+const SEND_IF_COND: u32 = command(8, Response::R48, Data::None);
+const SET_BLOCKLEN: u32 = command(16, Response::R48, Data::None);
+const READ_SINGLE_BLOCK: u32 = command(17, Response::R48, Data::Read);
+const WRITE_BLOCK: u32 = command(24, Response::R48, Data::Write);
+const APP_CMD: u32 = command(55, Response::R48, Data::None);
+// Application commands, each right after an APP_CMD
+const SET_BUS_WIDTH: u32 = command(6, Response::R48, Data::None);
+const SD_SEND_OP_COND: u32 = command(41, Response::R48NoCrc, Data::None);
+
+/// SEND_IF_COND's argument: 2.7-3.6V and a check pattern the card echoes
+pub const IF_COND_ARG: u32 = 0x1AA;
+
+// OCR bits
+const OCR_BUSY: u32 = 1 << 31;
+// Card capacity status in the response, host capacity support in the request
+const OCR_CCS: u32 = 1 << 30;
+// 2.7-3.6V
+const OCR_VOLTAGE_WINDOW: u32 = 0x00FF_8000;
+
+/// SD_SEND_OP_COND's argument, offering block addressing to cards that
+/// answered SEND_IF_COND
+pub const fn op_cond_arg(high_capacity: bool) -> u32 {
+    match high_capacity {
+        true => OCR_CCS | OCR_VOLTAGE_WINDOW,
+        false => OCR_VOLTAGE_WINDOW,
+    }
+}
+
+/// The RCA as APP_CMD and SELECT_CARD take it, and SEND_RELATIVE_ADDR
+/// returns it
+pub const fn rca_arg(rca: u16) -> u32 {
+    (rca as u32) << 16
+}
+
+/// Where block `lba` is for a data command: block addressed on SDHC and
+/// SDXC cards, byte addressed on standard capacity ones. `None` if a
+/// standard capacity card can't address it.
+pub const fn block_address(lba: u32, high_capacity: bool) -> Option<u32> {
+    match high_capacity {
+        true => Some(lba),
+        false => lba.checked_mul(BLOCK_SIZE as u32),
+    }
+}
+
+/// The 10-bit SD clock divider bringing `base_hz` down to at most
+/// `target_hz`, where the card gets `base_hz / (2 * div)`, or `base_hz`
+/// itself for 0
+pub const fn clock_divider(base_hz: u32, target_hz: u32) -> u32 {
+    if target_hz == 0 {
+        return 0x3FF;
+    }
+    if base_hz <= target_hz {
+        return 0;
+    }
+
+    let step = 2 * target_hz;
+    let rem = base_hz % step;
+    let div = base_hz / step + (rem != 0) as u32;
+    if div > 0x3FF {
+        0x3FF
+    } else {
+        div
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Card {
+    rca: u16,
+    high_capacity: bool,
+}
+
+struct EmmcInner {
+    base_addr: usize,
+    // Mailbox clock the controller runs from
+    clock_id: u32,
+    // CLK, CMD and DAT0-3, where they have to be routed over from SDHOST
+    pins: &'static [u32],
+    base_clock_hz: u32,
+    // None until a card has been through initialization
+    card: Option<Card>,
+}
+
+/// The SD card slot, through the Arasan SDHCI (EMMC) on the Pi 3 and EMMC2
+/// on the Pi 4. Commands and data are polled, a block at a time.
+///
+/// No interrupt handler touches it, so the lock leaves IRQs unmasked through
+/// the long waits on the card.
+pub struct Emmc {
+    inner: Spinlock<EmmcInner>,
+    gpio: &'static GPIO,
+    mailbox: &'static Mailbox,
+    timer: &'static SystemTimer,
+}
+
+impl ops::Deref for EmmcInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl EmmcInner {
+    const fn new() -> Self {
+        Self {
+            base_addr: 0,
+            clock_id: 0,
+            pins: &[],
+            base_clock_hz: 0,
+            card: None,
+        }
+    }
+
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    fn wait_for(
+        &self,
+        timer: &SystemTimer,
+        timeout_us: u64,
+        done: impl Fn(&Self) -> bool,
+    ) -> Result<(), DriverError> {
+        let start = timer.now_micros();
+        while !done(self) {
+            if deadline_passed(start, timer.now_micros(), timeout_us) {
+                return Err(DriverError::HardwareTimeout);
+            }
+            cpu::spin_hint();
+        }
+
+        Ok(())
+    }
+
+    fn set_clock(&mut self, timer: &SystemTimer, target_hz: u32) -> Result<(), DriverError> {
+        let div = clock_divider(self.base_clock_hz, target_hz);
+
+        self.CONTROL1.modify(CONTROL1::CLK_EN::CLEAR);
+        self.CONTROL1.modify(
+            CONTROL1::CLK_FREQ8.val(div & 0xFF)
+                + CONTROL1::CLK_FREQ_MS2.val(div >> 8)
+                + CONTROL1::CLK_INTLEN::SET,
+        );
+        self.wait_for(timer, RESET_TIMEOUT_US, |s| s.CONTROL1.is_set(CONTROL1::CLK_STABLE))?;
+        self.CONTROL1.modify(CONTROL1::CLK_EN::SET);
+
+        Ok(())
+    }
+
+    // Takes the command line out of an error, which otherwise sticks
+    fn reset_command_line(&mut self, timer: &SystemTimer) {
+        self.CONTROL1.modify(CONTROL1::SRST_CMD::SET);
+        let _ = self.wait_for(timer, RESET_TIMEOUT_US, |s| !s.CONTROL1.is_set(CONTROL1::SRST_CMD));
+    }
+
+    // The first response word
+    fn command(&mut self, timer: &SystemTimer, cmd: u32, arg: u32) -> Result<u32, DriverError> {
+        self.wait_for(timer, COMMAND_TIMEOUT_US, |s| !s.STATUS.is_set(STATUS::CMD_INHIBIT))?;
+
+        self.INTERRUPT.set(u32::MAX);
+        self.ARG1.set(arg);
+        self.CMDTM.set(cmd);
+
+        let done = INTERRUPT::CMD_DONE::SET.value | INTERRUPT::ERR::SET.value;
+        self.wait_for(timer, COMMAND_TIMEOUT_US, |s| s.INTERRUPT.get() & done != 0)?;
+
+        let irq = self.INTERRUPT.extract();
+        self.INTERRUPT.set(u32::MAX);
+        if irq.is_set(INTERRUPT::ERR) {
+            self.reset_command_line(timer);
+            return match irq.is_set(INTERRUPT::CTO_ERR) {
+                true => Err(DriverError::HardwareTimeout),
+                false => Err(DriverError::BusError),
+            };
+        }
+
+        Ok(self.RESP[0].get())
+    }
+
+    fn app_command(
+        &mut self,
+        timer: &SystemTimer,
+        rca: u16,
+        cmd: u32,
+        arg: u32,
+    ) -> Result<u32, DriverError> {
+        self.command(timer, APP_CMD, rca_arg(rca))?;
+        self.command(timer, cmd, arg)
+    }
+
+    // Waits for one of the INTERRUPT flags in `flag`, failing on an error
+    fn wait_for_data(&self, timer: &SystemTimer, flag: u32) -> Result<(), DriverError> {
+        let done = flag | INTERRUPT::ERR::SET.value;
+        self.wait_for(timer, COMMAND_TIMEOUT_US, |s| s.INTERRUPT.get() & done != 0)?;
+
+        let irq = self.INTERRUPT.extract();
+        self.INTERRUPT.set(flag);
+        match (irq.is_set(INTERRUPT::ERR), irq.is_set(INTERRUPT::DTO_ERR)) {
+            (false, _) => Ok(()),
+            (true, true) => Err(DriverError::HardwareTimeout),
+            (true, false) => Err(DriverError::BusError),
+        }
+    }
+
+    fn init_controller(&mut self, timer: &SystemTimer) -> Result<(), DriverError> {
+        self.CONTROL1.set(0);
+        self.CONTROL1.modify(CONTROL1::SRST_HC::SET);
+        self.wait_for(timer, RESET_TIMEOUT_US, |s| !s.CONTROL1.is_set(CONTROL1::SRST_HC))?;
+
+        self.CONTROL0
+            .write(CONTROL0::SD_BUS_VOLTAGE::V3_3 + CONTROL0::SD_BUS_POWER::SET);
+        self.CONTROL1.modify(CONTROL1::DATA_TOUNIT.val(0xE));
+
+        // Flags are polled, none raise an interrupt
+        self.IRPT_EN.set(0);
+        self.IRPT_MASK.set(u32::MAX);
+
+        self.set_clock(timer, IDENTIFICATION_HZ)
+    }
+
+    // GO_IDLE_STATE, SEND_IF_COND, SD_SEND_OP_COND, ALL_SEND_CID,
+    // SEND_RELATIVE_ADDR and SELECT_CARD, then a 4-bit bus at full speed
+    fn init_card(&mut self, timer: &SystemTimer) -> Result<Card, DriverError> {
+        self.command(timer, GO_IDLE_STATE, 0)?;
+
+        // Version 1 cards don't know the command, and only byte addressing
+        let v2 = match self.command(timer, SEND_IF_COND, IF_COND_ARG) {
+            Ok(resp) if resp & 0xFFF == IF_COND_ARG => true,
+            Ok(_) => return Err(DriverError::Unsupported),
+            Err(_) => false,
+        };
+
+        let start = timer.now_micros();
+        let ocr = loop {
+            let ocr = self.app_command(timer, 0, SD_SEND_OP_COND, op_cond_arg(v2))?;
+            if ocr & OCR_BUSY != 0 {
+                break ocr;
+            }
+            if deadline_passed(start, timer.now_micros(), OP_COND_TIMEOUT_US) {
+                return Err(DriverError::HardwareTimeout);
+            }
+            timer.delay_micros(OP_COND_RETRY_US);
+        };
+        let high_capacity = ocr & OCR_CCS != 0;
+
+        self.command(timer, ALL_SEND_CID, 0)?;
+        let rca = (self.command(timer, SEND_RELATIVE_ADDR, 0)? >> 16) as u16;
+
+        self.set_clock(timer, TRANSFER_HZ)?;
+        self.command(timer, SELECT_CARD, rca_arg(rca))?;
+        self.wait_for(timer, COMMAND_TIMEOUT_US, |s| !s.STATUS.is_set(STATUS::DAT_INHIBIT))?;
+
+        if !high_capacity {
+            self.command(timer, SET_BLOCKLEN, BLOCK_SIZE as u32)?;
+        }
+
+        // 0b10 selects 4 bits
+        self.app_command(timer, rca, SET_BUS_WIDTH, 0b10)?;
+        self.CONTROL0.modify(CONTROL0::HCTL_DWIDTH::SET);
+
+        self.BLKSIZECNT.set(1 << 16 | BLOCK_SIZE as u32);
+
+        Ok(Card { rca, high_capacity })
+    }
+
+    fn block_address(&self, lba: u32) -> Result<u32, DriverError> {
+        // No card, or it failed initialization
+        let card = self.card.ok_or(DriverError::Unsupported)?;
+        block_address(lba, card.high_capacity).ok_or(DriverError::InvalidConfig)
+    }
+
+    fn read_block(
+        &mut self,
+        timer: &SystemTimer,
+        lba: u32,
+        buf: &mut [u8; BLOCK_SIZE],
+    ) -> Result<(), DriverError> {
+        let addr = self.block_address(lba)?;
+
+        self.BLKSIZECNT.set(1 << 16 | BLOCK_SIZE as u32);
+        self.command(timer, READ_SINGLE_BLOCK, addr)?;
+
+        self.wait_for_data(timer, INTERRUPT::READ_RDY::SET.value)?;
+        for word in buf.chunks_exact_mut(4) {
+            word.copy_from_slice(&self.DATA.get().to_le_bytes());
+        }
+        self.wait_for_data(timer, INTERRUPT::DATA_DONE::SET.value)
+    }
+
+    fn write_block(
+        &mut self,
+        timer: &SystemTimer,
+        lba: u32,
+        buf: &[u8; BLOCK_SIZE],
+    ) -> Result<(), DriverError> {
+        let addr = self.block_address(lba)?;
+
+        self.BLKSIZECNT.set(1 << 16 | BLOCK_SIZE as u32);
+        self.command(timer, WRITE_BLOCK, addr)?;
+
+        self.wait_for_data(timer, INTERRUPT::WRITE_RDY::SET.value)?;
+        for word in buf.chunks_exact(4) {
+            self.DATA.set(u32::from_le_bytes([word[0], word[1], word[2], word[3]]));
+        }
+        self.wait_for_data(timer, INTERRUPT::DATA_DONE::SET.value)
+    }
+}
+
+impl Emmc {
+    /// Needs `set_controller` before `init`, as the controller differs by
+    /// board
+    pub const unsafe fn new(
+        gpio: &'static GPIO,
+        mailbox: &'static Mailbox,
+        timer: &'static SystemTimer,
+    ) -> Self {
+        Self {
+            inner: Spinlock::new(EmmcInner::new()),
+            gpio,
+            mailbox,
+            timer,
+        }
+    }
+
+    /// The controller at `base_addr`, clocked by mailbox clock `clock_id`.
+    /// `pins` go over to it on Alt3, CLK first and pulled up after it. Empty
+    /// where the slot is wired to it already.
+    pub unsafe fn set_controller(&self, base_addr: usize, clock_id: u32, pins: &'static [u32]) {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.base_addr = base_addr;
+            inner.clock_id = clock_id;
+            inner.pins = pins;
+        });
+    }
+
+    /// Reads the 512-byte block `lba`
+    pub fn read_block(&self, lba: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), DriverError> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.read_block(self.timer, lba, buf))
+    }
+
+    /// Writes the 512-byte block `lba`, returning once the controller has
+    /// sent it. The card may still be busy programming it after.
+    pub fn write_block(&self, lba: u32, buf: &[u8; BLOCK_SIZE]) -> Result<(), DriverError> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.write_block(self.timer, lba, buf))
+    }
+}
+
+impl fs::interface::BlockDevice for Emmc {
+    fn read_block(&self, lba: u32, buf: &mut [u8; fs::BLOCK_SIZE]) -> Result<(), DriverError> {
+        Emmc::read_block(self, lba, buf)
+    }
+}
+
+use synchronization::interface::Mutex;
+
+impl driver::interface::DeviceDriver for Emmc {
+    fn name(&self) -> &str {
+        "BCM EMMC"
+    }
+
+    fn compatible(&self) -> &str {
+        "brcm,bcm2835-sdhci"
+    }
+
+    // The divider runs off the EMMC clock, which the mailbox reports
+    fn dependencies(&self) -> &[&str] {
+        &["brcm,bcm2835-gpio", "brcm,bcm2835-mbox", "brcm,bcm2835-system-timer"]
+    }
+
+    // An empty slot isn't an error, the card is just left out. The controller
+    // not coming up is.
+    fn init(&self) -> Result<(), DriverError> {
+        let mut r = &self.inner;
+        let (clock_id, pins) = r.lock(|inner| (inner.clock_id, inner.pins));
+
+        let base_clock_hz = self
+            .mailbox
+            .get_clock_rate(clock_id)
+            .map_err(|_| DriverError::HardwareTimeout)?;
+
+        for (i, &pin) in pins.iter().enumerate() {
+            self.gpio.set_function(pin, Function::Alt3)?;
+            self.gpio.set_pull(pin, if i == 0 { Pull::None } else { Pull::Up })?;
+        }
+
+        r.lock(|inner| {
+            inner.base_clock_hz = base_clock_hz;
+            inner.init_controller(self.timer)?;
+            inner.card = inner.init_card(self.timer).ok();
+
+            Ok(())
+        })
+    }
+
+    fn probe(&self) -> Option<ProbeInfo> {
+        let mut r = &self.inner;
+        r.lock(|inner| match inner.card {
+            None => Some(ProbeInfo::new("no card")),
+            Some(card) => {
+                let summary = if card.high_capacity { "SDHC/SDXC" } else { "SDSC" };
+                Some(ProbeInfo::new(summary).with("rca", card.rca as u64))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_encodings() {
+        assert_eq!(GO_IDLE_STATE, 0);
+        assert_eq!(ALL_SEND_CID, 0x0209_0000);
+        assert_eq!(SEND_IF_COND, 0x081A_0000);
+        assert_eq!(SELECT_CARD, 0x071B_0000);
+        assert_eq!(READ_SINGLE_BLOCK, 0x113A_0010);
+        assert_eq!(WRITE_BLOCK, 0x183A_0000);
+        assert_eq!(SD_SEND_OP_COND, 0x2902_0000);
+    }
+
+    #[test]
+    fn op_cond_arg_offers_block_addressing() {
+        assert_eq!(op_cond_arg(true), 0x40FF_8000);
+        assert_eq!(op_cond_arg(false), 0x00FF_8000);
+    }
+
+    #[test]
+    fn rca_arg_is_the_upper_half() {
+        assert_eq!(rca_arg(0x1234), 0x1234_0000);
+    }
+
+    #[test]
+    fn block_address_by_capacity() {
+        assert_eq!(block_address(1, false), Some(0x200));
+        assert_eq!(block_address(1, true), Some(1));
+        assert_eq!(block_address(0x80_0000, false), None);
+    }
+
+    #[test]
+    fn clock_divider_off_both_base_clocks() {
+        // The Pi 3's 250 MHz and the Pi 4's 100 MHz base clocks
+        assert_eq!(clock_divider(250_000_000, IDENTIFICATION_HZ), 313);
+        assert_eq!(clock_divider(250_000_000, TRANSFER_HZ), 5);
+        assert_eq!(clock_divider(100_000_000, TRANSFER_HZ), 2);
+        assert_eq!(clock_divider(20_000_000, TRANSFER_HZ), 0);
+    }
+}
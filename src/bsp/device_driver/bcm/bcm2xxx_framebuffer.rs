@@ -0,0 +1,576 @@
+use super::{font8x8, Mailbox};
+use crate::{
+    console,
+    console::WriteError,
+    driver,
+    driver::{DriverError, ProbeInfo},
+    memory, synchronization,
+    synchronization::IRQSafeSpinlock,
+};
+use core::{fmt, ops::Range};
+
+const TAG_ALLOCATE_BUFFER: u32 = 0x0004_0001;
+const TAG_GET_PITCH: u32 = 0x0004_0008;
+const TAG_SET_PHYSICAL_SIZE: u32 = 0x0004_8003;
+const TAG_SET_VIRTUAL_SIZE: u32 = 0x0004_8004;
+const TAG_SET_DEPTH: u32 = 0x0004_8005;
+const TAG_SET_PIXEL_ORDER: u32 = 0x0004_8006;
+const TAG_SET_VIRTUAL_OFFSET: u32 = 0x0004_8009;
+
+const DEPTH: u32 = 32;
+const BYTES_PER_PIXEL: u32 = DEPTH / 8;
+const PIXEL_ORDER_RGB: u32 = 1;
+
+const GLYPH_WIDTH: u32 = 8;
+const GLYPH_HEIGHT: u32 = 8;
+
+const FOREGROUND: u32 = 0x00FF_FFFF;
+const BACKGROUND: u32 = 0x0000_0000;
+
+// SGR colors 0-7: black, red, green, yellow, blue, magenta, cyan and white
+const PALETTE: [u32; 8] = [
+    0x0000_0000,
+    0x00AA_0000,
+    0x0000_AA00,
+    0x00AA_5500,
+    0x0000_00AA,
+    0x00AA_00AA,
+    0x0000_AAAA,
+    0x00AA_AAAA,
+];
+
+/// Byte offset of pixel (`x`, `y`) from the start of the buffer. Scanlines
+/// are `pitch` bytes apart, which may be more than the visible width takes.
+pub const fn pixel_offset(x: u32, y: u32, pitch: u32, bytes_per_pixel: u32) -> usize {
+    y as usize * pitch as usize + x as usize * bytes_per_pixel as usize
+}
+
+// Parameters past this many are parsed but ignored
+const MAX_CSI_PARAMS: usize = 2;
+
+/// Where the parser is in an escape sequence. It keeps its place between
+/// `write_char` calls, so a sequence may arrive split up.
+#[derive(Copy, Clone)]
+enum Ansi {
+    Ground,
+    // After ESC
+    Escape,
+    // After ESC [, collecting parameters until the final byte
+    Csi {
+        params: [u16; MAX_CSI_PARAMS],
+        count: usize,
+    },
+}
+
+struct FrameBufferInner {
+    mailbox: &'static Mailbox,
+    base_addr: usize,
+    width: u32,
+    height: u32,
+    pitch: u32,
+    cursor_x: u32,
+    cursor_y: u32,
+    foreground: u32,
+    background: u32,
+    ansi: Ansi,
+    chars_written: usize,
+}
+
+pub struct FrameBuffer {
+    inner: IRQSafeSpinlock<FrameBufferInner>,
+}
+
+impl FrameBufferInner {
+    const fn new(mailbox: &'static Mailbox, width: u32, height: u32) -> Self {
+        Self {
+            mailbox,
+            base_addr: 0,
+            width,
+            height,
+            pitch: 0,
+            cursor_x: 0,
+            cursor_y: 0,
+            foreground: FOREGROUND,
+            background: BACKGROUND,
+            ansi: Ansi::Ground,
+            chars_written: 0,
+        }
+    }
+
+    fn init(&mut self) -> Result<(), DriverError> {
+        #[rustfmt::skip]
+        let mut tags = [
+            TAG_SET_PHYSICAL_SIZE,  8, 0, self.width, self.height,
+            TAG_SET_VIRTUAL_SIZE,   8, 0, self.width, self.height,
+            TAG_SET_VIRTUAL_OFFSET, 8, 0, 0, 0,
+            TAG_SET_DEPTH,          4, 0, DEPTH,
+            TAG_SET_PIXEL_ORDER,    4, 0, PIXEL_ORDER_RGB,
+            TAG_ALLOCATE_BUFFER,    8, 0, 4096, 0,
+            TAG_GET_PITCH,          4, 0, 0,
+        ];
+
+        if self.mailbox.property(&mut tags).is_err() {
+            return Err(DriverError::HardwareTimeout);
+        }
+
+        let (width, height, depth, base, pitch) = (tags[3], tags[4], tags[18], tags[26], tags[31]);
+        if depth != DEPTH || base == 0 || pitch < width * BYTES_PER_PIXEL {
+            return Err(DriverError::Unsupported);
+        }
+
+        self.width = width;
+        self.height = height;
+        self.pitch = pitch;
+        // The firmware hands out a VideoCore bus address
+        self.base_addr = (base & 0x3FFF_FFFF) as usize;
+        self.clear();
+
+        Ok(())
+    }
+
+    fn columns(&self) -> u32 {
+        self.width / GLYPH_WIDTH
+    }
+
+    fn rows(&self) -> u32 {
+        self.height / GLYPH_HEIGHT
+    }
+
+    // Pixels [x, x + len) of scanline y
+    fn span(&self, x: u32, y: u32, len: u32) -> Range<*mut u32> {
+        let start = (self.base_addr + pixel_offset(x, y, self.pitch, BYTES_PER_PIXEL)) as *mut u32;
+        Range {
+            start,
+            end: unsafe { start.add(len as usize) },
+        }
+    }
+
+    // Clipped to the screen
+    fn fill_rect(&mut self, x: u32, y: u32, w: u32, h: u32, color: u32) {
+        if self.base_addr == 0 || x >= self.width || y >= self.height {
+            return;
+        }
+
+        let w = w.min(self.width - x);
+        let h = h.min(self.height - y);
+        for y in y..y + h {
+            unsafe { memory::fill_volatile(self.span(x, y, w), color) };
+        }
+    }
+
+    fn clear_lines(&mut self, first: u32, count: u32) {
+        for y in first..first + count {
+            unsafe { memory::fill_volatile(self.span(0, y, self.width), self.background) };
+        }
+    }
+
+    fn clear(&mut self) {
+        self.clear_lines(0, self.height);
+        self.cursor_x = 0;
+        self.cursor_y = 0;
+    }
+
+    fn scroll(&mut self) {
+        let visible = (self.rows() - 1) * GLYPH_HEIGHT;
+        for y in 0..visible {
+            let src = self.span(0, y + GLYPH_HEIGHT, self.width).start as *const u32;
+            unsafe { memory::copy_volatile(self.span(0, y, self.width), src) };
+        }
+        self.clear_lines(visible, GLYPH_HEIGHT);
+    }
+
+    fn draw_glyph(&mut self, c: char) {
+        let index = match c as u32 {
+            x if x >= font8x8::FIRST as u32 && x <= font8x8::LAST as u32 => x as u8,
+            _ => b'?',
+        };
+        let glyph = &font8x8::GLYPHS[(index - font8x8::FIRST) as usize];
+
+        let x0 = self.cursor_x * GLYPH_WIDTH;
+        let y0 = self.cursor_y * GLYPH_HEIGHT;
+        for (dy, bits) in glyph.iter().enumerate() {
+            let row = self.span(x0, y0 + dy as u32, GLYPH_WIDTH).start;
+            for dx in 0..GLYPH_WIDTH {
+                let color = if bits & (1 << dx) != 0 {
+                    self.foreground
+                } else {
+                    self.background
+                };
+                unsafe { core::ptr::write_volatile(row.add(dx as usize), color) };
+            }
+        }
+    }
+
+    fn newline(&mut self) {
+        self.cursor_x = 0;
+        self.cursor_y += 1;
+        if self.cursor_y >= self.rows() {
+            self.scroll();
+            self.cursor_y = self.rows() - 1;
+        }
+    }
+
+    /// Runs a complete CSI sequence. Anything but clear screen, cursor
+    /// position and SGR colors is dropped.
+    fn csi(&mut self, params: &[u16], final_byte: char) {
+        let param = |i: usize, default: u16| match params.get(i) {
+            Some(&0) | None => default,
+            Some(&p) => p,
+        };
+
+        match final_byte {
+            'J' if param(0, 0) == 2 => self.clear_lines(0, self.height),
+            // 1-based row and column, kept on screen
+            'H' | 'f' => {
+                self.cursor_y = (param(0, 1) as u32 - 1).min(self.rows() - 1);
+                self.cursor_x = (param(1, 1) as u32 - 1).min(self.columns() - 1);
+            }
+            'm' if params.is_empty() => self.sgr(0),
+            'm' => {
+                for &p in params {
+                    self.sgr(p);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn sgr(&mut self, code: u16) {
+        match code {
+            0 => {
+                self.foreground = FOREGROUND;
+                self.background = BACKGROUND;
+            }
+            30..=37 => self.foreground = PALETTE[(code - 30) as usize],
+            39 => self.foreground = FOREGROUND,
+            40..=47 => self.background = PALETTE[(code - 40) as usize],
+            49 => self.background = BACKGROUND,
+            _ => {}
+        }
+    }
+
+    // Returns whether `c` was part of an escape sequence
+    fn parse_ansi(&mut self, c: char) -> bool {
+        self.ansi = match (self.ansi, c) {
+            (Ansi::Ground, '\x1b') => Ansi::Escape,
+            (Ansi::Ground, _) => return false,
+            (Ansi::Escape, '[') => Ansi::Csi {
+                params: [0; MAX_CSI_PARAMS],
+                count: 0,
+            },
+            // Other escapes are a single character, skip it
+            (Ansi::Escape, _) => Ansi::Ground,
+            (Ansi::Csi { mut params, count }, '0'..='9') => {
+                let count = count.max(1);
+                if let Some(p) = params.get_mut(count - 1) {
+                    *p = p.saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                }
+                Ansi::Csi { params, count }
+            }
+            (Ansi::Csi { params, count }, ';') => Ansi::Csi {
+                params,
+                count: count.max(1) + 1,
+            },
+            (Ansi::Csi { params, count }, '\x40'..='\x7e') => {
+                let count = count.min(MAX_CSI_PARAMS);
+                self.csi(&params[..count], c);
+                Ansi::Ground
+            }
+            // Intermediate bytes and the like
+            (csi, _) => csi,
+        };
+
+        true
+    }
+
+    fn write_char(&mut self, c: char) -> Result<(), WriteError> {
+        self.chars_written += 1;
+
+        // Not allocated (yet), drop the output
+        if self.base_addr == 0 {
+            return Err(WriteError::NotReady);
+        }
+
+        if self.parse_ansi(c) {
+            return Ok(());
+        }
+
+        // A mode too small for a single glyph, nothing can be drawn
+        if self.cursor_x >= self.columns() || self.cursor_y >= self.rows() {
+            return Err(WriteError::Fault);
+        }
+
+        match c {
+            '\n' => self.newline(),
+            '\r' => self.cursor_x = 0,
+            _ => {
+                self.draw_glyph(c);
+                self.cursor_x += 1;
+                if self.cursor_x >= self.columns() {
+                    self.newline();
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// `print!` panics on errors, so failures only show through `try_write_char`
+impl fmt::Write for FrameBufferInner {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let _ = self.write_char(c);
+        }
+
+        Ok(())
+    }
+}
+
+// Formats through `write_char`, keeping its first error
+struct TryWriter<'a> {
+    inner: &'a mut FrameBufferInner,
+    ret: Result<(), WriteError>,
+}
+
+impl fmt::Write for TryWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let ret = self.inner.write_char(c);
+            self.ret = self.ret.and(ret);
+        }
+
+        Ok(())
+    }
+}
+
+impl FrameBuffer {
+    pub const unsafe fn new(mailbox: &'static Mailbox, width: u32, height: u32) -> Self {
+        Self {
+            inner: IRQSafeSpinlock::new(FrameBufferInner::new(mailbox, width, height)),
+        }
+    }
+
+    /// The memory the firmware allocated for the screen, which the BSP must
+    /// map uncached before drawing starts. `None` if there is none.
+    pub fn buffer(&self) -> Option<Range<usize>> {
+        let mut r = &self.inner;
+        r.lock(|inner| match inner.base_addr {
+            0 => None,
+            base => Some(base..base + (inner.pitch * inner.height) as usize),
+        })
+    }
+
+    /// Sets pixel (`x`, `y`) to `color`, as 0x00RRGGBB. Pixels off the screen,
+    /// or drawn before the buffer is allocated, are dropped.
+    pub fn put_pixel(&self, x: u32, y: u32, color: u32) {
+        self.fill_rect(x, y, 1, 1, color);
+    }
+
+    /// Fills the `w` by `h` rectangle at (`x`, `y`), clipped to the screen
+    pub fn fill_rect(&self, x: u32, y: u32, w: u32, h: u32, color: u32) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.fill_rect(x, y, w, h, color));
+    }
+
+    /// Fills the whole screen. The text console keeps its cursor and colors.
+    pub fn clear(&self, color: u32) {
+        self.fill_rect(0, 0, u32::MAX, u32::MAX, color);
+    }
+}
+
+use synchronization::interface::Mutex;
+
+impl driver::interface::DeviceDriver for FrameBuffer {
+    fn name(&self) -> &str {
+        "BCM FrameBuffer"
+    }
+
+    fn compatible(&self) -> &str {
+        "raspberrypi,rpi-firmware-fb"
+    }
+
+    fn dependencies(&self) -> &[&str] {
+        &["brcm,bcm2835-mbox"]
+    }
+
+    // Without a display, or with a firmware that won't hand out a buffer,
+    // boot carries on with the serial console alone
+    fn init(&self) -> Result<(), DriverError> {
+        let mut r = &self.inner;
+        if let Err(e) = r.lock(|inner| inner.init()) {
+            crate::warn!("{}: {}, no framebuffer console", self.name(), e);
+        }
+
+        Ok(())
+    }
+
+    // The mode the firmware settled on, which may not be the one asked for
+    fn probe(&self) -> Option<ProbeInfo> {
+        let mut r = &self.inner;
+        r.lock(|inner| match inner.base_addr {
+            0 => None,
+            _ => Some(
+                ProbeInfo::new("32 bpp")
+                    .with("width", inner.width as u64)
+                    .with("height", inner.height as u64),
+            ),
+        })
+    }
+}
+
+impl console::interface::Write for FrameBuffer {
+    fn write_char(&self, c: char) {
+        let _ = self.try_write_char(c);
+    }
+
+    fn try_write_char(&self, c: char) -> Result<(), WriteError> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.write_char(c))
+    }
+
+    fn write_fmt(&self, args: core::fmt::Arguments) -> fmt::Result {
+        let mut r = &self.inner;
+        r.lock(|inner| fmt::Write::write_fmt(inner, args))
+    }
+
+    // The first error drawing ran into, which doesn't stop the rest
+    fn try_write_fmt(&self, args: core::fmt::Arguments) -> Result<(), WriteError> {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            let mut w = TryWriter { inner, ret: Ok(()) };
+            let _ = fmt::Write::write_fmt(&mut w, args);
+            w.ret
+        })
+    }
+
+    // Drawing needs the lock, so there is nothing safe to do
+    fn emergency_write_char(&self, _c: char) {}
+
+    // In whole glyphs, once the firmware has handed out the buffer
+    fn dimensions(&self) -> Option<(u16, u16)> {
+        let mut r = &self.inner;
+        r.lock(|inner| match inner.base_addr {
+            0 => None,
+            _ => Some((inner.columns() as u16, inner.rows() as u16)),
+        })
+    }
+}
+
+impl console::interface::Read for FrameBuffer {}
+
+impl console::interface::Statistics for FrameBuffer {
+    fn chars_written(&self) -> usize {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.chars_written)
+    }
+
+    // The framebuffer has no input
+    fn chars_read(&self) -> usize {
+        0
+    }
+
+    fn reset(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.chars_written = 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{boxed::Box, vec};
+
+    // A `width` x `height` screen in host memory, as `init` would leave it
+    fn fake_screen(width: u32, height: u32) -> FrameBufferInner {
+        let mailbox = Box::leak(Box::new(unsafe { Mailbox::new(0) }));
+        let pixels = vec![0u32; (width * height) as usize].leak();
+
+        let mut fb = FrameBufferInner::new(mailbox, width, height);
+        fb.base_addr = pixels.as_mut_ptr() as usize;
+        fb.pitch = width * BYTES_PER_PIXEL;
+        fb
+    }
+
+    fn pixel(fb: &FrameBufferInner, x: u32, y: u32) -> u32 {
+        unsafe { *fb.span(x, y, 1).start }
+    }
+
+    // One `write_char` per byte, so every sequence arrives split up
+    fn type_out(fb: &mut FrameBufferInner, s: &str) {
+        for c in s.chars() {
+            fb.write_char(c).unwrap();
+        }
+    }
+
+    #[test]
+    fn pixel_offset_steps_by_the_pitch() {
+        // 1025 pixels padded out to 4112 bytes a line
+        assert_eq!(pixel_offset(0, 1, 4112, 4), 4112);
+        assert_eq!(pixel_offset(3, 2, 4112, 4), 8236);
+        assert_eq!(pixel_offset(1024, 0, 4112, 4), 4096);
+    }
+
+    #[test]
+    fn split_sgr_sets_the_colors() {
+        let mut fb = fake_screen(64, 16);
+
+        type_out(&mut fb, "\x1b[31;44mA");
+        assert_eq!((fb.foreground, fb.background), (PALETTE[1], PALETTE[4]));
+        assert_eq!(fb.cursor_x, 1);
+        // Row 3 of 'A' is 0x33: columns 0, 1, 4 and 5 lit
+        assert_eq!(pixel(&fb, 0, 3), PALETTE[1]);
+        assert_eq!(pixel(&fb, 2, 3), PALETTE[4]);
+
+        type_out(&mut fb, "\x1b[m");
+        assert_eq!((fb.foreground, fb.background), (FOREGROUND, BACKGROUND));
+    }
+
+    #[test]
+    fn split_cursor_position_is_one_based_and_clamped() {
+        let mut fb = fake_screen(64, 16);
+
+        type_out(&mut fb, "\x1b[2;3H");
+        assert_eq!((fb.cursor_y, fb.cursor_x), (1, 2));
+        type_out(&mut fb, "\x1b[99;99H");
+        assert_eq!((fb.cursor_y, fb.cursor_x), (1, 7));
+        type_out(&mut fb, "\x1b[H");
+        assert_eq!((fb.cursor_y, fb.cursor_x), (0, 0));
+    }
+
+    #[test]
+    fn unknown_sequences_are_swallowed() {
+        let mut fb = fake_screen(64, 16);
+
+        type_out(&mut fb, "\x1b[6n\x1b[?25l\x1b7");
+        assert_eq!((fb.cursor_y, fb.cursor_x), (0, 0));
+        assert!(matches!(fb.ansi, Ansi::Ground));
+        assert_eq!(pixel(&fb, 0, 3), 0);
+    }
+
+    #[test]
+    fn clear_screen_blanks_every_pixel() {
+        let mut fb = fake_screen(64, 16);
+
+        type_out(&mut fb, "\x1b[42mAB\x1b[2J");
+        for y in 0..16 {
+            for x in 0..64 {
+                assert_eq!(pixel(&fb, x, y), PALETTE[2]);
+            }
+        }
+    }
+
+    #[test]
+    fn dimensions_are_whole_glyphs_once_allocated() {
+        use console::interface::Write;
+
+        let mailbox = Box::leak(Box::new(unsafe { Mailbox::new(0) }));
+        let fb = unsafe { FrameBuffer::new(mailbox, 1024, 768) };
+        assert_eq!(fb.dimensions(), None);
+
+        let screen = fake_screen(1028, 771);
+        let mut r = &fb.inner;
+        r.lock(|inner| *inner = screen);
+        assert_eq!(fb.dimensions(), Some((128, 96)));
+    }
+}
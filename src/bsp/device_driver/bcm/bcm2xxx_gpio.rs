@@ -1,25 +1,13 @@
-use crate::{cpu, driver, synchronization, synchronization::NullLock};
-use core::ops;
+use super::SystemTimer;
+use crate::{
+    bsp::device_driver::mmio::MMIODerefWrapper, driver, driver::DriverError, synchronization,
+    synchronization::NullLock,
+};
+use core::{ops, str::FromStr};
 use register::{mmio::*, register_bitfields, register_structs};
 
 register_bitfields! {
     u32,
-    // GPIO Function Select 1
-    GPFSEL1 [
-        // Pin 15
-        FSEL15 OFFSET(15) NUMBITS(3) [
-            Input = 0b000,
-            Output = 0b001,
-            AltFunc0 = 0b100 // PL011 UART RX
-        ],
-
-        // Pin 14
-        FSEL14 OFFSET(12) NUMBITS(3) [
-            Input = 0b000,
-            Output = 0b001,
-            AltFunc0 = 0b100 // PL011 UART TX
-        ]
-    ],
     // GPIO Pull-up/down Clock Register 0
     GPPUDCLK0 [
         // Pin 15
@@ -39,22 +27,147 @@ register_bitfields! {
 register_structs! {
     #[allow(non_snake_case)]
     RegisterBlock {
-        (0x00 => GPFSEL0: ReadWrite<u32>),
-        (0x04 => GPFSEL1: ReadWrite<u32, GPFSEL1::Register>),
-        (0x08 => GPFSEL2: ReadWrite<u32>),
-        (0x0C => GPFSEL3: ReadWrite<u32>),
-        (0x10 => GPFSEL4: ReadWrite<u32>),
-        (0x14 => GPFSEL5: ReadWrite<u32>),
+        (0x00 => GPFSEL: [ReadWrite<u32>; 6]),
         (0x18 => _reserved1),
+        (0x1C => GPSET: [WriteOnly<u32>; 2]),
+        (0x24 => _reserved2),
+        (0x28 => GPCLR: [WriteOnly<u32>; 2]),
+        (0x30 => _reserved3),
+        (0x34 => GPLEV: [ReadOnly<u32>; 2]),
+        (0x3C => _reserved4),
+        (0x40 => GPEDS: [ReadWrite<u32>; 2]),
+        (0x48 => _reserved5),
+        (0x4C => GPREN: [ReadWrite<u32>; 2]),
+        (0x54 => _reserved6),
+        (0x58 => GPFEN: [ReadWrite<u32>; 2]),
+        (0x60 => _reserved7),
+        (0x64 => GPHEN: [ReadWrite<u32>; 2]),
+        (0x6C => _reserved8),
+        (0x70 => GPLEN: [ReadWrite<u32>; 2]),
+        (0x78 => _reserved9),
         (0x94 => GPPUD: ReadWrite<u32>),
         (0x98 => GPPUDCLK0: ReadWrite<u32, GPPUDCLK0::Register>),
         (0x9C => GPPUDCLK1: ReadWrite<u32>),
-        (0xA0 => @END),
+        (0xA0 => _reserved10),
+        (0xE4 => GPIO_PUP_PDN_CNTRL: [ReadWrite<u32>; 4]),
+        (0xF4 => @END),
+    }
+}
+
+// BCM2837 exposes GPIO 0-53, BCM2711 GPIO 0-57
+#[cfg(feature = "bsp_rpi3")]
+const GPIO_PIN_COUNT: u32 = 54;
+#[cfg(feature = "bsp_rpi4")]
+const GPIO_PIN_COUNT: u32 = 58;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Pull {
+    None,
+    Up,
+    Down,
+}
+
+/// What a pin is connected to. The alternate functions differ per pin, e.g.
+/// GPIO 14/15 are the PL011 on `Alt0` and the mini UART on `Alt5`.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Function {
+    Input = 0b000,
+    Output = 0b001,
+    Alt0 = 0b100,
+    Alt1 = 0b101,
+    Alt2 = 0b110,
+    Alt3 = 0b111,
+    Alt4 = 0b011,
+    Alt5 = 0b010,
+}
+
+/// What sets a pin's bit in GPEDS. Edges are synchronized to the system
+/// clock, so glitches shorter than a couple of cycles are ignored.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Event {
+    RisingEdge,
+    FallingEdge,
+    HighLevel,
+    LowLevel,
+}
+
+// By the names the shell's `gpio` command takes
+impl FromStr for Pull {
+    type Err = DriverError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Pull::None),
+            "up" => Ok(Pull::Up),
+            "down" => Ok(Pull::Down),
+            _ => Err(DriverError::InvalidConfig),
+        }
     }
 }
 
+impl FromStr for Function {
+    type Err = DriverError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "in" => Ok(Function::Input),
+            "out" => Ok(Function::Output),
+            "alt0" => Ok(Function::Alt0),
+            "alt1" => Ok(Function::Alt1),
+            "alt2" => Ok(Function::Alt2),
+            "alt3" => Ok(Function::Alt3),
+            "alt4" => Ok(Function::Alt4),
+            "alt5" => Ok(Function::Alt5),
+            _ => Err(DriverError::InvalidConfig),
+        }
+    }
+}
+
+impl FromStr for Event {
+    type Err = DriverError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rising" => Ok(Event::RisingEdge),
+            "falling" => Ok(Event::FallingEdge),
+            "high" => Ok(Event::HighLevel),
+            "low" => Ok(Event::LowLevel),
+            _ => Err(DriverError::InvalidConfig),
+        }
+    }
+}
+
+// GPFSEL register index and shift of a pin's 3-bit field, 10 pins per register
+fn function_field(pin: u32) -> (usize, u32) {
+    ((pin / 10) as usize, (pin % 10) * 3)
+}
+
+fn select_function(regs: &RegisterBlock, pin: u32, func: Function) {
+    let (index, shift) = function_field(pin);
+    let reg = &regs.GPFSEL[index];
+    reg.set((reg.get() & !(0b111 << shift)) | (func as u32) << shift);
+}
+
+// Returns the register index and bit mask for a pin in a 32-pin-per-register bank
+fn pin_bank(pin: u32) -> Result<(usize, u32), DriverError> {
+    if pin >= GPIO_PIN_COUNT {
+        return Err(DriverError::InvalidConfig);
+    }
+
+    Ok(((pin / 32) as usize, 1 << (pin % 32)))
+}
+
+/// Routes GPIO 14/15 to the mini UART without going through the driver's
+/// lock, for the panic path only. Leaves the pull resistors alone.
+pub unsafe fn panic_map_mini_uart(base_addr: usize) {
+    let regs = MMIODerefWrapper::<RegisterBlock>::new(base_addr);
+    select_function(&regs, 14, Function::Alt5);
+    select_function(&regs, 15, Function::Alt5);
+}
+
 struct GPIOInner {
-    base_addr: usize,
+    registers: MMIODerefWrapper<RegisterBlock>,
+    timer: &'static SystemTimer,
 }
 
 pub struct GPIO {
@@ -65,51 +178,322 @@ impl ops::Deref for GPIOInner {
     type Target = RegisterBlock;
 
     fn deref(&self) -> &Self::Target {
-        unsafe { &*self.ptr() }
+        &self.registers
     }
 }
 
 impl GPIOInner {
-    const fn new(base_addr: usize) -> Self {
-        Self { base_addr }
+    const unsafe fn new(base_addr: usize, timer: &'static SystemTimer) -> Self {
+        Self {
+            registers: MMIODerefWrapper::new(base_addr),
+            timer,
+        }
+    }
+
+    fn event_enable(&self, event: Event) -> &[ReadWrite<u32>; 2] {
+        match event {
+            Event::RisingEdge => &self.GPREN,
+            Event::FallingEdge => &self.GPFEN,
+            Event::HighLevel => &self.GPHEN,
+            Event::LowLevel => &self.GPLEN,
+        }
+    }
+
+    // BCM2837: the pull control is latched into the pin by pulsing its GPPUDCLK bit
+    #[cfg(feature = "bsp_rpi3")]
+    fn set_pull(&mut self, pin: u32, pull: Pull) -> Result<(), DriverError> {
+        let (index, mask) = pin_bank(pin)?;
+        let pudclk = |val| match index {
+            0 => self.GPPUDCLK0.set(val),
+            _ => self.GPPUDCLK1.set(val),
+        };
+
+        self.GPPUD.set(match pull {
+            Pull::None => 0b00,
+            Pull::Down => 0b01,
+            Pull::Up => 0b10,
+        });
+        self.timer.delay_micros(1);
+
+        pudclk(mask);
+        self.timer.delay_micros(1);
+
+        self.GPPUD.set(0);
+        pudclk(0);
+
+        Ok(())
     }
 
-    fn ptr(&self) -> *const RegisterBlock {
-        self.base_addr as *const _
+    // BCM2711: GPPUD/GPPUDCLK are gone, each pin has a 2-bit field in GPIO_PUP_PDN_CNTRL
+    #[cfg(feature = "bsp_rpi4")]
+    fn set_pull(&mut self, pin: u32, pull: Pull) -> Result<(), DriverError> {
+        pin_bank(pin)?;
+
+        let reg = &self.GPIO_PUP_PDN_CNTRL[(pin / 16) as usize];
+        let shift = (pin % 16) * 2;
+        let val = match pull {
+            Pull::None => 0b00,
+            Pull::Up => 0b01,
+            Pull::Down => 0b10,
+        };
+        reg.set((reg.get() & !(0b11 << shift)) | (val << shift));
+
+        Ok(())
     }
 }
 
 impl GPIO {
-    pub const unsafe fn new(base_addr: usize) -> Self {
+    pub const unsafe fn new(base_addr: usize, timer: &'static SystemTimer) -> Self {
         Self {
-            inner: NullLock::new(GPIOInner::new(base_addr)),
+            inner: NullLock::new(GPIOInner::new(base_addr, timer)),
         }
     }
 
+    pub unsafe fn set_base_addr(&self, base_addr: usize) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.registers = MMIODerefWrapper::new(base_addr));
+    }
+
     pub fn map_pl011_uart(&self) {
         let mut r = &self.inner;
         r.lock(|inner| {
-            inner
-                .GPFSEL1
-                .modify(GPFSEL1::FSEL14::AltFunc0 + GPFSEL1::FSEL15::AltFunc0);
-            
+            select_function(inner, 14, Function::Alt0);
+            select_function(inner, 15, Function::Alt0);
+
             inner.GPPUD.set(0);
-            cpu::spin_for_cycles(150);
+            inner.timer.delay_micros(1);
 
             inner
                 .GPPUDCLK0
                 .write(GPPUDCLK0::PUDCLK14::AssertClock + GPPUDCLK0::PUDCLK15::AssertClock);
-            cpu::spin_for_cycles(150);
+            inner.timer.delay_micros(1);
 
             inner.GPPUDCLK0.set(0);
         });
     }
+
+    pub fn set_function(&self, pin: u32, func: Function) -> Result<(), DriverError> {
+        if pin >= GPIO_PIN_COUNT {
+            return Err(DriverError::InvalidConfig);
+        }
+
+        let mut r = &self.inner;
+        r.lock(|inner| select_function(inner, pin, func));
+
+        Ok(())
+    }
+
+    pub fn set_pull(&self, pin: u32, pull: Pull) -> Result<(), DriverError> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.set_pull(pin, pull))
+    }
+
+    pub fn set_high(&self, pin: u32) -> Result<(), DriverError> {
+        let (index, mask) = pin_bank(pin)?;
+        let mut r = &self.inner;
+        r.lock(|inner| inner.GPSET[index].set(mask));
+
+        Ok(())
+    }
+
+    pub fn set_low(&self, pin: u32) -> Result<(), DriverError> {
+        let (index, mask) = pin_bank(pin)?;
+        let mut r = &self.inner;
+        r.lock(|inner| inner.GPCLR[index].set(mask));
+
+        Ok(())
+    }
+
+    /// Starts latching `event` on `pin` into its GPEDS bit, for `poll_event`.
+    /// A pin can watch several events at once.
+    pub fn enable_event(&self, pin: u32, event: Event) -> Result<(), DriverError> {
+        let (index, mask) = pin_bank(pin)?;
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            let reg = &inner.event_enable(event)[index];
+            reg.set(reg.get() | mask);
+        });
+
+        Ok(())
+    }
+
+    pub fn disable_event(&self, pin: u32, event: Event) -> Result<(), DriverError> {
+        let (index, mask) = pin_bank(pin)?;
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            let reg = &inner.event_enable(event)[index];
+            reg.set(reg.get() & !mask);
+        });
+
+        Ok(())
+    }
+
+    /// Whether an enabled event has fired on `pin` since the last call. The
+    /// bit is cleared on the way out, though a level event that still holds
+    /// sets it straight back.
+    pub fn poll_event(&self, pin: u32) -> Result<bool, DriverError> {
+        let (index, mask) = pin_bank(pin)?;
+        let mut r = &self.inner;
+        Ok(r.lock(|inner| {
+            let fired = inner.GPEDS[index].get() & mask != 0;
+            if fired {
+                // Write 1 to clear, zeros leave the other pins alone
+                inner.GPEDS[index].set(mask);
+            }
+            fired
+        }))
+    }
+
+    pub fn read(&self, pin: u32) -> Result<bool, DriverError> {
+        let (index, mask) = pin_bank(pin)?;
+        let mut r = &self.inner;
+        Ok(r.lock(|inner| inner.GPLEV[index].get() & mask != 0))
+    }
 }
 
 use synchronization::interface::Mutex;
 
 impl driver::interface::DeviceDriver for GPIO {
-    fn compatible(&self) -> &str {
+    fn name(&self) -> &str {
         "BCM GPIO"
     }
-}
\ No newline at end of file
+
+    fn compatible(&self) -> &str {
+        "brcm,bcm2835-gpio"
+    }
+
+    // Pull and pin mapping sequences wait on the system timer
+    fn dependencies(&self) -> &[&str] {
+        &["brcm,bcm2835-system-timer"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::device_driver::mmio::fake_registers;
+    use core::sync::atomic::{AtomicU32, Ordering};
+    use std::boxed::Box;
+
+    fn fake_gpio() -> (&'static [AtomicU32], GPIO) {
+        let regs = fake_registers::<RegisterBlock>();
+        // Never read: only the pull and pin mapping sequences wait on it
+        let timer_regs = fake_registers::<[u32; 8]>();
+        let timer = Box::leak(Box::new(unsafe { SystemTimer::new(timer_regs.as_ptr() as usize) }));
+        let gpio = unsafe { GPIO::new(regs.as_ptr() as usize, timer) };
+
+        (regs, gpio)
+    }
+
+    fn word(regs: &[AtomicU32], offset: usize) -> u32 {
+        regs[offset / 4].load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn pin_bank_splits_at_32() {
+        assert_eq!(pin_bank(0), Ok((0, 1)));
+        assert_eq!(pin_bank(31), Ok((0, 1 << 31)));
+        assert_eq!(pin_bank(32), Ok((1, 1)));
+        assert_eq!(pin_bank(GPIO_PIN_COUNT - 1), Ok((1, 1 << (GPIO_PIN_COUNT - 33))));
+        assert_eq!(pin_bank(GPIO_PIN_COUNT), Err(DriverError::InvalidConfig));
+    }
+
+    #[test]
+    fn names_parse_to_their_variants() {
+        assert!("down".parse::<Pull>() == Ok(Pull::Down));
+        assert!("alt4".parse::<Function>() == Ok(Function::Alt4));
+        assert!("in".parse::<Function>() == Ok(Function::Input));
+        assert!("rising".parse::<Event>() == Ok(Event::RisingEdge));
+        assert!("alt6".parse::<Function>().is_err());
+        assert!("Up".parse::<Pull>().is_err());
+    }
+
+    #[test]
+    fn function_field_packs_ten_pins_per_register() {
+        assert_eq!(function_field(0), (0, 0));
+        assert_eq!(function_field(9), (0, 27));
+        assert_eq!(function_field(14), (1, 12));
+        assert_eq!(function_field(53), (5, 9));
+    }
+
+    #[test]
+    fn set_function_writes_only_the_pins_field() {
+        let (regs, gpio) = fake_gpio();
+
+        regs[0x04 / 4].store(0xFFFF_FFFF, Ordering::Relaxed);
+        gpio.set_function(14, Function::Alt5).unwrap();
+        assert_eq!(word(regs, 0x04), 0xFFFF_AFFF);
+        gpio.set_function(15, Function::Alt0).unwrap();
+        assert_eq!(word(regs, 0x04), 0xFFFE_2FFF);
+        let ret = gpio.set_function(GPIO_PIN_COUNT, Function::Output);
+        assert_eq!(ret, Err(DriverError::InvalidConfig));
+    }
+
+    #[test]
+    fn set_and_clear_hit_the_pin_bank() {
+        let (regs, gpio) = fake_gpio();
+
+        gpio.set_high(35).unwrap();
+        assert_eq!(word(regs, 0x20), 1 << 3);
+        gpio.set_low(4).unwrap();
+        assert_eq!(word(regs, 0x28), 1 << 4);
+        assert_eq!(gpio.set_high(GPIO_PIN_COUNT), Err(DriverError::InvalidConfig));
+    }
+
+    #[test]
+    fn read_masks_the_level() {
+        let (regs, gpio) = fake_gpio();
+
+        regs[0x38 / 4].store(1 << 1, Ordering::Relaxed);
+        assert_eq!(gpio.read(33), Ok(true));
+        assert_eq!(gpio.read(32), Ok(false));
+        assert_eq!(gpio.read(1), Ok(false));
+    }
+
+    #[test]
+    fn events_pick_the_register_and_bank() {
+        let (regs, gpio) = fake_gpio();
+
+        gpio.enable_event(31, Event::RisingEdge).unwrap();
+        gpio.enable_event(32, Event::RisingEdge).unwrap();
+        gpio.enable_event(33, Event::FallingEdge).unwrap();
+        gpio.enable_event(2, Event::HighLevel).unwrap();
+        gpio.enable_event(3, Event::HighLevel).unwrap();
+        gpio.enable_event(40, Event::LowLevel).unwrap();
+        assert_eq!((word(regs, 0x4C), word(regs, 0x50)), (1 << 31, 1 << 0));
+        assert_eq!((word(regs, 0x58), word(regs, 0x5C)), (0, 1 << 1));
+        assert_eq!((word(regs, 0x64), word(regs, 0x68)), (0b1100, 0));
+        assert_eq!((word(regs, 0x70), word(regs, 0x74)), (0, 1 << 8));
+
+        gpio.disable_event(2, Event::HighLevel).unwrap();
+        assert_eq!(word(regs, 0x64), 0b1000);
+        let ret = gpio.enable_event(GPIO_PIN_COUNT, Event::RisingEdge);
+        assert_eq!(ret, Err(DriverError::InvalidConfig));
+    }
+
+    #[test]
+    fn poll_event_clears_only_its_own_bit() {
+        let (regs, gpio) = fake_gpio();
+
+        regs[0x44 / 4].store(1 << 1 | 1 << 2, Ordering::Relaxed);
+        assert_eq!(gpio.poll_event(33), Ok(true));
+        // The fake keeps what's written, the hardware would clear that bit
+        assert_eq!(word(regs, 0x44), 1 << 1);
+        assert_eq!(gpio.poll_event(1), Ok(false));
+        assert_eq!(word(regs, 0x40), 0);
+        assert_eq!(gpio.poll_event(GPIO_PIN_COUNT), Err(DriverError::InvalidConfig));
+    }
+
+    #[cfg(feature = "bsp_rpi4")]
+    #[test]
+    fn set_pull_writes_only_the_pins_field() {
+        let (regs, gpio) = fake_gpio();
+
+        regs[0xE8 / 4].store(0xFFFF_FFFF, Ordering::Relaxed);
+        gpio.set_pull(17, Pull::Up).unwrap();
+        assert_eq!(word(regs, 0xE8), 0xFFFF_FFF7);
+        gpio.set_pull(17, Pull::None).unwrap();
+        assert_eq!(word(regs, 0xE8), 0xFFFF_FFF3);
+        gpio.set_pull(63, Pull::Down).unwrap_err();
+    }
+}
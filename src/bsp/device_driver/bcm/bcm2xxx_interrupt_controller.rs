@@ -0,0 +1,139 @@
+use crate::{driver, driver::DriverError, exception, synchronization, synchronization::NullLock};
+use core::ops;
+use register::{mmio::*, register_structs};
+
+register_structs! {
+    #[allow(non_snake_case)]
+    RegisterBlock {
+        (0x00 => BASIC_PENDING: ReadOnly<u32>),
+        (0x04 => PENDING: [ReadOnly<u32>; 2]),
+        (0x0C => FIQ_CONTROL: ReadWrite<u32>),
+        (0x10 => ENABLE: [WriteOnly<u32>; 2]),
+        (0x18 => ENABLE_BASIC: WriteOnly<u32>),
+        (0x1C => DISABLE: [WriteOnly<u32>; 2]),
+        (0x24 => DISABLE_BASIC: WriteOnly<u32>),
+        (0x28 => @END),
+    }
+}
+
+// The 64 VideoCore peripheral IRQs. The basic (ARM-side) sources are unused.
+pub const IRQ_COUNT: usize = 64;
+
+type Handler = &'static (dyn exception::interface::IRQHandler + Sync);
+
+struct InterruptControllerInner {
+    base_addr: usize,
+    handlers: [Option<Handler>; IRQ_COUNT],
+}
+
+/// The legacy (pre-GIC) interrupt controller. It routes to core 0 only, and
+/// on the BCM2711 it is only wired up when the firmware is told `enable_gic=0`.
+pub struct InterruptController {
+    inner: NullLock<InterruptControllerInner>,
+}
+
+impl ops::Deref for InterruptControllerInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl InterruptControllerInner {
+    const fn new(base_addr: usize) -> Self {
+        Self {
+            base_addr,
+            handlers: [None; IRQ_COUNT],
+        }
+    }
+
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+}
+
+impl InterruptController {
+    pub const unsafe fn new(base_addr: usize) -> Self {
+        Self {
+            inner: NullLock::new(InterruptControllerInner::new(base_addr)),
+        }
+    }
+
+    pub unsafe fn set_base_addr(&self, base_addr: usize) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.base_addr = base_addr);
+    }
+}
+
+use synchronization::interface::Mutex;
+
+impl driver::interface::DeviceDriver for InterruptController {
+    fn name(&self) -> &str {
+        "BCM Legacy Interrupt Controller"
+    }
+
+    fn compatible(&self) -> &str {
+        "brcm,bcm2836-armctrl-ic"
+    }
+
+    fn init(&self) -> Result<(), DriverError> {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.FIQ_CONTROL.set(0);
+            inner.DISABLE[0].set(u32::MAX);
+            inner.DISABLE[1].set(u32::MAX);
+            inner.DISABLE_BASIC.set(u32::MAX);
+        });
+
+        Ok(())
+    }
+}
+
+impl exception::interface::IRQManager for InterruptController {
+    fn register_handler(&self, irq: usize, handler: Handler) -> Result<(), DriverError> {
+        if irq >= IRQ_COUNT {
+            return Err(DriverError::InvalidConfig);
+        }
+
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            if inner.handlers[irq].is_some() {
+                return Err(DriverError::InvalidConfig);
+            }
+            inner.handlers[irq] = Some(handler);
+
+            Ok(())
+        })
+    }
+
+    fn enable(&self, irq: usize) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.ENABLE[irq / 32].set(1 << (irq % 32)));
+    }
+
+    fn disable(&self, irq: usize) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.DISABLE[irq / 32].set(1 << (irq % 32)));
+    }
+
+    fn dispatch(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            for bank in 0..inner.PENDING.len() {
+                let mut pending = inner.PENDING[bank].get();
+
+                while pending != 0 {
+                    let bit = pending.trailing_zeros() as usize;
+                    pending &= !(1 << bit);
+
+                    let irq = bank * 32 + bit;
+                    match inner.handlers[irq] {
+                        Some(handler) => handler.handle(),
+                        None => panic!("No handler for IRQ {}", irq),
+                    }
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,257 @@
+use crate::{cpu, driver, driver::ProbeInfo, synchronization, synchronization::NullLock};
+use core::ops;
+use register::{mmio::*, register_bitfields, register_structs};
+
+register_bitfields! {
+    u32,
+
+    // Mailbox status
+    STATUS [
+        // Mailbox full
+        FULL OFFSET(31) NUMBITS(1) [],
+        // Mailbox empty
+        EMPTY OFFSET(30) NUMBITS(1) []
+    ]
+}
+
+register_structs! {
+    #[allow(non_snake_case)]
+    RegisterBlock {
+        (0x00 => READ: ReadOnly<u32>),
+        (0x04 => _reserved1),
+        (0x18 => STATUS0: ReadOnly<u32, STATUS::Register>),
+        (0x1C => _reserved2),
+        (0x20 => WRITE: WriteOnly<u32>),
+        (0x24 => _reserved3),
+        (0x38 => STATUS1: ReadOnly<u32, STATUS::Register>),
+        (0x3C => @END),
+    }
+}
+
+const CHANNEL_PROPERTY: u32 = 8;
+
+const REQUEST: u32 = 0x0000_0000;
+const RESPONSE_SUCCESS: u32 = 0x8000_0000;
+const TAG_RESPONSE: u32 = 0x8000_0000;
+const TAG_END: u32 = 0;
+
+pub const TAG_GET_FIRMWARE_REVISION: u32 = 0x0000_0001;
+pub const TAG_GET_BOARD_REVISION: u32 = 0x0001_0002;
+pub const TAG_GET_CLOCK_RATE: u32 = 0x0003_0002;
+
+pub mod clock {
+    pub const EMMC: u32 = 1;
+    pub const UART: u32 = 2;
+    pub const ARM: u32 = 3;
+    pub const CORE: u32 = 4;
+    pub const EMMC2: u32 = 12;
+}
+
+const BUFFER_WORDS: usize = 64;
+
+// The low 4 bits of the address written to the mailbox carry the channel, so
+// the message buffer has to be 16-byte aligned. The VideoCore reads and
+// writes it behind the ARM caches, so `call` cleans it before and invalidates
+// it after, which needs it to have its cache lines to itself.
+#[repr(C, align(64))]
+struct MessageBuffer([u32; BUFFER_WORDS]);
+
+struct MailboxInner {
+    base_addr: usize,
+    buffer: MessageBuffer,
+}
+
+pub struct Mailbox {
+    inner: NullLock<MailboxInner>,
+}
+
+impl ops::Deref for MailboxInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl MailboxInner {
+    const fn new(base_addr: usize) -> Self {
+        Self {
+            base_addr,
+            buffer: MessageBuffer([0; BUFFER_WORDS]),
+        }
+    }
+
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    fn call(&mut self, channel: u32) {
+        let addr = &self.buffer as *const _ as usize as u32;
+
+        // The buffer contents must be in memory before the VideoCore sees the address
+        cpu::barrier::dmb();
+        cpu::cache::clean_range(addr as usize, BUFFER_WORDS * 4);
+
+        while self.STATUS1.matches_all(STATUS::FULL::SET) {
+            cpu::nop();
+        }
+        self.WRITE.set(addr | channel);
+
+        loop {
+            while self.STATUS0.matches_all(STATUS::EMPTY::SET) {
+                cpu::nop();
+            }
+
+            if self.READ.get() == addr | channel {
+                break;
+            }
+        }
+
+        // Nothing of the response is read before the mailbox says it's there
+        cpu::barrier::dmb();
+        cpu::cache::invalidate_range(addr as usize, BUFFER_WORDS * 4);
+    }
+
+    // `tags` is a sequence of (tag, value buffer size, request/response code,
+    // values...) entries. The response is copied back into `tags`.
+    fn property(&mut self, tags: &mut [u32]) -> Result<(), ()> {
+        write_request(&mut self.buffer.0, tags)?;
+        self.call(CHANNEL_PROPERTY);
+        read_response(&self.buffer.0, tags)
+    }
+}
+
+// Frames `tags` with the message header and end tag
+fn write_request(buf: &mut [u32; BUFFER_WORDS], tags: &[u32]) -> Result<(), ()> {
+    let len = tags.len() + 3;
+    if len > BUFFER_WORDS {
+        return Err(());
+    }
+
+    buf[0] = (len * 4) as u32;
+    buf[1] = REQUEST;
+    buf[2..len - 1].copy_from_slice(tags);
+    buf[len - 1] = TAG_END;
+
+    Ok(())
+}
+
+fn read_response(buf: &[u32; BUFFER_WORDS], tags: &mut [u32]) -> Result<(), ()> {
+    if buf[1] != RESPONSE_SUCCESS {
+        return Err(());
+    }
+    tags.copy_from_slice(&buf[2..tags.len() + 2]);
+
+    Ok(())
+}
+
+impl Mailbox {
+    pub const unsafe fn new(base_addr: usize) -> Self {
+        Self {
+            inner: NullLock::new(MailboxInner::new(base_addr)),
+        }
+    }
+
+    pub unsafe fn set_base_addr(&self, base_addr: usize) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.base_addr = base_addr);
+    }
+
+    pub fn property(&self, tags: &mut [u32]) -> Result<(), ()> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.property(tags))
+    }
+
+    pub fn get_clock_rate(&self, clock_id: u32) -> Result<u32, ()> {
+        let mut tags = [TAG_GET_CLOCK_RATE, 8, 0, clock_id, 0];
+        self.property(&mut tags)?;
+
+        if tags[2] & TAG_RESPONSE == 0 {
+            return Err(());
+        }
+
+        Ok(tags[4])
+    }
+
+    /// The VideoCore firmware's build, as a Unix timestamp
+    pub fn get_firmware_revision(&self) -> Result<u32, ()> {
+        let mut tags = [TAG_GET_FIRMWARE_REVISION, 4, 0, 0];
+        self.property(&mut tags)?;
+
+        if tags[2] & TAG_RESPONSE == 0 {
+            return Err(());
+        }
+
+        Ok(tags[3])
+    }
+
+    /// The board revision code from the OTP, which names the SoC among other things
+    pub fn get_board_revision(&self) -> Result<u32, ()> {
+        let mut tags = [TAG_GET_BOARD_REVISION, 4, 0, 0];
+        self.property(&mut tags)?;
+
+        if tags[2] & TAG_RESPONSE == 0 {
+            return Err(());
+        }
+
+        Ok(tags[3])
+    }
+}
+
+use synchronization::interface::Mutex;
+
+impl driver::interface::DeviceDriver for Mailbox {
+    fn name(&self) -> &str {
+        "BCM Mailbox"
+    }
+
+    fn compatible(&self) -> &str {
+        "brcm,bcm2835-mbox"
+    }
+
+    fn probe(&self) -> Option<ProbeInfo> {
+        let revision = self.get_firmware_revision().ok()?;
+        let info = ProbeInfo::new("VideoCore firmware").with("revision", revision as u64);
+        match self.get_clock_rate(clock::ARM) {
+            Ok(hz) => Some(info.with("arm_hz", hz as u64)),
+            Err(()) => Some(info),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_request_frames_the_tags() {
+        let mut buf = [0xFFFF_FFFF; BUFFER_WORDS];
+
+        write_request(&mut buf, &[TAG_GET_CLOCK_RATE, 8, 0, clock::UART, 0]).unwrap();
+        assert_eq!(buf[..2], [32, REQUEST]);
+        assert_eq!(buf[2..7], [TAG_GET_CLOCK_RATE, 8, 0, clock::UART, 0]);
+        assert_eq!(buf[7..9], [TAG_END, 0xFFFF_FFFF]);
+    }
+
+    #[test]
+    fn write_request_rejects_what_does_not_fit() {
+        let mut buf = [0; BUFFER_WORDS];
+
+        assert!(write_request(&mut buf, &[0; BUFFER_WORDS - 3]).is_ok());
+        assert_eq!(write_request(&mut buf, &[0; BUFFER_WORDS - 2]), Err(()));
+    }
+
+    #[test]
+    fn read_response_copies_the_tags_back() {
+        let mut buf = [0; BUFFER_WORDS];
+        buf[..2].copy_from_slice(&[24, RESPONSE_SUCCESS]);
+        buf[2..6].copy_from_slice(&[TAG_GET_FIRMWARE_REVISION, 4, TAG_RESPONSE | 4, 0x5F1E_2B3C]);
+        let mut tags = [TAG_GET_FIRMWARE_REVISION, 4, 0, 0];
+
+        read_response(&buf, &mut tags).unwrap();
+        assert_eq!(tags, [TAG_GET_FIRMWARE_REVISION, 4, TAG_RESPONSE | 4, 0x5F1E_2B3C]);
+
+        buf[1] = 0x8000_0001;
+        assert_eq!(read_response(&buf, &mut tags), Err(()));
+    }
+}
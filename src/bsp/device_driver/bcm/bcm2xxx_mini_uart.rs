@@ -0,0 +1,417 @@
+use super::{clock, Function, Mailbox, GPIO};
+use crate::{
+    console, cpu, driver,
+    driver::{DriverError, ProbeInfo},
+    synchronization,
+    synchronization::IRQSafeSpinlock,
+};
+use core::{
+    fmt, ops,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use register::{mmio::*, register_bitfields, register_structs};
+
+register_bitfields! {
+    u32,
+
+    // Auxiliary peripheral enables
+    AUX_ENABLES [
+        MINI_UART OFFSET(0) NUMBITS(1) []
+    ],
+
+    // Line Control register
+    AUX_MU_LCR [
+        DATA_SIZE OFFSET(0) NUMBITS(2) [
+            SevenBit = 0b00,
+            EightBit = 0b11
+        ]
+    ],
+
+    // Line Status register
+    AUX_MU_LSR [
+        // Transmit FIFO empty and the last byte shifted out
+        TX_IDLE OFFSET(6) NUMBITS(1) [],
+        // Transmit FIFO can accept at least one byte
+        TX_EMPTY OFFSET(5) NUMBITS(1) [],
+        // Receive FIFO holds at least one byte
+        DATA_READY OFFSET(0) NUMBITS(1) []
+    ],
+
+    // Extra Control register
+    AUX_MU_CNTL [
+        TX_ENABLE OFFSET(1) NUMBITS(1) [],
+        RX_ENABLE OFFSET(0) NUMBITS(1) []
+    ]
+}
+
+register_structs! {
+    #[allow(non_snake_case)]
+    pub RegisterBlock {
+        (0x00 => _reserved1),
+        (0x04 => AUX_ENABLES: ReadWrite<u32, AUX_ENABLES::Register>),
+        (0x08 => _reserved2),
+        (0x40 => AUX_MU_IO: ReadWrite<u32>),
+        (0x44 => AUX_MU_IER: ReadWrite<u32>),
+        (0x48 => _reserved3),
+        (0x4C => AUX_MU_LCR: ReadWrite<u32, AUX_MU_LCR::Register>),
+        (0x50 => AUX_MU_MCR: ReadWrite<u32>),
+        (0x54 => AUX_MU_LSR: ReadOnly<u32, AUX_MU_LSR::Register>),
+        (0x58 => _reserved4),
+        (0x60 => AUX_MU_CNTL: ReadWrite<u32, AUX_MU_CNTL::Register>),
+        (0x64 => _reserved5),
+        (0x68 => AUX_MU_BAUD: ReadWrite<u32>),
+        (0x6C => @END),
+    }
+}
+
+// TXD1 and RXD1
+const PINS: [u32; 2] = [14, 15];
+
+/// The AUX_MU_BAUD value for `baud`, from `baud = core_clk_hz / (8 * (reg + 1))`
+/// with the division rounded to the nearest step.
+pub fn mini_uart_baud_reg(baud: u32, core_clk_hz: u32) -> Result<u32, DriverError> {
+    if baud == 0 {
+        return Err(DriverError::InvalidConfig);
+    }
+
+    let div = (core_clk_hz as u64 + baud as u64 * 4) / (baud as u64 * 8);
+    if div == 0 || div > 0x1_0000 {
+        return Err(DriverError::InvalidConfig);
+    }
+
+    Ok(div as u32 - 1)
+}
+
+/// The mini UART (UART1) in the auxiliary peripherals block. Its baud rate
+/// is derived from the VPU core clock, so it drifts if that clock changes.
+pub struct MiniUartInner {
+    base_addr: usize,
+    chars_written: usize,
+    chars_read: usize,
+    bytes_written: usize,
+    bytes_read: usize,
+    rx_decoder: console::Utf8Decoder,
+}
+
+pub use MiniUartInner as PanicMiniUart;
+
+/// The mini UART as a console, polled in both directions
+pub struct MiniUart {
+    inner: IRQSafeSpinlock<MiniUartInner>,
+    baud_rate: u32,
+    gpio: &'static GPIO,
+    mailbox: &'static Mailbox,
+    // For the emergency path, which can't take `inner`
+    emergency_base_addr: AtomicUsize,
+    enabled: AtomicBool,
+}
+
+impl ops::Deref for MiniUartInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl MiniUartInner {
+    pub const unsafe fn new(base_addr: usize) -> Self {
+        Self {
+            base_addr,
+            chars_written: 0,
+            chars_read: 0,
+            bytes_written: 0,
+            bytes_read: 0,
+            rx_decoder: console::Utf8Decoder::new(),
+        }
+    }
+
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    pub fn init(&mut self, baud: u32, core_clk_hz: u32) -> Result<(), DriverError> {
+        let baud_reg = mini_uart_baud_reg(baud, core_clk_hz)?;
+
+        self.AUX_ENABLES.modify(AUX_ENABLES::MINI_UART::SET);
+        self.AUX_MU_CNTL.set(0);
+        self.AUX_MU_IER.set(0);
+        self.AUX_MU_LCR.write(AUX_MU_LCR::DATA_SIZE::EightBit);
+        self.AUX_MU_MCR.set(0);
+        self.AUX_MU_BAUD.set(baud_reg);
+        self.AUX_MU_CNTL
+            .write(AUX_MU_CNTL::TX_ENABLE::SET + AUX_MU_CNTL::RX_ENABLE::SET);
+
+        Ok(())
+    }
+
+    fn put_byte(&mut self, b: u8) {
+        while !self.AUX_MU_LSR.matches_all(AUX_MU_LSR::TX_EMPTY::SET) {
+            cpu::spin_hint();
+        }
+        self.AUX_MU_IO.set(b as u32);
+        self.bytes_written += 1;
+    }
+
+    // Sent as UTF-8
+    fn write_char(&mut self, c: char) {
+        let mut utf8 = [0u8; 4];
+        for &b in c.encode_utf8(&mut utf8).as_bytes() {
+            self.put_byte(b);
+        }
+        self.chars_written += 1;
+    }
+
+    // Received as UTF-8, like the PL011. A sequence cut short by an empty
+    // FIFO is finished on a later call.
+    fn try_read_char(&mut self) -> Option<char> {
+        let regs = unsafe { &*self.ptr() };
+        let bytes_read = &mut self.bytes_read;
+        let c = self.rx_decoder.decode(|| {
+            if !regs.AUX_MU_LSR.matches_all(AUX_MU_LSR::DATA_READY::SET) {
+                return None;
+            }
+
+            *bytes_read += 1;
+            Some(regs.AUX_MU_IO.get() as u8)
+        })?;
+
+        self.chars_read += 1;
+        Some(c)
+    }
+}
+
+impl fmt::Write for MiniUartInner {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.write_char(c);
+        }
+
+        Ok(())
+    }
+}
+
+impl MiniUart {
+    pub const unsafe fn new(
+        base_addr: usize,
+        baud_rate: u32,
+        gpio: &'static GPIO,
+        mailbox: &'static Mailbox,
+    ) -> Self {
+        Self {
+            inner: IRQSafeSpinlock::new(MiniUartInner::new(base_addr)),
+            baud_rate,
+            gpio,
+            mailbox,
+            emergency_base_addr: AtomicUsize::new(base_addr),
+            enabled: AtomicBool::new(true),
+        }
+    }
+
+    /// Whether the driver manager should initialize it. Set before `init`.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub unsafe fn set_base_addr(&self, base_addr: usize) {
+        self.emergency_base_addr.store(base_addr, Ordering::Relaxed);
+        let mut r = &self.inner;
+        r.lock(|inner| inner.base_addr = base_addr);
+    }
+
+    // The registers, bypassing `inner`. See `emergency_write_char`.
+    fn emergency_regs(&self) -> &RegisterBlock {
+        let base_addr = self.emergency_base_addr.load(Ordering::Relaxed);
+        unsafe { &*(base_addr as *const RegisterBlock) }
+    }
+
+    /// Whether `init` has switched the transmitter on
+    pub fn tx_enabled(&self) -> bool {
+        let regs = self.emergency_regs();
+        regs.AUX_ENABLES.is_set(AUX_ENABLES::MINI_UART)
+            && regs.AUX_MU_CNTL.is_set(AUX_MU_CNTL::TX_ENABLE)
+    }
+}
+
+use synchronization::interface::Mutex;
+
+impl driver::interface::DeviceDriver for MiniUart {
+    fn name(&self) -> &str {
+        "BCM Mini UART"
+    }
+
+    fn compatible(&self) -> &str {
+        "brcm,bcm2835-aux-uart"
+    }
+
+    // The baud rate divides down the core clock, which the mailbox reports
+    fn dependencies(&self) -> &[&str] {
+        &["brcm,bcm2835-gpio", "brcm,bcm2835-mbox"]
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    fn probe(&self) -> Option<ProbeInfo> {
+        match self.tx_enabled() {
+            true => Some(ProbeInfo::new("8N1").with("baud", self.baud_rate as u64)),
+            false => None,
+        }
+    }
+
+    fn init(&self) -> Result<(), DriverError> {
+        let core_clk_hz = self
+            .mailbox
+            .get_clock_rate(clock::CORE)
+            .map_err(|_| DriverError::HardwareTimeout)?;
+
+        let mut r = &self.inner;
+        r.lock(|inner| inner.init(self.baud_rate, core_clk_hz))?;
+
+        // From the mailbox and the UART to GPIO
+        cpu::barrier::dsb();
+        for &pin in PINS.iter() {
+            self.gpio.set_function(pin, Function::Alt5)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl console::interface::Write for MiniUart {
+    fn write_char(&self, c: char) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.write_char(c));
+    }
+
+    fn write_fmt(&self, args: core::fmt::Arguments) -> fmt::Result {
+        let mut r = &self.inner;
+        r.lock(|inner| fmt::Write::write_fmt(inner, args))
+    }
+
+    fn write_bytes(&self, bytes: &[u8]) {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            for &b in bytes {
+                inner.put_byte(b);
+            }
+            inner.chars_written += bytes.len();
+        });
+    }
+
+    fn flush(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            while !inner.AUX_MU_LSR.matches_all(AUX_MU_LSR::TX_IDLE::SET) {
+                cpu::spin_hint();
+            }
+        });
+    }
+
+    // Straight to the FIFO, without statistics
+    fn emergency_write_char(&self, c: char) {
+        let regs = self.emergency_regs();
+        let mut utf8 = [0u8; 4];
+        for &b in c.encode_utf8(&mut utf8).as_bytes() {
+            while !regs.AUX_MU_LSR.matches_all(AUX_MU_LSR::TX_EMPTY::SET) {
+                cpu::spin_hint();
+            }
+            regs.AUX_MU_IO.set(b as u32);
+        }
+    }
+}
+
+impl console::interface::Read for MiniUart {
+    fn read_char(&self) -> char {
+        loop {
+            if let Some(c) = self.try_read_char() {
+                return c;
+            }
+            cpu::spin_hint();
+        }
+    }
+
+    fn try_read_char(&self) -> Option<char> {
+        let mut r = &self.inner;
+        let ret = r.lock(|inner| inner.try_read_char())?;
+
+        if ret == '\r' {
+            return Some('\n');
+        }
+        Some(ret)
+    }
+}
+
+impl console::interface::Statistics for MiniUart {
+    fn chars_written(&self) -> usize {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.chars_written)
+    }
+
+    fn chars_read(&self) -> usize {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.chars_read)
+    }
+
+    fn bytes_written(&self) -> usize {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.bytes_written)
+    }
+
+    fn bytes_read(&self) -> usize {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.bytes_read)
+    }
+
+    fn reset(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.chars_written = 0;
+            inner.chars_read = 0;
+            inner.bytes_written = 0;
+            inner.bytes_read = 0;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::device_driver::mmio::fake_registers;
+
+    const IO_INDEX: usize = 0x40 / 4;
+    const LSR_INDEX: usize = 0x54 / 4;
+    const LSR_DATA_READY: u32 = 1 << 0;
+
+    #[test]
+    fn mini_uart_baud_reg_at_250mhz() {
+        assert_eq!(mini_uart_baud_reg(115_200, 250_000_000), Ok(270));
+        // 250 MHz / 8 / 9600 is 3255.2, which rounds down
+        assert_eq!(mini_uart_baud_reg(9_600, 250_000_000), Ok(3254));
+        assert_eq!(mini_uart_baud_reg(31_250_000, 250_000_000), Ok(0));
+    }
+
+    #[test]
+    fn mini_uart_baud_reg_out_of_range() {
+        assert_eq!(mini_uart_baud_reg(0, 250_000_000), Err(DriverError::InvalidConfig));
+        assert_eq!(mini_uart_baud_reg(100_000_000, 250_000_000), Err(DriverError::InvalidConfig));
+        assert_eq!(mini_uart_baud_reg(300, 250_000_000), Err(DriverError::InvalidConfig));
+    }
+
+    #[test]
+    fn try_read_char_decodes_utf8() {
+        let regs = fake_registers::<RegisterBlock>();
+        let mut uart = unsafe { MiniUartInner::new(regs.as_ptr() as usize) };
+
+        assert_eq!(uart.try_read_char(), None);
+
+        regs[LSR_INDEX].store(LSR_DATA_READY, Ordering::Relaxed);
+        regs[IO_INDEX].store(b'A' as u32, Ordering::Relaxed);
+        assert_eq!(uart.try_read_char(), Some('A'));
+        // Never a lead byte, so not the Latin-1 char a cast would make of it
+        regs[IO_INDEX].store(0xff, Ordering::Relaxed);
+        assert_eq!(uart.try_read_char(), Some(core::char::REPLACEMENT_CHARACTER));
+        assert_eq!((uart.chars_read, uart.bytes_read), (2, 2));
+    }
+}
@@ -1,10 +1,39 @@
-use crate::{console, cpu, driver, synchronization, synchronization::NullLock};
-use core::{fmt, ops};
-use register::{mmio::*, register_bitfields, register_structs};
+use super::{ControlBlock, Function, DMA, DREQ_UART_TX, GPIO, SystemTimer};
+use crate::{
+    bsp::device_driver::mmio::MMIODerefWrapper,
+    console, cpu,
+    cpu::time::deadline_passed,
+    driver,
+    driver::{DriverError, ProbeInfo},
+    exception,
+    memory::{PhysicalAddress, VirtualAddress},
+    synchronization,
+    synchronization::{IRQSafeSpinlock, RingBuffer},
+};
+use core::{
+    fmt, ops,
+    str::FromStr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use register::{mmio::*, register_bitfields, register_structs, FieldValue};
 
 register_bitfields! {
     u32,
 
+    // Data Register
+    DR [
+        // Overrun error
+        OE OFFSET(11) NUMBITS(1) [],
+        // Break error
+        BE OFFSET(10) NUMBITS(1) [],
+        // Parity error
+        PE OFFSET(9) NUMBITS(1) [],
+        // Framing error
+        FE OFFSET(8) NUMBITS(1) [],
+        // Data character
+        DATA OFFSET(0) NUMBITS(8) []
+    ],
+
     // Flag Register
     FR [
         // Transmit FIFO empty
@@ -12,7 +41,9 @@ register_bitfields! {
         // Transmit FIFO full
         TXFF OFFSET(5) NUMBITS(1) [],
         // Receive FIFO empty
-        RXFE OFFSET(4) NUMBITS(1) []
+        RXFE OFFSET(4) NUMBITS(1) [],
+        // UART busy transmitting
+        BUSY OFFSET(3) NUMBITS(1) []
     ],
     // Integer Baud rate divisor
     IBRD [
@@ -39,8 +70,24 @@ register_bitfields! {
         ]
     ],
 
+    // Interrupt FIFO Level Select Register
+    IFLS [
+        // Receive interrupt FIFO level
+        RXIFLSEL OFFSET(3) NUMBITS(3) [
+            OneEighth = 0b000,
+            OneQuarter = 0b001,
+            Half = 0b010,
+            ThreeQuarters = 0b011,
+            SevenEighths = 0b100
+        ]
+    ],
+
     // Control Register
     CR [
+        // CTS hardware flow control enable, TX waits for nUARTCTS
+        CTSEN  OFFSET(15) NUMBITS(1) [],
+        // RTS hardware flow control enable, nUARTRTS follows the RX FIFO
+        RTSEN  OFFSET(14) NUMBITS(1) [],
         // Receive enable
         RXE    OFFSET(9) NUMBITS(1) [
             Disabled = 0,
@@ -51,16 +98,36 @@ register_bitfields! {
             Disabled = 0,
             Enabled = 1
         ],
+        // Loopback enable, TX feeds RX internally
+        LBE    OFFSET(7) NUMBITS(1) [],
         // UART enable
         UARTEN OFFSET(0) NUMBITS(1) [
            Disabled = 0,
             Enabled = 1
         ]
     ],
+    // Interrupt Mask Set/Clear Register
+    IMSC [
+        // Receive timeout interrupt mask
+        RTIM OFFSET(6) NUMBITS(1) [],
+        // Receive interrupt mask
+        RXIM OFFSET(4) NUMBITS(1) []
+    ],
+
     // Interrupt Clear Register
     ICR [
+        // Receive timeout interrupt clear
+        RTIC OFFSET(6) NUMBITS(1) [],
+        // Receive interrupt clear
+        RXIC OFFSET(4) NUMBITS(1) [],
         // Meta field for all pending interrupts
         ALL OFFSET(0) NUMBITS(11) []
+    ],
+
+    // DMA Control Register
+    DMACR [
+        // Raise the TX DREQ while the transmit FIFO has room
+        TXDMAE OFFSET(1) NUMBITS(1) []
     ]
 }
 
@@ -68,69 +135,455 @@ register_bitfields! {
 register_structs! {
     #[allow(non_snake_case)]
     pub RegisterBlock {
-        (0x00 => DR: ReadWrite<u32>),
+        (0x00 => DR: ReadWrite<u32, DR::Register>),
         (0x04 => _reserved1),
         (0x18 => FR: ReadOnly<u32, FR::Register>),
         (0x1c => _reserved2),
         (0x24 => IBRD: WriteOnly<u32, IBRD::Register>),
         (0x28 => FBRD: WriteOnly<u32, FBRD::Register>),
-        (0x2c => LCRH: WriteOnly<u32, LCRH::Register>),
-        (0x30 => CR: WriteOnly<u32, CR::Register>),
-        (0x34 => _reserved3),
+        (0x2c => LCRH: ReadWrite<u32, LCRH::Register>),
+        (0x30 => CR: ReadWrite<u32, CR::Register>),
+        (0x34 => IFLS: ReadWrite<u32, IFLS::Register>),
+        (0x38 => IMSC: ReadWrite<u32, IMSC::Register>),
+        (0x3c => _reserved3),
         (0x44 => ICR: WriteOnly<u32, ICR::Register>),
-        (0x48 => @END),
+        (0x48 => DMACR: ReadWrite<u32, DMACR::Register>),
+        (0x4c => @END),
+    }
+}
+
+/// How full the receive FIFO gets before the RX interrupt fires. Input that
+/// stops short of the level still arrives through the receive timeout.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Trigger {
+    OneEighth,
+    OneQuarter,
+    Half,
+    ThreeQuarters,
+    SevenEighths,
+}
+
+// By the fractions the shell's `uart trigger` command takes
+impl FromStr for Trigger {
+    type Err = DriverError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1/8" => Ok(Trigger::OneEighth),
+            "1/4" => Ok(Trigger::OneQuarter),
+            "1/2" => Ok(Trigger::Half),
+            "3/4" => Ok(Trigger::ThreeQuarters),
+            "7/8" => Ok(Trigger::SevenEighths),
+            _ => Err(DriverError::InvalidConfig),
+        }
     }
 }
 
+// Alternating bits, all zeros and all ones, and each nibble alone
+const LOOPBACK_PATTERN: [u8; 8] = [0x55, 0xAA, 0x00, 0xFF, 0x0F, 0xF0, 0x5A, 0xA5];
+// Each byte takes under 50us at 230400 baud
+const LOOPBACK_TIMEOUT_US: u64 = 1_000;
+
+// Shorter writes aren't worth setting up a transfer for
+const DMA_THRESHOLD: usize = 64;
+const DMA_TX_WORDS: usize = 1024;
+
+/// Staging area for DMA transmits. DR takes one character per write and the
+/// engine moves whole words, so every byte gets a word of its own. Like the
+/// control block, it must live in uncached memory.
+#[repr(C, align(32))]
+pub struct DMATxBuffer {
+    cb: ControlBlock,
+    words: [u32; DMA_TX_WORDS],
+}
+
+impl DMATxBuffer {
+    pub const fn new() -> Self {
+        Self {
+            cb: ControlBlock::new(),
+            words: [0; DMA_TX_WORDS],
+        }
+    }
+}
+
+struct DMATx {
+    dma: &'static DMA,
+    buffer: &'static mut DMATxBuffer,
+    // Bus address of DR, as the DMA engine sees it
+    dr_bus_addr: u32,
+}
+
 pub struct PL011UartInner {
-    base_addr: usize,
-    chars_written: usize,
-    chars_read: usize,
+    registers: MMIODerefWrapper<RegisterBlock>,
+    // As last set, 0 before `init`
+    baud: u32,
+    // Most polls of a full transmit FIFO before a character went in
+    max_tx_wait_polls: usize,
+    // Send "\r\n" for every '\n'
+    crlf: bool,
+    // RTS/CTS, kept across the CR rewrites in `init` and `set_baud_rate`
+    flow_control: bool,
+    dma: Option<DMATx>,
+    rx_decoder: console::Utf8Decoder,
 }
 
 pub use PL011UartInner as PanicUart;
 
+const RX_BUFFER_SIZE: usize = 256;
+
+// CTS0 and RTS0, in ALT3
+const FLOW_CONTROL_PINS: [u32; 2] = [16, 17];
+
 pub struct PL011Uart {
-    inner: NullLock<PL011UartInner>,
+    inner: IRQSafeSpinlock<PL011UartInner>,
+    baud_rate: u32,
+    clk_hz: u32,
+    timer: &'static SystemTimer,
+    // For the flow control pins
+    gpio: &'static GPIO,
+    // The RX interrupt can fire while another core holds `inner`, so the handler
+    // reaches the registers through its own copy of the base address and
+    // keeps its counters outside the lock. The emergency path uses it too.
+    // Holds a `VirtualAddress`, see `irq_base_addr()`.
+    irq_base_addr: AtomicUsize,
+    // Stays set across `shutdown`, so the panic path knows the UART worked
+    initialized: AtomicBool,
+    rx_buffer: RingBuffer<u8, RX_BUFFER_SIZE>,
+    framing_errors: AtomicUsize,
+    parity_errors: AtomicUsize,
+    break_errors: AtomicUsize,
+    overrun_errors: AtomicUsize,
+    // Outside `inner` as well, so reading them doesn't wait for a writer.
+    // The injected '\r' doesn't count as written, in chars or bytes.
+    chars_written: AtomicUsize,
+    chars_read: AtomicUsize,
+    bytes_written: AtomicUsize,
+    bytes_read: AtomicUsize,
+}
+
+/// Computes the (IBRD, FBRD) pair for `BAUDDIV = clk_hz / (16 * baud)`, with the
+/// 6-bit fractional part rounded up, so the line never runs faster than asked.
+pub fn baud_divisors(baud: u32, clk_hz: u32) -> Result<(u32, u32), DriverError> {
+    if baud == 0 {
+        return Err(DriverError::InvalidConfig);
+    }
+
+    // BAUDDIV * 64 = clk_hz * 4 / baud, rounded up
+    let scaled = clk_hz as u64 * 4;
+    let rem = scaled % baud as u64;
+    let div = scaled / baud as u64 + (rem != 0) as u64;
+    let ibrd = div >> 6;
+    let fbrd = div & 0x3f;
+
+    if ibrd == 0 || ibrd > 0xffff {
+        return Err(DriverError::InvalidConfig);
+    }
+
+    Ok((ibrd as u32, fbrd as u32))
+}
+
+/// The index of the first byte `received` got wrong or is missing, if any
+pub fn loopback_mismatch(sent: &[u8], received: &[u8]) -> Option<usize> {
+    match sent.iter().zip(received).position(|(s, r)| s != r) {
+        Some(i) => Some(i),
+        None if received.len() < sent.len() => Some(received.len()),
+        None => None,
+    }
 }
 
 impl ops::Deref for PL011UartInner {
     type Target = RegisterBlock;
 
     fn deref(&self) -> &Self::Target {
-        unsafe { &*self.ptr() }
+        &self.registers
     }
 }
 
+/// `b` as it goes out: after a '\r' if it is a '\n' and `crlf` is on
+fn with_crlf(b: u8, crlf: bool) -> impl Iterator<Item = u8> {
+    let cr = match crlf && b == b'\n' {
+        true => Some(b'\r'),
+        false => None,
+    };
+
+    cr.into_iter().chain(Some(b))
+}
+
 impl PL011UartInner {
-    pub const unsafe fn new(base_addr: usize) -> Self {
+    pub const unsafe fn new(base_addr: PhysicalAddress) -> Self {
         Self {
-            base_addr,
-            chars_written: 0,
-            chars_read: 0,
+            registers: MMIODerefWrapper::new(base_addr.to_virtual().as_usize()),
+            baud: 0,
+            max_tx_wait_polls: 0,
+            crlf: false,
+            flow_control: false,
+            dma: None,
+            rx_decoder: console::Utf8Decoder::new(),
         }
     }
 
-    pub fn init(&mut self) {
+    pub fn init(&mut self, baud: u32, clk_hz: u32) -> Result<(), DriverError> {
+        let (ibrd, fbrd) = baud_divisors(baud, clk_hz)?;
+
         self.CR.set(0);
 
         self.ICR.write(ICR::ALL::CLEAR);
-        self.IBRD.write(IBRD::IBRD.val(13));
-        self.FBRD.write(FBRD::FBRD.val(2));
+        self.IBRD.write(IBRD::IBRD.val(ibrd));
+        self.FBRD.write(FBRD::FBRD.val(fbrd));
         self.LCRH.write(LCRH::WLEN::EightBit + LCRH::FEN::FifosEnabled);
-        self.CR.write(CR::UARTEN::Enabled + CR::TXE::Enabled + CR::RXE::Enabled);
+        // The timeout interrupt covers input that doesn't reach the FIFO level
+        self.IMSC.write(IMSC::RXIM::SET + IMSC::RTIM::SET);
+        self.CR.write(self.cr_enabled());
+        self.baud = baud;
+
+        Ok(())
+    }
+
+    // CR with the UART, both directions and, if on, flow control enabled
+    fn cr_enabled(&self) -> FieldValue<u32, CR::Register> {
+        let cr = CR::UARTEN::Enabled + CR::TXE::Enabled + CR::RXE::Enabled;
+        match self.flow_control {
+            true => cr + CR::RTSEN::SET + CR::CTSEN::SET,
+            false => cr,
+        }
+    }
+
+    /// Whether the UART is switched on, i.e. `init` has taken effect
+    pub fn is_enabled(&self) -> bool {
+        self.CR.is_set(CR::UARTEN)
+    }
+
+    fn set_baud_rate(&mut self, baud: u32, clk_hz: u32) -> Result<(), DriverError> {
+        let (ibrd, fbrd) = baud_divisors(baud, clk_hz)?;
+
+        // Let the current transmission finish before disabling the UART
+        while self.FR.matches_all(FR::BUSY::SET) {
+            cpu::spin_hint();
+        }
+        self.CR.set(0);
+
+        // The divisors are only latched by a write to LCRH
+        self.IBRD.write(IBRD::IBRD.val(ibrd));
+        self.FBRD.write(FBRD::FBRD.val(fbrd));
+        self.LCRH.set(self.LCRH.get());
+        self.CR.write(self.cr_enabled());
+        self.baud = baud;
+
+        Ok(())
     }
 
-    fn ptr(&self) -> *const RegisterBlock {
-        self.base_addr as *const _
+    // LCRH may only change with the UART disabled, which drops any transfer
+    // in flight, so let it drain first. Clearing FEN also flushes the FIFOs.
+    fn set_fifo(&mut self, enabled: bool) {
+        while self.FR.matches_all(FR::BUSY::SET) {
+            cpu::spin_hint();
+        }
+        let cr = self.CR.get();
+        self.CR.set(0);
+
+        let fen = if enabled {
+            LCRH::FEN::FifosEnabled
+        } else {
+            LCRH::FEN::FifosDisabled
+        };
+        self.LCRH.modify(fen);
+        self.CR.set(cr);
+    }
+
+    // CR may only change with the UART idle and disabled, like LCRH, so the
+    // new flow control bits go in with the same write that re-enables it. A
+    // UART that was off is left for `init` to enable.
+    fn set_flow_control(&mut self, enable: bool) {
+        while self.FR.matches_all(FR::BUSY::SET) {
+            cpu::spin_hint();
+        }
+        let was_enabled = self.is_enabled();
+        self.CR.set(0);
+
+        self.flow_control = enable;
+        if was_enabled {
+            self.CR.write(self.cr_enabled());
+        }
     }
 
+    fn set_rx_trigger(&mut self, level: Trigger) {
+        let level = match level {
+            Trigger::OneEighth => IFLS::RXIFLSEL::OneEighth,
+            Trigger::OneQuarter => IFLS::RXIFLSEL::OneQuarter,
+            Trigger::Half => IFLS::RXIFLSEL::Half,
+            Trigger::ThreeQuarters => IFLS::RXIFLSEL::ThreeQuarters,
+            Trigger::SevenEighths => IFLS::RXIFLSEL::SevenEighths,
+        };
+        self.IFLS.modify(level);
+    }
+
+    // Sent as UTF-8
     fn write_char(&mut self, c: char) {
+        let mut utf8 = [0u8; 4];
+        for &b in c.encode_utf8(&mut utf8).as_bytes() {
+            self.write_data(b);
+        }
+    }
+
+    fn write_data(&mut self, b: u8) {
+        for b in with_crlf(b, self.crlf) {
+            self.put_byte(b);
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        let mut tx = match self.dma.take() {
+            Some(tx) if bytes.len() >= DMA_THRESHOLD => tx,
+            dma => {
+                self.dma = dma;
+                for &b in bytes {
+                    self.write_data(b);
+                }
+                return;
+            }
+        };
+
+        let mut len = 0;
+        let mut ok = true;
+        for &b in bytes {
+            // Leave room for an injected '\r'
+            if len + 2 > DMA_TX_WORDS {
+                ok &= self.send_dma(&mut tx, len);
+                len = 0;
+            }
+            for b in with_crlf(b, self.crlf) {
+                tx.buffer.words[len] = b as u32;
+                len += 1;
+            }
+        }
+        ok &= self.send_dma(&mut tx, len);
+
+        // Don't keep using an engine that failed
+        if ok {
+            self.dma = Some(tx);
+        }
+    }
+
+    // Sends the first `len` staged words. If the engine fails they go out
+    // through the FIFO instead, possibly repeating some that already went out.
+    //
+    // The engine only waits for FIFO space if both ends agree on the DREQ:
+    // TI.PERMAP must name the UART TX DREQ and DMACR.TXDMAE must be set.
+    // With either missing it writes the whole buffer into a full FIFO.
+    fn send_dma(&mut self, tx: &mut DMATx, len: usize) -> bool {
+        let src = tx.buffer.words.as_ptr() as usize;
+        tx.buffer.cb.set_to_peripheral(src, tx.dr_bus_addr, len * 4, DREQ_UART_TX);
+
+        self.DMACR.write(DMACR::TXDMAE::SET);
+        let ret = tx.dma.transfer(&tx.buffer.cb);
+        self.DMACR.set(0);
+
+        if ret.is_err() {
+            for i in 0..len {
+                self.put_byte(tx.buffer.words[i] as u8);
+            }
+            return false;
+        }
+
+        true
+    }
+
+    // A full FIFO frees a slot every character time, about 43 µs at 230400
+    // baud. `spin_hint` sleeps on the event stream between polls, so that is
+    // 3 or 4 polls of FR on a Pi 3 and about 9 on a Pi 4.
+    fn put_byte(&mut self, b: u8) {
+        let mut polls = 0;
         while self.FR.matches_all(FR::TXFF::SET) {
-            cpu::nop();
+            cpu::spin_hint();
+            polls += 1;
+        }
+        if polls > self.max_tx_wait_polls {
+            self.max_tx_wait_polls = polls;
         }
-        self.DR.set(c as u32);
-        self.chars_written += 1;
+
+        self.DR.set(b as u32);
+    }
+
+    pub fn flush(&self) {
+        while !self.FR.matches_all(FR::TXFE::SET) {
+            cpu::spin_hint();
+        }
+    }
+
+    // Once the last byte is off the wire, not just out of the FIFO
+    fn shutdown(&mut self) {
+        while self.FR.matches_all(FR::BUSY::SET) {
+            cpu::spin_hint();
+        }
+        self.IMSC.set(0);
+        self.DMACR.set(0);
+        self.CR.set(0);
+    }
+
+    // Input already waiting goes to `rx_buffer`, so the test doesn't eat it.
+    // The RX interrupt stays masked throughout, or it would take the echo.
+    fn self_test(
+        &mut self,
+        timer: &SystemTimer,
+        rx_buffer: &RingBuffer<u8, RX_BUFFER_SIZE>,
+    ) -> Result<(), DriverError> {
+        let imsc = self.IMSC.get();
+        self.IMSC.set(0);
+        while self.FR.matches_all(FR::BUSY::SET) {
+            cpu::spin_hint();
+        }
+        while !self.FR.matches_all(FR::RXFE::SET) {
+            rx_buffer.push(self.DR.read(DR::DATA) as u8);
+        }
+
+        let cr = self.CR.get();
+        self.CR.set(0);
+        self.CR
+            .write(CR::UARTEN::Enabled + CR::TXE::Enabled + CR::RXE::Enabled + CR::LBE::SET);
+
+        let mut received = [0u8; LOOPBACK_PATTERN.len()];
+        let mut len = 0;
+        let mut ret = Ok(());
+        for &b in LOOPBACK_PATTERN.iter() {
+            self.put_byte(b);
+
+            let start = timer.now_micros();
+            while self.FR.matches_all(FR::RXFE::SET) {
+                if deadline_passed(start, timer.now_micros(), LOOPBACK_TIMEOUT_US) {
+                    ret = Err(DriverError::HardwareTimeout);
+                    break;
+                }
+                cpu::spin_hint();
+            }
+            if ret.is_err() {
+                break;
+            }
+
+            let data = self.DR.extract();
+            if data.matches_any(DR::FE::SET + DR::PE::SET + DR::BE::SET + DR::OE::SET) {
+                ret = Err(DriverError::BusError);
+                break;
+            }
+            received[len] = data.read(DR::DATA) as u8;
+            len += 1;
+        }
+        if ret.is_ok() && loopback_mismatch(&LOOPBACK_PATTERN, &received[..len]).is_some() {
+            ret = Err(DriverError::BusError);
+        }
+
+        // Anything left of a failed run mustn't be mistaken for input
+        while self.FR.matches_all(FR::BUSY::SET) {
+            cpu::spin_hint();
+        }
+        self.CR.set(0);
+        while !self.FR.matches_all(FR::RXFE::SET) {
+            self.DR.get();
+        }
+        self.ICR.write(ICR::ALL::CLEAR);
+        self.CR.set(cr);
+        self.IMSC.set(imsc);
+
+        ret
     }
 }
 
@@ -145,69 +598,665 @@ impl fmt::Write for PL011UartInner {
 }
 
 impl PL011Uart {
-    pub const unsafe fn new(base_addr: usize) -> Self {
+    pub const unsafe fn new(
+        base_addr: PhysicalAddress,
+        baud_rate: u32,
+        clk_hz: u32,
+        timer: &'static SystemTimer,
+        gpio: &'static GPIO,
+    ) -> Self {
         Self {
-            inner: NullLock::new(PL011UartInner::new(base_addr)),
+            inner: IRQSafeSpinlock::new(PL011UartInner::new(base_addr)),
+            baud_rate,
+            clk_hz,
+            timer,
+            gpio,
+            irq_base_addr: AtomicUsize::new(base_addr.to_virtual().as_usize()),
+            initialized: AtomicBool::new(false),
+            rx_buffer: RingBuffer::new(),
+            framing_errors: AtomicUsize::new(0),
+            parity_errors: AtomicUsize::new(0),
+            break_errors: AtomicUsize::new(0),
+            overrun_errors: AtomicUsize::new(0),
+            chars_written: AtomicUsize::new(0),
+            chars_read: AtomicUsize::new(0),
+            bytes_written: AtomicUsize::new(0),
+            bytes_read: AtomicUsize::new(0),
+        }
+    }
+
+    /// Whether `init` has ever succeeded, even if the UART was shut down since
+    pub fn was_initialized(&self) -> bool {
+        self.initialized.load(Ordering::Relaxed)
+    }
+
+    pub unsafe fn set_base_addr(&self, base_addr: PhysicalAddress) {
+        let base_addr = base_addr.to_virtual();
+        self.irq_base_addr.store(base_addr.as_usize(), Ordering::Relaxed);
+        let mut r = &self.inner;
+        r.lock(|inner| inner.registers = MMIODerefWrapper::new(base_addr.as_usize()));
+    }
+
+    /// Sends writes of `DMA_THRESHOLD` bytes or more through `dma`, staged in
+    /// `buffer`. `dr_bus_addr` is the bus address of this UART's DR.
+    ///
+    /// # Safety
+    ///
+    /// `buffer` must be mapped uncached so the engine sees what was staged.
+    pub unsafe fn attach_dma(
+        &self,
+        dma: &'static DMA,
+        buffer: &'static mut DMATxBuffer,
+        dr_bus_addr: u32,
+    ) {
+        let mut r = &self.inner;
+        r.lock(move |inner| {
+            inner.dma = Some(DMATx {
+                dma,
+                buffer,
+                dr_bus_addr,
+            })
+        });
+    }
+
+    fn count_written(&self, chars: usize, bytes: usize) {
+        self.chars_written.fetch_add(chars, Ordering::Relaxed);
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    // The registers, bypassing `inner`. See `emergency_write_char`.
+    fn emergency_regs(&self) -> &RegisterBlock {
+        unsafe { &*(self.irq_base_addr().as_usize() as *const RegisterBlock) }
+    }
+
+    fn irq_base_addr(&self) -> VirtualAddress {
+        VirtualAddress::new(self.irq_base_addr.load(Ordering::Relaxed))
+    }
+
+    /// Waits for the transmit FIFO to drain without taking the lock, with the
+    /// same caveats as `emergency_write_char`
+    pub fn emergency_flush(&self) {
+        let regs = self.emergency_regs();
+        while !regs.FR.matches_all(FR::TXFE::SET) {
+            cpu::spin_hint();
         }
     }
+
+    /// Off by default
+    pub fn set_crlf(&self, enable: bool) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.crlf = enable);
+    }
+
+    pub fn set_baud_rate(&self, baud: u32, clk_hz: u32) -> Result<(), DriverError> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.set_baud_rate(baud, clk_hz))
+    }
+
+    /// With the FIFOs off every character raises its own interrupt, for the
+    /// lowest latency. On by default. Switching drops unread input.
+    pub fn set_fifo(&self, enabled: bool) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.set_fifo(enabled));
+    }
+
+    /// Turns RTS/CTS hardware flow control on or off, off by default.
+    ///
+    /// On the Pi this takes GPIO 16 (CTS0) and GPIO 17 (RTS0) in ALT3, so
+    /// they can't be used for anything else meanwhile, and the other end has
+    /// to wire them up: a floating CTS stalls the transmitter. RTS is
+    /// deasserted once the RX FIFO fills to the `set_rx_trigger` level, so a
+    /// lower level leaves the sender more slack to react.
+    pub fn set_flow_control(&self, enable: bool) -> Result<(), DriverError> {
+        // The pins first, so CTS isn't sampled off a floating input
+        let func = if enable { Function::Alt3 } else { Function::Input };
+        for &pin in FLOW_CONTROL_PINS.iter() {
+            self.gpio.set_function(pin, func)?;
+        }
+        // From GPIO to the UART
+        cpu::barrier::dsb();
+
+        let mut r = &self.inner;
+        r.lock(|inner| inner.set_flow_control(enable));
+
+        Ok(())
+    }
+
+    /// Half full by default
+    pub fn set_rx_trigger(&self, level: Trigger) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.set_rx_trigger(level));
+    }
+
+    /// Sends a byte pattern through the internal loopback and checks it
+    /// comes back unchanged, leaving the line settings as they were. Nothing
+    /// reaches the TX pin while it runs.
+    pub fn self_test(&self) -> Result<(), DriverError> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.self_test(self.timer, &self.rx_buffer))
+    }
 }
 
 use synchronization::interface::Mutex;
 
 impl driver::interface::DeviceDriver for PL011Uart {
-    fn compatible(&self) -> &str {
+    fn name(&self) -> &str {
         "BCM PL011 UART"
     }
 
-    fn init(&self) -> Result<(), ()> {
-        let mut r = &self.inner;
-        r.lock(|inner| inner.init());
+    fn compatible(&self) -> &str {
+        "arm,pl011"
+    }
+
+    // `read_char_timeout` keeps time with the system timer
+    fn dependencies(&self) -> &[&str] {
+        &["brcm,bcm2835-system-timer"]
+    }
 
+    fn init(&self) -> Result<(), DriverError> {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.init(self.baud_rate, self.clk_hz))?;
+        self.initialized.store(true, Ordering::Relaxed);
         Ok(())
     }
+
+    // `panic_console_out` brings it back up if it is needed after all
+    fn shutdown(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.shutdown());
+    }
+
+    fn probe(&self) -> Option<ProbeInfo> {
+        let mut r = &self.inner;
+        r.lock(|inner| match inner.baud {
+            0 => None,
+            baud => Some(ProbeInfo::new("8N1").with("baud", baud as u64)),
+        })
+    }
+}
+
+// Tallies what goes through `write_fmt`, to be added to the counters once
+struct CountingWriter<'a> {
+    inner: &'a mut PL011UartInner,
+    chars: usize,
+    bytes: usize,
+}
+
+impl fmt::Write for CountingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.chars += s.chars().count();
+        self.bytes += s.len();
+        fmt::Write::write_str(self.inner, s)
+    }
 }
 
 impl console::interface::Write for PL011Uart {
     fn write_char(&self, c: char) {
         let mut r = &self.inner;
         r.lock(|inner| inner.write_char(c));
+        self.count_written(1, c.len_utf8());
     }
 
     fn write_fmt(&self, args: core::fmt::Arguments) -> fmt::Result {
         let mut r = &self.inner;
-        r.lock(|inner| fmt::Write::write_fmt(inner, args))
+        let (ret, chars, bytes) = r.lock(|inner| {
+            let mut w = CountingWriter { inner, chars: 0, bytes: 0 };
+            let ret = fmt::Write::write_fmt(&mut w, args);
+            (ret, w.chars, w.bytes)
+        });
+        self.count_written(chars, bytes);
+        ret
+    }
+
+    // Holds the lock for the whole slice. Long ones go out by DMA if an
+    // engine is attached.
+    fn write_bytes(&self, bytes: &[u8]) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.write_bytes(bytes));
+        self.count_written(bytes.len(), bytes.len());
+    }
+
+    fn flush(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.flush());
+    }
+
+    // Straight to DR, without CRLF translation or statistics
+    fn emergency_write_char(&self, c: char) {
+        let regs = self.emergency_regs();
+        let mut utf8 = [0u8; 4];
+        for &b in c.encode_utf8(&mut utf8).as_bytes() {
+            while regs.FR.matches_all(FR::TXFF::SET) {
+                cpu::spin_hint();
+            }
+            regs.DR.set(b as u32);
+        }
     }
 }
 
 impl console::interface::Read for PL011Uart {
+    // Input arrives through the RX interrupt. A `wfi` could sleep through
+    // one that came in right after the check, or forever with IRQs masked,
+    // so poll at the event stream's pace instead.
     fn read_char(&self) -> char {
+        loop {
+            if let Some(c) = self.try_read_char() {
+                return c;
+            }
+            cpu::spin_hint();
+        }
+    }
+
+    // Decoded as UTF-8, a char only once all of its bytes are in
+    fn try_read_char(&self) -> Option<char> {
         let mut r = &self.inner;
-        r.lock(|inner| {
-            while inner.FR.matches_all(FR::RXFE::SET) {
-                cpu::nop();
+        let mut bytes = 0;
+        let ret = r.lock(|inner| {
+            inner.rx_decoder.decode(|| {
+                let b = self.rx_buffer.pop()?;
+                bytes += 1;
+                Some(b)
+            })
+        });
+
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+        let ret = ret?;
+        self.chars_read.fetch_add(1, Ordering::Relaxed);
+
+        match ret {
+            '\r' => Some('\n'),
+            c => Some(c),
+        }
+    }
+
+    fn read_char_timeout(&self, ms: u32) -> Option<char> {
+        let start = self.timer.now_micros();
+        let timeout = ms as u64 * 1000;
+
+        loop {
+            if let Some(c) = self.try_read_char() {
+                return Some(c);
+            }
+            if deadline_passed(start, self.timer.now_micros(), timeout) {
+                return None;
             }
+            cpu::spin_hint();
+        }
+    }
 
-            let mut ret = inner.DR.get() as u8 as char;
+    fn read_byte_timeout(&self, ms: u32) -> Option<u8> {
+        let start = self.timer.now_micros();
+        let timeout = ms as u64 * 1000;
 
-            if ret == '\r' {
-                ret = '\n';
+        loop {
+            if let Some(b) = self.rx_buffer.pop() {
+                self.bytes_read.fetch_add(1, Ordering::Relaxed);
+                return Some(b);
             }
+            if deadline_passed(start, self.timer.now_micros(), timeout) {
+                return None;
+            }
+            cpu::spin_hint();
+        }
+    }
 
-            inner.chars_read += 1;
+    // Straight from the RX buffer, polled like `read_char`. Bytes of a char
+    // `try_read_char` has started decoding stay with the decoder.
+    fn read_bytes(&self, buf: &mut [u8]) -> usize {
+        for b in buf.iter_mut() {
+            *b = loop {
+                if let Some(b) = self.rx_buffer.pop() {
+                    break b;
+                }
+                cpu::spin_hint();
+            };
+        }
 
-            ret
-        })
+        self.bytes_read.fetch_add(buf.len(), Ordering::Relaxed);
+        buf.len()
+    }
+}
+
+impl exception::interface::IRQHandler for PL011Uart {
+    // Drains the receive FIFO into the RX buffer
+    fn handle(&self) {
+        let regs = self.emergency_regs();
+
+        while !regs.FR.matches_all(FR::RXFE::SET) {
+            let data = regs.DR.extract();
+
+            if data.is_set(DR::FE) {
+                self.framing_errors.fetch_add(1, Ordering::Relaxed);
+            }
+            if data.is_set(DR::PE) {
+                self.parity_errors.fetch_add(1, Ordering::Relaxed);
+            }
+            if data.is_set(DR::BE) {
+                self.break_errors.fetch_add(1, Ordering::Relaxed);
+            }
+            if data.is_set(DR::OE) {
+                self.overrun_errors.fetch_add(1, Ordering::Relaxed);
+            }
+
+            self.rx_buffer.push(data.read(DR::DATA) as u8);
+        }
+
+        regs.ICR.write(ICR::RXIC::SET + ICR::RTIC::SET);
     }
 }
 
 impl console::interface::Statistics for PL011Uart {
     fn chars_written(&self) -> usize {
-        let mut r = &self.inner;
-        r.lock(|inner| inner.chars_written)
+        self.chars_written.load(Ordering::Relaxed)
     }
 
     fn chars_read(&self) -> usize {
+        self.chars_read.load(Ordering::Relaxed)
+    }
+
+    fn bytes_written(&self) -> usize {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    fn bytes_read(&self) -> usize {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    fn max_tx_wait_polls(&self) -> usize {
         let mut r = &self.inner;
-        r.lock(|inner| inner.chars_read)
+        r.lock(|inner| inner.max_tx_wait_polls)
+    }
+
+    fn framing_errors(&self) -> usize {
+        self.framing_errors.load(Ordering::Relaxed)
+    }
+
+    fn parity_errors(&self) -> usize {
+        self.parity_errors.load(Ordering::Relaxed)
     }
-}
\ No newline at end of file
+
+    fn break_errors(&self) -> usize {
+        self.break_errors.load(Ordering::Relaxed)
+    }
+
+    fn overrun_errors(&self) -> usize {
+        self.overrun_errors.load(Ordering::Relaxed)
+    }
+
+    // The counters outside the lock, I/O and the RX interrupt's errors, may
+    // still take an increment mid-reset
+    fn reset(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.max_tx_wait_polls = 0;
+
+            self.chars_written.store(0, Ordering::Relaxed);
+            self.chars_read.store(0, Ordering::Relaxed);
+            self.bytes_written.store(0, Ordering::Relaxed);
+            self.bytes_read.store(0, Ordering::Relaxed);
+            self.framing_errors.store(0, Ordering::Relaxed);
+            self.parity_errors.store(0, Ordering::Relaxed);
+            self.break_errors.store(0, Ordering::Relaxed);
+            self.overrun_errors.store(0, Ordering::Relaxed);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::device_driver::mmio::fake_registers;
+    use core::sync::atomic::AtomicU32;
+    use std::{boxed::Box, thread, time, vec::Vec};
+
+    fn fake_uart(regs: &'static [AtomicU32]) -> PL011UartInner {
+        unsafe { PL011UartInner::new(PhysicalAddress::new(regs.as_ptr() as usize)) }
+    }
+
+    const FR_INDEX: usize = 0x18 / 4;
+    const FR_TXFE: u32 = 1 << 7;
+    const FR_TXFF: u32 = 1 << 5;
+
+    #[test]
+    fn flush_returns_once_txfe_is_set() {
+        let regs = fake_registers::<RegisterBlock>();
+        let uart = fake_uart(regs);
+
+        let drained = thread::spawn(move || {
+            thread::sleep(time::Duration::from_millis(20));
+            let at = time::Instant::now();
+            regs[FR_INDEX].store(FR_TXFE, Ordering::Release);
+            at
+        });
+        uart.flush();
+        let returned = time::Instant::now();
+
+        assert!(returned >= drained.join().unwrap());
+    }
+
+    // The GPIO's registers are fake as well, the timer is never touched
+    fn fake_pl011_and_gpio(regs: &'static [AtomicU32]) -> (PL011Uart, &'static [AtomicU32]) {
+        // GPIO's register block, up to GPIO_PUP_PDN_CNTRL
+        let gpio_regs = fake_registers::<[u32; 0xF4 / 4]>();
+        let timer = Box::leak(Box::new(unsafe { SystemTimer::new(0) }));
+        let gpio = Box::leak(Box::new(unsafe { GPIO::new(gpio_regs.as_ptr() as usize, timer) }));
+        let base_addr = PhysicalAddress::new(regs.as_ptr() as usize);
+        let uart = unsafe { PL011Uart::new(base_addr, 230_400, 48_000_000, timer, gpio) };
+
+        (uart, gpio_regs)
+    }
+
+    fn fake_pl011(regs: &'static [AtomicU32]) -> PL011Uart {
+        fake_pl011_and_gpio(regs).0
+    }
+
+    #[test]
+    fn emergency_write_char_waits_for_fifo_room_without_counting() {
+        use console::interface::{Statistics, Write};
+
+        let regs = fake_registers::<RegisterBlock>();
+        let uart = fake_pl011(regs);
+        regs[FR_INDEX].store(FR_TXFF, Ordering::Release);
+
+        let drained = thread::spawn(move || {
+            thread::sleep(time::Duration::from_millis(20));
+            let at = time::Instant::now();
+            regs[FR_INDEX].store(0, Ordering::Release);
+            at
+        });
+        uart.emergency_write_char('a');
+        let returned = time::Instant::now();
+
+        assert!(returned >= drained.join().unwrap());
+        assert_eq!(regs[0].load(Ordering::Acquire), b'a' as u32);
+        assert_eq!(uart.chars_written(), 0);
+    }
+
+    #[test]
+    fn reset_zeroes_the_counters() {
+        use console::interface::{Statistics, Write};
+
+        let uart = fake_pl011(fake_registers::<RegisterBlock>());
+        uart.write_char('\u{e9}');
+        uart.write_fmt(format_args!("{}", 42)).unwrap();
+        uart.overrun_errors.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(uart.chars_written(), 3);
+
+        uart.reset();
+        assert_eq!(uart.chars_written(), 0);
+        assert_eq!(uart.chars_read(), 0);
+        assert_eq!(uart.bytes_written.load(Ordering::Relaxed), 0);
+        assert_eq!(uart.overrun_errors(), 0);
+    }
+
+    #[test]
+    fn concurrent_writes_lose_no_counts() {
+        use console::interface::{Statistics, Write};
+
+        let uart = fake_pl011(fake_registers::<RegisterBlock>());
+        let uart: &'static PL011Uart = Box::leak(Box::new(uart));
+
+        // Read without the lock while the writers hold it, never going back
+        let reader = thread::spawn(move || {
+            let mut last = 0;
+            while last < 4 * 1000 {
+                let now = uart.chars_written();
+                assert!(now >= last);
+                last = now;
+                thread::yield_now();
+            }
+        });
+        let writers: Vec<_> = (0..4)
+            .map(|_| thread::spawn(move || (0..1000).for_each(|_| uart.write_char('\u{e9}'))))
+            .collect();
+        for t in writers {
+            t.join().unwrap();
+        }
+        reader.join().unwrap();
+
+        assert_eq!(uart.chars_written(), 4 * 1000);
+        assert_eq!(uart.bytes_written(), 4 * 1000 * 2);
+    }
+
+    const LCRH_INDEX: usize = 0x2c / 4;
+    const CR_INDEX: usize = 0x30 / 4;
+    const IFLS_INDEX: usize = 0x34 / 4;
+
+    #[test]
+    fn set_fifo_and_rx_trigger_keep_the_word_length() {
+        let regs = fake_registers::<RegisterBlock>();
+        let mut uart = fake_uart(regs);
+        // 8N1 with FIFOs, TXIFLSEL at 3/4 and the UART running
+        regs[LCRH_INDEX].store(0b11 << 5 | 1 << 4, Ordering::Relaxed);
+        regs[IFLS_INDEX].store(0b011, Ordering::Relaxed);
+        regs[CR_INDEX].store(0x301, Ordering::Relaxed);
+
+        uart.set_fifo(false);
+        assert_eq!(regs[LCRH_INDEX].load(Ordering::Relaxed), 0b11 << 5);
+        uart.set_rx_trigger(Trigger::ThreeQuarters);
+        assert_eq!(regs[IFLS_INDEX].load(Ordering::Relaxed), 0b011 << 3 | 0b011);
+        uart.set_fifo(true);
+        assert_eq!(regs[LCRH_INDEX].load(Ordering::Relaxed), 0b11 << 5 | 1 << 4);
+        assert_eq!(regs[CR_INDEX].load(Ordering::Relaxed), 0x301);
+    }
+
+    const CR_RTSEN: u32 = 1 << 14;
+    const CR_CTSEN: u32 = 1 << 15;
+
+    #[test]
+    fn set_flow_control_sets_the_cr_bits_and_pin_functions() {
+        let regs = fake_registers::<RegisterBlock>();
+        let (uart, gpio_regs) = fake_pl011_and_gpio(regs);
+        // UARTEN, TXE and RXE, as `init` leaves them
+        regs[CR_INDEX].store(0x301, Ordering::Relaxed);
+
+        uart.set_flow_control(true).unwrap();
+        assert_eq!(regs[CR_INDEX].load(Ordering::Relaxed), 0x301 | CR_RTSEN | CR_CTSEN);
+        // GPFSEL1: GPIO 16 (CTS0) and 17 (RTS0) in ALT3
+        assert_eq!(gpio_regs[1].load(Ordering::Relaxed), 0b111 << 18 | 0b111 << 21);
+
+        uart.set_flow_control(false).unwrap();
+        assert_eq!(regs[CR_INDEX].load(Ordering::Relaxed), 0x301);
+        assert_eq!(gpio_regs[1].load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn set_flow_control_leaves_a_disabled_uart_off() {
+        let regs = fake_registers::<RegisterBlock>();
+        let mut uart = fake_uart(regs);
+
+        uart.set_flow_control(true);
+        assert_eq!(regs[CR_INDEX].load(Ordering::Relaxed), 0);
+        assert_eq!(uart.cr_enabled().value & (CR_RTSEN | CR_CTSEN), CR_RTSEN | CR_CTSEN);
+    }
+
+    #[test]
+    fn loopback_mismatch_finds_the_first_bad_or_missing_byte() {
+        assert_eq!(loopback_mismatch(b"\x55\xaa\x00", b"\x55\xaa\x00"), None);
+        assert_eq!(loopback_mismatch(b"\x55\xaa\x00", b"\x55\xab\x01"), Some(1));
+        assert_eq!(loopback_mismatch(b"\x55\xaa\x00", b"\x55"), Some(1));
+        assert_eq!(loopback_mismatch(b"\x55", b""), Some(0));
+        assert_eq!(loopback_mismatch(b"", b""), None);
+    }
+
+    #[test]
+    fn trigger_parses_from_fractions() {
+        assert!("1/8".parse::<Trigger>() == Ok(Trigger::OneEighth));
+        assert!("7/8".parse::<Trigger>() == Ok(Trigger::SevenEighths));
+        assert!("2/4".parse::<Trigger>().is_err());
+    }
+
+    #[test]
+    fn probe_reports_the_baud_after_set_baud_rate() {
+        use driver::interface::DeviceDriver;
+
+        let regs = fake_registers::<RegisterBlock>();
+        let uart = fake_pl011(regs);
+        assert!(uart.probe().is_none());
+
+        uart.set_baud_rate(115_200, 48_000_000).unwrap();
+        assert_eq!(std::format!("{}", uart.probe().unwrap()), "8N1, baud 115200");
+        assert_eq!(regs[0x24 / 4].load(Ordering::Relaxed), 26);
+        assert_eq!(regs[0x28 / 4].load(Ordering::Relaxed), 3);
+
+        // A rate the divisors can't reach leaves the UART as it was
+        assert_eq!(uart.set_baud_rate(0, 48_000_000), Err(DriverError::InvalidConfig));
+        assert_eq!(std::format!("{}", uart.probe().unwrap()), "8N1, baud 115200");
+    }
+
+    #[test]
+    fn read_bytes_keeps_line_endings() {
+        use console::interface::{Read, Statistics};
+
+        let uart = fake_pl011(fake_registers::<RegisterBlock>());
+        for &b in b"a\r\n\r" {
+            uart.rx_buffer.push(b);
+        }
+
+        let mut buf = [0u8; 4];
+        assert_eq!(uart.read_bytes(&mut buf), 4);
+        assert_eq!(&buf, b"a\r\n\r");
+        assert_eq!(uart.chars_read(), 0);
+        assert_eq!(uart.bytes_read(), 4);
+    }
+
+    #[test]
+    fn with_crlf_only_expands_newlines() {
+        let out = |b, crlf| with_crlf(b, crlf).collect::<Vec<_>>();
+
+        assert_eq!(out(b'\n', true), b"\r\n");
+        assert_eq!(out(b'\n', false), b"\n");
+        assert_eq!(out(b'\r', true), b"\r");
+        assert_eq!(out(b'a', true), b"a");
+    }
+
+    #[test]
+    fn counting_writer_leaves_out_the_injected_cr() {
+        let mut uart = fake_uart(fake_registers::<RegisterBlock>());
+        uart.crlf = true;
+        let mut w = CountingWriter {
+            inner: &mut uart,
+            chars: 0,
+            bytes: 0,
+        };
+
+        fmt::Write::write_str(&mut w, "aé\n").unwrap();
+        assert_eq!((w.chars, w.bytes), (3, 4));
+    }
+
+    #[test]
+    fn baud_divisors_at_48mhz() {
+        assert_eq!(baud_divisors(230_400, 48_000_000), Ok((13, 2)));
+        assert_eq!(baud_divisors(115_200, 48_000_000), Ok((26, 3)));
+        assert_eq!(baud_divisors(921_600, 48_000_000), Ok((3, 17)));
+    }
+
+    #[test]
+    fn baud_divisors_out_of_range() {
+        assert_eq!(baud_divisors(0, 48_000_000), Err(DriverError::InvalidConfig));
+        // BAUDDIV under 1
+        assert_eq!(baud_divisors(4_000_000, 48_000_000), Err(DriverError::InvalidConfig));
+        // IBRD over 16 bits
+        assert_eq!(baud_divisors(1, 48_000_000), Err(DriverError::InvalidConfig));
+    }
+}
@@ -0,0 +1,298 @@
+use super::{Function, SystemTimer, GPIO};
+use crate::{
+    cpu,
+    cpu::time::deadline_passed,
+    driver,
+    driver::DriverError,
+    synchronization,
+    synchronization::NullLock,
+};
+use core::ops;
+use register::{mmio::*, register_bitfields, register_structs};
+
+register_bitfields! {
+    u32,
+
+    // Control
+    CTL [
+        // Mark/space: high for DAT2 out of every RNG2 cycles, low the rest
+        MSEN2 OFFSET(15) NUMBITS(1) [],
+        PWEN2 OFFSET(8) NUMBITS(1) [],
+        MSEN1 OFFSET(7) NUMBITS(1) [],
+        // Clear the FIFO
+        CLRF OFFSET(6) NUMBITS(1) [],
+        PWEN1 OFFSET(0) NUMBITS(1) []
+    ],
+
+    // Clock manager control, for the PWM clock
+    CM_CTL [
+        PASSWD OFFSET(24) NUMBITS(8) [],
+        // Noise shaping, 1 to use the fractional part of the divisor
+        MASH OFFSET(9) NUMBITS(2) [],
+        BUSY OFFSET(7) NUMBITS(1) [],
+        ENAB OFFSET(4) NUMBITS(1) [],
+        SRC OFFSET(0) NUMBITS(4) [
+            Oscillator = 1
+        ]
+    ],
+
+    // Clock manager divisor, 12.12 fixed point
+    CM_DIV [
+        PASSWD OFFSET(24) NUMBITS(8) [],
+        DIV OFFSET(0) NUMBITS(24) []
+    ]
+}
+
+register_structs! {
+    #[allow(non_snake_case)]
+    RegisterBlock {
+        (0x00 => CTL: ReadWrite<u32, CTL::Register>),
+        (0x04 => _reserved1),
+        (0x10 => RNG1: ReadWrite<u32>),
+        (0x14 => DAT1: ReadWrite<u32>),
+        (0x18 => _reserved2),
+        (0x20 => RNG2: ReadWrite<u32>),
+        (0x24 => DAT2: ReadWrite<u32>),
+        (0x28 => @END),
+    },
+
+    #[allow(non_snake_case)]
+    ClockRegisterBlock {
+        (0x00 => _reserved1),
+        (0xA0 => PWMCTL: ReadWrite<u32, CM_CTL::Register>),
+        (0xA4 => PWMDIV: ReadWrite<u32, CM_DIV::Register>),
+        (0xA8 => @END),
+    }
+}
+
+// Like the PM's, every clock manager write must carry it in the top byte
+const CM_PASSWORD: u32 = 0x5A;
+
+/// What the PWM clock is divided down to. At 1 MHz a range counts
+/// microseconds, which is what servos are specified in.
+pub const PWM_CLOCK_HZ: u32 = 1_000_000;
+
+const CLOCK_TIMEOUT_US: u64 = 10_000;
+
+// Channel 0 and 1 on Alt5
+const PINS: [u32; 2] = [18, 19];
+
+/// The clock manager's 12.12 fixed point divisor taking `osc_hz` down to
+/// `pwm_hz`, or 0 if the integer part falls outside the 2..=4095 it takes
+pub const fn clock_divisor(osc_hz: u32, pwm_hz: u32) -> u32 {
+    if pwm_hz == 0 {
+        return 0;
+    }
+
+    let div = (osc_hz as u64 * 4096 + pwm_hz as u64 / 2) / pwm_hz as u64;
+    if div < 2 << 12 || div > 0xff_ffff {
+        0
+    } else {
+        div as u32
+    }
+}
+
+/// The range for a period of `freq_hz` counted at `pwm_hz`, or 0 if it
+/// doesn't fit a whole count
+pub const fn period_range(pwm_hz: u32, freq_hz: u32) -> u32 {
+    if freq_hz == 0 {
+        return 0;
+    }
+
+    (pwm_hz + freq_hz / 2) / freq_hz
+}
+
+struct PwmInner {
+    base_addr: usize,
+    cm_base_addr: usize,
+}
+
+/// PWM0, both channels in mark/space mode: channel 0 on GPIO 18, channel 1
+/// on GPIO 19. They share the one clock.
+pub struct Pwm {
+    inner: NullLock<PwmInner>,
+    osc_hz: u32,
+    gpio: &'static GPIO,
+    timer: &'static SystemTimer,
+}
+
+impl ops::Deref for PwmInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*(self.base_addr as *const _) }
+    }
+}
+
+impl PwmInner {
+    const fn new(base_addr: usize, cm_base_addr: usize) -> Self {
+        Self {
+            base_addr,
+            cm_base_addr,
+        }
+    }
+
+    fn clock(&self) -> &ClockRegisterBlock {
+        unsafe { &*(self.cm_base_addr as *const _) }
+    }
+
+    // The divisor may only change with the clock stopped and no longer busy
+    fn init(&mut self, timer: &SystemTimer, div: u32) -> Result<(), DriverError> {
+        self.CTL.set(0);
+
+        let password = CM_CTL::PASSWD.val(CM_PASSWORD);
+        self.clock().PWMCTL.write(password + CM_CTL::SRC::Oscillator);
+        let start = timer.now_micros();
+        while self.clock().PWMCTL.is_set(CM_CTL::BUSY) {
+            if deadline_passed(start, timer.now_micros(), CLOCK_TIMEOUT_US) {
+                return Err(DriverError::HardwareTimeout);
+            }
+            cpu::spin_hint();
+        }
+
+        self.clock()
+            .PWMDIV
+            .write(CM_DIV::PASSWD.val(CM_PASSWORD) + CM_DIV::DIV.val(div));
+        self.clock()
+            .PWMCTL
+            .write(password + CM_CTL::SRC::Oscillator + CM_CTL::MASH.val(1));
+        self.clock().PWMCTL.write(
+            password + CM_CTL::SRC::Oscillator + CM_CTL::MASH.val(1) + CM_CTL::ENAB::SET,
+        );
+
+        self.CTL.write(CTL::CLRF::SET);
+
+        Ok(())
+    }
+
+    fn set_duty(&mut self, channel: u8, range: u32, data: u32) {
+        match channel {
+            0 => {
+                self.RNG1.set(range);
+                self.DAT1.set(data);
+                self.CTL.modify(CTL::MSEN1::SET + CTL::PWEN1::SET);
+            }
+            _ => {
+                self.RNG2.set(range);
+                self.DAT2.set(data);
+                self.CTL.modify(CTL::MSEN2::SET + CTL::PWEN2::SET);
+            }
+        }
+    }
+
+    fn disable(&mut self, channel: u8) {
+        match channel {
+            0 => self.CTL.modify(CTL::PWEN1::CLEAR),
+            _ => self.CTL.modify(CTL::PWEN2::CLEAR),
+        }
+    }
+}
+
+impl Pwm {
+    /// `osc_hz` is the crystal oscillator the PWM clock is divided from
+    pub const unsafe fn new(
+        base_addr: usize,
+        cm_base_addr: usize,
+        osc_hz: u32,
+        gpio: &'static GPIO,
+        timer: &'static SystemTimer,
+    ) -> Self {
+        Self {
+            inner: NullLock::new(PwmInner::new(base_addr, cm_base_addr)),
+            osc_hz,
+            gpio,
+            timer,
+        }
+    }
+
+    pub unsafe fn set_base_addr(&self, base_addr: usize, cm_base_addr: usize) {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.base_addr = base_addr;
+            inner.cm_base_addr = cm_base_addr;
+        });
+    }
+
+    /// Drives `channel` high for `data` out of every `range` ticks of
+    /// `PWM_CLOCK_HZ`, taking its pin over. `data` past `range` stays high.
+    pub fn set_duty(&self, channel: u8, range: u32, data: u32) -> Result<(), DriverError> {
+        if channel as usize >= PINS.len() || range == 0 {
+            return Err(DriverError::InvalidConfig);
+        }
+
+        self.gpio.set_function(PINS[channel as usize], Function::Alt5)?;
+
+        let mut r = &self.inner;
+        r.lock(|inner| inner.set_duty(channel, range, data));
+
+        Ok(())
+    }
+
+    /// A square wave at `freq_hz` on channel 0, e.g. for a piezo buzzer. 0
+    /// silences it.
+    pub fn tone(&self, freq_hz: u32) -> Result<(), DriverError> {
+        if freq_hz == 0 {
+            let mut r = &self.inner;
+            r.lock(|inner| inner.disable(0));
+            return Ok(());
+        }
+
+        // At least 2 ticks, so there is a high and a low half
+        match period_range(PWM_CLOCK_HZ, freq_hz) {
+            range if range < 2 => Err(DriverError::InvalidConfig),
+            range => self.set_duty(0, range, range / 2),
+        }
+    }
+}
+
+use synchronization::interface::Mutex;
+
+impl driver::interface::DeviceDriver for Pwm {
+    fn name(&self) -> &str {
+        "BCM PWM"
+    }
+
+    fn compatible(&self) -> &str {
+        "brcm,bcm2835-pwm"
+    }
+
+    // Pins are only taken once a channel is used
+    fn dependencies(&self) -> &[&str] {
+        &["brcm,bcm2835-gpio", "brcm,bcm2835-system-timer"]
+    }
+
+    fn init(&self) -> Result<(), DriverError> {
+        let div = match clock_divisor(self.osc_hz, PWM_CLOCK_HZ) {
+            0 => return Err(DriverError::InvalidConfig),
+            div => div,
+        };
+
+        let mut r = &self.inner;
+        r.lock(|inner| inner.init(self.timer, div))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_divisor_off_both_oscillators() {
+        // The Pi 3's 19.2 MHz and the Pi 4's 54 MHz, down to 1 µs counts
+        assert_eq!(clock_divisor(19_200_000, PWM_CLOCK_HZ), 19 << 12 | 819);
+        assert_eq!(clock_divisor(54_000_000, PWM_CLOCK_HZ), 54 << 12);
+    }
+
+    #[test]
+    fn clock_divisor_out_of_range() {
+        assert_eq!(clock_divisor(19_200_000, 10_000_000), 0);
+        assert_eq!(clock_divisor(19_200_000, 0), 0);
+    }
+
+    #[test]
+    fn period_range_of_a_servo_signal() {
+        // 50 Hz: 20000 counts, 1000 to 2000 of them high
+        assert_eq!(period_range(PWM_CLOCK_HZ, 50), 20_000);
+        assert_eq!(period_range(PWM_CLOCK_HZ, 0), 0);
+    }
+}
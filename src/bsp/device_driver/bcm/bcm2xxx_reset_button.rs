@@ -0,0 +1,139 @@
+use super::{Event, Function, Pull, GPIO};
+use crate::{cpu, driver, driver::DriverError};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+// Stands for no pin, the button is disabled until `set_pin` is given one
+const NO_PIN: u32 = u32::MAX;
+
+// The second read comes this long after the first
+const DEBOUNCE_MS: u32 = 20;
+const POLL_INTERVAL_MS: u32 = 50;
+
+/// Where the debounce of a press stands after each read of the pin
+#[derive(Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum Debounce {
+    Idle,
+    /// Low once, waiting for the second read
+    Pending,
+    Pressed,
+}
+
+/// The next state after reading the pin `low`. A press takes two low reads
+/// in a row, any high one starts over.
+pub const fn debounce(state: Debounce, low: bool) -> Debounce {
+    match (state, low) {
+        (_, false) => Debounce::Idle,
+        (Debounce::Idle, true) => Debounce::Pending,
+        (_, true) => Debounce::Pressed,
+    }
+}
+
+/// A push button from a GPIO pin to ground that reboots the board through
+/// `reboot`, the BSP's usual path that shuts the drivers down first. The pin
+/// is pulled up and watched for falling edges.
+pub struct ResetButton {
+    pin: AtomicU32,
+    gpio: &'static GPIO,
+    reboot: fn() -> !,
+}
+
+impl ResetButton {
+    pub const unsafe fn new(gpio: &'static GPIO, reboot: fn() -> !) -> Self {
+        Self {
+            pin: AtomicU32::new(NO_PIN),
+            gpio,
+            reboot,
+        }
+    }
+
+    /// The pin the button is on, or `None` to leave it disabled. Only takes
+    /// effect before the drivers are initialized.
+    pub fn set_pin(&self, pin: Option<u32>) {
+        self.pin.store(pin.unwrap_or(NO_PIN), Ordering::Relaxed);
+    }
+
+    fn is_low(&self, pin: u32) -> bool {
+        self.gpio.read(pin) == Ok(false)
+    }
+
+    /// Reboots if the button was pressed since the last call. A falling edge
+    /// only counts once the pin reads low twice, `DEBOUNCE_MS` apart.
+    pub fn poll(&self) {
+        let pin = self.pin.load(Ordering::Relaxed);
+        if pin == NO_PIN || self.gpio.poll_event(pin) != Ok(true) {
+            return;
+        }
+
+        let mut state = debounce(Debounce::Idle, self.is_low(pin));
+        if state == Debounce::Pending {
+            cpu::sleep_ms(DEBOUNCE_MS);
+            state = debounce(state, self.is_low(pin));
+        }
+
+        if state == Debounce::Pressed {
+            (self.reboot)()
+        }
+    }
+
+    fn claim(&self, pin: u32) -> Result<(), DriverError> {
+        self.gpio.set_function(pin, Function::Input)?;
+        self.gpio.set_pull(pin, Pull::Up)?;
+        self.gpio.enable_event(pin, Event::FallingEdge)?;
+        // An edge latched before now doesn't count
+        self.gpio.poll_event(pin)?;
+
+        Ok(())
+    }
+
+    /// Polls forever, e.g. as a task on a secondary core
+    pub fn watch(&self) -> ! {
+        loop {
+            self.poll();
+            cpu::sleep_ms(POLL_INTERVAL_MS);
+        }
+    }
+}
+
+impl driver::interface::DeviceDriver for ResetButton {
+    fn name(&self) -> &str {
+        "GPIO Reset Button"
+    }
+
+    fn compatible(&self) -> &str {
+        "gpio-keys"
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.pin.load(Ordering::Relaxed) != NO_PIN
+    }
+
+    fn dependencies(&self) -> &[&str] {
+        &["brcm,bcm2835-gpio", "brcm,bcm2835-pm-wdt"]
+    }
+
+    // A pin the GPIO won't take, e.g. a typo in the command line, leaves the
+    // button off rather than stopping the boot
+    fn init(&self) -> Result<(), DriverError> {
+        let pin = self.pin.load(Ordering::Relaxed);
+        if let Err(e) = self.claim(pin) {
+            crate::warn!("{}: pin {}: {}, disabled", self.name(), pin, e);
+            self.pin.store(NO_PIN, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debounce_takes_two_low_reads() {
+        assert!(debounce(Debounce::Idle, true) == Debounce::Pending);
+        assert!(debounce(Debounce::Pending, true) == Debounce::Pressed);
+        assert!(debounce(Debounce::Pending, false) == Debounce::Idle);
+        assert!(debounce(Debounce::Idle, false) == Debounce::Idle);
+    }
+}
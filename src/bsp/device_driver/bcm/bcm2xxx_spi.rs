@@ -0,0 +1,275 @@
+use super::{clock, Function, Mailbox, SystemTimer, GPIO};
+use crate::{
+    cpu,
+    cpu::time::deadline_passed,
+    driver,
+    driver::DriverError,
+    synchronization,
+    synchronization::NullLock,
+};
+use core::{
+    ops,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use register::{mmio::*, register_bitfields, register_structs};
+
+register_bitfields! {
+    u32,
+
+    // Control and status
+    CS [
+        // Polarity of CE1 and CE0
+        CSPOL1 OFFSET(22) NUMBITS(1) [],
+        CSPOL0 OFFSET(21) NUMBITS(1) [],
+        // FIFO has room to write
+        TXD OFFSET(18) NUMBITS(1) [],
+        // FIFO has data to read
+        RXD OFFSET(17) NUMBITS(1) [],
+        DONE OFFSET(16) NUMBITS(1) [],
+        // Transfer active, asserts the chip select
+        TA OFFSET(7) NUMBITS(1) [],
+        CLEAR OFFSET(4) NUMBITS(2) [
+            ClearBoth = 0b11
+        ],
+        CPOL OFFSET(3) NUMBITS(1) [],
+        CPHA OFFSET(2) NUMBITS(1) [],
+        // Chip select
+        CS OFFSET(0) NUMBITS(2) []
+    ]
+}
+
+register_structs! {
+    #[allow(non_snake_case)]
+    RegisterBlock {
+        (0x00 => CS: ReadWrite<u32, CS::Register>),
+        (0x04 => FIFO: ReadWrite<u32>),
+        (0x08 => CLK: ReadWrite<u32>),
+        (0x0C => _reserved1),
+        (0x18 => @END),
+    }
+}
+
+// CE1, CE0, MISO, MOSI and SCLK, all on Alt0
+const PINS: [u32; 5] = [7, 8, 9, 10, 11];
+
+// Far longer than any transfer takes, even at the slowest SCLK
+const TRANSFER_TIMEOUT_US: u64 = 10_000_000;
+
+/// The clock divider for SCLK at no more than `bus_hz`. The hardware only
+/// uses even divisors, so odd ones are rounded up.
+pub fn spi_divider(core_clk_hz: u32, bus_hz: u32) -> Result<u32, DriverError> {
+    if bus_hz == 0 {
+        return Err(DriverError::InvalidConfig);
+    }
+
+    let rem = core_clk_hz % bus_hz;
+    let mut div = core_clk_hz / bus_hz + (rem != 0) as u32;
+    div += div & 1;
+
+    // 0 would mean 65536, keep to what the register holds as written
+    if !(2..=0xfffe).contains(&div) {
+        return Err(DriverError::InvalidConfig);
+    }
+
+    Ok(div)
+}
+
+struct SPIInner {
+    base_addr: usize,
+}
+
+/// SPI0 as master, in mode 0. Transfers are polled, one at a time.
+pub struct SPI {
+    inner: NullLock<SPIInner>,
+    bus_hz: u32,
+    chip_select: u32,
+    cs_active_high: bool,
+    gpio: &'static GPIO,
+    mailbox: &'static Mailbox,
+    timer: &'static SystemTimer,
+    enabled: AtomicBool,
+}
+
+impl ops::Deref for SPIInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl SPIInner {
+    const fn new(base_addr: usize) -> Self {
+        Self { base_addr }
+    }
+
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    fn init(&mut self, div: u32, chip_select: u32, cs_active_high: bool) {
+        let polarity = match (chip_select, cs_active_high) {
+            (0, true) => CS::CSPOL0::SET,
+            (1, true) => CS::CSPOL1::SET,
+            _ => CS::CSPOL0::CLEAR,
+        };
+
+        self.CLK.set(div);
+        self.CS.write(CS::CS.val(chip_select) + polarity + CS::CLEAR::ClearBoth);
+    }
+
+    // The shorter side is padded: missing `tx` bytes go out as zeros and
+    // received bytes beyond `rx` are dropped
+    fn transfer(
+        &mut self,
+        timer: &SystemTimer,
+        tx: &[u8],
+        rx: &mut [u8],
+    ) -> Result<(), DriverError> {
+        let len = tx.len().max(rx.len());
+
+        self.CS.modify(CS::CLEAR::ClearBoth + CS::TA::SET);
+
+        let start = timer.now_micros();
+        let (mut sent, mut received) = (0, 0);
+        let ret = loop {
+            while sent < len && self.CS.is_set(CS::TXD) {
+                self.FIFO.set(tx.get(sent).copied().unwrap_or(0) as u32);
+                sent += 1;
+            }
+            while received < len && self.CS.is_set(CS::RXD) {
+                let b = self.FIFO.get() as u8;
+                if let Some(slot) = rx.get_mut(received) {
+                    *slot = b;
+                }
+                received += 1;
+            }
+
+            if received == len && self.CS.is_set(CS::DONE) {
+                break Ok(());
+            }
+            if deadline_passed(start, timer.now_micros(), TRANSFER_TIMEOUT_US) {
+                break Err(DriverError::HardwareTimeout);
+            }
+            cpu::spin_hint();
+        };
+
+        // Drops whatever a timed out transfer left in the FIFOs
+        self.CS.modify(CS::TA::CLEAR + CS::CLEAR::ClearBoth);
+
+        ret
+    }
+}
+
+impl SPI {
+    /// Talks to the device on CE`chip_select`, whose select line is active
+    /// low unless `cs_active_high`
+    pub const unsafe fn new(
+        base_addr: usize,
+        bus_hz: u32,
+        chip_select: u32,
+        cs_active_high: bool,
+        gpio: &'static GPIO,
+        mailbox: &'static Mailbox,
+        timer: &'static SystemTimer,
+    ) -> Self {
+        Self {
+            inner: NullLock::new(SPIInner::new(base_addr)),
+            bus_hz,
+            chip_select,
+            cs_active_high,
+            gpio,
+            mailbox,
+            timer,
+            enabled: AtomicBool::new(true),
+        }
+    }
+
+    /// Whether the driver manager should initialize it, and so take over
+    /// GPIO 7-11. Set before `init`.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub unsafe fn set_base_addr(&self, base_addr: usize) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.base_addr = base_addr);
+    }
+
+    /// Clocks out `tx` while clocking in `rx`, for as many bytes as the
+    /// longer of the two
+    pub fn transfer(&self, tx: &[u8], rx: &mut [u8]) -> Result<(), DriverError> {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return Err(DriverError::Unsupported);
+        }
+
+        let mut r = &self.inner;
+        r.lock(|inner| inner.transfer(self.timer, tx, rx))
+    }
+}
+
+use synchronization::interface::Mutex;
+
+impl driver::interface::DeviceDriver for SPI {
+    fn name(&self) -> &str {
+        "BCM SPI0"
+    }
+
+    fn compatible(&self) -> &str {
+        "brcm,bcm2835-spi"
+    }
+
+    // The divider runs off the core clock, which the mailbox reports
+    fn dependencies(&self) -> &[&str] {
+        &["brcm,bcm2835-gpio", "brcm,bcm2835-mbox", "brcm,bcm2835-system-timer"]
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    fn init(&self) -> Result<(), DriverError> {
+        if self.chip_select > 1 {
+            return Err(DriverError::InvalidConfig);
+        }
+
+        let core_clk_hz = self
+            .mailbox
+            .get_clock_rate(clock::CORE)
+            .map_err(|_| DriverError::HardwareTimeout)?;
+        let div = spi_divider(core_clk_hz, self.bus_hz)?;
+
+        for &pin in PINS.iter() {
+            self.gpio.set_function(pin, Function::Alt0)?;
+        }
+
+        let mut r = &self.inner;
+        r.lock(|inner| inner.init(div, self.chip_select, self.cs_active_high));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spi_divider_rounds_up_to_even() {
+        assert_eq!(spi_divider(250_000_000, 1_000_000), Ok(250));
+        assert_eq!(spi_divider(250_000_000, 125_000_000), Ok(2));
+        // 250 / 3 MHz is 83.3, up to 84 so SCLK stays under the request
+        assert_eq!(spi_divider(250_000_000, 3_000_000), Ok(84));
+        // 166.7 rounds up to 167, then to the even 168
+        assert_eq!(spi_divider(250_000_000, 1_500_000), Ok(168));
+    }
+
+    #[test]
+    fn spi_divider_out_of_range() {
+        assert_eq!(spi_divider(250_000_000, 0), Err(DriverError::InvalidConfig));
+        assert_eq!(spi_divider(0, 1_000_000), Err(DriverError::InvalidConfig));
+        assert_eq!(spi_divider(250_000_000, 1_000), Err(DriverError::InvalidConfig));
+        assert_eq!(spi_divider(0xfffe, 1), Ok(0xfffe));
+        assert_eq!(spi_divider(0xffff, 1), Err(DriverError::InvalidConfig));
+    }
+}
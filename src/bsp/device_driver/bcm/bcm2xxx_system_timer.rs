@@ -0,0 +1,185 @@
+use crate::{cpu, cpu::time, driver, synchronization, synchronization::NullLock};
+use core::ops;
+use register::{mmio::*, register_structs};
+
+register_structs! {
+    #[allow(non_snake_case)]
+    RegisterBlock {
+        (0x00 => CS: ReadWrite<u32>),
+        (0x04 => CLO: ReadOnly<u32>),
+        (0x08 => CHI: ReadOnly<u32>),
+        (0x0C => C: [ReadWrite<u32>; 4]),
+        (0x1C => @END),
+    }
+}
+
+/// The 64-bit counter from CHI read before and after CLO. `None` if CLO
+/// wrapped in between, so CHI changed and `lo` may belong to either half.
+pub const fn counter_value(hi_before: u32, lo: u32, hi_after: u32) -> Option<u64> {
+    if hi_before != hi_after {
+        return None;
+    }
+
+    Some((hi_before as u64) << 32 | lo as u64)
+}
+
+struct SystemTimerInner {
+    base_addr: usize,
+}
+
+pub struct SystemTimer {
+    inner: NullLock<SystemTimerInner>,
+}
+
+impl ops::Deref for SystemTimerInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl SystemTimerInner {
+    const fn new(base_addr: usize) -> Self {
+        Self { base_addr }
+    }
+
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    fn now_micros(&self) -> u64 {
+        loop {
+            let hi = self.CHI.get();
+            let lo = self.CLO.get();
+            if let Some(now) = counter_value(hi, lo, self.CHI.get()) {
+                return now;
+            }
+        }
+    }
+}
+
+impl SystemTimer {
+    pub const unsafe fn new(base_addr: usize) -> Self {
+        Self {
+            inner: NullLock::new(SystemTimerInner::new(base_addr)),
+        }
+    }
+
+    pub unsafe fn set_base_addr(&self, base_addr: usize) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.base_addr = base_addr);
+    }
+
+    /// Free-running 1 MHz counter
+    pub fn now_micros(&self) -> u64 {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.now_micros())
+    }
+
+    /// Raises channel `channel`'s match interrupt once the low 32 bits of the
+    /// counter reach `deadline`. The GPU uses channels 0 and 2.
+    pub fn set_compare(&self, channel: usize, deadline: u32) {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            inner.CS.set(1 << channel);
+            inner.C[channel].set(deadline);
+        });
+    }
+
+    /// Clears a pending match on `channel`, returning whether there was one
+    pub fn ack(&self, channel: usize) -> bool {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            let matched = inner.CS.get() & 1 << channel != 0;
+            inner.CS.set(1 << channel);
+            matched
+        })
+    }
+
+    pub fn delay_micros(&self, us: u64) {
+        let start = self.now_micros();
+        while self.now_micros().wrapping_sub(start) < us {
+            cpu::spin_hint();
+        }
+    }
+}
+
+use synchronization::interface::Mutex;
+
+impl time::interface::Timer for SystemTimer {
+    fn now_nanos(&self) -> u64 {
+        self.now_micros() * 1000
+    }
+
+    fn spin_until(&self, deadline_nanos: u64) {
+        while self.now_nanos() < deadline_nanos {
+            cpu::spin_hint();
+        }
+    }
+}
+
+impl driver::interface::DeviceDriver for SystemTimer {
+    fn name(&self) -> &str {
+        "BCM System Timer"
+    }
+
+    fn compatible(&self) -> &str {
+        "brcm,bcm2835-system-timer"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::device_driver::mmio::fake_registers;
+    use core::sync::atomic::Ordering;
+    use std::{thread, time::Duration, vec::Vec};
+
+    // CLO, at 0x04
+    const CLO_INDEX: usize = 1;
+
+    #[test]
+    fn counter_value_rejects_a_torn_read() {
+        // Just below and just past the first wrap, and a read torn by it
+        assert_eq!(counter_value(0, 0xFFFF_FFFF, 0), Some(0xFFFF_FFFF));
+        assert_eq!(counter_value(1, 0x0000_0002, 1), Some(0x1_0000_0002));
+        assert_eq!(counter_value(0, 0x0000_0002, 1), None);
+    }
+
+    // Every read that succeeds lies between the counter at its first and its
+    // last register access, so successive reads never go backwards
+    #[test]
+    fn counter_value_is_monotonic_across_the_wrap() {
+        let counter: Vec<u64> = (0xFFFF_FFFD..0x1_0000_0003).collect();
+        let hi = |i: usize| (counter[i] >> 32) as u32;
+
+        for first in 0..counter.len() {
+            for mid in first..counter.len() {
+                for last in mid..counter.len() {
+                    if let Some(v) = counter_value(hi(first), counter[mid] as u32, hi(last)) {
+                        assert!(counter[first] <= v && v <= counter[last]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn delay_micros_waits_for_the_counter() {
+        let regs = fake_registers::<RegisterBlock>();
+        let timer = unsafe { SystemTimer::new(regs.as_ptr() as usize) };
+        regs[CLO_INDEX].store(1000, Ordering::Release);
+
+        let ticker = thread::spawn(move || {
+            for now in (1010..=1100).step_by(10) {
+                thread::sleep(Duration::from_millis(1));
+                regs[CLO_INDEX].store(now, Ordering::Release);
+            }
+        });
+        timer.delay_micros(100);
+
+        assert!(timer.now_micros() >= 1100);
+        ticker.join().unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+use crate::{cpu, driver, synchronization, synchronization::NullLock};
+use core::ops;
+use register::{mmio::*, register_bitfields, register_structs};
+
+register_bitfields! {
+    u32,
+
+    // Reset control
+    PM_RSTC [
+        PASSWD OFFSET(24) NUMBITS(8) [],
+        // Reset configuration
+        WRCFG OFFSET(4) NUMBITS(2) [
+            Clear = 0b00,
+            FullReset = 0b10
+        ]
+    ],
+
+    // Watchdog countdown
+    PM_WDOG [
+        PASSWD OFFSET(24) NUMBITS(8) [],
+        TIME OFFSET(0) NUMBITS(20) []
+    ]
+}
+
+register_structs! {
+    #[allow(non_snake_case)]
+    RegisterBlock {
+        (0x00 => _reserved1),
+        (0x1C => RSTC: ReadWrite<u32, PM_RSTC::Register>),
+        (0x20 => _reserved2),
+        (0x24 => WDOG: ReadWrite<u32, PM_WDOG::Register>),
+        (0x28 => @END),
+    }
+}
+
+// Every write to a PM register must carry 0x5A in the top byte
+// (0x5A000000), otherwise the write is ignored.
+const PM_PASSWORD: u32 = 0x5A;
+
+// The watchdog counts down at 65536 ticks per second
+const TICKS_PER_SECOND: u64 = 1 << 16;
+const MAX_TICKS: u32 = (1 << 20) - 1;
+
+// Long enough for the write to land, short enough to be immediate
+const REBOOT_TICKS: u32 = 10;
+
+/// Watchdog ticks for `timeout_ms`, clamped to what the 20-bit counter holds
+/// (about 16 seconds).
+pub fn timeout_to_ticks(timeout_ms: u32) -> u32 {
+    let ticks = timeout_ms as u64 * TICKS_PER_SECOND / 1000;
+    if ticks > MAX_TICKS as u64 {
+        MAX_TICKS
+    } else {
+        ticks as u32
+    }
+}
+
+struct WatchdogInner {
+    base_addr: usize,
+    ticks: u32,
+}
+
+pub struct Watchdog {
+    inner: NullLock<WatchdogInner>,
+}
+
+impl ops::Deref for WatchdogInner {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl WatchdogInner {
+    const fn new(base_addr: usize) -> Self {
+        Self { base_addr, ticks: 0 }
+    }
+
+    fn ptr(&self) -> *const RegisterBlock {
+        self.base_addr as *const _
+    }
+
+    fn arm(&mut self, ticks: u32) {
+        self.ticks = ticks;
+        self.WDOG
+            .write(PM_WDOG::PASSWD.val(PM_PASSWORD) + PM_WDOG::TIME.val(ticks));
+
+        self.RSTC
+            .modify(PM_RSTC::PASSWD.val(PM_PASSWORD) + PM_RSTC::WRCFG::FullReset);
+    }
+}
+
+impl Watchdog {
+    pub const unsafe fn new(base_addr: usize) -> Self {
+        Self {
+            inner: NullLock::new(WatchdogInner::new(base_addr)),
+        }
+    }
+
+    pub unsafe fn set_base_addr(&self, base_addr: usize) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.base_addr = base_addr);
+    }
+
+    /// Resets the board unless `feed` is called within `timeout_ms`.
+    pub fn start(&self, timeout_ms: u32) {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.arm(timeout_to_ticks(timeout_ms)));
+    }
+
+    /// Restarts the countdown with the timeout given to `start`.
+    pub fn feed(&self) {
+        let mut r = &self.inner;
+        r.lock(|inner| {
+            let ticks = inner.ticks;
+            inner.arm(ticks)
+        });
+    }
+
+    pub fn reboot(&self) -> ! {
+        let mut r = &self.inner;
+        r.lock(|inner| inner.arm(REBOOT_TICKS));
+
+        cpu::wait_forever()
+    }
+}
+
+use synchronization::interface::Mutex;
+
+impl driver::interface::DeviceDriver for Watchdog {
+    fn name(&self) -> &str {
+        "BCM Watchdog"
+    }
+
+    fn compatible(&self) -> &str {
+        "brcm,bcm2835-pm-wdt"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::device_driver::mmio::fake_registers;
+    use core::sync::atomic::Ordering;
+
+    #[test]
+    fn timeout_to_ticks_clamps_to_20_bits() {
+        assert_eq!(timeout_to_ticks(0), 0);
+        assert_eq!(timeout_to_ticks(1000), 65_536);
+        assert_eq!(timeout_to_ticks(15_000), 983_040);
+        assert_eq!(timeout_to_ticks(16_000), MAX_TICKS);
+        assert_eq!(timeout_to_ticks(u32::MAX), MAX_TICKS);
+    }
+
+    #[test]
+    fn feed_rearms_with_the_password() {
+        let regs = fake_registers::<RegisterBlock>();
+        let watchdog = unsafe { Watchdog::new(regs.as_ptr() as usize) };
+        // Some other RSTC bits that must survive
+        regs[0x1C / 4].store(0x0000_0003, Ordering::Relaxed);
+
+        watchdog.start(1000);
+        regs[0x24 / 4].store(0, Ordering::Relaxed);
+        watchdog.feed();
+
+        assert_eq!(regs[0x24 / 4].load(Ordering::Relaxed), 0x5A01_0000);
+        assert_eq!(regs[0x1C / 4].load(Ordering::Relaxed), 0x5A00_0023);
+    }
+}
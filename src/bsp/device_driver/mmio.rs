@@ -0,0 +1,80 @@
+use core::{marker::PhantomData, ops};
+
+/// A register block of type `T` at a fixed address, reached through `Deref`
+pub struct MMIODerefWrapper<T> {
+    start_addr: usize,
+    phantom: PhantomData<fn() -> T>,
+}
+
+impl<T> MMIODerefWrapper<T> {
+    /// # Safety
+    ///
+    /// `start_addr` must be the mapped address of a `T` for as long as the
+    /// wrapper is dereferenced.
+    pub const unsafe fn new(start_addr: usize) -> Self {
+        Self {
+            start_addr,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> ops::Deref for MMIODerefWrapper<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*(self.start_addr as *const _) }
+    }
+}
+
+/// Zeroed memory the size of a `T` register block, one word per register, for
+/// driver tests. It is leaked, so another thread can keep playing the hardware.
+#[cfg(test)]
+pub fn fake_registers<T>() -> &'static [core::sync::atomic::AtomicU32] {
+    use core::sync::atomic::AtomicU32;
+
+    let words = core::mem::size_of::<T>() / 4;
+    std::boxed::Box::leak((0..words).map(|_| AtomicU32::new(0)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::Ordering;
+    use register::{mmio::ReadWrite, register_bitfields, register_structs};
+
+    register_bitfields! {
+        u32,
+
+        CTRL [
+            ENABLE OFFSET(0) NUMBITS(1) [],
+            MODE OFFSET(4) NUMBITS(2) []
+        ]
+    }
+
+    register_structs! {
+        #[allow(non_snake_case)]
+        FakeBlock {
+            (0x00 => DATA: ReadWrite<u32>),
+            (0x04 => _reserved1),
+            (0x08 => CTRL: ReadWrite<u32, CTRL::Register>),
+            (0x0C => @END),
+        }
+    }
+
+    #[test]
+    fn derefs_to_the_block_at_its_address() {
+        let regs = fake_registers::<FakeBlock>();
+        let block = unsafe { MMIODerefWrapper::<FakeBlock>::new(regs.as_ptr() as usize) };
+
+        block.DATA.set(0xdead_beef);
+        block.CTRL.write(CTRL::ENABLE::SET + CTRL::MODE.val(2));
+        assert_eq!(regs[0].load(Ordering::Relaxed), 0xdead_beef);
+        assert_eq!(regs[1].load(Ordering::Relaxed), 0);
+        assert_eq!(regs[2].load(Ordering::Relaxed), 0x21);
+
+        regs[2].store(0x30, Ordering::Relaxed);
+        assert_eq!(block.CTRL.read(CTRL::MODE), 3);
+        assert!(!block.CTRL.is_set(CTRL::ENABLE));
+    }
+}
@@ -0,0 +1,345 @@
+use core::{slice, str};
+
+const MAGIC: u32 = 0xd00d_feed;
+const HEADER_SIZE: usize = 40;
+// The Pi firmware's trees are well under 100 KiB. Anything past this is a
+// corrupt header, and reading that far could run off the end of RAM.
+const MAX_TOTAL_SIZE: usize = 0x20_0000;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+// Defaults from the devicetree spec when a node doesn't say
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+const DEFAULT_SIZE_CELLS: u32 = 1;
+
+/// A flattened device tree, read in place. Lookups walk the structure
+/// block from the start every time, so hang on to the results.
+#[derive(Copy, Clone)]
+pub struct Fdt<'a> {
+    data: &'a [u8],
+    structs: usize,
+    strings: usize,
+}
+
+fn be_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+// The NUL-terminated string at `offset`
+fn c_str(data: &[u8], offset: usize) -> Option<&str> {
+    let rest = data.get(offset..)?;
+    let len = rest.iter().position(|&b| b == 0)?;
+    str::from_utf8(&rest[..len]).ok()
+}
+
+// "memory" matches "memory@0" too, unless the unit address is spelled out
+fn name_matches(name: &str, component: &str) -> bool {
+    match name.strip_prefix(component) {
+        Some("") => true,
+        Some(unit) => !component.contains('@') && unit.starts_with('@'),
+        None => false,
+    }
+}
+
+/// Splits a number of `cells` 32-bit cells off the front of `value`
+fn take_cells(value: &[u8], cells: u32) -> Option<(usize, &[u8])> {
+    let len = cells as usize * 4;
+    if cells > 2 || value.len() < len {
+        return None;
+    }
+
+    let mut n = 0u64;
+    for i in 0..cells as usize {
+        n = n << 32 | be_u32(value, i * 4)? as u64;
+    }
+
+    Some((n as usize, &value[len..]))
+}
+
+impl<'a> Fdt<'a> {
+    /// Checks the header and that the blocks it points to fit in `data`
+    pub fn new(data: &'a [u8]) -> Option<Self> {
+        if data.len() < HEADER_SIZE || be_u32(data, 0)? != MAGIC {
+            return None;
+        }
+
+        let total = be_u32(data, 4)? as usize;
+        let structs = be_u32(data, 8)? as usize;
+        let strings = be_u32(data, 12)? as usize;
+        if total > data.len() || structs >= total || strings >= total {
+            return None;
+        }
+
+        Some(Self {
+            data: &data[..total],
+            structs,
+            strings,
+        })
+    }
+
+    /// # Safety
+    ///
+    /// `addr` must point to `HEADER_SIZE` readable bytes, followed by as much
+    /// of a device tree as its header claims, up to `MAX_TOTAL_SIZE`.
+    pub unsafe fn from_addr(addr: usize) -> Option<Fdt<'static>> {
+        let header = slice::from_raw_parts(addr as *const u8, HEADER_SIZE);
+        if be_u32(header, 0)? != MAGIC {
+            return None;
+        }
+
+        let total = be_u32(header, 4)? as usize;
+        if total > MAX_TOTAL_SIZE {
+            return None;
+        }
+        Fdt::new(slice::from_raw_parts(addr as *const u8, total))
+    }
+
+    /// The value of property `name` of the node at `path`, e.g. "/chosen".
+    /// Path components without a unit address match any, so "/memory" finds
+    /// "/memory@0".
+    pub fn property(&self, path: &str, name: &str) -> Option<&'a [u8]> {
+        let components = || path.split('/').filter(|c| !c.is_empty());
+        let target = components().count() + 1;
+
+        let mut offset = self.structs;
+        // Open nodes, counting the root, and how many of them are on `path`
+        let mut depth = 0usize;
+        let mut matched = 0;
+
+        loop {
+            let token = be_u32(self.data, offset)?;
+            offset += 4;
+
+            match token {
+                FDT_BEGIN_NODE => {
+                    let node = c_str(self.data, offset)?;
+                    offset = align4(offset + node.len() + 1);
+
+                    depth += 1;
+                    let on_path = match depth.checked_sub(2) {
+                        None => true,
+                        Some(i) => matches!(components().nth(i), Some(c) if name_matches(node, c)),
+                    };
+                    if on_path && matched == depth - 1 {
+                        matched = depth;
+                    }
+                }
+                FDT_END_NODE => {
+                    if matched == depth {
+                        matched -= 1;
+                    }
+                    depth = depth.checked_sub(1)?;
+                }
+                FDT_PROP => {
+                    let len = be_u32(self.data, offset)? as usize;
+                    let name_offset = be_u32(self.data, offset + 4)? as usize;
+                    let value = self.data.get(offset + 8..offset + 8 + len)?;
+                    offset = align4(offset + 8 + len);
+
+                    if depth == target
+                        && matched == target
+                        && c_str(self.data, self.strings + name_offset)? == name
+                    {
+                        return Some(value);
+                    }
+                }
+                FDT_NOP => {}
+                FDT_END => return None,
+                _ => return None,
+            }
+        }
+    }
+
+    fn cells(&self, path: &str, name: &str, default: u32) -> u32 {
+        self.property(path, name)
+            .and_then(|v| be_u32(v, 0))
+            .unwrap_or(default)
+    }
+
+    /// The kernel command line from `/chosen`
+    pub fn bootargs(&self) -> Option<&'a str> {
+        let value = self.property("/chosen", "bootargs")?;
+        let len = value.iter().position(|&b| b == 0).unwrap_or(value.len());
+        str::from_utf8(&value[..len]).ok()
+    }
+
+    /// Base and size of the first region of the `/memory` node
+    pub fn memory(&self) -> Option<(usize, usize)> {
+        let address_cells = self.cells("/", "#address-cells", DEFAULT_ADDRESS_CELLS);
+        let size_cells = self.cells("/", "#size-cells", DEFAULT_SIZE_CELLS);
+
+        let reg = self.property("/memory", "reg")?;
+        let (base, rest) = take_cells(reg, address_cells)?;
+        let (size, _) = take_cells(rest, size_cells)?;
+
+        Some((base, size))
+    }
+
+    /// The first translation in `/soc`'s `ranges` as (bus address, CPU
+    /// address, size). On the Raspberry Pi that's where the peripherals are.
+    pub fn soc_ranges(&self) -> Option<(usize, usize, usize)> {
+        let parent_cells = self.cells("/", "#address-cells", DEFAULT_ADDRESS_CELLS);
+        let child_cells = self.cells("/soc", "#address-cells", DEFAULT_ADDRESS_CELLS);
+        let size_cells = self.cells("/soc", "#size-cells", DEFAULT_SIZE_CELLS);
+
+        let ranges = self.property("/soc", "ranges")?;
+        let (child, rest) = take_cells(ranges, child_cells)?;
+        let (parent, rest) = take_cells(rest, parent_cells)?;
+        let (size, _) = take_cells(rest, size_cells)?;
+
+        Some((child, parent, size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// Lays out a device tree blob the way dtc does
+    #[derive(Default)]
+    struct Blob {
+        structs: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl Blob {
+        fn token(&mut self, token: u32) -> &mut Self {
+            self.structs.extend_from_slice(&token.to_be_bytes());
+            self
+        }
+
+        fn pad(&mut self) {
+            let len = align4(self.structs.len());
+            self.structs.resize(len, 0);
+        }
+
+        fn begin(&mut self, name: &str) -> &mut Self {
+            self.token(FDT_BEGIN_NODE);
+            self.structs.extend_from_slice(name.as_bytes());
+            self.structs.push(0);
+            self.pad();
+            self
+        }
+
+        fn end(&mut self) -> &mut Self {
+            self.token(FDT_END_NODE)
+        }
+
+        fn prop(&mut self, name: &str, value: &[u8]) -> &mut Self {
+            let name_offset = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+
+            self.token(FDT_PROP).token(value.len() as u32).token(name_offset);
+            self.structs.extend_from_slice(value);
+            self.pad();
+            self
+        }
+
+        fn cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+            let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+            self.prop(name, &value)
+        }
+
+        fn build(&mut self) -> Vec<u8> {
+            self.token(FDT_END);
+            let structs = HEADER_SIZE;
+            let strings = structs + self.structs.len();
+            let total = strings + self.strings.len();
+
+            let mut header = [0u32; HEADER_SIZE / 4];
+            header[0] = MAGIC;
+            header[1] = total as u32;
+            header[2] = structs as u32;
+            header[3] = strings as u32;
+            // Version 17, compatible back to 16
+            header[5] = 17;
+            header[6] = 16;
+            header[8] = self.strings.len() as u32;
+            header[9] = self.structs.len() as u32;
+
+            let mut blob: Vec<u8> = header.iter().flat_map(|w| w.to_be_bytes()).collect();
+            blob.extend_from_slice(&self.structs);
+            blob.extend_from_slice(&self.strings);
+            blob
+        }
+    }
+
+    // A cut-down Pi 3 tree
+    fn pi3_blob() -> Vec<u8> {
+        Blob::default()
+            .begin("")
+            .cells("#address-cells", &[1])
+            .cells("#size-cells", &[1])
+            .begin("chosen")
+            .prop("bootargs", b"console=serial quiet\0")
+            .end()
+            .begin("memory@0")
+            .prop("device_type", b"memory\0")
+            .cells("reg", &[0, 0x3b40_0000])
+            .end()
+            .begin("soc")
+            .cells("#address-cells", &[1])
+            .cells("#size-cells", &[1])
+            .cells("ranges", &[0x7e00_0000, 0x3f00_0000, 0x0100_0000])
+            .end()
+            .end()
+            .build()
+    }
+
+    #[test]
+    fn reads_the_pi3_fixture() {
+        let blob = pi3_blob();
+        let dt = Fdt::new(&blob).unwrap();
+
+        assert_eq!(dt.bootargs(), Some("console=serial quiet"));
+        assert_eq!(dt.memory(), Some((0, 0x3b40_0000)));
+        assert_eq!(dt.soc_ranges(), Some((0x7e00_0000, 0x3f00_0000, 0x0100_0000)));
+        assert_eq!(dt.property("/memory@1", "reg"), None);
+        assert_eq!(dt.property("/soc", "missing"), None);
+    }
+
+    #[test]
+    fn default_cells_when_the_root_has_none() {
+        let blob = Blob::default()
+            .begin("")
+            .begin("memory@0")
+            .cells("reg", &[0, 0x1000_0000, 0x4000_0000])
+            .end()
+            .end()
+            .build();
+
+        assert_eq!(Fdt::new(&blob).unwrap().memory(), Some((0x1000_0000, 0x4000_0000)));
+    }
+
+    #[test]
+    fn new_rejects_a_bad_header() {
+        let mut blob = pi3_blob();
+        assert!(Fdt::new(&blob[..HEADER_SIZE - 1]).is_none());
+        // Shorter than totalsize
+        assert!(Fdt::new(&blob[..blob.len() - 1]).is_none());
+
+        blob[0] ^= 0xff;
+        assert!(Fdt::new(&blob).is_none());
+    }
+
+    #[test]
+    fn from_addr_rejects_an_oversized_totalsize() {
+        let mut blob = pi3_blob();
+        assert!(unsafe { Fdt::from_addr(blob.as_ptr() as usize) }.is_some());
+
+        // Only the header is read before giving up
+        blob[4..8].copy_from_slice(&(MAX_TOTAL_SIZE as u32 + 1).to_be_bytes());
+        assert!(unsafe { Fdt::from_addr(blob.as_ptr() as usize) }.is_none());
+    }
+}
@@ -1,23 +1,303 @@
 pub mod console;
 pub mod cpu;
 pub mod driver;
+pub mod exception;
 pub mod memory;
+pub mod time;
 
-use super::device_driver;
+use super::{device_driver, fdt};
+use crate::{
+    memory::{mmu::MemAttributes, PhysicalAddress},
+    synchronization::Once,
+};
+use core::sync::atomic::{AtomicU8, Ordering};
 
 static GPIO: device_driver::GPIO  = 
-    unsafe { device_driver::GPIO::new(memory::map::mmio::GPIO_BASE) };
-static PL011_UART: device_driver::PL011Uart =
-    unsafe { device_driver::PL011Uart::new(memory::map::mmio::PL011_UART_BASE) };
+    unsafe { device_driver::GPIO::new(memory::map::mmio::GPIO_BASE, &SYSTEM_TIMER) };
+static PL011_UART: device_driver::PL011Uart = unsafe {
+    device_driver::PL011Uart::new(
+        PhysicalAddress::new(memory::map::mmio::PL011_UART_BASE),
+        console::PL011_UART_BAUD_RATE,
+        console::PL011_UART_CLOCK_HZ,
+        &SYSTEM_TIMER,
+        &GPIO,
+    )
+};
+static MINI_UART: device_driver::MiniUart = unsafe {
+    device_driver::MiniUart::new(
+        memory::map::mmio::MINI_UART_BASE,
+        console::MINI_UART_BAUD_RATE,
+        &GPIO,
+        &MAILBOX,
+    )
+};
+static SYSTEM_TIMER: device_driver::SystemTimer =
+    unsafe { device_driver::SystemTimer::new(memory::map::mmio::SYSTEM_TIMER_BASE) };
+static INTERRUPT_CONTROLLER: device_driver::InterruptController = unsafe {
+    device_driver::InterruptController::new(memory::map::mmio::INTERRUPT_CONTROLLER_BASE)
+};
+static MAILBOX: device_driver::Mailbox =
+    unsafe { device_driver::Mailbox::new(memory::map::mmio::MAILBOX_BASE) };
+static WATCHDOG: device_driver::Watchdog =
+    unsafe { device_driver::Watchdog::new(memory::map::mmio::WATCHDOG_BASE) };
+static DMA: device_driver::DMA = unsafe {
+    device_driver::DMA::new(
+        memory::map::mmio::DMA_BASE,
+        console::PL011_UART_DMA_CHANNEL,
+        &SYSTEM_TIMER,
+    )
+};
+// On the header's SDA1/SCL1 pins, GPIO 2/3, at standard mode. Only with `i2c`
+// on the command line.
+static I2C: device_driver::BSC = unsafe {
+    device_driver::BSC::new(
+        memory::map::mmio::BSC1_BASE,
+        (2, 3),
+        100_000,
+        &GPIO,
+        &MAILBOX,
+        &SYSTEM_TIMER,
+    )
+};
+// 1 MHz to the device on CE0, selected low. Only with `spi` on the command
+// line.
+static SPI: device_driver::SPI = unsafe {
+    device_driver::SPI::new(
+        memory::map::mmio::SPI0_BASE,
+        1_000_000,
+        0,
+        false,
+        &GPIO,
+        &MAILBOX,
+        &SYSTEM_TIMER,
+    )
+};
+// The crystal oscillator the PWM clock is divided from
+#[cfg(feature = "bsp_rpi3")]
+const OSCILLATOR_HZ: u32 = 19_200_000;
+#[cfg(feature = "bsp_rpi4")]
+const OSCILLATOR_HZ: u32 = 54_000_000;
 
-pub fn board_name() -> &'static str {
-    #[cfg(feature = "bsp_rpi3")]
-    {
-        "Raspberry Pi 3"
+static PWM: device_driver::Pwm = unsafe {
+    device_driver::Pwm::new(
+        memory::map::mmio::PWM0_BASE,
+        memory::map::mmio::CLOCK_MANAGER_BASE,
+        OSCILLATOR_HZ,
+        &GPIO,
+        &SYSTEM_TIMER,
+    )
+};
+// The SD slot, whose controller `rebase_drivers` picks for the board
+static EMMC: device_driver::Emmc =
+    unsafe { device_driver::Emmc::new(&GPIO, &MAILBOX, &SYSTEM_TIMER) };
+// Disabled unless `reset_button=<pin>` names its pin
+static RESET_BUTTON: device_driver::ResetButton =
+    unsafe { device_driver::ResetButton::new(&GPIO, driver::reboot) };
+// The green ACT LED: GPIO 29 on the Pi 3 B+ and GPIO 42 on the Pi 4. The
+// Pi 3 B has it on the firmware's GPIO expander, out of reach here.
+#[cfg(feature = "bsp_rpi3")]
+const ACT_LED_PIN: u32 = 29;
+#[cfg(feature = "bsp_rpi4")]
+const ACT_LED_PIN: u32 = 42;
+
+static ACT_LED: device_driver::ActivityLed =
+    unsafe { device_driver::ActivityLed::new(ACT_LED_PIN, &GPIO, &SYSTEM_TIMER) };
+static FRAMEBUFFER: device_driver::FrameBuffer = unsafe {
+    device_driver::FrameBuffer::new(
+        &MAILBOX,
+        console::FRAMEBUFFER_WIDTH,
+        console::FRAMEBUFFER_HEIGHT,
+    )
+};
+
+// Only ever handed to the UART, once
+#[link_section = ".dma"]
+static mut PL011_UART_DMA_BUFFER: device_driver::DMATxBuffer =
+    device_driver::DMATxBuffer::new();
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Board {
+    RaspberryPi3,
+    RaspberryPi4,
+}
+
+#[cfg(feature = "bsp_rpi3")]
+const DEFAULT_BOARD: Board = Board::RaspberryPi3;
+#[cfg(feature = "bsp_rpi4")]
+const DEFAULT_BOARD: Board = Board::RaspberryPi4;
+
+// Detected before the MMU is on, so not a `Once`, which needs exclusives
+static BOARD: AtomicU8 = AtomicU8::new(DEFAULT_BOARD as u8);
+// From the device tree, unset unless `probe_device_tree` finds it
+static PERIPHERAL_BASE: Once<usize> = Once::new();
+
+/// The SoC a new-style board revision code names in bits 12-15. Old-style
+/// codes only ever belonged to BCM2835 boards.
+fn board_from_revision(revision: u32) -> Option<Board> {
+    const NEW_STYLE: u32 = 1 << 23;
+
+    if revision & NEW_STYLE == 0 {
+        return None;
     }
 
-    #[cfg(feature = "bsp_rpi4")]
+    match (revision >> 12) & 0xf {
+        2 => Some(Board::RaspberryPi3),
+        3 => Some(Board::RaspberryPi4),
+        _ => None,
+    }
+}
+
+/// The SoC going by the MIDR_EL1 part number: Cortex-A53 on BCM2837,
+/// Cortex-A72 on BCM2711
+fn board_from_midr(midr: u64) -> Option<Board> {
+    match (midr >> 4) & 0xfff {
+        0xd03 => Some(Board::RaspberryPi3),
+        0xd08 => Some(Board::RaspberryPi4),
+        _ => None,
+    }
+}
+
+/// Picks the board from the CPU part number `cpu`, asking `revision` only
+/// when that doesn't rule out the board the image was built for. Any other
+/// board has plain RAM where the mailbox is looked for, and polling it would
+/// never finish.
+fn pick_board(cpu: Option<Board>, revision: impl FnOnce() -> Option<Board>) -> Board {
+    match cpu {
+        Some(board) if board != DEFAULT_BOARD => board,
+        _ => revision().or(cpu).unwrap_or(DEFAULT_BOARD),
+    }
+}
+
+/// Identifies the SoC from the CPU part number, confirmed by the board
+/// revision the firmware reports when the image was built for that SoC.
+/// Falls back to the board the image was built for.
+///
+/// Board-specific driver behavior (e.g. GPIO pull resistors) still follows
+/// the `bsp_*` feature.
+pub unsafe fn detect_board() {
+    let cpu = board_from_midr(crate::cpu::main_id());
+    let board = pick_board(cpu, || {
+        MAILBOX.get_board_revision().ok().and_then(board_from_revision)
+    });
+    BOARD.store(board as u8, Ordering::Relaxed);
+}
+
+/// The device tree the firmware passed in x0, if it points into RAM and
+/// looks like one
+pub fn device_tree() -> Option<fdt::Fdt<'static>> {
+    let addr = crate::cpu::dtb_addr();
+    if addr == 0 || addr & 7 != 0 || memory::memory_attributes(addr) != Some(MemAttributes::Normal)
     {
-        "Raspberry Pi 4"
+        return None;
+    }
+
+    unsafe { fdt::Fdt::from_addr(addr) }
+}
+
+/// Takes the peripheral base from the device tree, as long as it falls in
+/// the detected board's device memory. Must run after the MMU is on, since
+/// the tree isn't read with aligned accesses only, and before
+/// `rebase_drivers`.
+pub unsafe fn probe_device_tree() {
+    if let Some((_, base, _)) = device_tree().and_then(|dt| dt.soc_ranges()) {
+        if memory::memory_attributes(base) == Some(MemAttributes::Device) {
+            PERIPHERAL_BASE.call_once(|| base);
+        }
+    }
+}
+
+/// Points the drivers at the detected board's peripheral base. Must run
+/// before the first UART access, with the MMU on since the UART sits behind
+/// a spinlock.
+pub unsafe fn rebase_drivers() {
+    GPIO.set_base_addr(peripheral_base() + memory::map::GPIO_OFFSET);
+    PL011_UART.set_base_addr(PhysicalAddress::new(peripheral_base() + memory::map::UART_OFFSET));
+    MINI_UART.set_base_addr(peripheral_base() + memory::map::MINI_UART_OFFSET);
+    SYSTEM_TIMER.set_base_addr(peripheral_base() + memory::map::SYSTEM_TIMER_OFFSET);
+    INTERRUPT_CONTROLLER
+        .set_base_addr(peripheral_base() + memory::map::INTERRUPT_CONTROLLER_OFFSET);
+    MAILBOX.set_base_addr(peripheral_base() + memory::map::MAILBOX_OFFSET);
+    WATCHDOG.set_base_addr(peripheral_base() + memory::map::WATCHDOG_OFFSET);
+    DMA.set_base_addr(peripheral_base() + memory::map::DMA_OFFSET);
+    I2C.set_base_addr(peripheral_base() + memory::map::BSC1_OFFSET);
+    SPI.set_base_addr(peripheral_base() + memory::map::SPI0_OFFSET);
+    PWM.set_base_addr(
+        peripheral_base() + memory::map::PWM0_OFFSET,
+        peripheral_base() + memory::map::CLOCK_MANAGER_OFFSET,
+    );
+    // The Pi 3 routes the SD slot to SDHOST at boot, so GPIO 48-53 are
+    // taken over; the Pi 4's EMMC2 has it to itself
+    let (emmc_offset, emmc_clock, emmc_pins): (usize, u32, &'static [u32]) = match board() {
+        Board::RaspberryPi3 => (
+            memory::map::EMMC_OFFSET,
+            device_driver::clock::EMMC,
+            &[48, 49, 50, 51, 52, 53],
+        ),
+        Board::RaspberryPi4 => (memory::map::EMMC2_OFFSET, device_driver::clock::EMMC2, &[]),
+    };
+    EMMC.set_controller(peripheral_base() + emmc_offset, emmc_clock, emmc_pins);
+}
+
+pub fn board() -> Board {
+    match BOARD.load(Ordering::Relaxed) {
+        x if x == Board::RaspberryPi4 as u8 => Board::RaspberryPi4,
+        _ => Board::RaspberryPi3,
+    }
+}
+
+fn peripheral_base() -> usize {
+    match (PERIPHERAL_BASE.get(), board()) {
+        (Some(&base), _) => base,
+        (None, Board::RaspberryPi3) => memory::map::BCM2837_BASE,
+        (None, Board::RaspberryPi4) => memory::map::BCM2711_BASE,
+    }
+}
+
+fn local_peripheral_base() -> usize {
+    match board() {
+        Board::RaspberryPi3 => memory::map::BCM2837_LOCAL_BASE,
+        Board::RaspberryPi4 => memory::map::BCM2711_LOCAL_BASE,
+    }
+}
+
+pub fn board_name() -> &'static str {
+    match board() {
+        Board::RaspberryPi3 => "Raspberry Pi 3",
+        Board::RaspberryPi4 => "Raspberry Pi 4",
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn board_from_revision_reads_the_processor() {
+        // 3 B+, 3 A+, 4 B 4GB
+        assert_eq!(board_from_revision(0x00a0_20d3), Some(Board::RaspberryPi3));
+        assert_eq!(board_from_revision(0x0090_20e0), Some(Board::RaspberryPi3));
+        assert_eq!(board_from_revision(0x00c0_3111), Some(Board::RaspberryPi4));
+        // Zero W (BCM2835), and an old-style code
+        assert_eq!(board_from_revision(0x0090_00c1), None);
+        assert_eq!(board_from_revision(0x0000_0010), None);
+    }
+
+    #[test]
+    fn board_from_midr_reads_the_part_number() {
+        assert_eq!(board_from_midr(0x410f_d034), Some(Board::RaspberryPi3));
+        assert_eq!(board_from_midr(0x410f_d083), Some(Board::RaspberryPi4));
+        assert_eq!(board_from_midr(0x410f_c075), None);
+    }
+
+    #[test]
+    fn pick_board_skips_the_mailbox_of_another_board() {
+        let other = match DEFAULT_BOARD {
+            Board::RaspberryPi3 => Board::RaspberryPi4,
+            Board::RaspberryPi4 => Board::RaspberryPi3,
+        };
+
+        assert_eq!(pick_board(Some(other), || panic!("asked the mailbox")), other);
+        assert_eq!(pick_board(Some(DEFAULT_BOARD), || Some(other)), other);
+        assert_eq!(pick_board(None, || None), DEFAULT_BOARD);
+        assert_eq!(pick_board(Some(DEFAULT_BOARD), || None), DEFAULT_BOARD);
     }
-}
\ No newline at end of file
+}
@@ -1,13 +1,124 @@
 use super::memory;
-use crate::{bsp::device_driver, console};
+use crate::{bsp::device_driver, cmdline, console, cpu, memory::PhysicalAddress};
 use core::fmt;
 
-pub unsafe fn panic_console_out() -> impl fmt::Write {
-    let mut uart = device_driver::PanicUart::new(memory::map::mmio::PL011_UART_BASE);
-    uart.init();
-    uart
+pub const PL011_UART_BAUD_RATE: u32 = 230_400;
+pub const PL011_UART_CLOCK_HZ: u32 = 48_000_000;
+// Free for the ARM under the firmware's default channel mask
+pub const PL011_UART_DMA_CHANNEL: usize = 5;
+
+pub const MINI_UART_BAUD_RATE: u32 = 115_200;
+
+pub const FRAMEBUFFER_WIDTH: u32 = 1024;
+pub const FRAMEBUFFER_HEIGHT: u32 = 768;
+
+// VPU core clock the mini UART divides down from, as set by `enable_uart=1`
+fn mini_uart_clock_hz() -> u32 {
+    match super::board() {
+        super::Board::RaspberryPi3 => 250_000_000,
+        super::Board::RaspberryPi4 => 500_000_000,
+    }
+}
+
+/// `console=mini` swaps the PL011 for the mini UART on GPIO 14/15, e.g. where
+/// the PL011 is taken by Bluetooth
+pub fn use_mini_uart() -> bool {
+    cmdline::get("console") == Some("mini")
 }
 
-pub fn console() -> &'static impl console::interface::All {
-    &super::PL011_UART
+/// Unless `console=` names a UART or `none`, the framebuffer joins the
+/// console
+pub fn use_framebuffer() -> bool {
+    !matches!(cmdline::get("console"), Some("serial") | Some("mini") | Some("none"))
 }
+
+pub enum PanicConsole {
+    // The running driver, through its lock-free emergency path
+    Live(&'static device_driver::PL011Uart),
+    LiveMiniUart(&'static device_driver::MiniUart),
+    PL011(device_driver::PanicUart),
+    MiniUart(device_driver::PanicMiniUart),
+}
+
+impl PanicConsole {
+    /// Waits for the output so far to leave the UART
+    pub fn flush(&self) {
+        match self {
+            PanicConsole::Live(uart) => uart.emergency_flush(),
+            PanicConsole::LiveMiniUart(_) => {}
+            PanicConsole::PL011(uart) => uart.flush(),
+            PanicConsole::MiniUart(_) => {}
+        }
+    }
+}
+
+impl fmt::Write for PanicConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        use console::interface::Write;
+
+        match self {
+            PanicConsole::Live(uart) => {
+                for c in s.chars() {
+                    uart.emergency_write_char(c);
+                }
+                Ok(())
+            }
+            PanicConsole::LiveMiniUart(uart) => {
+                for c in s.chars() {
+                    uart.emergency_write_char(c);
+                }
+                Ok(())
+            }
+            PanicConsole::PL011(uart) => uart.write_str(s),
+            PanicConsole::MiniUart(uart) => uart.write_str(s),
+        }
+    }
+}
+
+/// A console for the panic path, which neither locks nor allocates. With
+/// `console=mini` that is the mini UART driver, if it's up. Otherwise it
+/// tries, in order:
+///
+/// 1. The PL011 driver, if UARTEN says it's up. It keeps its settings and
+///    whatever is still in the FIFO.
+/// 2. The PL011, re-initialized, if the driver brought it up before and it
+///    has been shut down since.
+/// 3. The mini UART, with GPIO 14/15 switched over to it. There is no way to
+///    check it works, so it is the last resort.
+pub unsafe fn panic_console_out() -> PanicConsole {
+    let base = super::peripheral_base();
+
+    if use_mini_uart() && super::MINI_UART.tx_enabled() {
+        return PanicConsole::LiveMiniUart(&super::MINI_UART);
+    }
+
+    let uart_base = PhysicalAddress::new(base + memory::map::UART_OFFSET);
+    let mut uart = device_driver::PanicUart::new(uart_base);
+    // UARTEN has to be read before any re-init, which sets it whatever happens
+    if uart.is_enabled() {
+        return PanicConsole::Live(&super::PL011_UART);
+    }
+    // Shut down since, but known to work
+    if super::PL011_UART.was_initialized()
+        && uart.init(PL011_UART_BAUD_RATE, PL011_UART_CLOCK_HZ).is_ok()
+    {
+        return PanicConsole::PL011(uart);
+    }
+
+    device_driver::panic_map_mini_uart(base + memory::map::GPIO_OFFSET);
+    cpu::barrier::dsb();
+    let mut mini_uart = device_driver::PanicMiniUart::new(base + memory::map::MINI_UART_OFFSET);
+    // Nothing left to fall back to if this fails
+    let _ = mini_uart.init(MINI_UART_BAUD_RATE, mini_uart_clock_hz());
+    PanicConsole::MiniUart(mini_uart)
+}
+
+// Serial and HDMI, once the framebuffer is up
+pub static MULTIPLEXER: console::Multiplexer =
+    console::Multiplexer::new(&[&super::PL011_UART, &super::FRAMEBUFFER]);
+
+// What `console::set_output` can switch to
+pub static PL011_UART_OUTPUT: console::Output = console::Output(&super::PL011_UART);
+pub static MINI_UART_OUTPUT: console::Output = console::Output(&super::MINI_UART);
+pub static FRAMEBUFFER_OUTPUT: console::Output = console::Output(&super::FRAMEBUFFER);
+pub static MULTIPLEXER_OUTPUT: console::Output = console::Output(&MULTIPLEXER);
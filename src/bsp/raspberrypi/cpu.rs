@@ -1,2 +1,30 @@
-pub const BOOT_CORE_ID: usize = 0;
-pub const BOOT_CORE_STACK_START: u64 = 0x80_000;
+// Where core 0's stack starts, the other cores' follow below it
+pub const STACKS_START: usize = 0x80_000;
+pub const KERNEL_LOAD_ADDR: usize = 0x80_000;
+// Free memory below the secondary cores' stacks
+pub const CHAINLOAD_TRAMPOLINE_ADDR: usize = 0x30_000;
+pub const CORE_COUNT: usize = 4;
+pub const CORE_STACK_SIZE: usize = 0x10_000;
+
+/// Stacks sit below core 0's, one `CORE_STACK_SIZE` apart. boot.S sets them
+/// up from the matching symbols in link.ld.
+pub const fn core_stack_start(core_id: u8) -> usize {
+    STACKS_START - core_id as usize * CORE_STACK_SIZE
+}
+
+/// The core that runs `kernel_init`, with the others parked until it starts
+/// them. Core 0 unless the build sets `BOOT_CORE_ID`, for harnesses that
+/// bring up a different core first.
+pub const fn boot_core_id() -> usize {
+    match option_env!("BOOT_CORE_ID") {
+        Some(id) => (id.as_bytes()[0] - b'0') as usize,
+        None => 0,
+    }
+}
+
+// A single digit naming one of the cores
+const _: [(); 1] = [(); (boot_core_id() < CORE_COUNT) as usize];
+const _: [(); 1] = [(); match option_env!("BOOT_CORE_ID") {
+    Some(id) => id.len(),
+    None => 1,
+}];
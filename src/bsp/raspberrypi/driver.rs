@@ -1,25 +1,203 @@
-use crate::driver;
+use super::memory;
+use crate::{
+    bsp::device_driver, cmdline, console, cpu, driver, driver::DriverList, exception,
+    synchronization, synchronization::NullLock,
+};
+
+type Driver = &'static (dyn DeviceDriver + Sync);
 
 pub struct BSPDriverManager {
-    device_drivers: [&'static (dyn DeviceDriver + Sync); 2],
+    device_drivers: NullLock<DriverList>,
 }
 
 static BSP_DRIVER_MANAGER: BSPDriverManager = BSPDriverManager {
-    device_drivers: [&super::GPIO, &super::PL011_UART],
+    device_drivers: NullLock::new(DriverList::new()),
 };
 
 pub fn driver_manager() -> &'static impl driver::interface::DriverManager {
     &BSP_DRIVER_MANAGER
 }
 
+/// Registers the drivers every board has. Optional ones can be added with
+/// `register` before the drivers are initialized.
+pub fn register_drivers() {
+    use driver::interface::DriverManager;
+
+    let drivers: [Driver; 14] = [
+        &super::INTERRUPT_CONTROLLER,
+        &super::GPIO,
+        &super::PL011_UART,
+        &super::SYSTEM_TIMER,
+        &super::MAILBOX,
+        &super::WATCHDOG,
+        &super::DMA,
+        &super::I2C,
+        &super::SPI,
+        &super::ACT_LED,
+        &super::MINI_UART,
+        &super::PWM,
+        &super::RESET_BUTTON,
+        &super::EMMC,
+    ];
+    // It takes GPIO 14/15 from the PL011, so only when asked for
+    super::MINI_UART.set_enabled(super::console::use_mini_uart());
+    // SDA1/SCL1 are header pins that may be wired to something else
+    super::I2C.set_enabled(cmdline::has("i2c"));
+    // As are GPIO 7-11
+    super::SPI.set_enabled(cmdline::has("spi"));
+    super::RESET_BUTTON.set_pin(cmdline::get("reset_button").and_then(|arg| {
+        let pin = arg.parse().ok();
+        if pin.is_none() {
+            crate::warn!("reset_button={}: not a pin number, button disabled", arg);
+        }
+        pin
+    }));
+
+    // Only asks the firmware for a screen if a console is going to use it
+    let framebuffer: Option<Driver> = match super::console::use_framebuffer() {
+        true => Some(&super::FRAMEBUFFER),
+        false => None,
+    };
+
+    for &d in drivers.iter().chain(framebuffer.iter()) {
+        if driver_manager().register(d).is_err() {
+            panic!("Error registering driver: {}", d.name());
+        }
+    }
+}
+
+pub fn watchdog() -> &'static device_driver::Watchdog {
+    &super::WATCHDOG
+}
+
+/// Shuts the drivers down and resets the board through the watchdog
+pub fn reboot() -> ! {
+    use driver::interface::DriverManager;
+
+    // The mini UART doesn't drain on shutdown
+    console::console().flush();
+    driver_manager().shutdown_all();
+    super::WATCHDOG.reboot()
+}
+
+/// Starts the ACT LED blinking off the system timer, or stops it where it is
+pub fn set_heartbeat(enable: bool) {
+    use exception::interface::IRQManager;
+
+    let irq = super::exception::SYSTEM_TIMER_MATCH1_IRQ;
+    if enable {
+        super::exception::irq_manager().enable(irq);
+        super::ACT_LED.start_heartbeat();
+    } else {
+        super::exception::irq_manager().disable(irq);
+    }
+}
+
+pub fn gpio() -> &'static device_driver::GPIO {
+    &super::GPIO
+}
+
+pub fn pl011_uart() -> &'static device_driver::PL011Uart {
+    &super::PL011_UART
+}
+
+/// The PL011's reference clock as the firmware set it up, for
+/// `set_baud_rate`. The boot-time rate if the firmware won't say.
+pub fn pl011_clock_hz() -> u32 {
+    super::MAILBOX
+        .get_clock_rate(device_driver::clock::UART)
+        .unwrap_or(super::console::PL011_UART_CLOCK_HZ)
+}
+
+pub fn activity_led() -> &'static device_driver::ActivityLed {
+    &super::ACT_LED
+}
+
+pub fn i2c() -> &'static device_driver::BSC {
+    &super::I2C
+}
+
+pub fn spi() -> &'static device_driver::SPI {
+    &super::SPI
+}
+
+pub fn pwm() -> &'static device_driver::Pwm {
+    &super::PWM
+}
+
+pub fn framebuffer() -> &'static device_driver::FrameBuffer {
+    &super::FRAMEBUFFER
+}
+
+pub fn emmc() -> &'static device_driver::Emmc {
+    &super::EMMC
+}
+
+pub fn reset_button() -> &'static device_driver::ResetButton {
+    &super::RESET_BUTTON
+}
+
 use driver::interface::DeviceDriver;
+use synchronization::interface::Mutex;
 
 impl driver::interface::DriverManager for BSPDriverManager {
-    fn all_device_drivers(&self) -> &[&'static (dyn DeviceDriver + Sync)] {
-        &self.device_drivers[..]
+    fn all_device_drivers(&self) -> DriverList {
+        let mut r = &self.device_drivers;
+        r.lock(|list| *list)
+    }
+
+    fn register(&self, driver: &'static (dyn DeviceDriver + Sync)) -> Result<(), ()> {
+        let mut r = &self.device_drivers;
+        r.lock(|list| list.push(driver))
     }
 
     fn post_device_driver_init(&self) {
-        super::GPIO.map_pl011_uart();
+        use exception::interface::IRQManager;
+
+        if !super::console::use_mini_uart() {
+            super::GPIO.map_pl011_uart();
+        }
+        // From GPIO to the interrupt controller and the UARTs
+        cpu::barrier::dsb();
+
+        // Without a working channel the UART sticks to its FIFO
+        if super::DMA.is_ready() {
+            let dr_bus_addr = device_driver::BUS_PERIPHERAL_BASE + memory::map::UART_OFFSET as u32;
+            unsafe {
+                let buffer = &mut *core::ptr::addr_of_mut!(super::PL011_UART_DMA_BUFFER);
+                super::PL011_UART.attach_dma(&super::DMA, buffer, dr_bus_addr);
+            }
+        }
+
+        let irq_manager = super::exception::irq_manager();
+        let uart_irq = super::exception::PL011_UART_IRQ;
+        if let Err(e) = irq_manager.register_handler(uart_irq, &super::PL011_UART) {
+            panic!("Error registering IRQ handler: {}", e);
+        }
+        irq_manager.enable(uart_irq);
+
+        // Blinks once a second for as long as IRQs are serviced
+        let heartbeat_irq = super::exception::SYSTEM_TIMER_MATCH1_IRQ;
+        if let Err(e) = irq_manager.register_handler(heartbeat_irq, &super::ACT_LED) {
+            panic!("Error registering IRQ handler: {}", e);
+        }
+        set_heartbeat(true);
+
+        let framebuffer = super::FRAMEBUFFER.buffer();
+        if let Some(buffer) = framebuffer.clone() {
+            unsafe { memory::map_framebuffer(buffer) };
+        }
+
+        // `console=serial`, `console=mini` or `console=fb` picks one and
+        // `console=none` discards everything, otherwise it's both the PL011
+        // and the framebuffer, if there is one
+        match (cmdline::get("console"), framebuffer.is_some()) {
+            (Some("none"), _) => console::set_output(&console::NULL_OUTPUT),
+            (Some("serial"), _) => console::set_output(&super::console::PL011_UART_OUTPUT),
+            (Some("mini"), _) => console::set_output(&super::console::MINI_UART_OUTPUT),
+            (Some("fb"), true) => console::set_output(&super::console::FRAMEBUFFER_OUTPUT),
+            (_, true) => console::set_output(&super::console::MULTIPLEXER_OUTPUT),
+            (_, false) => console::set_output(&super::console::PL011_UART_OUTPUT),
+        }
     }
 }
\ No newline at end of file
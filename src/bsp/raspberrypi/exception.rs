@@ -0,0 +1,22 @@
+use super::memory;
+use crate::{cpu, exception};
+
+pub const SYSTEM_TIMER_MATCH1_IRQ: usize = 1;
+pub const PL011_UART_IRQ: usize = 57;
+
+// CNTPNSIRQ routed to the core's IRQ line, in its timers interrupt control
+const LOCAL_TIMER_CNTPNSIRQ_IRQ: u32 = 1 << 1;
+
+/// Routes the calling core's physical timer interrupt to it. The ARM local
+/// peripherals handle this, not the IRQ manager.
+pub fn enable_local_timer_irq() {
+    let core = cpu::smp::core_id::<usize>();
+    let reg = (super::local_peripheral_base() + memory::map::LOCAL_TIMER_CONTROL_OFFSET + core * 4)
+        as *mut u32;
+
+    unsafe { reg.write_volatile(reg.read_volatile() | LOCAL_TIMER_CNTPNSIRQ_IRQ) };
+}
+
+pub fn irq_manager() -> &'static impl exception::interface::IRQManager {
+    &super::INTERRUPT_CONTROLLER
+}
@@ -1,23 +1,167 @@
+use crate::{
+    memory::{mmu, mmu::MemAttributes},
+    synchronization::Once,
+};
+use core::ops::Range;
+
 #[rustfmt::skip]
 pub(super) mod map {
     pub const GPIO_OFFSET: usize = 0x0020_0000;
     pub const UART_OFFSET: usize = 0x0020_1000;
+    pub const MINI_UART_OFFSET: usize = 0x0021_5000;
+    pub const SYSTEM_TIMER_OFFSET: usize = 0x0000_3000;
+    pub const INTERRUPT_CONTROLLER_OFFSET: usize = 0x0000_B200;
+    pub const MAILBOX_OFFSET: usize = 0x0000_B880;
+    pub const WATCHDOG_OFFSET: usize = 0x0010_0000;
+    pub const DMA_OFFSET: usize = 0x0000_7000;
+    pub const BSC1_OFFSET: usize = 0x0080_4000;
+    pub const SPI0_OFFSET: usize = 0x0020_4000;
+    pub const PWM0_OFFSET: usize = 0x0020_C000;
+    pub const CLOCK_MANAGER_OFFSET: usize = 0x0010_1000;
+    // The SD slot's controller: the Arasan EMMC, or EMMC2 on the BCM2711
+    pub const EMMC_OFFSET: usize = 0x0030_0000;
+    pub const EMMC2_OFFSET: usize = 0x0034_0000;
+
+    pub const BCM2837_BASE: usize = 0x3F00_0000;
+    pub const BCM2711_BASE: usize = 0xFE00_0000;
+
+    // ARM local peripherals, with the per-core timer interrupt routing. The
+    // BCM2711 ones are at their low-peripheral-mode address.
+    pub const BCM2837_LOCAL_BASE: usize = 0x4000_0000;
+    pub const BCM2711_LOCAL_BASE: usize = 0xFF80_0000;
+    pub const LOCAL_TIMER_CONTROL_OFFSET: usize = 0x40;
+
+    // Bounds of everything mapped as Device memory: the BCM peripherals plus
+    // the ARM local peripherals (BCM2837) or the GIC and PCIe (BCM2711)
+    pub const BCM2837_DEVICE_START: usize = 0x3F00_0000;
+    pub const BCM2837_DEVICE_END: usize = 0x4020_0000;
+    pub const BCM2711_DEVICE_START: usize = 0xFC00_0000;
+    pub const BCM2711_DEVICE_END: usize = 0x1_0000_0000;
+
+    // Only the first GiB of RAM, which every model has, is mapped
+    pub const RAM_END: usize = 0x4000_0000;
 
     #[cfg(feature = "bsp_rpi3")]
     pub mod mmio {
         use super::*;
 
-        pub const BASE: usize = 0x3F00_0000;
-        pub const GPIO_BASE: usize = BASE + GPIO_OFFSET;
-        pub const PL011_UART_BASE: usize = BASE + UART_OFFSET;
+        pub const PERIPHERAL_BASE: usize = BCM2837_BASE;
+        pub const GPIO_BASE: usize = PERIPHERAL_BASE + GPIO_OFFSET;
+        pub const PL011_UART_BASE: usize = PERIPHERAL_BASE + UART_OFFSET;
+        pub const MINI_UART_BASE: usize = PERIPHERAL_BASE + MINI_UART_OFFSET;
+        pub const SYSTEM_TIMER_BASE: usize = PERIPHERAL_BASE + SYSTEM_TIMER_OFFSET;
+        pub const INTERRUPT_CONTROLLER_BASE: usize = PERIPHERAL_BASE + INTERRUPT_CONTROLLER_OFFSET;
+        pub const MAILBOX_BASE: usize = PERIPHERAL_BASE + MAILBOX_OFFSET;
+        pub const WATCHDOG_BASE: usize = PERIPHERAL_BASE + WATCHDOG_OFFSET;
+        pub const DMA_BASE: usize = PERIPHERAL_BASE + DMA_OFFSET;
+        pub const BSC1_BASE: usize = PERIPHERAL_BASE + BSC1_OFFSET;
+        pub const SPI0_BASE: usize = PERIPHERAL_BASE + SPI0_OFFSET;
+        pub const PWM0_BASE: usize = PERIPHERAL_BASE + PWM0_OFFSET;
+        pub const CLOCK_MANAGER_BASE: usize = PERIPHERAL_BASE + CLOCK_MANAGER_OFFSET;
     }
 
     #[cfg(feature = "bsp_rpi4")]
     pub mod mmio {
         use super::*;
 
-        pub const BASE: usize = 0xFE00_0000;
-        pub const GPIO_BASE: usize = BASE + GPIO_OFFSET;
-        pub const PL011_UART_BASE: usize = BASE + UART_OFFSET;
+        pub const PERIPHERAL_BASE: usize = BCM2711_BASE;
+        pub const GPIO_BASE: usize = PERIPHERAL_BASE + GPIO_OFFSET;
+        pub const PL011_UART_BASE: usize = PERIPHERAL_BASE + UART_OFFSET;
+        pub const MINI_UART_BASE: usize = PERIPHERAL_BASE + MINI_UART_OFFSET;
+        pub const SYSTEM_TIMER_BASE: usize = PERIPHERAL_BASE + SYSTEM_TIMER_OFFSET;
+        pub const INTERRUPT_CONTROLLER_BASE: usize = PERIPHERAL_BASE + INTERRUPT_CONTROLLER_OFFSET;
+        pub const MAILBOX_BASE: usize = PERIPHERAL_BASE + MAILBOX_OFFSET;
+        pub const WATCHDOG_BASE: usize = PERIPHERAL_BASE + WATCHDOG_OFFSET;
+        pub const DMA_BASE: usize = PERIPHERAL_BASE + DMA_OFFSET;
+        pub const BSC1_BASE: usize = PERIPHERAL_BASE + BSC1_OFFSET;
+        pub const SPI0_BASE: usize = PERIPHERAL_BASE + SPI0_OFFSET;
+        pub const PWM0_BASE: usize = PERIPHERAL_BASE + PWM0_OFFSET;
+        pub const CLOCK_MANAGER_BASE: usize = PERIPHERAL_BASE + CLOCK_MANAGER_OFFSET;
+    }
+}
+
+// Memory shared with the DMA engine, which reads RAM behind the ARM caches
+unsafe fn dma_range() -> Range<usize> {
+    extern "C" {
+        static __dma_start: usize;
+        static __dma_end: usize;
+    }
+
+    Range {
+        start: &__dma_start as *const _ as usize,
+        end: &__dma_end as *const _ as usize,
+    }
+}
+
+// The framebuffer the firmware allocated, rounded out to whole blocks, which
+// the VideoCore scans out behind the ARM caches. Empty until mapped.
+static FRAMEBUFFER: Once<Range<usize>> = Once::new();
+
+fn framebuffer_range() -> Range<usize> {
+    FRAMEBUFFER.get().cloned().unwrap_or(0..0)
+}
+
+/// Maps the framebuffer uncached like the DMA region, once the firmware has
+/// allocated it. Whatever shares its blocks goes uncached as well. Only the
+/// first call's range counts.
+pub unsafe fn map_framebuffer(range: Range<usize>) {
+    FRAMEBUFFER.call_once(|| range.start & !(mmu::BLOCK_SIZE - 1)..range.end);
+    mmu::remap(range);
+}
+
+/// How far the heap may grow: the end of the first RAM region in the device
+/// tree, as far as it is mapped. A tree above the kernel stops it short,
+/// so it stays readable.
+pub fn heap_end() -> Option<usize> {
+    let dt = super::device_tree()?;
+    let (base, size) = dt.memory()?;
+
+    let mut end = core::cmp::min(base.checked_add(size)?, map::RAM_END);
+    let dtb_addr = crate::cpu::dtb_addr();
+    if dtb_addr >= super::cpu::KERNEL_LOAD_ADDR && dtb_addr < end {
+        end = dtb_addr;
+    }
+
+    Some(end)
+}
+
+/// Attributes for the identity mapping of `addr`, or `None` to leave it
+/// unmapped.
+pub fn memory_attributes(addr: usize) -> Option<MemAttributes> {
+    let device = match super::board() {
+        super::Board::RaspberryPi3 => map::BCM2837_DEVICE_START..map::BCM2837_DEVICE_END,
+        super::Board::RaspberryPi4 => map::BCM2711_DEVICE_START..map::BCM2711_DEVICE_END,
+    };
+
+    if device.contains(&addr) {
+        Some(MemAttributes::Device)
+    } else if unsafe { dma_range() }.contains(&addr) || framebuffer_range().contains(&addr) {
+        Some(MemAttributes::NormalNonCacheable)
+    } else if addr < map::RAM_END {
+        Some(MemAttributes::Normal)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The peripheral docs' 0x7E00_0000 bus addresses, as the ARM sees them
+    #[test]
+    #[cfg(feature = "bsp_rpi3")]
+    fn mmio_bases_match_the_bus_addresses() {
+        assert_eq!(map::mmio::PL011_UART_BASE, 0x3F20_1000);
+        assert_eq!(map::mmio::GPIO_BASE, 0x3F20_0000);
+        assert_eq!(map::mmio::SYSTEM_TIMER_BASE, 0x3F00_3000);
+    }
+
+    #[test]
+    #[cfg(feature = "bsp_rpi4")]
+    fn mmio_bases_match_the_bus_addresses() {
+        assert_eq!(map::mmio::PL011_UART_BASE, 0xFE20_1000);
+        assert_eq!(map::mmio::GPIO_BASE, 0xFE20_0000);
+        assert_eq!(map::mmio::SYSTEM_TIMER_BASE, 0xFE00_3000);
     }
-}
\ No newline at end of file
+}
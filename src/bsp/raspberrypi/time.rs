@@ -0,0 +1,11 @@
+use crate::{bsp::device_driver, cpu, cpu::time::interface::Timer};
+
+/// The clock for timestamps: the generic timer, which counts from reset on
+/// every core. The system timer only works once its driver has been rebased.
+pub fn timer() -> &'static (dyn Timer + Sync) {
+    cpu::time::generic_timer()
+}
+
+pub fn system_timer() -> &'static device_driver::SystemTimer {
+    &super::SYSTEM_TIMER
+}
@@ -0,0 +1,100 @@
+use crate::synchronization::Once;
+
+// Empty until `init`
+static CMDLINE: Once<&'static str> = Once::new();
+
+/// Space-separated `key=value` pairs and bare flags. A value may be
+/// double-quoted to hold spaces, and the quotes are stripped.
+pub struct Args<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Args<'a> {
+    pub fn new(cmdline: &'a str) -> Self {
+        Self { rest: cmdline }
+    }
+}
+
+impl<'a> Iterator for Args<'a> {
+    type Item = (&'a str, Option<&'a str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let s = self.rest.trim_start();
+        if s.is_empty() {
+            self.rest = s;
+            return None;
+        }
+
+        // Whitespace inside quotes doesn't end the argument
+        let mut quoted = false;
+        let end = s
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    quoted = !quoted;
+                }
+                c.is_whitespace() && !quoted
+            })
+            .map_or(s.len(), |(i, _)| i);
+        let (arg, rest) = s.split_at(end);
+        self.rest = rest;
+
+        Some(match arg.find('=') {
+            Some(i) => {
+                let value = &arg[i + 1..];
+                let unquoted = value.strip_prefix('"').and_then(|v| v.strip_suffix('"'));
+                (&arg[..i], Some(unquoted.unwrap_or(value)))
+            }
+            None => (arg, None),
+        })
+    }
+}
+
+/// Keeps `cmdline` for `get` and `has`. Only the first call has any effect.
+pub fn init(cmdline: &'static str) {
+    CMDLINE.call_once(|| cmdline);
+}
+
+pub fn args() -> Args<'static> {
+    Args::new(CMDLINE.get().copied().unwrap_or(""))
+}
+
+/// The value of the last `key=value`
+pub fn get(key: &str) -> Option<&'static str> {
+    args().filter(|&(k, _)| k == key).filter_map(|(_, v)| v).last()
+}
+
+/// Whether `flag` appears at all, with or without a value
+pub fn has(flag: &str) -> bool {
+    args().any(|(k, _)| k == flag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn parse(cmdline: &str) -> Vec<(&str, Option<&str>)> {
+        Args::new(cmdline).collect()
+    }
+
+    #[test]
+    fn splits_pairs_and_flags() {
+        assert_eq!(
+            parse("  console=serial quiet\treset_button=21 "),
+            [("console", Some("serial")), ("quiet", None), ("reset_button", Some("21"))]
+        );
+        assert_eq!(parse(""), []);
+        assert_eq!(parse("   "), []);
+    }
+
+    #[test]
+    fn quoted_values_keep_their_spaces() {
+        assert_eq!(
+            parse(r#"init="/bin/sh -c x" empty= a=b=c"#),
+            [("init", Some("/bin/sh -c x")), ("empty", Some("")), ("a", Some("b=c"))]
+        );
+        // An unterminated quote runs to the end and is kept
+        assert_eq!(parse(r#"x="a b"#), [("x", Some(r#""a b"#))]);
+    }
+}
@@ -1,15 +1,79 @@
 pub mod interface {
+    use super::WriteError;
     use core::fmt;
 
     pub trait Write {
         fn write_char(&self, c: char);
         fn write_fmt(&self, args: fmt::Arguments) -> fmt::Result;
+
+        /// `write_char` for consoles that can tell when output went nowhere
+        fn try_write_char(&self, c: char) -> Result<(), WriteError> {
+            self.write_char(c);
+            Ok(())
+        }
+
+        /// `write_fmt` for consoles that can tell when output went nowhere.
+        /// A formatting error is no fault of the console's.
+        fn try_write_fmt(&self, args: fmt::Arguments) -> Result<(), WriteError> {
+            self.write_fmt(args).map_err(|_| WriteError::NotReady)
+        }
+
+        /// Writes raw bytes, each counted as one character.
+        fn write_bytes(&self, bytes: &[u8]) {
+            for &b in bytes {
+                self.write_char(b as char);
+            }
+        }
+
+        fn flush(&self) {}
+
+        /// Writes without taking any lock, for the panic and exception
+        /// handlers only, where the lock may be held by the code that died.
+        ///
+        /// This races with whoever holds the lock, so output can interleave
+        /// with or corrupt a write in progress, and statistics may not be
+        /// updated. Only call it once normal operation is over.
+        fn emergency_write_char(&self, c: char) {
+            self.write_char(c);
+        }
+
+        /// (columns, rows), for consoles that know them. A serial line has no
+        /// idea what's on the other end.
+        fn dimensions(&self) -> Option<(u16, u16)> {
+            None
+        }
     }
 
     pub trait Read {
         fn read_char(&self) -> char {
             ' '
         }
+
+        fn try_read_char(&self) -> Option<char> {
+            None
+        }
+
+        /// Gives up after `ms` milliseconds. Consoles without a clock block
+        /// like `read_char` instead.
+        fn read_char_timeout(&self, _ms: u32) -> Option<char> {
+            Some(self.read_char())
+        }
+
+        /// A single byte of input, raw where `read_bytes` is
+        fn read_byte_timeout(&self, ms: u32) -> Option<u8> {
+            self.read_char_timeout(ms).map(|c| c as u8)
+        }
+
+        /// Fills `buf` with input, blocking until it's full, and returns how
+        /// much was stored. Consoles with a raw path hand bytes over as
+        /// received, without newline translation or UTF-8 decoding.
+        fn read_bytes(&self, buf: &mut [u8]) -> usize {
+            for b in buf.iter_mut() {
+                *b = self.read_char() as u8;
+            }
+
+            buf.len()
+        }
     }
 
     pub trait Statistics {
@@ -20,6 +84,731 @@ pub mod interface {
         fn chars_read(&self) -> usize {
             0
         }
+
+        fn bytes_written(&self) -> usize {
+            0
+        }
+
+        fn bytes_read(&self) -> usize {
+            0
+        }
+
+        /// Worst-case number of times the transmit FIFO was found full before
+        /// a character could go in, each followed by a `cpu::spin_hint`
+        fn max_tx_wait_polls(&self) -> usize {
+            0
+        }
+
+        fn framing_errors(&self) -> usize {
+            0
+        }
+
+        fn parity_errors(&self) -> usize {
+            0
+        }
+
+        fn break_errors(&self) -> usize {
+            0
+        }
+
+        fn overrun_errors(&self) -> usize {
+            0
+        }
+
+        /// Zeroes every counter
+        fn reset(&self) {}
+    }
+
+    pub trait ReadLine {
+        fn read_line(&self, buf: &mut [u8]) -> usize;
+    }
+
+    /// Reads a line into `buf`, echoing as it goes. Returns the number of bytes
+    /// stored, excluding the newline. Stops early once `buf` is full.
+    impl<T: Read + Write + ?Sized> ReadLine for T {
+        fn read_line(&self, buf: &mut [u8]) -> usize {
+            let mut len = 0;
+
+            while len < buf.len() {
+                match self.read_char() {
+                    '\n' => {
+                        self.write_char('\n');
+                        break;
+                    }
+                    '\x7f' | '\x08' => {
+                        if len > 0 {
+                            len -= 1;
+                            self.write_char('\x08');
+                            self.write_char(' ');
+                            self.write_char('\x08');
+                        }
+                    }
+                    c if c.is_ascii() => {
+                        buf[len] = c as u8;
+                        len += 1;
+                        self.write_char(c);
+                    }
+                    _ => {}
+                }
+            }
+
+            len
+        }
+    }
+
+    pub trait All: Write + Read + Statistics {}
+
+    impl<T: Write + Read + Statistics + ?Sized> All for T {}
+}
+
+use crate::print::{fmt::Hex, BufWriter};
+use core::{
+    fmt, str,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WriteError {
+    /// Nothing to write to yet, later output may still get through
+    NotReady,
+    /// The console is broken and will drop everything from now on
+    Fault,
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WriteError::NotReady => write!(f, "not ready"),
+            WriteError::Fault => write!(f, "fault"),
+        }
+    }
+}
+
+/// Length of the UTF-8 sequence `first` starts, or `None` if no sequence
+/// can start with it
+pub fn utf8_len(first: u8) -> Option<usize> {
+    match first {
+        0x00..=0x7f => Some(1),
+        0xc2..=0xdf => Some(2),
+        0xe0..=0xef => Some(3),
+        0xf0..=0xf4 => Some(4),
+        _ => None,
+    }
+}
+
+/// Puts received bytes back together into chars. A sequence may arrive over
+/// several calls, and anything that isn't valid UTF-8 comes out as U+FFFD.
+pub struct Utf8Decoder {
+    buf: [u8; 4],
+    len: usize,
+    want: usize,
+    // The byte that cut the last sequence short, and so starts the next one
+    held: Option<u8>,
+}
+
+impl Utf8Decoder {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; 4],
+            len: 0,
+            want: 0,
+            held: None,
+        }
+    }
+
+    /// Pulls bytes from `next` until a char is complete. Returns `None` if
+    /// `next` runs dry first, keeping what it has for the next call.
+    pub fn decode(&mut self, mut next: impl FnMut() -> Option<u8>) -> Option<char> {
+        loop {
+            let b = match self.held.take() {
+                Some(b) => b,
+                None => next()?,
+            };
+
+            if self.len == 0 {
+                match utf8_len(b) {
+                    Some(1) => return Some(b as char),
+                    Some(want) => {
+                        self.buf[0] = b;
+                        self.len = 1;
+                        self.want = want;
+                    }
+                    None => return Some(core::char::REPLACEMENT_CHARACTER),
+                }
+            } else if b & 0xc0 != 0x80 {
+                self.len = 0;
+                self.held = Some(b);
+                return Some(core::char::REPLACEMENT_CHARACTER);
+            } else {
+                self.buf[self.len] = b;
+                self.len += 1;
+
+                if self.len == self.want {
+                    self.len = 0;
+                    // Still catches overlong forms and surrogates
+                    let c = str::from_utf8(&self.buf[..self.want])
+                        .ok()
+                        .and_then(|s| s.chars().next());
+                    return Some(c.unwrap_or(core::char::REPLACEMENT_CHARACTER));
+                }
+            }
+        }
+    }
+}
+
+/// Fans output out to several consoles. Input comes from the first one.
+///
+/// Holds no lock of its own, so it is as safe to use from the panic handler
+/// as its sinks are.
+pub struct Multiplexer {
+    sinks: &'static [&'static (dyn interface::All + Sync)],
+    // One bit per sink that reported a `WriteError::Fault`, which gets no
+    // output from then on
+    dead: AtomicUsize,
+}
+
+impl Multiplexer {
+    pub const fn new(sinks: &'static [&'static (dyn interface::All + Sync)]) -> Self {
+        Self {
+            sinks,
+            dead: AtomicUsize::new(0),
+        }
+    }
+
+    fn live_sinks(&self) -> impl Iterator<Item = (usize, &&'static (dyn interface::All + Sync))> {
+        let dead = self.dead.load(Ordering::Relaxed);
+        self.sinks
+            .iter()
+            .enumerate()
+            .filter(move |(i, _)| dead & (1 << i) == 0)
+    }
+
+    // Folds sink `i`'s result into `ret`, retiring the sink on a fault
+    fn settle(&self, i: usize, result: Result<(), WriteError>, ret: &mut Result<(), WriteError>) {
+        match result {
+            Ok(()) => *ret = Ok(()),
+            Err(WriteError::NotReady) => {}
+            Err(WriteError::Fault) => {
+                self.dead.fetch_or(1 << i, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl interface::Write for Multiplexer {
+    fn write_char(&self, c: char) {
+        let _ = self.try_write_char(c);
+    }
+
+    fn write_fmt(&self, args: fmt::Arguments) -> fmt::Result {
+        self.try_write_fmt(args).map_err(|_| fmt::Error)
+    }
+
+    // Fine as long as one sink took it
+    fn try_write_char(&self, c: char) -> Result<(), WriteError> {
+        let mut ret = Err(WriteError::NotReady);
+        for (i, sink) in self.live_sinks() {
+            self.settle(i, sink.try_write_char(c), &mut ret);
+        }
+
+        ret
+    }
+
+    // Likewise
+    fn try_write_fmt(&self, args: fmt::Arguments) -> Result<(), WriteError> {
+        let mut ret = Err(WriteError::NotReady);
+        for (i, sink) in self.live_sinks() {
+            self.settle(i, sink.try_write_fmt(args), &mut ret);
+        }
+
+        ret
+    }
+
+    fn write_bytes(&self, bytes: &[u8]) {
+        for (_, sink) in self.live_sinks() {
+            sink.write_bytes(bytes);
+        }
+    }
+
+    fn flush(&self) {
+        for (_, sink) in self.live_sinks() {
+            sink.flush();
+        }
+    }
+
+    fn emergency_write_char(&self, c: char) {
+        for (_, sink) in self.live_sinks() {
+            sink.emergency_write_char(c);
+        }
+    }
+
+    // What fits on every sink that knows its size
+    fn dimensions(&self) -> Option<(u16, u16)> {
+        self.sinks
+            .iter()
+            .filter_map(|sink| sink.dimensions())
+            .fold(None, |min: Option<(u16, u16)>, (cols, rows)| match min {
+                Some((c, r)) => Some((c.min(cols), r.min(rows))),
+                None => Some((cols, rows)),
+            })
+    }
+}
+
+impl interface::Read for Multiplexer {
+    fn read_char(&self) -> char {
+        match self.sinks.first() {
+            Some(sink) => sink.read_char(),
+            None => ' ',
+        }
+    }
+
+    fn try_read_char(&self) -> Option<char> {
+        self.sinks.first().and_then(|sink| sink.try_read_char())
+    }
+
+    fn read_char_timeout(&self, ms: u32) -> Option<char> {
+        self.sinks.first().and_then(|sink| sink.read_char_timeout(ms))
+    }
+
+    fn read_byte_timeout(&self, ms: u32) -> Option<u8> {
+        self.sinks.first().and_then(|sink| sink.read_byte_timeout(ms))
+    }
+
+    fn read_bytes(&self, buf: &mut [u8]) -> usize {
+        self.sinks.first().map_or(0, |sink| sink.read_bytes(buf))
+    }
+}
+
+impl interface::Statistics for Multiplexer {
+    fn chars_written(&self) -> usize {
+        self.sinks.iter().map(|sink| sink.chars_written()).sum()
+    }
+
+    // Input only ever comes from the first sink
+    fn chars_read(&self) -> usize {
+        self.sinks.first().map_or(0, |sink| sink.chars_read())
+    }
+
+    fn bytes_written(&self) -> usize {
+        self.sinks.iter().map(|sink| sink.bytes_written()).sum()
+    }
+
+    fn bytes_read(&self) -> usize {
+        self.sinks.first().map_or(0, |sink| sink.bytes_read())
+    }
+
+    fn max_tx_wait_polls(&self) -> usize {
+        self.sinks.iter().map(|sink| sink.max_tx_wait_polls()).max().unwrap_or(0)
+    }
+
+    fn reset(&self) {
+        for sink in self.sinks {
+            sink.reset();
+        }
+    }
+}
+
+/// Discards output and never has input, for `console=none` and as the host
+/// tests' console.
+pub struct NullConsole;
+
+impl interface::Write for NullConsole {
+    fn write_char(&self, _c: char) {}
+
+    fn write_fmt(&self, _args: fmt::Arguments) -> fmt::Result {
+        Ok(())
+    }
+}
+
+impl interface::Read for NullConsole {
+    fn read_char(&self) -> char {
+        '\0'
+    }
+}
+
+impl interface::Statistics for NullConsole {}
+
+static NULL_CONSOLE: NullConsole = NullConsole;
+
+/// A console `set_output` can swap in. A `&dyn` takes two words, too many
+/// for one atomic store, so the swap goes through a thin pointer to one of
+/// these instead.
+pub struct Output(pub &'static (dyn interface::All + Sync));
+
+pub static NULL_OUTPUT: Output = Output(&NULL_CONSOLE);
+
+// The UART until `set_output` picks something else. Host tests have no UART
+// to write to.
+#[cfg(not(test))]
+static OUTPUT: AtomicPtr<Output> = AtomicPtr::new(
+    &crate::bsp::console::PL011_UART_OUTPUT as *const Output as *mut Output,
+);
+#[cfg(test)]
+static OUTPUT: AtomicPtr<Output> = AtomicPtr::new(&NULL_OUTPUT as *const Output as *mut Output);
+
+static LIVE: AtomicBool = AtomicBool::new(false);
+
+/// Replaces the active console. Safe to call on any core at any time: each
+/// write goes wholly to either the old or the new console.
+pub fn set_output(output: &'static Output) {
+    OUTPUT.store(output as *const Output as *mut Output, Ordering::Release);
+    LIVE.store(true, Ordering::Release);
+}
+
+/// A console as a `core::fmt::Write`, for code that formats into any writer.
+/// Whole `write!`s go through the console's `write_fmt` in one piece, so they
+/// are counted the same and don't interleave with other cores' output.
+#[derive(Copy, Clone)]
+pub struct Writer(pub &'static dyn interface::All);
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_fmt(format_args!("{}", s))
+    }
+
+    fn write_char(&mut self, c: char) -> fmt::Result {
+        self.0.write_char(c);
+        Ok(())
+    }
+
+    fn write_fmt(&mut self, args: fmt::Arguments) -> fmt::Result {
+        self.0.write_fmt(args)
+    }
+}
+
+/// Whether the BSP has picked the console with `set_output` yet, which it
+/// does once the drivers are up
+pub fn is_live() -> bool {
+    LIVE.load(Ordering::Acquire)
+}
+
+/// The console `print!` and friends currently write to
+pub fn console() -> &'static dyn interface::All {
+    // Only ever set from a `&'static Output`
+    unsafe { (*OUTPUT.load(Ordering::Acquire)).0 }
+}
+
+/// Prints `len` bytes starting at `addr` to the active console, 16 per line
+/// with the address, the bytes in hex and the printable ones as ASCII.
+///
+/// # Safety
+///
+/// The whole range must be readable.
+pub unsafe fn hexdump(addr: usize, len: usize) {
+    write_hexdump(console(), addr, len)
+}
+
+unsafe fn write_hexdump(out: &dyn interface::Write, addr: usize, len: usize) {
+    use fmt::Write;
+
+    const BYTES_PER_LINE: usize = 16;
+    // Address, hex, text and the separators
+    const LINE_LENGTH: usize = 16 + 2 + BYTES_PER_LINE * 4 + 3;
+
+    for line in (0..len).step_by(BYTES_PER_LINE) {
+        let mut bytes = [0u8; BYTES_PER_LINE];
+        let count = core::cmp::min(BYTES_PER_LINE, len - line);
+        for (i, byte) in bytes[..count].iter_mut().enumerate() {
+            *byte = core::ptr::read_volatile((addr + line + i) as *const u8);
+        }
+
+        // Built up first, so output from other cores can't land mid-line
+        let mut buf = [0u8; LINE_LENGTH];
+        let mut w = BufWriter::new(&mut buf);
+        let _ = write!(w, "{}: ", Hex(addr + line));
+        for &byte in &bytes[..count] {
+            let _ = write!(w, "{} ", Hex(byte));
+        }
+        for _ in count..BYTES_PER_LINE {
+            let _ = w.write_str("   ");
+        }
+
+        let _ = w.write_char('|');
+        for &byte in &bytes[..count] {
+            let _ = match byte {
+                0x20..=0x7e => w.write_char(byte as char),
+                _ => w.write_char('.'),
+            };
+        }
+        let _ = w.write_str("|\n");
+
+        out.write_bytes(w.as_str().as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        interface::{All, Read, ReadLine, Statistics, Write},
+        console, is_live, set_output, write_hexdump, Multiplexer, NullConsole, Output, Utf8Decoder,
+        WriteError, Writer,
+    };
+    use core::{
+        cell::RefCell,
+        fmt,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use std::{boxed::Box, collections::VecDeque, format, string::String, sync::Mutex, vec::Vec};
+
+    /// Plays back `input` and records everything echoed
+    struct Script {
+        input: RefCell<VecDeque<char>>,
+        output: RefCell<String>,
+    }
+
+    impl Script {
+        fn new(input: &str) -> Self {
+            Self {
+                input: RefCell::new(input.chars().collect()),
+                output: RefCell::new(String::new()),
+            }
+        }
+    }
+
+    impl Write for Script {
+        fn write_char(&self, c: char) {
+            self.output.borrow_mut().push(c);
+        }
+
+        fn write_fmt(&self, args: fmt::Arguments) -> fmt::Result {
+            fmt::Write::write_fmt(&mut *self.output.borrow_mut(), args)
+        }
+    }
+
+    impl Read for Script {
+        fn read_char(&self) -> char {
+            self.input.borrow_mut().pop_front().expect("script ran dry")
+        }
+    }
+
+    impl Statistics for Script {}
+
+    #[test]
+    fn read_line_stops_at_the_newline() {
+        let con = Script::new("ls\nrest");
+        let mut buf = [0u8; 16];
+
+        assert_eq!(con.read_line(&mut buf), 2);
+        assert_eq!(&buf[..2], b"ls");
+        assert_eq!(*con.output.borrow(), "ls\n");
+        assert_eq!(con.input.borrow().len(), 4);
+    }
+
+    #[test]
+    fn read_line_backspace_erases_and_echoes() {
+        let con = Script::new("ab\x7fc\x08d\n");
+        let mut buf = [0u8; 16];
+
+        assert_eq!(con.read_line(&mut buf), 2);
+        assert_eq!(&buf[..2], b"ad");
+        assert_eq!(*con.output.borrow(), "ab\x08 \x08c\x08 \x08d\n");
+    }
+
+    #[test]
+    fn read_line_backspace_on_an_empty_line_does_nothing() {
+        let con = Script::new("\x7f\x08x\n");
+        let mut buf = [0u8; 16];
+
+        assert_eq!(con.read_line(&mut buf), 1);
+        assert_eq!(&buf[..1], b"x");
+        assert_eq!(*con.output.borrow(), "x\n");
+    }
+
+    #[test]
+    fn read_line_stops_once_the_buffer_is_full() {
+        let con = Script::new("abcdef\n");
+        let mut buf = [0u8; 4];
+
+        assert_eq!(con.read_line(&mut buf), 4);
+        assert_eq!(&buf, b"abcd");
+        assert_eq!(*con.output.borrow(), "abcd");
+    }
+
+    #[test]
+    fn read_line_drops_non_ascii() {
+        let con = Script::new("a\u{e9}b\n");
+        let mut buf = [0u8; 16];
+
+        assert_eq!(con.read_line(&mut buf), 2);
+        assert_eq!(&buf[..2], b"ab");
+        assert_eq!(*con.output.borrow(), "ab\n");
+    }
+
+    // Feeds `bytes` in, `split` at a time, and collects what comes out
+    fn decode(bytes: &[u8], split: usize) -> String {
+        let mut decoder = Utf8Decoder::new();
+        let mut out = String::new();
+        for chunk in bytes.chunks(split) {
+            let mut chunk = chunk.iter().copied();
+            while let Some(c) = decoder.decode(|| chunk.next()) {
+                out.push(c);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn utf8_decoder_joins_multibyte_sequences() {
+        let text = "a\u{e9}\u{20ac}\u{1f600}z";
+
+        for split in 1..=text.len() {
+            assert_eq!(decode(text.as_bytes(), split), text);
+        }
+    }
+
+    #[test]
+    fn utf8_decoder_replaces_invalid_bytes() {
+        // A stray continuation byte, and a lead byte cut short by an ASCII one
+        assert_eq!(decode(b"\x80a\xc3b", 1), "\u{fffd}a\u{fffd}b");
+        // Overlong and surrogate forms are well shaped but still invalid
+        assert_eq!(decode(b"\xe0\x80\xaf\xed\xa0\x80", 6), "\u{fffd}\u{fffd}");
+    }
+
+    #[test]
+    fn null_console_discards_writes_and_counts_nothing() {
+        let con = NullConsole;
+
+        con.write_char('x');
+        con.write_fmt(format_args!("{} {}", 1, "two")).unwrap();
+        con.flush();
+        assert_eq!(con.read_char(), '\0');
+        assert_eq!(con.chars_written(), 0);
+        assert_eq!(con.chars_read(), 0);
+    }
+
+    #[test]
+    fn hexdump_pads_the_last_line() {
+        let bytes = *b"Hello, world!\n\x00\xffxyz";
+        let addr = bytes.as_ptr() as usize;
+        let con = Script::new("");
+
+        unsafe { write_hexdump(&con, addr, bytes.len()) };
+        let out = con.output.borrow();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(
+            lines[0],
+            format!(
+                "{:016x}: 48 65 6c 6c 6f 2c 20 77 6f 72 6c 64 21 0a 00 ff |Hello, world!...|",
+                addr
+            )
+        );
+        assert_eq!(lines[1], format!("{:016x}: 78 79 7a {:39}|xyz|", addr + 16, ""));
+        assert_eq!(lines.len(), 2);
+    }
+
+    /// A sink that records its output and answers every write with `result`
+    struct Sink {
+        output: Mutex<String>,
+        result: Result<(), WriteError>,
+        chars_read: AtomicUsize,
+    }
+
+    fn sink(result: Result<(), WriteError>, chars_read: usize) -> &'static Sink {
+        Box::leak(Box::new(Sink {
+            output: Mutex::new(String::new()),
+            result,
+            chars_read: AtomicUsize::new(chars_read),
+        }))
+    }
+
+    impl Write for Sink {
+        fn write_char(&self, c: char) {
+            let _ = self.try_write_char(c);
+        }
+
+        fn try_write_char(&self, c: char) -> Result<(), WriteError> {
+            self.output.lock().unwrap().push(c);
+            self.result
+        }
+
+        fn write_fmt(&self, args: fmt::Arguments) -> fmt::Result {
+            self.try_write_fmt(args).map_err(|_| fmt::Error)
+        }
+
+        fn try_write_fmt(&self, args: fmt::Arguments) -> Result<(), WriteError> {
+            fmt::Write::write_fmt(&mut *self.output.lock().unwrap(), args).unwrap();
+            self.result
+        }
+    }
+
+    impl Read for Sink {}
+
+    impl Statistics for Sink {
+        fn chars_written(&self) -> usize {
+            self.output.lock().unwrap().chars().count()
+        }
+
+        fn chars_read(&self) -> usize {
+            self.chars_read.load(Ordering::Relaxed)
+        }
+    }
+
+    fn multiplexer(sinks: &[&'static Sink]) -> Multiplexer {
+        let sinks: Vec<&'static (dyn All + Sync)> = sinks.iter().map(|&s| s as _).collect();
+        Multiplexer::new(Box::leak(sinks.into_boxed_slice()))
+    }
+
+    #[test]
+    fn multiplexer_counts_reads_from_the_first_sink_only() {
+        let (a, b) = (sink(Ok(()), 3), sink(Ok(()), 5));
+        let mux = multiplexer(&[a, b]);
+
+        mux.write_char('x');
+        assert_eq!(mux.chars_written(), 2);
+        assert_eq!(mux.chars_read(), 3);
+    }
+
+    #[test]
+    fn multiplexer_write_fmt_retires_a_faulting_sink() {
+        let (good, bad) = (sink(Ok(()), 0), sink(Err(WriteError::Fault), 0));
+        let mux = multiplexer(&[bad, good]);
+
+        assert_eq!(mux.write_fmt(format_args!("{}", 1)), Ok(()));
+        assert_eq!(mux.write_fmt(format_args!("{}", 2)), Ok(()));
+        assert_eq!(*bad.output.lock().unwrap(), "1");
+        assert_eq!(*good.output.lock().unwrap(), "12");
+    }
+
+    #[test]
+    fn multiplexer_write_fmt_fails_if_no_sink_took_it() {
+        let (busy, bad) = (sink(Err(WriteError::NotReady), 0), sink(Err(WriteError::Fault), 0));
+        let mux = multiplexer(&[busy, bad]);
+
+        assert_eq!(mux.write_fmt(format_args!("x")), Err(fmt::Error));
+        assert_eq!(mux.write_fmt(format_args!("y")), Err(fmt::Error));
+        assert_eq!(*busy.output.lock().unwrap(), "xy");
+        assert_eq!(*bad.output.lock().unwrap(), "x");
+    }
+
+    #[test]
+    fn writer_matches_write_fmt() {
+        use fmt::Write as _;
+
+        let (via_writer, direct) = (sink(Ok(()), 0), sink(Ok(()), 0));
+        let mut w = Writer(via_writer);
+        write!(w, "id {:#x}", 42).unwrap();
+        writeln!(w, "\u{e9}").unwrap();
+        direct.write_fmt(format_args!("id {:#x}", 42)).unwrap();
+        direct.write_fmt(format_args!("\u{e9}\n")).unwrap();
+
+        assert_eq!(*via_writer.output.lock().unwrap(), "id 0x2a\u{e9}\n");
+        assert_eq!(*via_writer.output.lock().unwrap(), *direct.output.lock().unwrap());
+    }
+
+    #[test]
+    fn set_output_redirects_later_writes() {
+        let (a, b) = (sink(Ok(()), 0), sink(Ok(()), 0));
+        let (to_a, to_b) = (Box::leak(Box::new(Output(a))), Box::leak(Box::new(Output(b))));
+
+        set_output(to_a);
+        assert!(is_live());
+        console().write_fmt(format_args!("one ")).unwrap();
+        set_output(to_b);
+        console().write_fmt(format_args!("two ")).unwrap();
+
+        // Other tests may print meanwhile, but nothing goes to a sink twice
+        let (a, b) = (a.output.lock().unwrap(), b.output.lock().unwrap());
+        assert!(a.contains("one ") && !a.contains("two "));
+        assert!(b.contains("two ") && !b.contains("one "));
     }
-    pub trait All = Write + Read + Statistics;
 }
@@ -1,8 +1,15 @@
-#[cfg(target_arch = "aarch64")]
+#[cfg(any(target_arch = "aarch64", test))]
 #[path = "_arch/aarch64/cpu.rs"]
-
-
 mod arch_cpu;
 pub use arch_cpu::*;
 
+pub mod barrier;
+pub mod cache;
+pub mod features;
+pub mod percpu;
 pub mod smp;
+pub mod stack;
+pub mod time;
+
+#[allow(unused_imports)]
+pub use time::{delay_ns, sleep_ms};
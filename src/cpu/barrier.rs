@@ -0,0 +1,13 @@
+//! Barriers for handing over between peripherals.
+//!
+//! The BCM283x/BCM2711 peripheral bus doesn't keep accesses to different
+//! peripherals in order: a read from one can return after a later read from
+//! another, and writes may land in either order. The peripheral docs ask for a
+//! barrier before the first access to a peripheral and after the last one,
+//! whenever the code moves from one peripheral to another. Accesses to the
+//! same peripheral do arrive in order.
+
+#[cfg(any(target_arch = "aarch64", test))]
+#[path = "../_arch/aarch64/cpu/barrier.rs"]
+mod arch_cpu_barrier;
+pub use arch_cpu_barrier::*;
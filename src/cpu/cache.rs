@@ -0,0 +1,4 @@
+#[cfg(any(target_arch = "aarch64", test))]
+#[path = "../_arch/aarch64/cpu/cache.rs"]
+mod arch_cpu_cache;
+pub use arch_cpu_cache::*;
@@ -0,0 +1,4 @@
+#[cfg(any(target_arch = "aarch64", test))]
+#[path = "../_arch/aarch64/cpu/features.rs"]
+mod arch_cpu_features;
+pub use arch_cpu_features::*;
@@ -0,0 +1,90 @@
+use crate::{bsp, cpu, exception};
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// One `T` per core, reached without a lock because each core only ever
+/// touches its own slot.
+///
+/// `with` masks IRQs while it runs, so a handler can't get at the slot in
+/// the middle, and panics if it is re-entered from inside the closure.
+pub struct PerCore<T> {
+    slots: UnsafeCell<[T; bsp::cpu::CORE_COUNT]>,
+    // One bit per core that is inside `with`
+    busy: AtomicUsize,
+}
+
+// Slots are never shared between cores, and `with` never hands out two
+// references to the same one, so `T` only has to be sendable to the core
+// that owns it
+unsafe impl<T: Send> Sync for PerCore<T> {}
+
+impl<T> PerCore<T> {
+    pub const fn new(slots: [T; bsp::cpu::CORE_COUNT]) -> Self {
+        Self {
+            slots: UnsafeCell::new(slots),
+            busy: AtomicUsize::new(0),
+        }
+    }
+
+    /// Runs `f` on the calling core's slot, with IRQs masked
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        self.with_core(cpu::smp::core_id(), f)
+    }
+
+    fn with_core<R>(&self, core: usize, f: impl FnOnce(&mut T) -> R) -> R {
+        assert!(core < bsp::cpu::CORE_COUNT);
+
+        let daif = unsafe { exception::local_irq_save() };
+        // Only this core ever sets or clears its bit
+        if self.busy.fetch_or(1 << core, Ordering::Relaxed) & 1 << core != 0 {
+            panic!("PerCore::with re-entered on core {}", core);
+        }
+
+        // Straight to the element, so no reference to the whole array (and
+        // so to the other cores' slots) is ever made
+        let slot = unsafe { &mut *(self.slots.get() as *mut T).add(core) };
+        let ret = f(slot);
+
+        self.busy.fetch_and(!(1 << core), Ordering::Relaxed);
+        unsafe { exception::local_irq_restore(daif) };
+
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn with_masks_irqs_and_restores_them() {
+        let counts = PerCore::new([0u32; bsp::cpu::CORE_COUNT]);
+
+        unsafe { exception::set_daif(0) };
+        counts.with_core(1, |count| {
+            assert_eq!(exception::daif(), exception::DAIF_I | exception::DAIF_F);
+            *count += 1;
+        });
+        assert_eq!(exception::daif(), 0);
+        assert_eq!(counts.with_core(1, |count| *count), 1);
+        assert_eq!(counts.with_core(0, |count| *count), 0);
+    }
+
+    #[test]
+    fn only_the_same_core_counts_as_reentry() {
+        let counts = PerCore::new([0u32; bsp::cpu::CORE_COUNT]);
+
+        counts.with_core(0, |a| counts.with_core(1, |b| *a = *b + 1));
+        assert_eq!(counts.with_core(0, |count| *count), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "re-entered on core 2")]
+    fn reentering_on_the_same_core_panics() {
+        let counts = PerCore::new([0u32; bsp::cpu::CORE_COUNT]);
+
+        counts.with_core(2, |_| counts.with_core(2, |_| ()));
+    }
+}
@@ -1,4 +1,4 @@
-#[cfg(target_arch = "aarch64")]
+#[cfg(any(target_arch = "aarch64", test))]
 #[path = "../_arch/aarch64/cpu/smp.rs"]
 mod arch_cpu_smp;
 pub use arch_cpu_smp::*;
@@ -0,0 +1,57 @@
+use crate::{bsp, cpu};
+
+/// A stack growing down from `start` (exclusive) towards `end`.
+#[derive(Copy, Clone)]
+pub struct StackBounds {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl StackBounds {
+    pub fn contains(&self, sp: usize) -> bool {
+        sp > self.end && sp <= self.start
+    }
+}
+
+/// Bounds of the calling core's stack, as laid out by `bsp::cpu`
+pub fn bounds() -> StackBounds {
+    let start = bsp::cpu::core_stack_start(cpu::smp::core_id());
+    StackBounds {
+        start,
+        end: start - bsp::cpu::CORE_STACK_SIZE,
+    }
+}
+
+/// If the stack pointer has left the calling core's stack, returns it along
+/// with the bounds it should be in. With no guard page an overflow runs
+/// silently into the next core's stack, so this is only a hint after the fact.
+pub fn check_overflow() -> Option<(usize, StackBounds)> {
+    let sp = cpu::stack_pointer();
+    let bounds = bounds();
+
+    if bounds.contains(sp) {
+        None
+    } else {
+        Some((sp, bounds))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contains_excludes_the_end_and_includes_the_start() {
+        let bounds = StackBounds {
+            start: 0x8_0000,
+            end: 0x7_0000,
+        };
+
+        assert!(bounds.contains(0x8_0000));
+        assert!(bounds.contains(0x7_0010));
+        assert!(bounds.contains(0x7_0001));
+        assert!(!bounds.contains(0x7_0000));
+        assert!(!bounds.contains(0x6_fff0));
+        assert!(!bounds.contains(0x8_0010));
+    }
+}
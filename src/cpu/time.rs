@@ -0,0 +1,81 @@
+#[cfg(any(target_arch = "aarch64", test))]
+#[path = "../_arch/aarch64/cpu/time.rs"]
+mod arch_cpu_time;
+pub use arch_cpu_time::*;
+
+/// Whether `timeout` ticks have gone by between counter readings `start` and
+/// `now`, allowing for the counter wrapping in between.
+pub fn deadline_passed(start: u64, now: u64, timeout: u64) -> bool {
+    now.wrapping_sub(start) >= timeout
+}
+
+pub mod interface {
+    pub trait TimeManager {
+        /// Counter frequency in Hz, or `None` if the firmware never set it up
+        fn frequency(&self) -> Option<u32>;
+
+        fn uptime_nanos(&self) -> u64;
+
+        fn spin_for_micros(&self, us: u64);
+    }
+
+    /// A clock that only goes forward
+    pub trait Timer {
+        /// Since the counter started, usually at reset
+        fn now_nanos(&self) -> u64;
+
+        /// Busy-waits until `now_nanos` reaches `deadline_nanos`
+        fn spin_until(&self, deadline_nanos: u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deadline_passed_counts_from_start() {
+        assert!(!deadline_passed(1000, 1000, 10));
+        assert!(!deadline_passed(1000, 1009, 10));
+        assert!(deadline_passed(1000, 1010, 10));
+        assert!(deadline_passed(1000, 5000, 10));
+    }
+
+    #[test]
+    fn deadline_passed_across_a_counter_wrap() {
+        let start = u64::MAX - 4;
+
+        assert!(!deadline_passed(start, u64::MAX, 10));
+        assert!(!deadline_passed(start, 4, 10));
+        assert!(deadline_passed(start, 5, 10));
+    }
+
+    #[test]
+    fn ms_to_ticks_scales_by_the_frequency() {
+        // The Pi 3's 19.2 MHz counter and the Pi 4's 54 MHz one
+        assert_eq!(ms_to_ticks(19_200_000, 0), 0);
+        assert_eq!(ms_to_ticks(19_200_000, 1), 19_200);
+        assert_eq!(ms_to_ticks(54_000_000, 1_000), 54_000_000);
+
+        // A minute on the Pi 4 no longer fits a single CNTP_TVAL_EL0
+        assert_eq!(ms_to_ticks(54_000_000, 60_000), 3_240_000_000);
+        assert!(ms_to_ticks(54_000_000, 60_000) > i32::MAX as u64);
+    }
+
+    #[test]
+    fn ns_to_ticks_rounds_sub_tick_delays_up() {
+        assert_eq!(ns_to_ticks(19_200_000, 0), 0);
+        assert_eq!(ns_to_ticks(19_200_000, 1), 1);
+        // 19.2 ticks
+        assert_eq!(ns_to_ticks(19_200_000, 1_000), 20);
+        // Exactly one tick at 1 GHz
+        assert_eq!(ns_to_ticks(1_000_000_000, 1), 1);
+    }
+
+    #[test]
+    fn ns_to_ticks_over_seconds() {
+        assert_eq!(ns_to_ticks(54_000_000, 10_000_000_000), 540_000_000);
+        assert_eq!(ns_to_ticks(19_200_000, 3_600_000_000_000), 69_120_000_000);
+        assert_eq!(ns_to_ticks(u64::MAX, u64::MAX), u64::MAX);
+    }
+}
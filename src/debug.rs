@@ -0,0 +1,50 @@
+use core::fmt;
+
+#[doc(hidden)]
+#[cold]
+#[inline(never)]
+pub fn _assert_failed(cond: &str, file: &str, line: u32, args: fmt::Arguments) -> ! {
+    // The panic handler prints this and dumps the registers, once
+    panic!("Assertion failed: `{}` at {}:{}: {}", cond, file, line, args)
+}
+
+/// Like `assert!`, but reports the condition and its location along with the
+/// message, and the panic handler adds the registers. Only checked with debug
+/// assertions on: in release builds the condition isn't evaluated and nothing
+/// is left of the call.
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr $(,)?) => ($crate::kassert!($cond, ""));
+    ($cond:expr, $($arg:tt)+) => ({
+        if cfg!(debug_assertions) && !$cond {
+            $crate::debug::_assert_failed(
+                stringify!($cond),
+                file!(),
+                line!(),
+                format_args!($($arg)+),
+            );
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn kassert_passes_on_true() {
+        kassert!(1 + 1 == 2, "never printed");
+        kassert!(true);
+    }
+
+    #[test]
+    #[should_panic(expected = "Assertion failed: `1 + 1 == 3` at src/debug.rs")]
+    fn kassert_panics_with_the_condition_and_location() {
+        kassert!(1 + 1 == 3);
+    }
+
+    #[test]
+    #[should_panic(expected = ": off by 1")]
+    fn kassert_panics_with_the_message() {
+        let x = 1;
+        kassert!(x == 0, "off by {}", x);
+    }
+}
@@ -1,15 +1,447 @@
+use core::fmt;
+use interface::DeviceDriver;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DriverError {
+    BusError,
+    HardwareTimeout,
+    InvalidConfig,
+    Nack,
+    Unsupported,
+}
+
+impl fmt::Display for DriverError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DriverError::BusError => write!(f, "bus error"),
+            DriverError::HardwareTimeout => write!(f, "hardware timeout"),
+            DriverError::InvalidConfig => write!(f, "invalid configuration"),
+            DriverError::Nack => write!(f, "not acknowledged"),
+            DriverError::Unsupported => write!(f, "unsupported"),
+        }
+    }
+}
+
+const MAX_PROBE_FIELDS: usize = 2;
+
+/// What a driver found or set up, for diagnostics: a short description and a
+/// few named values.
+#[derive(Copy, Clone)]
+pub struct ProbeInfo {
+    summary: &'static str,
+    fields: [Option<(&'static str, u64)>; MAX_PROBE_FIELDS],
+}
+
+impl ProbeInfo {
+    pub const fn new(summary: &'static str) -> Self {
+        Self {
+            summary,
+            fields: [None; MAX_PROBE_FIELDS],
+        }
+    }
+
+    /// Adds a value, ignored once there are `MAX_PROBE_FIELDS` of them
+    pub fn with(mut self, name: &'static str, value: u64) -> Self {
+        if let Some(slot) = self.fields.iter_mut().find(|f| f.is_none()) {
+            *slot = Some((name, value));
+        }
+
+        self
+    }
+}
+
+impl fmt::Display for ProbeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.summary)?;
+        for (name, value) in self.fields.iter().flatten() {
+            write!(f, ", {} {}", name, value)?;
+        }
+
+        Ok(())
+    }
+}
+
 pub mod interface {
+    use super::{DriverError, ProbeInfo};
+
     pub trait DeviceDriver {
+        /// For people, e.g. in the boot banner
+        fn name(&self) -> &str;
+
+        /// The device tree `compatible` string the driver matches
         fn compatible(&self) -> &str;
 
-        fn init(&self) -> Result<(), ()> {
+        /// Disabled drivers stay registered, but `init` is skipped for them
+        fn is_enabled(&self) -> bool {
+            true
+        }
+
+        /// What `init` found, for drivers with something to report
+        fn probe(&self) -> Option<ProbeInfo> {
+            None
+        }
+
+        fn init(&self) -> Result<(), DriverError> {
             Ok(())
         }
+
+        /// Compatible strings of the drivers that must be initialized first
+        fn dependencies(&self) -> &[&str] {
+            &[]
+        }
+
+        /// Quiesces the device before a reboot or chainload, e.g. drains
+        /// output and stops transfers. Drivers depending on it are shut down
+        /// first.
+        fn shutdown(&self) {}
     }
 
     pub trait DriverManager {
-        fn all_device_drivers(&self) -> &[&'static (dyn DeviceDriver + Sync)];
+        /// A copy of the list, so drivers registered meanwhile don't show up
+        fn all_device_drivers(&self) -> super::DriverList;
+
+        /// Adds a driver, failing once `MAX_DRIVERS` are registered
+        fn register(&self, driver: &'static (dyn DeviceDriver + Sync)) -> Result<(), ()>;
 
         fn post_device_driver_init(&self);
+
+        /// Shuts the enabled drivers down, in reverse init order. The panic
+        /// handler's reboot skips this, see `panic_wait::reboot`.
+        fn shutdown_all(&self) {
+            let drivers = self.all_device_drivers();
+            for i in super::init_order(&drivers).iter().rev() {
+                if drivers[i].is_enabled() {
+                    drivers[i].shutdown();
+                }
+            }
+        }
+    }
+}
+
+pub const MAX_DRIVERS: usize = 16;
+
+/// Fills the unused slots of a `DriverList`
+struct NoDriver;
+
+impl DeviceDriver for NoDriver {
+    fn name(&self) -> &str {
+        ""
+    }
+
+    fn compatible(&self) -> &str {
+        ""
+    }
+}
+
+/// Up to `MAX_DRIVERS` drivers, in registration order. Derefs to a slice of
+/// the ones added so far.
+#[derive(Copy, Clone)]
+pub struct DriverList {
+    drivers: [&'static (dyn DeviceDriver + Sync); MAX_DRIVERS],
+    count: usize,
+}
+
+impl DriverList {
+    pub const fn new() -> Self {
+        Self {
+            drivers: [&NoDriver; MAX_DRIVERS],
+            count: 0,
+        }
+    }
+
+    /// Fails once `MAX_DRIVERS` are in
+    pub fn push(&mut self, driver: &'static (dyn DeviceDriver + Sync)) -> Result<(), ()> {
+        if self.count == MAX_DRIVERS {
+            return Err(());
+        }
+        self.drivers[self.count] = driver;
+        self.count += 1;
+
+        Ok(())
+    }
+}
+
+impl core::ops::Deref for DriverList {
+    type Target = [&'static (dyn DeviceDriver + Sync)];
+
+    fn deref(&self) -> &Self::Target {
+        &self.drivers[..self.count]
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Mark {
+    Unvisited,
+    Visiting,
+    Done,
+}
+
+/// Indices into a driver slice, ordered so that every driver comes after its
+/// dependencies.
+pub struct InitOrder {
+    indices: [usize; MAX_DRIVERS],
+    len: usize,
+}
+
+impl InitOrder {
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = usize> + '_ {
+        self.indices[..self.len].iter().copied()
+    }
+}
+
+struct CyclePath<'a> {
+    drivers: &'a [&'static (dyn DeviceDriver + Sync)],
+    path: &'a [usize],
+}
+
+impl fmt::Display for CyclePath<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &i in self.path {
+            write!(f, "{} -> ", self.drivers[i].name())?;
+        }
+        write!(f, "{}", self.drivers[self.path[0]].name())
+    }
+}
+
+struct Sorter<'a> {
+    drivers: &'a [&'static (dyn DeviceDriver + Sync)],
+    marks: [Mark; MAX_DRIVERS],
+    // Drivers currently being visited, for reporting cycles
+    path: [usize; MAX_DRIVERS],
+    path_len: usize,
+    order: InitOrder,
+}
+
+impl Sorter<'_> {
+    fn visit(&mut self, i: usize) {
+        match self.marks[i] {
+            Mark::Done => return,
+            Mark::Visiting => {
+                let start = self.path[..self.path_len].iter().position(|&p| p == i).unwrap();
+                let path = &self.path[start..self.path_len];
+                panic!("Driver dependency cycle: {}", CyclePath { drivers: self.drivers, path });
+            }
+            Mark::Unvisited => {}
+        }
+
+        self.marks[i] = Mark::Visiting;
+        self.path[self.path_len] = i;
+        self.path_len += 1;
+
+        let driver = self.drivers[i];
+        for dep in driver.dependencies() {
+            match self.drivers.iter().position(|d| d.compatible() == *dep) {
+                Some(j) => self.visit(j),
+                None => panic!("{} depends on missing driver {}", driver.name(), dep),
+            }
+        }
+
+        self.path_len -= 1;
+        self.marks[i] = Mark::Done;
+        self.order.indices[self.order.len] = i;
+        self.order.len += 1;
+    }
+}
+
+/// Topologically sorts `drivers` by their `dependencies()`, keeping the slice
+/// order where there are none. Panics on a cycle or a missing dependency.
+pub fn init_order(drivers: &[&'static (dyn DeviceDriver + Sync)]) -> InitOrder {
+    if drivers.len() > MAX_DRIVERS {
+        panic!("Too many drivers: {}", drivers.len());
+    }
+
+    let mut sorter = Sorter {
+        drivers,
+        marks: [Mark::Unvisited; MAX_DRIVERS],
+        path: [0; MAX_DRIVERS],
+        path_len: 0,
+        order: InitOrder {
+            indices: [0; MAX_DRIVERS],
+            len: 0,
+        },
+    };
+    for i in 0..drivers.len() {
+        sorter.visit(i);
+    }
+
+    sorter.order
+}
+
+/// Initializes the enabled drivers in `init_order`, skipping disabled ones
+/// entirely. Panics if one fails.
+pub fn init_all(drivers: &[&'static (dyn DeviceDriver + Sync)]) {
+    for i in init_order(drivers).iter() {
+        if !drivers[i].is_enabled() {
+            continue;
+        }
+        // Names the driver a hang in `init` is in
+        crate::debug!("{}: init", drivers[i].name());
+        if let Err(e) = drivers[i].init() {
+            panic!("Error loading driver: {}: {}", drivers[i].name(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::{boxed::Box, vec, vec::Vec};
+
+    struct FakeDriver {
+        compatible: &'static str,
+        dependencies: &'static [&'static str],
+        enabled: bool,
+        inits: AtomicUsize,
+        // Where in `SHUTDOWNS` it was shut down, 0 if never
+        shut_down_at: AtomicUsize,
+    }
+
+    static SHUTDOWNS: AtomicUsize = AtomicUsize::new(0);
+
+    impl DeviceDriver for FakeDriver {
+        fn name(&self) -> &str {
+            self.compatible
+        }
+
+        fn compatible(&self) -> &str {
+            self.compatible
+        }
+
+        fn dependencies(&self) -> &[&str] {
+            self.dependencies
+        }
+
+        fn is_enabled(&self) -> bool {
+            self.enabled
+        }
+
+        fn init(&self) -> Result<(), DriverError> {
+            self.inits.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn shutdown(&self) {
+            let at = SHUTDOWNS.fetch_add(1, Ordering::Relaxed) + 1;
+            self.shut_down_at.store(at, Ordering::Relaxed);
+        }
+    }
+
+    struct FakeManager(Vec<&'static (dyn DeviceDriver + Sync)>);
+
+    impl interface::DriverManager for FakeManager {
+        fn all_device_drivers(&self) -> DriverList {
+            let mut list = DriverList::new();
+            for &d in &self.0 {
+                list.push(d).unwrap();
+            }
+            list
+        }
+
+        fn register(&self, _driver: &'static (dyn DeviceDriver + Sync)) -> Result<(), ()> {
+            Err(())
+        }
+
+        fn post_device_driver_init(&self) {}
+    }
+
+    fn fake_driver(
+        compatible: &'static str,
+        dependencies: &'static [&'static str],
+        enabled: bool,
+    ) -> &'static FakeDriver {
+        Box::leak(Box::new(FakeDriver {
+            compatible,
+            dependencies,
+            enabled,
+            inits: AtomicUsize::new(0),
+            shut_down_at: AtomicUsize::new(0),
+        }))
+    }
+
+    fn fake(
+        compatible: &'static str,
+        dependencies: &'static [&'static str],
+    ) -> &'static (dyn DeviceDriver + Sync) {
+        fake_driver(compatible, dependencies, true)
+    }
+
+    fn order(drivers: &[&'static (dyn DeviceDriver + Sync)]) -> Vec<&'static str> {
+        init_order(drivers).iter().map(|i| drivers[i].compatible()).collect()
+    }
+
+    #[test]
+    fn init_order_puts_dependencies_first() {
+        let drivers = [
+            fake("uart", &["gpio", "timer"]),
+            fake("gpio", &["timer"]),
+            fake("timer", &[]),
+        ];
+
+        assert_eq!(order(&drivers), ["timer", "gpio", "uart"]);
+    }
+
+    #[test]
+    fn init_order_keeps_the_slice_order_otherwise() {
+        let drivers = [fake("a", &[]), fake("b", &[]), fake("c", &["a"])];
+
+        assert_eq!(order(&drivers), ["a", "b", "c"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Driver dependency cycle: b -> c -> b")]
+    fn init_order_panics_on_a_cycle() {
+        let drivers = [fake("a", &["b"]), fake("b", &["c"]), fake("c", &["b"])];
+
+        init_order(&drivers);
+    }
+
+    #[test]
+    fn driver_list_fills_up() {
+        let mut list = DriverList::new();
+        assert!(list.is_empty());
+
+        for _ in 0..MAX_DRIVERS {
+            list.push(fake("a", &[])).unwrap();
+        }
+        assert_eq!(list.push(fake("b", &[])), Err(()));
+        assert_eq!(list.len(), MAX_DRIVERS);
+    }
+
+    #[test]
+    #[should_panic(expected = "a depends on missing driver z")]
+    fn init_order_panics_on_a_missing_dependency() {
+        init_order(&[fake("a", &["z"])]);
+    }
+
+    #[test]
+    fn init_all_never_inits_a_disabled_driver() {
+        let (timer, i2c, uart) = (
+            fake_driver("timer", &[], true),
+            fake_driver("i2c", &["timer"], false),
+            fake_driver("uart", &["timer"], true),
+        );
+
+        init_all(&[uart, i2c, timer]);
+        assert_eq!(timer.inits.load(Ordering::Relaxed), 1);
+        assert_eq!(i2c.inits.load(Ordering::Relaxed), 0);
+        assert_eq!(uart.inits.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn shutdown_all_goes_in_reverse_init_order() {
+        use interface::DriverManager;
+
+        let (timer, i2c, gpio, uart) = (
+            fake_driver("timer", &[], true),
+            fake_driver("i2c", &["gpio"], false),
+            fake_driver("gpio", &["timer"], true),
+            fake_driver("uart", &["gpio", "timer"], true),
+        );
+
+        FakeManager(vec![uart, i2c, gpio, timer]).shutdown_all();
+        let at = |d: &FakeDriver| d.shut_down_at.load(Ordering::Relaxed);
+        assert_eq!(at(i2c), 0);
+        assert!(at(uart) != 0 && at(uart) < at(gpio) && at(gpio) < at(timer));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,29 @@
+#[cfg(any(target_arch = "aarch64", test))]
+#[path = "_arch/aarch64/exception.rs"]
+mod arch_exception;
+pub use arch_exception::*;
+
+pub mod interface {
+    use crate::driver::DriverError;
+
+    pub trait IRQHandler {
+        /// Runs in IRQ context with interrupts masked, so it must not block
+        /// on a lock that thread context may hold on the same core.
+        fn handle(&self);
+    }
+
+    pub trait IRQManager {
+        fn register_handler(
+            &self,
+            irq: usize,
+            handler: &'static (dyn IRQHandler + Sync),
+        ) -> Result<(), DriverError>;
+
+        fn enable(&self, irq: usize);
+
+        fn disable(&self, irq: usize);
+
+        /// Calls the handler of every pending IRQ
+        fn dispatch(&self);
+    }
+}
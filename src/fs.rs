@@ -0,0 +1,14 @@
+pub mod fat32;
+
+/// The unit block devices are read in
+pub const BLOCK_SIZE: usize = 512;
+
+pub mod interface {
+    use super::BLOCK_SIZE;
+    use crate::driver::DriverError;
+
+    /// Storage read a block at a time, like an SD card
+    pub trait BlockDevice {
+        fn read_block(&self, lba: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), DriverError>;
+    }
+}
@@ -0,0 +1,584 @@
+//! Read-only FAT32, on the first MBR partition of type 0x0B or 0x0C or, with
+//! no partition table, from block 0. Only short (8.3) names are looked up,
+//! long name entries are skipped.
+
+use super::{interface::BlockDevice, BLOCK_SIZE};
+use crate::driver::DriverError;
+use core::cmp;
+
+const DIR_ENTRY_SIZE: usize = 32;
+const NAME_LENGTH: usize = 11;
+
+const ATTR_DIRECTORY: u8 = 0x10;
+// Set on volume labels, and on long name entries along with their other bits
+const ATTR_VOLUME_ID: u8 = 0x08;
+// First name byte of the entry after the last one, and of a deleted one
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xE5;
+
+// Entries are 28 bits, the top 4 are reserved
+const FAT_ENTRY_MASK: u32 = 0x0FFF_FFFF;
+// Bad cluster, anything above ends the chain
+const FAT_BAD: u32 = 0x0FFF_FFF7;
+
+const BOOT_SIGNATURE: u16 = 0xAA55;
+// The first entry of the MBR's partition table
+const PARTITION_ENTRY: usize = 446;
+
+const fn le16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+const fn le32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+/// The cluster after one whose FAT entry is `entry`, or `None` where the
+/// chain ends. A free, reserved or bad entry ends it too.
+pub const fn next_cluster(entry: u32) -> Option<u32> {
+    match entry & FAT_ENTRY_MASK {
+        next if next < 2 || next >= FAT_BAD => None,
+        next => Some(next),
+    }
+}
+
+/// Where `cluster`'s entry is: the block of the FAT, counted from its first,
+/// and the byte within that block
+pub const fn fat_entry_position(cluster: u32) -> (u32, usize) {
+    let offset = cluster as usize * 4;
+    ((offset / BLOCK_SIZE) as u32, offset % BLOCK_SIZE)
+}
+
+/// `name` as the upper case, space padded 11 bytes of a directory entry, or
+/// `None` if it isn't 8.3
+pub const fn short_name(name: &str) -> Option<[u8; NAME_LENGTH]> {
+    let bytes = name.as_bytes();
+    if bytes.is_empty() {
+        return None;
+    }
+
+    let mut out = [b' '; NAME_LENGTH];
+    let mut i = 0;
+    let mut at = 0;
+    let mut end = 8;
+    while i < bytes.len() {
+        match bytes[i] {
+            // The extension starts at the first dot, but not a leading one
+            b'.' if i > 0 && end == 8 => {
+                at = 8;
+                end = NAME_LENGTH;
+            }
+            b'.' | b' ' | b'/' => return None,
+            _ if at == end => return None,
+            c => {
+                out[at] = if c.is_ascii_lowercase() { c - (b'a' - b'A') } else { c };
+                at += 1;
+            }
+        }
+        i += 1;
+    }
+
+    Some(out)
+}
+
+/// Whether the directory entry at `at` in `block` has the short name `name`
+pub const fn name_matches(name: &[u8; NAME_LENGTH], block: &[u8], at: usize) -> bool {
+    let mut i = 0;
+    while i < NAME_LENGTH {
+        if block[at + i] != name[i] {
+            return false;
+        }
+        i += 1;
+    }
+
+    true
+}
+
+/// Where the volume starts per the MBR in `block`, or `None` if the first
+/// partition isn't FAT32
+const fn partition_start(block: &[u8]) -> Option<u32> {
+    match (le16(block, 510), block[PARTITION_ENTRY + 4]) {
+        // FAT32 with CHS or LBA addressing
+        (BOOT_SIGNATURE, 0x0B) | (BOOT_SIGNATURE, 0x0C) => Some(le32(block, PARTITION_ENTRY + 8)),
+        _ => None,
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Layout {
+    fat_lba: u32,
+    data_lba: u32,
+    blocks_per_cluster: u32,
+    root_cluster: u32,
+    // Data clusters, numbered from 2
+    clusters: u32,
+}
+
+/// The layout described by the BPB in `block`, the volume's first, which
+/// is at `lba`. `None` unless it's FAT32 with 512-byte sectors.
+const fn parse_bpb(block: &[u8], lba: u32) -> Option<Layout> {
+    let bytes_per_sector = le16(block, 11) as usize;
+    let blocks_per_cluster = block[13] as u32;
+    let reserved = le16(block, 14) as u32;
+    let fats = block[16] as u32;
+    // Only set on FAT12 and FAT16
+    let fat16_size = le16(block, 22);
+    // The 16-bit count is 0 once it doesn't fit, as on any FAT32 volume
+    let total_blocks = match le16(block, 19) {
+        0 => le32(block, 32),
+        total => total as u32,
+    };
+    let fat_size = le32(block, 36);
+    let root_cluster = le32(block, 44);
+
+    if le16(block, 510) != BOOT_SIGNATURE
+        || bytes_per_sector != BLOCK_SIZE
+        || !blocks_per_cluster.is_power_of_two()
+        || fats == 0
+        || fat16_size != 0
+        || fat_size == 0
+        || root_cluster < 2
+    {
+        return None;
+    }
+
+    let fat_blocks = fats.saturating_mul(fat_size);
+    let clusters = total_blocks.saturating_sub(reserved.saturating_add(fat_blocks))
+        / blocks_per_cluster;
+    if root_cluster - 2 >= clusters {
+        return None;
+    }
+
+    let fat_lba = lba.saturating_add(reserved);
+    Some(Layout {
+        fat_lba,
+        data_lba: fat_lba.saturating_add(fat_blocks),
+        blocks_per_cluster,
+        root_cluster,
+        clusters,
+    })
+}
+
+#[derive(Copy, Clone)]
+struct Entry {
+    cluster: u32,
+    size: u32,
+    is_dir: bool,
+}
+
+impl Entry {
+    fn parse(block: &[u8], at: usize) -> Self {
+        Self {
+            cluster: (le16(block, at + 20) as u32) << 16 | le16(block, at + 26) as u32,
+            size: le32(block, at + 28),
+            is_dir: block[at + 11] & ATTR_DIRECTORY != 0,
+        }
+    }
+}
+
+/// A mounted FAT32 volume. Nothing is cached, every lookup goes back to the
+/// device.
+#[derive(Copy, Clone)]
+pub struct Volume<'a> {
+    dev: &'a dyn BlockDevice,
+    layout: Layout,
+}
+
+impl<'a> Volume<'a> {
+    /// Finds the volume on `dev`, `None` if there isn't one or it can't be
+    /// read
+    pub fn mount(dev: &'a dyn BlockDevice) -> Option<Self> {
+        let mut block = [0u8; BLOCK_SIZE];
+        dev.read_block(0, &mut block).ok()?;
+
+        let lba = match partition_start(&block) {
+            Some(lba) => {
+                dev.read_block(lba, &mut block).ok()?;
+                lba
+            }
+            None => 0,
+        };
+
+        Some(Self {
+            dev,
+            layout: parse_bpb(&block, lba)?,
+        })
+    }
+
+    fn cluster_size(&self) -> u32 {
+        self.layout.blocks_per_cluster * BLOCK_SIZE as u32
+    }
+
+    // A corrupt entry or FAT can name any cluster, not just one on the volume
+    fn cluster_lba(&self, cluster: u32) -> Result<u32, DriverError> {
+        if cluster < 2 || cluster - 2 >= self.layout.clusters {
+            return Err(DriverError::InvalidConfig);
+        }
+
+        Ok(self.layout.data_lba + (cluster - 2) * self.layout.blocks_per_cluster)
+    }
+
+    // Reads the first FAT, `None` at the end of the chain or on an error
+    fn next_cluster(&self, cluster: u32) -> Option<u32> {
+        let (index, at) = fat_entry_position(cluster);
+        let mut block = [0u8; BLOCK_SIZE];
+        self.dev.read_block(self.layout.fat_lba + index, &mut block).ok()?;
+
+        next_cluster(le32(&block, at))
+    }
+
+    // Looks `name` up in the directory starting at `cluster`
+    fn find(&self, cluster: u32, name: &[u8; NAME_LENGTH]) -> Option<Entry> {
+        let mut block = [0u8; BLOCK_SIZE];
+        let mut cluster = Some(cluster);
+
+        // No chain is longer than the volume, only a cyclic FAT would be
+        for _ in 0..self.layout.clusters {
+            let c = cluster?;
+            let lba = self.cluster_lba(c).ok()?;
+            for i in 0..self.layout.blocks_per_cluster {
+                self.dev.read_block(lba + i, &mut block).ok()?;
+
+                for at in (0..BLOCK_SIZE).step_by(DIR_ENTRY_SIZE) {
+                    match block[at] {
+                        ENTRY_END => return None,
+                        ENTRY_DELETED => continue,
+                        _ if block[at + 11] & ATTR_VOLUME_ID != 0 => continue,
+                        _ if name_matches(name, &block, at) => {
+                            return Some(Entry::parse(&block, at));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            cluster = self.next_cluster(c);
+        }
+
+        None
+    }
+
+    /// Opens the file at `path`, from the root directory with `/` between
+    /// components. Case doesn't matter, and neither does a leading `/`.
+    pub fn open(&self, path: &str) -> Option<File<'a>> {
+        let mut components = path.split('/').filter(|c| !c.is_empty()).peekable();
+        let mut dir = self.layout.root_cluster;
+
+        while let Some(component) = components.next() {
+            let entry = self.find(dir, &short_name(component)?)?;
+            match (components.peek(), entry.is_dir) {
+                (None, false) => return Some(File::new(*self, entry)),
+                (Some(_), true) => dir = entry.cluster,
+                _ => return None,
+            }
+        }
+
+        None
+    }
+}
+
+/// A file open for reading, from the start
+pub struct File<'a> {
+    volume: Volume<'a>,
+    size: u32,
+    pos: u32,
+    // The cluster `pos` is in
+    cluster: Option<u32>,
+}
+
+impl<'a> File<'a> {
+    fn new(volume: Volume<'a>, entry: Entry) -> Self {
+        Self {
+            volume,
+            size: entry.size,
+            pos: 0,
+            // Empty files have none
+            cluster: Some(entry.cluster).filter(|&c| c >= 2),
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Reads on from where the last read stopped, returning how many bytes
+    /// went into `buf`. Fewer than asked for means the end of the file, or a
+    /// read error.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let cluster_size = self.volume.cluster_size();
+        let mut block = [0u8; BLOCK_SIZE];
+        let mut done = 0;
+
+        while done < buf.len() && self.pos < self.size {
+            let cluster = match self.cluster {
+                Some(cluster) => cluster,
+                None => break,
+            };
+
+            let in_cluster = self.pos % cluster_size;
+            let lba = match self.volume.cluster_lba(cluster) {
+                Ok(lba) => lba + in_cluster / BLOCK_SIZE as u32,
+                Err(_) => break,
+            };
+            if self.volume.dev.read_block(lba, &mut block).is_err() {
+                break;
+            }
+
+            let at = in_cluster as usize % BLOCK_SIZE;
+            let len = cmp::min(
+                cmp::min(BLOCK_SIZE - at, buf.len() - done),
+                (self.size - self.pos) as usize,
+            );
+            buf[done..done + len].copy_from_slice(&block[at..at + len]);
+            done += len;
+            self.pos += len as u32;
+
+            // Stepped off the end of the cluster
+            if in_cluster + len as u32 == cluster_size {
+                self.cluster = self.volume.next_cluster(cluster);
+            }
+        }
+
+        done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{vec, vec::Vec};
+
+    // A disk image in memory, reads past its end fail
+    struct RamDisk<'a>(&'a [u8]);
+
+    impl BlockDevice for RamDisk<'_> {
+        fn read_block(&self, lba: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), DriverError> {
+            let at = lba as usize * BLOCK_SIZE;
+            let block = self.0.get(at..at + BLOCK_SIZE).ok_or(DriverError::BusError)?;
+            buf.copy_from_slice(block);
+            Ok(())
+        }
+    }
+
+    fn put16(image: &mut [u8], at: usize, value: u16) {
+        image[at..at + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put32(image: &mut [u8], at: usize, value: u32) {
+        image[at..at + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    // A cluster and a bit
+    const KERNEL_SIZE: usize = 2 * BLOCK_SIZE + 100;
+
+    fn kernel_byte(i: usize) -> u8 {
+        (i % 251) as u8
+    }
+
+    // The volume's first block is `start`. Two blocks to a cluster, one
+    // reserved block, two FATs of one block and 16 data clusters, so cluster
+    // `c` starts at block `start` + 3 + 2 * (`c` - 2). The image goes on past
+    // the volume's end, filled with 0xEE.
+    //
+    // /QUANTUM is the volume label, /OLD.TXT deleted, /BOOT a directory in
+    // cluster 3 and /CONFIG.TXT a file in 5. /BOOT/KERNEL8.IMG runs 4 then
+    // 6. /LONG.BIN starts at 7, whose FAT entry points off the volume,
+    // /BADDIR claims cluster 1 and /LOOP is a directory in 9, full of deleted
+    // entries and chained to itself.
+    fn image(start: u32) -> Vec<u8> {
+        let block = |lba: u32| (start + lba) as usize * BLOCK_SIZE;
+        let cluster = |c: u32| block(3 + 2 * (c - 2));
+        let mut image = vec![0xEE; block(3 + 2 * 24)];
+        image[..block(3 + 2 * 16)].fill(0);
+
+        let bpb = block(0);
+        put16(&mut image, bpb + 11, BLOCK_SIZE as u16);
+        image[bpb + 13] = 2;
+        put16(&mut image, bpb + 14, 1);
+        image[bpb + 16] = 2;
+        put32(&mut image, bpb + 32, 3 + 2 * 16);
+        put32(&mut image, bpb + 36, 1);
+        put32(&mut image, bpb + 44, 2);
+        put16(&mut image, bpb + 510, BOOT_SIGNATURE);
+
+        #[rustfmt::skip]
+        let fat = [
+            0x0FFF_FFF8, 0x0FFF_FFFF, 0x0FFF_FFFF, 0x0FFF_FFFF,
+            // The reserved top bits don't count
+            0xF000_0006, 0x0FFF_FFFF, 0x0FFF_FFFF, 20, FAT_BAD, 9,
+        ];
+        for (i, &entry) in fat.iter().enumerate() {
+            put32(&mut image, block(1) + i * 4, entry);
+        }
+
+        let mut entry = |dir: usize, i: usize, name: &[u8; 11], attr, c: u32, size: u32| {
+            let at = dir + i * DIR_ENTRY_SIZE;
+            image[at..at + NAME_LENGTH].copy_from_slice(name);
+            image[at + 11] = attr;
+            put16(&mut image, at + 20, (c >> 16) as u16);
+            put16(&mut image, at + 26, c as u16);
+            put32(&mut image, at + 28, size);
+        };
+        entry(cluster(2), 0, b"QUANTUM    ", ATTR_VOLUME_ID, 0, 0);
+        entry(cluster(2), 1, b"\xE5LD     TXT", 0, 5, 11);
+        entry(cluster(2), 2, b"BOOT       ", ATTR_DIRECTORY, 3, 0);
+        entry(cluster(2), 3, b"CONFIG  TXT", 0, 5, 11);
+        entry(cluster(2), 4, b"LONG    BIN", 0, 7, 4 * BLOCK_SIZE as u32);
+        entry(cluster(2), 5, b"BADDIR     ", ATTR_DIRECTORY, 1, 0);
+        entry(cluster(2), 6, b"LOOP       ", ATTR_DIRECTORY, 9, 0);
+        entry(cluster(3), 0, b"KERNEL8 IMG", 0, 4, KERNEL_SIZE as u32);
+
+        for at in (cluster(9)..cluster(10)).step_by(DIR_ENTRY_SIZE) {
+            image[at] = ENTRY_DELETED;
+        }
+        image[cluster(5)..cluster(5) + 11].copy_from_slice(b"arm_64bit=1");
+        let cluster_size = 2 * BLOCK_SIZE;
+        for i in 0..KERNEL_SIZE {
+            let c = if i < cluster_size { 4 } else { 6 };
+            image[cluster(c) + i % cluster_size] = kernel_byte(i);
+        }
+
+        image
+    }
+
+    fn read_all(file: &mut File, chunk: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut buf = vec![0; chunk];
+        loop {
+            let n = file.read(&mut buf);
+            out.extend_from_slice(&buf[..n]);
+            if n < chunk {
+                return out;
+            }
+        }
+    }
+
+    #[test]
+    fn short_name_pads_and_upper_cases() {
+        assert_eq!(short_name("config.txt"), Some(*b"CONFIG  TXT"));
+        assert_eq!(short_name("KERNEL8"), Some(*b"KERNEL8    "));
+        assert!(name_matches(&short_name("boot").unwrap(), b"xBOOT       ", 1));
+    }
+
+    #[test]
+    fn short_name_rejects_what_isnt_8_3() {
+        assert_eq!(short_name("toolongname.txt"), None);
+        assert_eq!(short_name("a.b.c"), None);
+        assert_eq!(short_name(".hidden"), None);
+        assert_eq!(short_name(""), None);
+    }
+
+    #[test]
+    fn fat_entries_end_the_chain_when_free_or_bad() {
+        assert_eq!(next_cluster(0), None);
+        assert_eq!(next_cluster(1), None);
+        assert_eq!(next_cluster(FAT_BAD), None);
+        assert_eq!(next_cluster(0x0FFF_FFFF), None);
+        assert_eq!(next_cluster(0xF000_0003), Some(3));
+        assert_eq!(fat_entry_position(130), (1, 8));
+    }
+
+    #[test]
+    fn mount_reads_the_layout() {
+        let image = image(0);
+        let disk = RamDisk(&image);
+        let layout = Volume::mount(&disk).unwrap().layout;
+
+        assert_eq!((layout.fat_lba, layout.data_lba), (1, 3));
+        assert_eq!((layout.blocks_per_cluster, layout.root_cluster), (2, 2));
+        assert_eq!(layout.clusters, 16);
+        assert_eq!(partition_start(&image), None);
+    }
+
+    #[test]
+    fn mount_follows_the_partition_table() {
+        let mut image = image(2048);
+        image[PARTITION_ENTRY + 4] = 0x0C;
+        put32(&mut image, PARTITION_ENTRY + 8, 2048);
+        put16(&mut image, 510, BOOT_SIGNATURE);
+        let disk = RamDisk(&image);
+
+        let volume = Volume::mount(&disk).unwrap();
+        assert_eq!((volume.layout.fat_lba, volume.layout.data_lba), (2049, 2051));
+        assert_eq!(read_all(&mut volume.open("config.txt").unwrap(), 64), b"arm_64bit=1");
+    }
+
+    #[test]
+    fn find_skips_labels_and_deleted_entries() {
+        let image = image(0);
+        let disk = RamDisk(&image);
+        let volume = Volume::mount(&disk).unwrap();
+        let find = |name| volume.find(2, &short_name(name).unwrap());
+
+        let config = find("config.txt").unwrap();
+        assert_eq!((config.cluster, config.size, config.is_dir), (5, 11, false));
+        assert!(find("boot").unwrap().is_dir);
+        assert!(find("quantum").is_none());
+        assert!(find("old.txt").is_none());
+        assert!(find("missing").is_none());
+    }
+
+    #[test]
+    fn next_cluster_follows_the_fat() {
+        let image = image(0);
+        let disk = RamDisk(&image);
+        let volume = Volume::mount(&disk).unwrap();
+
+        assert_eq!(volume.next_cluster(4), Some(6));
+        assert_eq!(volume.next_cluster(6), None);
+        assert_eq!(volume.next_cluster(8), None);
+    }
+
+    #[test]
+    fn read_crosses_blocks_and_clusters() {
+        let image = image(0);
+        let disk = RamDisk(&image);
+        let mut file = Volume::mount(&disk).unwrap().open("/boot/KERNEL8.img").unwrap();
+
+        assert_eq!(file.size() as usize, KERNEL_SIZE);
+        let data = read_all(&mut file, 300);
+        assert_eq!(data.len(), KERNEL_SIZE);
+        assert!(data.iter().enumerate().all(|(i, &b)| b == kernel_byte(i)));
+        assert_eq!(file.read(&mut [0; 16]), 0);
+    }
+
+    #[test]
+    fn open_wants_files_at_the_end_and_directories_before() {
+        let image = image(0);
+        let disk = RamDisk(&image);
+        let volume = Volume::mount(&disk).unwrap();
+
+        assert!(volume.open("boot").is_none());
+        assert!(volume.open("config.txt/x").is_none());
+        assert!(volume.open("/").is_none());
+        assert!(volume.open("boot/missing").is_none());
+    }
+
+    #[test]
+    fn clusters_off_the_volume_are_rejected() {
+        let image = image(0);
+        let disk = RamDisk(&image);
+        let volume = Volume::mount(&disk).unwrap();
+
+        assert_eq!(volume.cluster_lba(0), Err(DriverError::InvalidConfig));
+        assert_eq!(volume.cluster_lba(1), Err(DriverError::InvalidConfig));
+        assert_eq!(volume.cluster_lba(17), Ok(3 + 2 * 15));
+        assert_eq!(volume.cluster_lba(18), Err(DriverError::InvalidConfig));
+
+        // The chain leaves the volume after one cluster, the 0xEE past it
+        // is never read
+        let data = read_all(&mut volume.open("long.bin").unwrap(), BLOCK_SIZE);
+        assert_eq!(data, vec![0; 2 * BLOCK_SIZE]);
+        assert!(volume.open("baddir/x.txt").is_none());
+    }
+
+    #[test]
+    fn find_gives_up_on_a_cyclic_directory() {
+        let image = image(0);
+        let disk = RamDisk(&image);
+        let volume = Volume::mount(&disk).unwrap();
+
+        assert_eq!(volume.next_cluster(9), Some(9));
+        assert!(volume.find(9, &short_name("x.txt").unwrap()).is_none());
+        assert!(volume.open("loop/x.txt").is_none());
+    }
+}
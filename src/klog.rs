@@ -0,0 +1,285 @@
+use crate::{console, synchronization, synchronization::IRQSafeSpinlock};
+use core::fmt;
+
+// A power of two, so wrapping `written` stays consistent modulo the size
+const CAPACITY: usize = 8 * 1024;
+
+const _: [(); 1] = [(); CAPACITY.is_power_of_two() as usize];
+
+/// Where the oldest retained byte sits, and how many bytes are retained,
+/// after `written` bytes have gone into a ring of `capacity`
+pub const fn oldest_offset(written: usize, capacity: usize) -> (usize, usize) {
+    if written <= capacity {
+        (0, written)
+    } else {
+        (written % capacity, capacity)
+    }
+}
+
+/// Everything printed since boot, up to the last `CAPACITY` bytes. Unlike
+/// the deferred log this overwrites the oldest bytes and never drains.
+struct KernelLog {
+    data: [u8; CAPACITY],
+    written: usize,
+}
+
+impl KernelLog {
+    const fn new() -> Self {
+        Self {
+            data: [0; CAPACITY],
+            written: 0,
+        }
+    }
+}
+
+impl fmt::Write for KernelLog {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &b in s.as_bytes() {
+            self.data[self.written % CAPACITY] = b;
+            self.written = self.written.wrapping_add(1);
+        }
+
+        Ok(())
+    }
+}
+
+// IRQ-safe like the consoles, so `println!` works from a handler
+static KLOG: IRQSafeSpinlock<KernelLog> = IRQSafeSpinlock::new(KernelLog::new());
+
+use synchronization::interface::Mutex;
+
+/// Appends formatted output to the kernel log
+pub fn write(args: fmt::Arguments) {
+    let mut r = &KLOG;
+    r.lock(|klog| {
+        let _ = fmt::write(klog, args);
+    });
+}
+
+// How much `write_tee` formats before passing it on
+const TEE_CHUNK: usize = 128;
+
+// Formatted output on its way to a log and a console, a buffer at a time
+struct TeeBuffer<'a> {
+    log: &'a IRQSafeSpinlock<KernelLog>,
+    out: &'a mut dyn fmt::Write,
+    buf: [u8; TEE_CHUNK],
+    len: usize,
+    ret: fmt::Result,
+}
+
+impl TeeBuffer<'_> {
+    // The log is only locked to copy the buffer in, `out` gets it after
+    fn flush(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+
+        // Only ever filled up to a char boundary
+        let s = core::str::from_utf8(&self.buf[..self.len]).unwrap_or("");
+
+        let mut r = self.log;
+        r.lock(|klog| {
+            let _ = fmt::Write::write_str(klog, s);
+        });
+        self.ret = self.out.write_str(s).and(self.ret);
+        self.len = 0;
+    }
+}
+
+impl fmt::Write for TeeBuffer<'_> {
+    fn write_str(&mut self, mut s: &str) -> fmt::Result {
+        while !s.is_empty() {
+            let mut n = s.len().min(TEE_CHUNK - self.len);
+            while !s.is_char_boundary(n) {
+                n -= 1;
+            }
+            if n == 0 {
+                self.flush();
+                continue;
+            }
+
+            self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+            self.len += n;
+            s = &s[n..];
+        }
+
+        Ok(())
+    }
+}
+
+fn write_tee_to(
+    log: &IRQSafeSpinlock<KernelLog>,
+    args: fmt::Arguments,
+    out: &mut dyn fmt::Write,
+) -> fmt::Result {
+    let mut tee = TeeBuffer {
+        log,
+        out,
+        buf: [0; TEE_CHUNK],
+        len: 0,
+        ret: Ok(()),
+    };
+    let ret = fmt::write(&mut tee, args);
+    tee.flush();
+
+    ret.and(tee.ret)
+}
+
+/// Appends formatted output to the kernel log while writing it to `out`,
+/// formatting only once. It goes through a small buffer, and the log is
+/// locked just to copy each one in, so interrupts aren't masked while `out`
+/// transmits. Output from several cores can interleave a buffer at a time.
+pub fn write_tee(args: fmt::Arguments, out: &mut dyn fmt::Write) -> fmt::Result {
+    write_tee_to(&KLOG, args, out)
+}
+
+/// Replays the kernel log to the active console, oldest byte first. The
+/// lock is only held to copy a chunk out, so printing carries on meanwhile
+/// and what gets replayed is whatever is retained at each step.
+pub fn dump() {
+    replay(&KLOG, &mut |bytes| console::console().write_bytes(bytes));
+}
+
+fn replay(log: &IRQSafeSpinlock<KernelLog>, out: &mut dyn FnMut(&[u8])) {
+    const CHUNK: usize = 64;
+
+    let mut r = log;
+    // Where the replay starts, counted in bytes written since boot
+    let (mut pos, end) = r.lock(|klog| {
+        let (_, len) = oldest_offset(klog.written, CAPACITY);
+        (klog.written.wrapping_sub(len), klog.written)
+    });
+
+    while pos != end {
+        let mut chunk = [0u8; CHUNK];
+        let len = r.lock(|klog| {
+            // Skip ahead if the bytes got overwritten in the meantime, and
+            // stop if everything up to `end` did
+            let (_, retained) = oldest_offset(klog.written, CAPACITY);
+            if klog.written.wrapping_sub(end) > retained {
+                return 0;
+            }
+            if klog.written.wrapping_sub(pos) > retained {
+                pos = klog.written.wrapping_sub(retained);
+            }
+
+            let len = end.wrapping_sub(pos).min(CHUNK);
+            for (i, dst) in chunk[..len].iter_mut().enumerate() {
+                *dst = klog.data[pos.wrapping_add(i) % CAPACITY];
+            }
+            len
+        });
+        if len == 0 {
+            return;
+        }
+
+        out(&chunk[..len]);
+        pos = pos.wrapping_add(len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fmt::Write;
+    use std::{boxed::Box, string::String, vec::Vec};
+
+    fn log_with(s: &str) -> Box<IRQSafeSpinlock<KernelLog>> {
+        let log = Box::new(IRQSafeSpinlock::new(KernelLog::new()));
+        (&*log).lock(|klog| klog.write_str(s).unwrap());
+        log
+    }
+
+    fn replayed(log: &IRQSafeSpinlock<KernelLog>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        replay(log, &mut |chunk| bytes.extend_from_slice(chunk));
+        bytes
+    }
+
+    // Distinct bytes for a whole ring and then some
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| b'a' + (i % 26) as u8).collect()
+    }
+
+    #[test]
+    fn write_tee_sends_the_same_text_to_both() {
+        let log = log_with("");
+        let mut out = String::new();
+
+        // Over a few buffers, with a two-byte char straddling the first
+        let text = "x".repeat(TEE_CHUNK - 1) + "é" + &"y".repeat(2 * TEE_CHUNK);
+        write_tee_to(&log, format_args!("{}-{:#x}", text, 0xff), &mut out).unwrap();
+
+        assert_eq!(out, text + "-0xff");
+        assert_eq!(replayed(&log), out.as_bytes());
+    }
+
+    struct Failing;
+
+    impl Write for Failing {
+        fn write_str(&mut self, _: &str) -> fmt::Result {
+            Err(fmt::Error)
+        }
+    }
+
+    #[test]
+    fn write_tee_logs_what_out_refused() {
+        let log = log_with("");
+
+        assert!(write_tee_to(&log, format_args!("{}", 42), &mut Failing).is_err());
+        assert_eq!(replayed(&log), b"42");
+    }
+
+    #[test]
+    fn oldest_offset_wraps_with_the_ring() {
+        assert_eq!(oldest_offset(0, 16), (0, 0));
+        assert_eq!(oldest_offset(16, 16), (0, 16));
+        assert_eq!(oldest_offset(21, 16), (5, 16));
+        assert_eq!(oldest_offset(32, 16), (0, 16));
+    }
+
+    #[test]
+    fn replay_before_wrapping_returns_everything() {
+        assert_eq!(replayed(&log_with("")), b"");
+        assert_eq!(replayed(&log_with("boot\n")), b"boot\n");
+    }
+
+    #[test]
+    fn replay_after_overflow_starts_at_the_oldest_byte() {
+        let bytes = pattern(CAPACITY + 5);
+        let log = log_with(core::str::from_utf8(&bytes).unwrap());
+
+        assert_eq!(replayed(&log), &bytes[5..]);
+    }
+
+    #[test]
+    fn replay_skips_bytes_overwritten_while_it_runs() {
+        let bytes = pattern(CAPACITY);
+        let log = log_with(core::str::from_utf8(&bytes).unwrap());
+        let mut out = Vec::new();
+
+        // The first chunk is 64 bytes, then 100 more are overwritten
+        replay(&log, &mut |chunk| {
+            if out.is_empty() {
+                (&*log).lock(|klog| klog.write_str(&"x".repeat(100)).unwrap());
+            }
+            out.extend_from_slice(chunk);
+        });
+
+        assert_eq!(&out[..64], &bytes[..64]);
+        assert_eq!(&out[64..], &bytes[100..]);
+    }
+
+    #[test]
+    fn replay_stops_once_its_end_is_overwritten() {
+        let log = log_with(core::str::from_utf8(&pattern(CAPACITY)).unwrap());
+        let mut calls = 0;
+
+        replay(&log, &mut |_| {
+            calls += 1;
+            (&*log).lock(|klog| klog.write_str(&"x".repeat(CAPACITY + 1)).unwrap());
+        });
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,241 @@
+use crate::{
+    bsp, console, format_into, klog, print, synchronization, synchronization::IRQSafeSpinlock,
+};
+use alloc::collections::VecDeque;
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+#[derive(Copy, Clone, PartialEq, PartialOrd)]
+#[repr(u8)]
+pub enum LogLevel {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    fn tag(self) -> &'static str {
+        match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+        }
+    }
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+pub fn set_max_level(level: LogLevel) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+#[doc(hidden)]
+pub fn _enabled(level: LogLevel) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+#[doc(hidden)]
+pub fn _log(level: LogLevel, args: fmt::Arguments) {
+    let uptime = bsp::time::timer().now_nanos();
+    let line = format_args_nl!(
+        "[{:>5}.{:06}] {:>5}: {}",
+        uptime / 1_000_000_000,
+        uptime % 1_000_000_000 / 1_000,
+        level.tag(),
+        args
+    );
+    if DEFERRED_ENABLED.load(Ordering::Relaxed) {
+        // Formatted once, so the kernel log, where `_print` would have put
+        // it, gets the same cut-short line as the deferred buffer
+        let mut buf = [0u8; MAX_LINE];
+        let line = format_into!(&mut buf, "{}", line);
+        klog::write(format_args!("{}", line));
+        DEFERRED.enqueue(line);
+    } else {
+        print::_print(line);
+    }
+}
+
+// Enough for a few hundred lines
+const DEFERRED_CAPACITY: usize = 16 * 1024;
+
+// Longer lines are cut short on their way into the deferred buffer
+const MAX_LINE: usize = 256;
+
+static DEFERRED: DeferredLogger = DeferredLogger::new(DEFERRED_CAPACITY);
+static DEFERRED_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Log output held in RAM until `flush`, for paths too hot to wait on the
+/// UART. Drops the oldest bytes to make room once full.
+///
+/// Lines are formatted on the stack and the buffer never grows, so nothing
+/// allocates with the lock held and it is safe to log from an IRQ handler.
+pub struct DeferredLogger {
+    // Allocated by `reserve`, so this can live in a static
+    buffer: IRQSafeSpinlock<Option<VecDeque<u8>>>,
+    capacity: usize,
+}
+
+use synchronization::interface::Mutex;
+
+impl DeferredLogger {
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            buffer: IRQSafeSpinlock::new(None),
+            capacity,
+        }
+    }
+
+    /// Allocates the buffer, if that hasn't happened yet. `enqueue` drops
+    /// lines until it has.
+    pub fn reserve(&self) {
+        let mut r = &self.buffer;
+        if r.lock(|buffer| buffer.is_some()) {
+            return;
+        }
+
+        let new = VecDeque::with_capacity(self.capacity);
+        // Another core got there first, its buffer stays and this one is
+        // freed outside the lock
+        let _spare = r.lock(|buffer| match buffer {
+            Some(_) => Some(new),
+            None => buffer.replace(new),
+        });
+    }
+
+    /// Queues `line`, cut short at a char boundary past `MAX_LINE` bytes
+    pub fn enqueue(&self, line: &str) {
+        let mut n = line.len().min(MAX_LINE);
+        while !line.is_char_boundary(n) {
+            n -= 1;
+        }
+        let line = &line.as_bytes()[..n];
+
+        let capacity = self.capacity;
+        let mut r = &self.buffer;
+        r.lock(|buffer| {
+            if let Some(buffer) = buffer {
+                for &b in line {
+                    if buffer.len() == capacity {
+                        buffer.pop_front();
+                    }
+                    buffer.push_back(b);
+                }
+            }
+        });
+    }
+
+    /// Writes everything queued so far to the active console. The lock is
+    /// only held to take a chunk out, so logging carries on meanwhile.
+    pub fn flush(&self) {
+        const CHUNK: usize = 64;
+
+        loop {
+            let mut chunk = [0u8; CHUNK];
+            let mut r = &self.buffer;
+            let len = r.lock(|buffer| match buffer {
+                Some(buffer) => {
+                    let len = buffer.len().min(CHUNK);
+                    for (dst, src) in chunk.iter_mut().zip(buffer.drain(..len)) {
+                        *dst = src;
+                    }
+                    len
+                }
+                None => 0,
+            });
+            if len == 0 {
+                return;
+            }
+
+            console::console().write_bytes(&chunk[..len]);
+        }
+    }
+}
+
+/// Sends log lines to the deferred buffer instead of the console. It lives
+/// on the heap, so only turn this on once the heap is up. Turning it off
+/// doesn't flush.
+pub fn set_deferred(enable: bool) {
+    if enable {
+        DEFERRED.reserve();
+    }
+    DEFERRED_ENABLED.store(enable, Ordering::Relaxed);
+}
+
+/// Prints the deferred log lines queued so far
+pub fn flush() {
+    DEFERRED.flush();
+}
+
+/// Logs at `level`; nothing is evaluated if the level is filtered out
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => ({
+        if $crate::log::_enabled($level) {
+            $crate::log::_log($level, format_args!($($arg)*));
+        }
+    })
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::LogLevel::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::LogLevel::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::LogLevel::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::LogLevel::Debug, $($arg)*));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn contents(logger: &DeferredLogger) -> Vec<u8> {
+        let mut r = &logger.buffer;
+        r.lock(|buffer| buffer.iter().flatten().copied().collect())
+    }
+
+    #[test]
+    fn deferred_logger_drops_the_oldest_bytes_at_capacity() {
+        let logger = DeferredLogger::new(8);
+        logger.enqueue("unreserved");
+        assert_eq!(contents(&logger), b"");
+
+        logger.reserve();
+        logger.enqueue("abc123");
+        assert_eq!(contents(&logger), b"abc123");
+        logger.enqueue("xyz");
+        assert_eq!(contents(&logger), b"bc123xyz");
+        logger.enqueue("0123456789");
+        assert_eq!(contents(&logger), b"23456789");
+    }
+
+    #[test]
+    fn deferred_logger_truncates_long_lines() {
+        let logger = DeferredLogger::new(2 * MAX_LINE);
+        logger.reserve();
+
+        logger.enqueue(&"x".repeat(MAX_LINE + 1));
+        assert_eq!(contents(&logger).len(), MAX_LINE);
+
+        // Not through the middle of a char
+        logger.enqueue(&("x".repeat(MAX_LINE - 1) + "é"));
+        assert_eq!(contents(&logger).len(), 2 * MAX_LINE - 1);
+    }
+}
@@ -1,51 +1,134 @@
+#![cfg_attr(not(test), feature(alloc_error_handler))]
+#![feature(asm)]
 #![feature(format_args_nl)]
+#![feature(global_asm)]
+#![feature(min_const_generics)]
 #![feature(naked_functions)]
 #![feature(panic_info_message)]
-#![feature(trait_alias)]
-#![no_main]
-#![no_std]
+// Unit tests build for and run on the host, with std and its test harness.
+// The aarch64 code is compiled for them too, with its inline assembly stubbed
+// out the way cortex-a does it.
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
+// Nothing reaches `kernel_init` from the test harness
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+// Drivers are named as in the datasheets, GPIO, SPI and so on
+#![allow(clippy::upper_case_acronyms)]
 
 //! Quantum
+extern crate alloc;
+
+mod boot;
 mod bsp;
+mod cmdline;
 mod console;
 mod cpu;
+mod debug;
 mod driver;
+mod exception;
+mod fs;
+mod klog;
+mod log;
 mod memory;
 mod panic_wait;
 mod print;
 mod runtime_init;
+mod scheduler;
+mod shell;
 mod synchronization;
+mod xmodem;
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// Set by each secondary core once it is up
+static CORE_ONLINE: [AtomicBool; bsp::cpu::CORE_COUNT] = [
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+];
 
 unsafe fn kernel_init() -> ! {
     use driver::interface::DriverManager;
-    for i in bsp::driver::driver_manager().all_device_drivers().iter() {
-        if i.init().is_err() {
-            panic!("Error loading driver: {}", i.compatible());
-        }
+
+    bsp::detect_board();
+    memory::mmu::enable();
+    boot::stage("mmu enabled");
+    bsp::probe_device_tree();
+    cmdline::init(bsp::device_tree().and_then(|dt| dt.bootargs()).unwrap_or(""));
+    if cmdline::has("quiet") {
+        log::set_max_level(log::LogLevel::Error);
+    } else if cmdline::has("debug") {
+        log::set_max_level(log::LogLevel::Debug);
+    }
+    bsp::rebase_drivers();
+    memory::heap::init_heap(bsp::memory::heap_end());
+    boot::stage("heap ready");
+    // Keeps the log off the UART until the shell starts
+    if cmdline::has("deferred_log") {
+        log::set_deferred(true);
     }
+    bsp::driver::register_drivers();
+
+    driver::init_all(&bsp::driver::driver_manager().all_device_drivers());
     bsp::driver::driver_manager().post_device_driver_init();
+    boot::stage("drivers loaded");
+    // Only once the watchdog and the LED are up
+    match cmdline::get("panic") {
+        Some("reboot") => panic_wait::set_policy(panic_wait::PanicPolicy::Reboot),
+        Some("blink") => panic_wait::set_policy(panic_wait::PanicPolicy::BlinkForever),
+        _ => {}
+    }
+    // Solid rather than wherever the heartbeat stopped
+    let _ = panic_wait::set_hook(|| bsp::driver::activity_led().on());
+    exception::local_irq_unmask();
     kernel_main();
 }
 
 fn kernel_main() -> ! {
-    use console::interface::All;
-    use driver::interface::DriverManager;
+    use driver::interface::{DeviceDriver, DriverManager};
 
     /*loop {
-        if bsp::console::console().read_char() == '\n' {
+        if console::console().read_char() == '\n' {
             break;
         }
     }*/
-    println!("[0] Booting on: {}", bsp::board_name());
+    println!("[0] Booting on: {} at EL{}", bsp::board_name(), cpu::current_el());
 
     println!("[1] Drivers loaded: ");
-    for (i, driver) in bsp::driver::driver_manager().all_device_drivers().iter().enumerate() {
-        println!("        ({}) {}", i+1, driver.compatible());
+    let drivers = bsp::driver::driver_manager().all_device_drivers();
+    for (i, driver) in drivers.iter().filter(|d| d.is_enabled()).enumerate() {
+        match driver.probe() {
+            Some(info) => println!("        ({}) {}: {}", i+1, driver.name(), info),
+            None => println!("        ({}) {}", i+1, driver.name()),
+        }
     }
-    println!("[2] Chars written: {}", bsp::console::console().chars_written());
-    println!("[3] Echoing input...");
-    loop {
-        let c = bsp::console::console().read_char();
-        bsp::console::console().write_char(c);
+    println!("[2] Starting secondary cores: ");
+    // One at a time, so the cores report in order
+    let boot_core_id = bsp::cpu::boot_core_id() as u8;
+    for core in (0..bsp::cpu::CORE_COUNT as u8).filter(|&core| core != boot_core_id) {
+        unsafe { cpu::smp::start_core(core, secondary_main, bsp::cpu::core_stack_start(core)) };
+        while !CORE_ONLINE[core as usize].load(Ordering::Acquire) {
+            cpu::nop();
+        }
     }
+    boot::stage("secondary cores online");
+    // Takes one of them for good
+    if bsp::driver::reset_button().is_enabled() {
+        scheduler::spawn(|| bsp::driver::reset_button().watch());
+    }
+    println!("[3] Chars written: {}", console::console().chars_written());
+    println!("[4] Chars read: {}", console::console().chars_read());
+    log::set_deferred(false);
+    log::flush();
+    println!("[5] Starting shell...");
+    shell::run()
+}
+
+fn secondary_main() -> ! {
+    unsafe { memory::mmu::enable() };
+    cpu::enable_event_stream();
+    println!("        Core {} online", cpu::smp::core_id::<u8>());
+    CORE_ONLINE[cpu::smp::core_id::<usize>()].store(true, Ordering::Release);
+    scheduler::run()
 }
\ No newline at end of file
@@ -1,12 +1,134 @@
-use core::ops::Range;
+use crate::print::fmt::Hex;
+use core::{fmt, ops::Range};
 
+mod address;
+pub mod heap;
+pub mod mmu;
+
+pub use address::*;
+
+impl<A: Copy> fmt::Display for Address<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#}", Hex(self.as_usize()))
+    }
+}
+
+/// Zeroes every element in `range`, which may be empty.
+///
+/// # Safety
+///
+/// The range must be writable, and all-zero bytes a valid `T`.
 pub unsafe fn zero_volatile<T>(range: Range<*mut T>)
 where
-    T: From<u8>
+    T: Copy
 {
+    fill_volatile(range, core::mem::zeroed());
+}
+
+pub unsafe fn fill_volatile<T>(range: Range<*mut T>, value: T)
+where
+    T: Copy
+{
+    // The pointer would never move, and there is nothing to write anyway
+    if core::mem::size_of::<T>() == 0 {
+        return;
+    }
+
     let mut ptr = range.start;
     while ptr < range.end {
-        core::ptr::write_volatile(ptr, T::from(0));
+        core::ptr::write_volatile(ptr, value);
         ptr = ptr.offset(1);
     }
 }
+
+/// Copies `src` into `dst` element by element, backwards if the ranges overlap
+/// with `dst` above `src`.
+pub unsafe fn copy_volatile<T>(dst: Range<*mut T>, src: *const T)
+where
+    T: Copy
+{
+    let len = (dst.end as usize - dst.start as usize) / core::mem::size_of::<T>();
+
+    if (dst.start as *const T) <= src || (dst.start as *const T) >= src.add(len) {
+        for i in 0..len {
+            core::ptr::write_volatile(dst.start.add(i), core::ptr::read_volatile(src.add(i)));
+        }
+    } else {
+        for i in (0..len).rev() {
+            core::ptr::write_volatile(dst.start.add(i), core::ptr::read_volatile(src.add(i)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_volatile_writes_every_element() {
+        let mut buf = [0u16; 5];
+        let range = buf[1..4].as_mut_ptr_range();
+
+        unsafe { fill_volatile(range, 0xABCD) };
+        assert_eq!(buf, [0, 0xABCD, 0xABCD, 0xABCD, 0]);
+    }
+
+    #[test]
+    fn fill_volatile_empty_and_zero_sized() {
+        let mut buf = [7u32; 2];
+        let start = buf.as_mut_ptr();
+        unsafe { fill_volatile(start..start, 0) };
+        assert_eq!(buf, [7, 7]);
+
+        let mut units = [(); 4];
+        let range = units.as_mut_ptr_range();
+        unsafe { fill_volatile(range, ()) };
+    }
+
+    #[test]
+    fn zero_volatile_clears_only_the_range() {
+        let mut bytes = [0xFFu8; 6];
+        let range = bytes[1..5].as_mut_ptr_range();
+        unsafe { zero_volatile(range) };
+        assert_eq!(bytes, [0xFF, 0, 0, 0, 0, 0xFF]);
+
+        let mut words = [u32::MAX; 4];
+        let range = words[..3].as_mut_ptr_range();
+        unsafe { zero_volatile(range) };
+        assert_eq!(words, [0, 0, 0, u32::MAX]);
+
+        let mut sizes = [usize::MAX; 3];
+        let range = sizes[1..].as_mut_ptr_range();
+        unsafe { zero_volatile(range) };
+        assert_eq!(sizes, [usize::MAX, 0, 0]);
+    }
+
+    fn copy_within(buf: &mut [u32], src: usize, dst: usize, len: usize) {
+        let base = buf.as_mut_ptr();
+        unsafe { copy_volatile(base.add(dst)..base.add(dst + len), base.add(src)) };
+    }
+
+    #[test]
+    fn copy_volatile_disjoint() {
+        let mut buf = [1, 2, 3, 0, 0, 0];
+
+        copy_within(&mut buf, 0, 3, 3);
+        assert_eq!(buf, [1, 2, 3, 1, 2, 3]);
+    }
+
+    #[test]
+    fn copy_volatile_overlapping_upwards() {
+        let mut buf = [1, 2, 3, 4, 0, 0];
+
+        copy_within(&mut buf, 0, 2, 4);
+        assert_eq!(buf, [1, 2, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn copy_volatile_overlapping_downwards() {
+        let mut buf = [0, 0, 1, 2, 3, 4];
+
+        copy_within(&mut buf, 2, 0, 4);
+        assert_eq!(buf, [1, 2, 3, 4, 3, 4]);
+    }
+}
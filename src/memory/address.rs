@@ -0,0 +1,75 @@
+//! Kept free of other kernel modules, so the compile-fail tests can build it
+//! on its own.
+
+use core::marker::PhantomData;
+
+#[derive(Copy, Clone, PartialEq, PartialOrd)]
+pub enum Physical {}
+
+#[derive(Copy, Clone, PartialEq, PartialOrd)]
+pub enum Virtual {}
+
+/// An address tagged with the address space it belongs to, so physical and
+/// virtual ones can't be mixed up. Converting between them is explicit.
+#[derive(Copy, Clone, PartialEq, PartialOrd)]
+pub struct Address<A> {
+    value: usize,
+    _space: PhantomData<fn() -> A>,
+}
+
+pub type PhysicalAddress = Address<Physical>;
+pub type VirtualAddress = Address<Virtual>;
+
+impl<A> Address<A> {
+    pub const fn new(value: usize) -> Self {
+        Self {
+            value,
+            _space: PhantomData,
+        }
+    }
+
+    pub const fn as_usize(&self) -> usize {
+        self.value
+    }
+
+    /// `None` if the result would wrap
+    pub fn offset(self, by: usize) -> Option<Self> {
+        Some(Self::new(self.value.checked_add(by)?))
+    }
+}
+
+// Everything is identity-mapped, so the conversions keep the value
+impl PhysicalAddress {
+    pub const fn to_virtual(self) -> VirtualAddress {
+        Address::new(self.value)
+    }
+}
+
+impl VirtualAddress {
+    pub const fn to_physical(self) -> PhysicalAddress {
+        Address::new(self.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversions_keep_the_value() {
+        let phys = PhysicalAddress::new(0x3F20_1000);
+
+        assert_eq!(phys.to_virtual().as_usize(), 0x3F20_1000);
+        assert!(phys.to_virtual().to_physical() == phys);
+    }
+
+    #[test]
+    fn offset_checks_for_wrapping() {
+        let addr = PhysicalAddress::new(0x8_0000);
+
+        assert_eq!(addr.offset(0x1000).map(|a| a.as_usize()), Some(0x8_1000));
+        assert_eq!(addr.offset(0).map(|a| a.as_usize()), Some(0x8_0000));
+        assert!(PhysicalAddress::new(usize::MAX).offset(1).is_none());
+        assert!(addr.offset(usize::MAX).is_none());
+    }
+}
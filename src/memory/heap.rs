@@ -0,0 +1,298 @@
+use crate::{
+    synchronization,
+    synchronization::{IRQSafeSpinlock, Once},
+};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem,
+    ops::Range,
+    ptr,
+};
+
+// Header written into every free region; also the smallest block the heap hands out
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+const MIN_BLOCK: usize = mem::size_of::<FreeBlock>();
+
+struct HeapInner {
+    head: *mut FreeBlock,
+}
+
+// The free list is only ever reached through the lock
+unsafe impl Send for HeapInner {}
+
+// IRQ-safe, as any core or handler may allocate
+pub struct KernelHeap {
+    inner: IRQSafeSpinlock<HeapInner>,
+}
+
+// Handing the range out twice would give every block two owners
+static HEAP_READY: Once<()> = Once::new();
+
+#[cfg_attr(not(test), global_allocator)]
+static KERNEL_HEAP: KernelHeap = KernelHeap {
+    inner: IRQSafeSpinlock::new(HeapInner::new()),
+};
+
+unsafe fn heap_range() -> Range<usize> {
+    extern "C" {
+        static __heap_start: usize;
+        static __heap_end: usize;
+    }
+
+    Range {
+        start: &__heap_start as *const _ as usize,
+        end: &__heap_end as *const _ as usize,
+    }
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+// Rounds a request up so that the block can hold a FreeBlock once it's freed
+fn block_layout(layout: Layout) -> (usize, usize) {
+    let align = layout.align().max(mem::align_of::<FreeBlock>());
+    let size = align_up(layout.size().max(MIN_BLOCK), mem::align_of::<FreeBlock>());
+
+    (size, align)
+}
+
+impl HeapInner {
+    const fn new() -> Self {
+        Self {
+            head: ptr::null_mut(),
+        }
+    }
+
+    unsafe fn init(&mut self, range: Range<usize>) {
+        let start = align_up(range.start, mem::align_of::<FreeBlock>());
+        let end = range.end & !(mem::align_of::<FreeBlock>() - 1);
+
+        if end > start && end - start >= MIN_BLOCK {
+            self.free(start, end - start);
+        }
+    }
+
+    // First fit. Leftovers in front of or behind the allocation must be big
+    // enough to go back on the free list, otherwise the block is skipped.
+    unsafe fn alloc(&mut self, size: usize, align: usize) -> *mut u8 {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut cur = self.head;
+
+        while !cur.is_null() {
+            let block_start = cur as usize;
+            let block_end = block_start + (*cur).size;
+            let next = (*cur).next;
+
+            let mut start = align_up(block_start, align);
+            if start != block_start && start - block_start < MIN_BLOCK {
+                start = align_up(block_start + MIN_BLOCK, align);
+            }
+
+            if let Some(end) = start.checked_add(size) {
+                if end <= block_end && (end == block_end || block_end - end >= MIN_BLOCK) {
+                    if prev.is_null() {
+                        self.head = next;
+                    } else {
+                        (*prev).next = next;
+                    }
+
+                    if start != block_start {
+                        self.free(block_start, start - block_start);
+                    }
+                    if end != block_end {
+                        self.free(end, block_end - end);
+                    }
+
+                    return start as *mut u8;
+                }
+            }
+
+            prev = cur;
+            cur = next;
+        }
+
+        ptr::null_mut()
+    }
+
+    // Inserts into the address-sorted free list, merging with adjacent blocks
+    unsafe fn free(&mut self, addr: usize, size: usize) {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut cur = self.head;
+
+        while !cur.is_null() && (cur as usize) < addr {
+            prev = cur;
+            cur = (*cur).next;
+        }
+
+        let block = addr as *mut FreeBlock;
+        block.write(FreeBlock { size, next: cur });
+
+        if !cur.is_null() && addr + size == cur as usize {
+            (*block).size += (*cur).size;
+            (*block).next = (*cur).next;
+        }
+
+        if prev.is_null() {
+            self.head = block;
+        } else if prev as usize + (*prev).size == addr {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+}
+
+use synchronization::interface::Mutex;
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(layout);
+        let mut r = &self.inner;
+        r.lock(|inner| inner.alloc(size, align))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = block_layout(layout);
+        let mut r = &self.inner;
+        r.lock(|inner| inner.free(ptr as usize, size))
+    }
+}
+
+/// Hands the allocator everything from `__heap_start` up to `end`, or just
+/// the linker's reservation if that is bigger or there is no `end`. Later
+/// calls do nothing.
+pub unsafe fn init_heap(end: Option<usize>) {
+    let mut range = heap_range();
+    if let Some(end) = end {
+        range.end = range.end.max(end);
+    }
+
+    HEAP_READY.call_once(|| {
+        let mut r = &KERNEL_HEAP.inner;
+        r.lock(|inner| inner.init(range));
+    });
+}
+
+#[cfg(not(test))]
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    panic!("Heap allocation of {} bytes failed", layout.size())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{boxed::Box, vec, vec::Vec};
+
+    // A heap over a leaked, suitably aligned buffer
+    fn heap(len: usize) -> (HeapInner, Range<usize>) {
+        let buf = Box::leak(vec![0u64; len / 8].into_boxed_slice());
+        let start = buf.as_mut_ptr() as usize;
+        let range = start..start + len;
+
+        let mut heap = HeapInner::new();
+        unsafe { heap.init(range.clone()) };
+
+        (heap, range)
+    }
+
+    fn free_sizes(heap: &HeapInner) -> Vec<usize> {
+        let mut sizes = Vec::new();
+        let mut cur = heap.head;
+        while !cur.is_null() {
+            unsafe {
+                sizes.push((*cur).size);
+                cur = (*cur).next;
+            }
+        }
+
+        sizes
+    }
+
+    #[test]
+    fn block_layout_rounds_up_to_a_free_block() {
+        let align = mem::align_of::<FreeBlock>();
+
+        assert_eq!(block_layout(Layout::from_size_align(1, 1).unwrap()), (MIN_BLOCK, align));
+        assert_eq!(
+            block_layout(Layout::from_size_align(MIN_BLOCK + 1, 64).unwrap()),
+            (MIN_BLOCK + align, 64)
+        );
+    }
+
+    #[test]
+    fn alloc_is_first_fit_and_aligned() {
+        let (mut heap, range) = heap(1024);
+
+        let a = unsafe { heap.alloc(64, 8) } as usize;
+        assert_eq!(a, range.start);
+        let b = unsafe { heap.alloc(64, 256) } as usize;
+        assert_eq!(b % 256, 0);
+        assert!(b >= a + 64 && b + 64 <= range.end);
+    }
+
+    #[test]
+    fn alloc_fails_once_the_heap_is_exhausted() {
+        let (mut heap, _) = heap(256);
+
+        assert!(!unsafe { heap.alloc(256, 8) }.is_null());
+        assert!(unsafe { heap.alloc(MIN_BLOCK, 8) }.is_null());
+    }
+
+    #[test]
+    fn free_merges_neighbours_back_together() {
+        let (mut heap, _) = heap(1024);
+
+        let a = unsafe { heap.alloc(128, 8) } as usize;
+        let b = unsafe { heap.alloc(128, 8) } as usize;
+        let c = unsafe { heap.alloc(128, 8) } as usize;
+        unsafe {
+            heap.free(a, 128);
+            heap.free(c, 128);
+        }
+        assert_eq!(free_sizes(&heap), [128, 1024 - 256]);
+
+        unsafe { heap.free(b, 128) };
+        assert_eq!(free_sizes(&heap), [1024]);
+    }
+
+    #[test]
+    fn global_alloc_roundtrips_through_realloc() {
+        let (inner, range) = heap(1024);
+        let heap = KernelHeap {
+            inner: IRQSafeSpinlock::new(inner),
+        };
+        let layout = Layout::from_size_align(32, 8).unwrap();
+
+        unsafe {
+            let a = heap.alloc(layout);
+            assert!(!a.is_null());
+            for i in 0..32 {
+                *a.add(i) = i as u8;
+            }
+
+            // A second block in the way, so growing has to move it
+            let b = heap.alloc(layout);
+            let grown = heap.realloc(a, layout, 256);
+            assert!(!grown.is_null() && grown != a);
+            assert!((0..32).all(|i| *grown.add(i) == i as u8));
+
+            let grown_layout = Layout::from_size_align(256, 8).unwrap();
+            let shrunk = heap.realloc(grown, grown_layout, 16);
+            assert!(!shrunk.is_null());
+            assert!((0..16).all(|i| *shrunk.add(i) == i as u8));
+
+            heap.dealloc(shrunk, Layout::from_size_align(16, 8).unwrap());
+            heap.dealloc(b, layout);
+        }
+
+        let mut r = &heap.inner;
+        assert_eq!(r.lock(|inner| free_sizes(inner)), [range.end - range.start]);
+    }
+}
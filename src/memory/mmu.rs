@@ -0,0 +1,14 @@
+#[cfg(any(target_arch = "aarch64", test))]
+#[path = "../_arch/aarch64/memory/mmu.rs"]
+mod arch_mmu;
+pub use arch_mmu::*;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum MemAttributes {
+    /// Strongly ordered and uncached, for peripheral registers
+    Device,
+    /// Write-back cacheable RAM
+    Normal,
+    /// RAM shared with bus masters that bypass the caches
+    NormalNonCacheable,
+}
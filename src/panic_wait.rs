@@ -1,23 +1,236 @@
-use crate::{bsp, cpu};
-use core::{fmt, panic::PanicInfo};
+use crate::{bsp, cpu, exception, scheduler};
+use core::{
+    fmt,
+    fmt::Write,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+};
 
-fn _panic_print(args: fmt::Arguments) {
-    use fmt::Write;
-    unsafe { bsp::console::panic_console_out().write_fmt(args).unwrap() };
+/// What the panic handler does once it has printed the message
+#[derive(Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum PanicPolicy {
+    /// Parks the core in `wfe` with interrupts masked
+    Halt,
+    /// Resets the board through the watchdog
+    Reboot,
+    /// Toggles the activity LED, for boards without a console attached
+    BlinkForever,
 }
-#[macro_export]
-macro_rules! panic_println {
-    ($($arg:tt)*) => ({
-        _panic_print(format_args_nl!($($arg)*));
-    })
+
+// The LED's half period under `BlinkForever`, fast enough to tell apart from
+// the heartbeat
+const PANIC_BLINK_PERIOD_NANOS: u64 = 250_000_000;
+// The same by `nop` count, without a timer frequency to go by. At least
+// 250 ms at the 1.5 GHz of the fastest core.
+const PANIC_BLINK_PERIOD_CYCLES: usize = 375_000_000;
+
+static POLICY: AtomicU8 = AtomicU8::new(PanicPolicy::Halt as u8);
+
+// A `fn()`, or 0 for none
+static HOOK: AtomicUsize = AtomicUsize::new(0);
+
+// Set by the first panic before it runs the hook. Any later one, from the
+// hook, the printing below or another core, would only garble its output.
+static PANIC_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Has the panic handler call `hook` before it prints anything, e.g. to light
+/// an LED. It runs with IRQs masked, at most once, and a panic inside it
+/// halts the core. Only one hook can be set, later calls fail.
+pub fn set_hook(hook: fn()) -> Result<(), ()> {
+    HOOK.compare_exchange(0, hook as usize, Ordering::AcqRel, Ordering::Relaxed)
+        .map(|_| ())
+        .map_err(|_| ())
+}
+
+/// Picks what happens after a panic. Defaults to `PanicPolicy::Halt`.
+pub fn set_policy(policy: PanicPolicy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+fn policy() -> PanicPolicy {
+    match POLICY.load(Ordering::Relaxed) {
+        1 => PanicPolicy::Reboot,
+        2 => PanicPolicy::BlinkForever,
+        _ => PanicPolicy::Halt,
+    }
+}
+
+// The timer reads 0 for good if the firmware never set CNTFRQ_EL0
+fn blink_forever() -> ! {
+    use cpu::time::interface::TimeManager;
+
+    let timer = bsp::time::timer();
+    let has_frequency = cpu::time::time_manager().frequency().is_some();
+    let led = bsp::driver::activity_led();
+    loop {
+        led.toggle();
+        if has_frequency {
+            timer.spin_until(timer.now_nanos() + PANIC_BLINK_PERIOD_NANOS);
+        } else {
+            cpu::spin_for_cycles(PANIC_BLINK_PERIOD_CYCLES);
+        }
+    }
+}
+
+// Deliberately without `shutdown_all`: the driver locks may be held by what
+// died, and draining output or stopping DMA is not worth hanging the reboot
+fn reboot() -> ! {
+    bsp::driver::watchdog().reboot()
 }
 
+// What each policy ends in, as pointers so the tests can dispatch to fakes
+struct Actions {
+    halt: fn() -> !,
+    reboot: fn() -> !,
+    blink_forever: fn() -> !,
+}
+
+static ACTIONS: Actions = Actions {
+    halt: cpu::halt,
+    reboot,
+    blink_forever,
+};
+
+fn action(policy: PanicPolicy, actions: &Actions) -> fn() -> ! {
+    match policy {
+        PanicPolicy::Halt => actions.halt,
+        PanicPolicy::Reboot => actions.reboot,
+        PanicPolicy::BlinkForever => actions.blink_forever,
+    }
+}
+
+fn run_hook() {
+    // Cleared first, so a panicking hook isn't run again
+    match HOOK.swap(0, Ordering::Acquire) {
+        0 => {}
+        hook => unsafe { core::mem::transmute::<usize, fn()>(hook)() },
+    }
+}
+
+// Claims the panic output for the first panic and runs the hook. False for
+// any later one, which then must not touch the console.
+fn begin_panic() -> bool {
+    if PANIC_IN_PROGRESS.swap(true, Ordering::AcqRel) {
+        return false;
+    }
+
+    run_hook();
+    true
+}
+
+// The first line of the report, stamped like the log's
+fn write_fatal(
+    w: &mut impl fmt::Write,
+    uptime_nanos: u64,
+    message: Option<impl fmt::Display>,
+) -> fmt::Result {
+    let (secs, micros) = (uptime_nanos / 1_000_000_000, uptime_nanos % 1_000_000_000 / 1_000);
+
+    match message {
+        Some(args) => writeln!(w, "\n[{:>5}.{:06}] Fatal error: {}", secs, micros, args),
+        None => writeln!(w, "\n[{:>5}.{:06}] Fatal error!", secs, micros),
+    }
+}
+
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    if let Some(args) = info.message() {
-        panic_println!("\nFatal error: {}", args);
-    } else {
-        panic_println!("\nFatal error!");
+    unsafe { exception::local_irq_mask() };
+    if !begin_panic() {
+        cpu::halt()
+    }
+
+    // Written and flushed through the same lock-free console, since a panic
+    // can come from under the console driver's lock
+    let mut out = unsafe { bsp::console::panic_console_out() };
+    let _ = write_fatal(&mut out, bsp::time::timer().now_nanos(), info.message());
+
+    if let Some((sp, bounds)) = cpu::stack::check_overflow() {
+        let _ = writeln!(
+            out,
+            "Stack overflow: SP {:#x} outside {:#x}..{:#x}",
+            sp, bounds.end, bounds.start
+        );
+    }
+
+    if let Some(task) = scheduler::current_task() {
+        let _ = writeln!(out, "In task {:#x}", task as usize);
+    }
+
+    let _ = cpu::dump_registers(&mut out, &exception::ExceptionContext::capture());
+    out.flush();
+
+    action(policy(), &ACTIONS)()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+
+    #[test]
+    fn write_fatal_stamps_the_message() {
+        let mut out = String::new();
+
+        write_fatal(&mut out, 12_345_678_900, Some(format_args!("boom {}", 7))).unwrap();
+        assert_eq!(out, "\n[   12.345678] Fatal error: boom 7\n");
+
+        out.clear();
+        write_fatal(&mut out, 0, None::<&str>).unwrap();
+        assert_eq!(out, "\n[    0.000000] Fatal error!\n");
+    }
+
+    static HOOK_RUNS: AtomicUsize = AtomicUsize::new(0);
+    static NESTED_PANIC_BEGAN: AtomicBool = AtomicBool::new(true);
+
+    // Panics again from inside the hook
+    fn panicking_hook() {
+        HOOK_RUNS.fetch_add(1, Ordering::Relaxed);
+        NESTED_PANIC_BEGAN.store(begin_panic(), Ordering::Relaxed);
+    }
+
+    #[test]
+    fn hook_runs_exactly_once_and_later_panics_short_circuit() {
+        run_hook();
+        assert_eq!(set_hook(panicking_hook), Ok(()));
+        assert_eq!(set_hook(panicking_hook), Err(()));
+
+        assert!(begin_panic());
+        assert!(!NESTED_PANIC_BEGAN.load(Ordering::Relaxed));
+        assert!(!begin_panic());
+        run_hook();
+        assert_eq!(HOOK_RUNS.load(Ordering::Relaxed), 1);
+    }
+
+    fn fake_halt() -> ! {
+        panic!("halt")
+    }
+
+    fn fake_reboot() -> ! {
+        panic!("reboot")
+    }
+
+    fn fake_blink_forever() -> ! {
+        panic!("blink forever")
+    }
+
+    #[test]
+    fn the_set_policy_dispatches_to_its_action() {
+        let fakes = Actions {
+            halt: fake_halt,
+            reboot: fake_reboot,
+            blink_forever: fake_blink_forever,
+        };
+        let addr = |f: fn() -> !| f as usize;
+        let picked = || addr(action(policy(), &fakes));
+
+        assert_eq!(picked(), addr(fake_halt));
+        set_policy(PanicPolicy::Reboot);
+        assert_eq!(picked(), addr(fake_reboot));
+        set_policy(PanicPolicy::BlinkForever);
+        assert_eq!(picked(), addr(fake_blink_forever));
+        set_policy(PanicPolicy::Halt);
+        assert_eq!(picked(), addr(fake_halt));
     }
-    cpu::wait_forever()
 }
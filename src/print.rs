@@ -1,11 +1,47 @@
-use crate::{bsp, console};
-use core::fmt;
+use crate::{console, klog};
+use core::fmt::{Arguments, Write};
 
-#[doc(hidden)]
-pub fn _print(args: fmt::Arguments) {
-    use console::interface::Write;
+pub mod fmt;
+
+/// Formats into a fixed buffer, silently truncating (at a char boundary)
+/// once it is full.
+pub struct BufWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
 
-    bsp::console::console().write_fmt(args).unwrap();
+impl<'a> BufWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+
+    pub fn into_str(self) -> &'a str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for BufWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut n = core::cmp::min(s.len(), self.buf.len() - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: Arguments) {
+    // Nowhere to report it if no console took the output
+    let _ = klog::write_tee(args, &mut console::Writer(console::console()));
 }
 /// Prints without a newline
 #[macro_export]
@@ -19,3 +55,38 @@ macro_rules! println {
         $crate::print::_print(format_args_nl!($($arg)*));
     })
 }
+/// Formats into a `&mut [u8]` and evaluates to the written `&str`
+#[macro_export]
+macro_rules! format_into {
+    ($buf:expr, $($arg:tt)*) => ({
+        let mut w = $crate::print::BufWriter::new($buf);
+        let _ = core::fmt::Write::write_fmt(&mut w, format_args!($($arg)*));
+        w.into_str()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buf_writer_truncates_at_a_char_boundary() {
+        let mut buf = [0u8; 6];
+        let mut w = BufWriter::new(&mut buf);
+
+        write!(w, "ab").unwrap();
+        write!(w, "cé").unwrap();
+        assert_eq!(w.as_str(), "abcé");
+        // 'é' is two bytes and only one is left
+        write!(w, "é!").unwrap();
+        assert_eq!(w.into_str(), "abcé");
+    }
+
+    #[test]
+    fn format_into_evaluates_to_the_written_str() {
+        let mut buf = [0u8; 8];
+
+        assert_eq!(format_into!(&mut buf, "{:#x}", 0xbeef), "0xbeef");
+        assert_eq!(format_into!(&mut buf, "{}", 123_456_789), "12345678");
+    }
+}
@@ -0,0 +1,112 @@
+//! Fixed-width number formatting, zero-padded to the full width of the type:
+//! `Hex(5u32)` shows as "00000005", or "0x00000005" with `{:#}`.
+
+use core::fmt;
+
+/// Hex digits, two per byte
+#[derive(Copy, Clone)]
+pub struct Hex<T>(pub T);
+
+/// Binary digits, one per bit
+#[derive(Copy, Clone)]
+pub struct Bin<T>(pub T);
+
+/// Decimal digits, as many as the type's maximum has
+#[derive(Copy, Clone)]
+pub struct Dec<T>(pub T);
+
+macro_rules! impl_fixed_width {
+    ($($t:ty => $dec_digits:expr),*) => {$(
+        impl fmt::Display for Hex<$t> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                const DIGITS: usize = core::mem::size_of::<$t>() * 2;
+                if f.alternate() {
+                    write!(f, "0x")?;
+                }
+                write!(f, "{:0w$x}", self.0, w = DIGITS)
+            }
+        }
+
+        impl fmt::LowerHex for Hex<$t> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                fmt::Display::fmt(self, f)
+            }
+        }
+
+        impl fmt::Display for Bin<$t> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                const DIGITS: usize = core::mem::size_of::<$t>() * 8;
+                if f.alternate() {
+                    write!(f, "0b")?;
+                }
+                write!(f, "{:0w$b}", self.0, w = DIGITS)
+            }
+        }
+
+        impl fmt::Display for Dec<$t> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{:0w$}", self.0, w = $dec_digits)
+            }
+        }
+    )*};
+}
+
+impl_fixed_width!(u8 => 3, u16 => 5, u32 => 10, u64 => 20, usize => 20);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::format;
+
+    #[test]
+    fn hex_pads_to_two_digits_per_byte() {
+        assert_eq!(format!("{}", Hex(0u8)), "00");
+        assert_eq!(format!("{}", Hex(u8::MAX)), "ff");
+        assert_eq!(format!("{}", Hex(0u16)), "0000");
+        assert_eq!(format!("{}", Hex(u16::MAX)), "ffff");
+        assert_eq!(format!("{}", Hex(0u32)), "00000000");
+        assert_eq!(format!("{}", Hex(u32::MAX)), "ffffffff");
+        assert_eq!(format!("{}", Hex(0u64)), "0000000000000000");
+        assert_eq!(format!("{}", Hex(u64::MAX)), "ffffffffffffffff");
+        assert_eq!(format!("{}", Hex(0usize)), "0000000000000000");
+        assert_eq!(format!("{}", Hex(usize::MAX)), "ffffffffffffffff");
+    }
+
+    #[test]
+    fn hex_alternate_and_lower_hex() {
+        assert_eq!(format!("{:#}", Hex(5u32)), "0x00000005");
+        assert_eq!(format!("{:#}", Hex(u8::MAX)), "0xff");
+        assert_eq!(format!("{:x}", Hex(0xabu16)), "00ab");
+        assert_eq!(format!("{:#x}", Hex(0u8)), "0x00");
+    }
+
+    #[test]
+    fn bin_pads_to_one_digit_per_bit() {
+        assert_eq!(format!("{}", Bin(0u8)), "00000000");
+        assert_eq!(format!("{}", Bin(u8::MAX)), "11111111");
+        assert_eq!(format!("{}", Bin(0u16)), "0".repeat(16));
+        assert_eq!(format!("{}", Bin(u16::MAX)), "1".repeat(16));
+        assert_eq!(format!("{}", Bin(0u32)), "0".repeat(32));
+        assert_eq!(format!("{}", Bin(u32::MAX)), "1".repeat(32));
+        assert_eq!(format!("{}", Bin(0u64)), "0".repeat(64));
+        assert_eq!(format!("{}", Bin(u64::MAX)), "1".repeat(64));
+        assert_eq!(format!("{}", Bin(0usize)), "0".repeat(64));
+        assert_eq!(format!("{}", Bin(usize::MAX)), "1".repeat(64));
+        assert_eq!(format!("{:#}", Bin(0b101u8)), "0b00000101");
+    }
+
+    #[test]
+    fn dec_pads_to_the_digits_of_max() {
+        assert_eq!(format!("{}", Dec(0u8)), "000");
+        assert_eq!(format!("{}", Dec(u8::MAX)), "255");
+        assert_eq!(format!("{}", Dec(0u16)), "00000");
+        assert_eq!(format!("{}", Dec(u16::MAX)), "65535");
+        assert_eq!(format!("{}", Dec(0u32)), "0000000000");
+        assert_eq!(format!("{}", Dec(u32::MAX)), "4294967295");
+        assert_eq!(format!("{}", Dec(0u64)), "0".repeat(20));
+        assert_eq!(format!("{}", Dec(u64::MAX)), "18446744073709551615");
+        assert_eq!(format!("{}", Dec(0usize)), "0".repeat(20));
+        assert_eq!(format!("{}", Dec(usize::MAX)), "18446744073709551615");
+        assert_eq!(format!("{}", Dec(42u16)), "00042");
+    }
+}
@@ -1,5 +1,5 @@
-use crate::memory;
-use core::ops::Range;
+use crate::{cpu, exception, memory};
+use core::{ops::Range, ptr};
 
 unsafe fn bss_range() -> Range<*mut usize> {
     extern "C" {
@@ -8,8 +8,38 @@ unsafe fn bss_range() -> Range<*mut usize> {
     }
 
     Range {
-        start: &mut __bss_start,
-        end: &mut __bss_end,
+        start: ptr::addr_of_mut!(__bss_start),
+        end: ptr::addr_of_mut!(__bss_end),
+    }
+}
+
+unsafe fn data_range() -> Range<*mut usize> {
+    extern "C" {
+        static mut __data_start: usize;
+        static mut __data_end: usize;
+    }
+
+    Range {
+        start: ptr::addr_of_mut!(__data_start),
+        end: ptr::addr_of_mut!(__data_end),
+    }
+}
+
+unsafe fn data_load_start() -> *const usize {
+    extern "C" {
+        static __data_load_start: usize;
+    }
+
+    &__data_load_start
+}
+
+#[inline(always)]
+unsafe fn copy_data() {
+    let data = data_range();
+    let load = data_load_start();
+
+    if !ptr::eq(load, data.start) {
+        memory::copy_volatile(data, load);
     }
 }
 
@@ -19,8 +49,12 @@ unsafe fn zero_bss() {
 }
 
 #[no_mangle]
-pub unsafe fn runtime_init() -> ! {
+pub unsafe fn runtime_init(dtb_addr: usize) -> ! {
+    copy_data();
     zero_bss();
+    cpu::set_dtb_addr(dtb_addr);
+    cpu::enable_event_stream();
+    exception::init();
 
     crate::kernel_init();
 }
@@ -0,0 +1,137 @@
+use crate::{
+    bsp, cpu,
+    cpu::percpu::PerCore,
+    synchronization,
+    synchronization::{RingBuffer, Spinlock},
+};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const QUEUE_SIZE: usize = 64;
+
+/// Tasks waiting for a core, oldest first. Any core may push or pop, the
+/// lock around the ring is what makes that safe.
+static QUEUE: Spinlock<RingBuffer<fn(), QUEUE_SIZE>> = Spinlock::new(RingBuffer::new());
+
+// The queue's length, so idle cores can check it without taking the lock.
+// Every lock release does a `sev`, which would wake them all right back up.
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+
+// What each core is running, for the panic report
+static CURRENT: PerCore<Option<fn()>> = PerCore::new([None; bsp::cpu::CORE_COUNT]);
+
+use synchronization::interface::Mutex;
+
+/// Queues `task` to run to completion on the next idle secondary core.
+/// Returns false, dropping the task, when the queue is full.
+pub fn spawn(task: fn()) -> bool {
+    let mut r = &QUEUE;
+    // Releasing the lock does a `sev`, which wakes the idle cores
+    r.lock(|queue| {
+        let queued = queue.push(task);
+        if queued {
+            QUEUED.fetch_add(1, Ordering::Release);
+        }
+        queued
+    })
+}
+
+/// Runs queued tasks on the calling core forever, in `wfe` while there are
+/// none.
+///
+/// An idle core can't miss a `spawn`: if it lands between seeing the queue
+/// empty and the `wfe`, its `sev` has already set the event register and the
+/// `wfe` falls straight through.
+pub fn run() -> ! {
+    loop {
+        if QUEUED.load(Ordering::Acquire) == 0 {
+            cpu::wfe();
+            continue;
+        }
+
+        // Another core may have beaten this one to it
+        if let Some(task) = take() {
+            CURRENT.with(|current| *current = Some(task));
+            task();
+            CURRENT.with(|current| *current = None);
+        }
+    }
+}
+
+/// The task the calling core is running, if it is one of `run`'s
+pub fn current_task() -> Option<fn()> {
+    CURRENT.with(|current| *current)
+}
+
+// The oldest queued task, if any
+fn take() -> Option<fn()> {
+    let mut r = &QUEUE;
+    r.lock(|queue| {
+        let task = queue.pop();
+        if task.is_some() {
+            QUEUED.fetch_sub(1, Ordering::Relaxed);
+        }
+        task
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, vec::Vec};
+
+    // The IDs of the tasks that ran, in the order they did
+    static RAN: [AtomicUsize; 8] = [
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+    ];
+    static RAN_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    fn task<const ID: usize>() {
+        RAN[RAN_COUNT.fetch_add(1, Ordering::Relaxed)].store(ID, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn tasks_run_in_spawn_order() {
+        let producers = [
+            [task::<0>, task::<1>, task::<2>, task::<3>],
+            [task::<10>, task::<11>, task::<12>, task::<13>],
+        ];
+
+        // Two cores spawning at once, a third draining as they go
+        let spawners: Vec<_> = producers
+            .iter()
+            .map(|&tasks| {
+                thread::spawn(move || {
+                    for &t in tasks.iter() {
+                        assert!(spawn(t));
+                    }
+                })
+            })
+            .collect();
+        let mut ran = 0;
+        while ran < 8 {
+            if let Some(t) = take() {
+                t();
+                ran += 1;
+            }
+        }
+        for s in spawners {
+            s.join().unwrap();
+        }
+
+        let ran: Vec<_> = RAN.iter().map(|id| id.load(Ordering::Relaxed)).collect();
+        let from = |base: usize| -> Vec<usize> {
+            ran.iter().copied().filter(|id| (base..base + 4).contains(id)).collect()
+        };
+        assert_eq!(from(0), [0, 1, 2, 3]);
+        assert_eq!(from(10), [10, 11, 12, 13]);
+        assert!(take().is_none());
+        assert_eq!(QUEUED.load(Ordering::Relaxed), 0);
+    }
+}
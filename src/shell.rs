@@ -0,0 +1,554 @@
+use crate::{
+    bsp, console, cpu, driver, exception, fs, klog, memory::VirtualAddress, print, println, xmodem,
+};
+use alloc::vec;
+
+const LINE_LENGTH: usize = 128;
+
+// Longest bus transfer the `i2c` and `spi` commands will do
+const MAX_TRANSFER: usize = 64;
+
+// Room on the heap for an image sent with `load`
+const MAX_IMAGE_SIZE: usize = 8 * 1024 * 1024;
+
+// How long `timer test` spins for
+const TIMER_TEST_US: u64 = 1_000_000;
+
+// For consoles that can't tell, like a serial line
+const DEFAULT_DIMENSIONS: (u16, u16) = (80, 24);
+
+/// Reads commands from the console and runs them, forever.
+pub fn run() -> ! {
+    use console::interface::ReadLine;
+
+    let mut buf = [0u8; LINE_LENGTH];
+    loop {
+        print!("> ");
+        let len = console::console().read_line(&mut buf);
+
+        match core::str::from_utf8(&buf[..len]) {
+            Ok(line) => execute(line.trim()),
+            Err(_) => println!("Invalid input"),
+        }
+    }
+}
+
+fn execute(command: &str) {
+    match command {
+        "" => {}
+        "reboot" => bsp::driver::reboot(),
+        "halt" => cpu::halt(),
+        // To check the exception handler fires
+        "fault" => unsafe { exception::trigger_synchronous_exception() },
+        "uptime" => uptime(),
+        "cpu" => cpu_features(),
+        "drivers" => drivers(),
+        "size" => size(),
+        "stats" => stats(),
+        "stats reset" => console::console().reset(),
+        "uart test" => uart_test(),
+        "timer test" => timer_test(),
+        "dmesg" => klog::dump(),
+        "load" => load(),
+        "load raw" => load_raw(),
+        "led on" => led(true),
+        "led off" => led(false),
+        "led blink" => bsp::driver::set_heartbeat(true),
+        "watchdog feed" => bsp::driver::watchdog().feed(),
+        _ => match command.split_once(' ') {
+            Some(("cat", path)) => cat(path.trim()),
+            Some(("hexdump", args)) => hexdump(args),
+            Some(("gpio", args)) => gpio(args),
+            Some(("i2c", args)) => i2c(args),
+            Some(("spi", args)) => spi(args),
+            Some(("pwm", args)) => pwm(args),
+            Some(("tone", freq)) => tone(freq.trim()),
+            Some(("watchdog", timeout)) => watchdog(timeout.trim()),
+            Some(("uart", args)) => uart(args),
+            Some(("fb", args)) => fb(args),
+            Some(("sd", args)) => sd(args),
+            _ => println!("Unknown command: {}", command),
+        },
+    }
+}
+
+/// A number in decimal, or in hex after `0x`
+fn parse_number(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+// `hexdump <addr> <len>`. Reading memory that isn't mapped takes an exception.
+fn hexdump(args: &str) {
+    let mut args = args.split_whitespace().map(parse_number);
+    match (args.next(), args.next(), args.next()) {
+        (Some(Some(addr)), Some(Some(len)), None)
+            if VirtualAddress::new(addr).offset(len).is_none() =>
+        {
+            println!("hexdump: range wraps past the end of memory")
+        }
+        (Some(Some(addr)), Some(Some(len)), None) => unsafe { console::hexdump(addr, len) },
+        _ => println!("Usage: hexdump <addr> <len>"),
+    }
+}
+
+// `gpio <pin>` shows the level, and whether a watched event fired since the
+// last look. `gpio <pin> high|low|in|out|alt0-5`, `gpio <pin> pull
+// up|down|none` and `gpio <pin> event|noevent rising|falling|high|low`
+// change it.
+fn gpio(args: &str) {
+    use core::convert::TryFrom;
+
+    let gpio = bsp::driver::gpio();
+
+    let mut args = args.split_whitespace();
+    let pin = args.next().and_then(parse_number).and_then(|pin| u32::try_from(pin).ok());
+    let (pin, op, arg) = match (pin, args.next(), args.next(), args.next()) {
+        (Some(pin), op, arg, None) => (pin, op, arg),
+        _ => {
+            println!("Usage: gpio <pin> [high|low|in|out|alt0-5|pull|event|noevent] [...]");
+            return;
+        }
+    };
+
+    let ret = match (op, arg) {
+        (None, None) => gpio.read(pin).and_then(|high| {
+            let fired = gpio.poll_event(pin)?;
+            let event = if fired { ", event" } else { "" };
+            println!("{}{}", if high { "high" } else { "low" }, event);
+            Ok(())
+        }),
+        (Some("high"), None) => gpio.set_high(pin),
+        (Some("low"), None) => gpio.set_low(pin),
+        (Some("pull"), Some(pull)) => pull.parse().and_then(|pull| gpio.set_pull(pin, pull)),
+        (Some("event"), Some(event)) => event.parse().and_then(|e| gpio.enable_event(pin, e)),
+        (Some("noevent"), Some(event)) => event.parse().and_then(|e| gpio.disable_event(pin, e)),
+        (Some(function), None) => function.parse().and_then(|f| gpio.set_function(pin, f)),
+        _ => Err(driver::DriverError::InvalidConfig),
+    };
+    if let Err(e) = ret {
+        println!("gpio {}: {}", pin, e);
+    }
+}
+
+fn on_off(s: &str) -> Result<bool, driver::DriverError> {
+    match s {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(driver::DriverError::InvalidConfig),
+    }
+}
+
+// Parses the rest of the line into `buf`, if it all fits
+fn parse_numbers<'a, T>(args: core::str::SplitWhitespace, buf: &'a mut [T]) -> Option<&'a [T]>
+where
+    T: core::convert::TryFrom<usize>,
+{
+    let mut len = 0;
+    for arg in args {
+        let x = parse_number(arg).and_then(|x| T::try_from(x).ok())?;
+        *buf.get_mut(len)? = x;
+        len += 1;
+    }
+    Some(&buf[..len])
+}
+
+fn print_bytes(bytes: &[u8]) {
+    for byte in bytes {
+        print!("{} ", print::fmt::Hex(*byte));
+    }
+    println!("");
+}
+
+// `i2c <addr> read <len>` and `i2c <addr> write <byte>...`, against the bus
+// enabled with `i2c` on the command line
+fn i2c(args: &str) {
+    use core::convert::TryFrom;
+
+    let i2c = bsp::driver::i2c();
+    let mut buf = [0u8; MAX_TRANSFER];
+
+    let mut args = args.split_whitespace();
+    let addr = args.next().and_then(parse_number).and_then(|addr| u8::try_from(addr).ok());
+    let ret = match (addr, args.next()) {
+        (Some(addr), Some("read")) => {
+            match (args.next().and_then(parse_number), args.next()) {
+                (Some(len), None) if len <= MAX_TRANSFER => {
+                    i2c.read(addr, &mut buf[..len]).map(|()| print_bytes(&buf[..len]))
+                }
+                _ => Err(driver::DriverError::InvalidConfig),
+            }
+        }
+        (Some(addr), Some("write")) => match parse_numbers(args, &mut buf) {
+            Some(data) => i2c.write(addr, data),
+            None => Err(driver::DriverError::InvalidConfig),
+        },
+        _ => {
+            println!("Usage: i2c <addr> read <len> | i2c <addr> write <byte>...");
+            return;
+        }
+    };
+    if let Err(e) = ret {
+        println!("i2c: {}", e);
+    }
+}
+
+// `spi <byte>...` clocks the bytes out and shows what came back, on the bus
+// enabled with `spi` on the command line
+fn spi(args: &str) {
+    let mut tx = [0u8; MAX_TRANSFER];
+    let tx = match parse_numbers(args.split_whitespace(), &mut tx) {
+        Some(tx) => tx,
+        None => {
+            println!("Usage: spi <byte>...");
+            return;
+        }
+    };
+
+    let mut rx = [0u8; MAX_TRANSFER];
+    let rx = &mut rx[..tx.len()];
+    match bsp::driver::spi().transfer(tx, rx) {
+        Ok(()) => print_bytes(rx),
+        Err(e) => println!("spi: {}", e),
+    }
+}
+
+// `pwm <channel> <range> <data>` drives the channel high for `data` out of
+// every `range` ticks
+fn pwm(args: &str) {
+    use core::convert::TryFrom;
+
+    let mut args = args.split_whitespace().map(parse_number);
+    let channel = args.next().flatten().and_then(|channel| u8::try_from(channel).ok());
+    let range = args.next().flatten().and_then(|range| u32::try_from(range).ok());
+    let data = args.next().flatten().and_then(|data| u32::try_from(data).ok());
+    match (channel, range, data, args.next()) {
+        (Some(channel), Some(range), Some(data), None) => {
+            if let Err(e) = bsp::driver::pwm().set_duty(channel, range, data) {
+                println!("pwm: {}", e);
+            }
+        }
+        _ => println!("Usage: pwm <channel> <range> <data>"),
+    }
+}
+
+// `tone <hz>` on PWM channel 0, `tone 0` to stop it
+fn tone(freq: &str) {
+    use core::convert::TryFrom;
+
+    match parse_number(freq).and_then(|freq| u32::try_from(freq).ok()) {
+        Some(freq) => {
+            if let Err(e) = bsp::driver::pwm().tone(freq) {
+                println!("tone: {}", e);
+            }
+        }
+        None => println!("Usage: tone <hz>"),
+    }
+}
+
+// `fb clear <color>`, `fb pixel <x> <y> <color>` and `fb rect <x> <y> <w> <h>
+// <color>` draw on the screen, colors as 0x00RRGGBB. Without one they do
+// nothing.
+fn fb(args: &str) {
+    let fb = bsp::driver::framebuffer();
+
+    let mut args = args.split_whitespace();
+    let op = args.next();
+    let mut buf = [0u32; 5];
+    match (op, parse_numbers(args, &mut buf)) {
+        (Some("clear"), Some(&[color])) => fb.clear(color),
+        (Some("pixel"), Some(&[x, y, color])) => fb.put_pixel(x, y, color),
+        (Some("rect"), Some(&[x, y, w, h, color])) => fb.fill_rect(x, y, w, h, color),
+        _ => println!("Usage: fb clear|pixel|rect [<x> <y>] [<w> <h>] <color>"),
+    }
+}
+
+// `watchdog <ms>` resets the board unless `watchdog feed` comes in time
+fn watchdog(timeout: &str) {
+    use core::convert::TryFrom;
+
+    match parse_number(timeout).and_then(|timeout| u32::try_from(timeout).ok()) {
+        Some(timeout) => bsp::driver::watchdog().start(timeout),
+        None => println!("Usage: watchdog <ms> | watchdog feed"),
+    }
+}
+
+fn uptime() {
+    use cpu::time::interface::TimeManager;
+
+    let time = cpu::time::time_manager();
+    if time.frequency().is_none() {
+        println!("Timer not available");
+        return;
+    }
+
+    let uptime = time.uptime_nanos();
+    println!("{}.{:06}s", uptime / 1_000_000_000, uptime % 1_000_000_000 / 1_000);
+}
+
+fn cpu_features() {
+    let f = cpu::features::features();
+    let yes_no = |b: bool| if b { "yes" } else { "no" };
+
+    match cpu::features::pa_bits(f.pa_range) {
+        Some(bits) => println!("PA bits:     {}", bits),
+        None => println!("PA bits:     reserved ({:#x})", f.pa_range),
+    }
+    println!(
+        "Granules:    4K {}, 16K {}, 64K {}",
+        yes_no(f.granule_4k),
+        yes_no(f.granule_16k),
+        yes_no(f.granule_64k)
+    );
+    println!("FP/SIMD:     {}/{}", yes_no(f.fp), yes_no(f.advsimd));
+    println!("EL2/EL3:     {}/{}", yes_no(f.el2), yes_no(f.el3));
+    println!("Cache lines: {}B data, {}B instruction", f.dcache_line, f.icache_line);
+}
+
+// Spins on the generic timer and times it on the system timer. They run off
+// different clocks, so more than a few microseconds apart means one of the
+// frequencies is wrong.
+fn timer_test() {
+    use cpu::time::interface::TimeManager;
+
+    let time = cpu::time::time_manager();
+    if time.frequency().is_none() {
+        println!("Timer not available");
+        return;
+    }
+
+    let system_timer = bsp::time::system_timer();
+    let start = system_timer.now_micros();
+    time.spin_for_micros(TIMER_TEST_US);
+    let elapsed = system_timer.now_micros().wrapping_sub(start);
+    println!("spin_for_micros({}): {}us on the system timer", TIMER_TEST_US, elapsed);
+
+    let start = system_timer.now_micros();
+    cpu::delay_ns(TIMER_TEST_US * 1_000);
+    let elapsed = system_timer.now_micros().wrapping_sub(start);
+    println!("delay_ns({}): {}us on the system timer", TIMER_TEST_US * 1_000, elapsed);
+}
+
+fn dimensions() -> (u16, u16) {
+    console::console().dimensions().unwrap_or(DEFAULT_DIMENSIONS)
+}
+
+fn size() {
+    let (cols, rows) = dimensions();
+    println!("{}x{}", cols, rows);
+}
+
+fn stats() {
+    use print::fmt::Dec;
+
+    // Fixed width, so the counters line up
+    let console = console::console();
+    println!("Chars written: {}", Dec(console.chars_written()));
+    println!("Chars read:    {}", Dec(console.chars_read()));
+    println!("Bytes written: {}", Dec(console.bytes_written()));
+    println!("Bytes read:    {}", Dec(console.bytes_read()));
+    println!("Max TX wait:   {} polls", Dec(console.max_tx_wait_polls()));
+    println!(
+        "Errors: {} framing, {} parity, {} break, {} overrun",
+        console.framing_errors(),
+        console.parity_errors(),
+        console.break_errors(),
+        console.overrun_errors()
+    );
+}
+
+fn uart_test() {
+    match bsp::driver::pl011_uart().self_test() {
+        Ok(()) => println!("UART loopback test passed"),
+        Err(e) => println!("UART loopback test failed: {}", e),
+    }
+}
+
+// `uart baud <rate>` changes the PL011's line settings, the other end has to
+// follow. `uart crlf on|off` sends "\r\n" for each "\n". `uart fifo on|off`
+// and `uart trigger 1/8|1/4|1/2|3/4|7/8` trade latency against interrupts.
+// `uart flow on|off` takes GPIO 16/17 for RTS/CTS.
+fn uart(args: &str) {
+    use core::convert::TryFrom;
+
+    let uart = bsp::driver::pl011_uart();
+
+    let mut args = args.split_whitespace();
+    let ret = match (args.next(), args.next(), args.next()) {
+        (Some("baud"), Some(baud), None) => {
+            match parse_number(baud).and_then(|baud| u32::try_from(baud).ok()) {
+                Some(baud) => uart.set_baud_rate(baud, bsp::driver::pl011_clock_hz()),
+                None => Err(driver::DriverError::InvalidConfig),
+            }
+        }
+        (Some("crlf"), Some(enable), None) => on_off(enable).map(|enable| uart.set_crlf(enable)),
+        (Some("fifo"), Some(enable), None) => on_off(enable).map(|enable| uart.set_fifo(enable)),
+        (Some("trigger"), Some(level), None) => level.parse().map(|l| uart.set_rx_trigger(l)),
+        (Some("flow"), Some(enable), None) => on_off(enable).and_then(|e| uart.set_flow_control(e)),
+        _ => {
+            println!("Usage: uart baud|crlf|fifo|trigger|flow <value>");
+            return;
+        }
+    };
+    if let Err(e) = ret {
+        println!("uart: {}", e);
+    }
+}
+
+// Receives a kernel image over the PL011 by Xmodem and chainloads it
+fn load() {
+    let mut image = vec![0u8; MAX_IMAGE_SIZE];
+
+    println!("Send the kernel image by Xmodem");
+    match xmodem::receive(bsp::driver::pl011_uart(), &mut image) {
+        Ok(len) => {
+            println!("Received {} bytes, chainloading", len);
+            unsafe { cpu::chainload(image.as_ptr() as usize, len) }
+        }
+        Err(e) => println!("Xmodem receive failed: {}", e),
+    }
+}
+
+// Receives a kernel image over the PL011 as its size, 4 bytes little endian,
+// and then the image itself, with no error checking, and chainloads it
+fn load_raw() {
+    use console::interface::Read;
+
+    let uart = bsp::driver::pl011_uart();
+
+    println!("Send the kernel image size and then the image");
+    let mut size = [0u8; 4];
+    uart.read_bytes(&mut size);
+    let len = u32::from_le_bytes(size) as usize;
+    if len > MAX_IMAGE_SIZE {
+        println!("{} bytes won't fit in {}", len, MAX_IMAGE_SIZE);
+        return;
+    }
+
+    let mut image = vec![0u8; len];
+    uart.read_bytes(&mut image);
+    println!("Received {} bytes, chainloading", len);
+    unsafe { cpu::chainload(image.as_ptr() as usize, len) }
+}
+
+// Takes the LED over from the heartbeat
+fn led(on: bool) {
+    let led = bsp::driver::activity_led();
+
+    bsp::driver::set_heartbeat(false);
+    if on {
+        led.on();
+    } else {
+        led.off();
+    }
+}
+
+fn drivers() {
+    use driver::interface::DriverManager;
+
+    for (i, driver) in bsp::driver::driver_manager().all_device_drivers().iter().enumerate() {
+        let state = if driver.is_enabled() { "" } else { ", disabled" };
+        println!("({}) {} [{}{}]", i + 1, driver.name(), driver.compatible(), state);
+    }
+}
+
+fn open(path: &str) -> Option<fs::fat32::File<'static>> {
+    let volume = match fs::fat32::Volume::mount(bsp::driver::emmc()) {
+        Some(volume) => volume,
+        None => {
+            println!("No FAT32 volume");
+            return None;
+        }
+    };
+
+    let file = volume.open(path);
+    if file.is_none() {
+        println!("{}: not found", path);
+    }
+    file
+}
+
+// `sd read <lba>` dumps a block of the SD card. `sd test <lba>` writes the
+// block back as it was and checks it reads the same.
+fn sd(args: &str) {
+    use core::convert::TryFrom;
+
+    let emmc = bsp::driver::emmc();
+    let mut block = [0u8; fs::BLOCK_SIZE];
+
+    let mut args = args.split_whitespace();
+    let op = args.next();
+    let lba = args.next().and_then(parse_number).and_then(|lba| u32::try_from(lba).ok());
+    let ret = match (op, lba) {
+        (Some("read"), Some(lba)) if args.next().is_none() => emmc
+            .read_block(lba, &mut block)
+            .map(|()| unsafe { console::hexdump(block.as_ptr() as usize, block.len()) }),
+        (Some("test"), Some(lba)) if args.next().is_none() => {
+            let mut check = [0u8; fs::BLOCK_SIZE];
+            emmc.read_block(lba, &mut block)
+                .and_then(|()| emmc.write_block(lba, &block))
+                .and_then(|()| emmc.read_block(lba, &mut check))
+                .map(|()| {
+                    if check == block {
+                        println!("SD write test passed");
+                    } else {
+                        println!("SD write test failed: block {} reads back different", lba);
+                    }
+                })
+        }
+        _ => {
+            println!("Usage: sd read|test <lba>");
+            return;
+        }
+    };
+    if let Err(e) = ret {
+        println!("sd: {}", e);
+    }
+}
+
+fn cat(path: &str) {
+    let mut file = match open(path) {
+        Some(file) => file,
+        None => return,
+    };
+
+    let mut buf = [0u8; fs::BLOCK_SIZE];
+    let mut done = 0;
+    loop {
+        let len = file.read(&mut buf);
+        if len == 0 {
+            break;
+        }
+        console::console().write_bytes(&buf[..len]);
+        done += len;
+    }
+
+    if done < file.size() as usize {
+        println!("{}: read failed after {} of {} bytes", path, done, file.size());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_number_takes_decimal_and_hex() {
+        assert_eq!(parse_number("4096"), Some(4096));
+        assert_eq!(parse_number("0x8_0000"), None);
+        assert_eq!(parse_number("0x80000"), Some(0x8_0000));
+        assert_eq!(parse_number("0xg"), None);
+        assert_eq!(parse_number(""), None);
+    }
+
+    #[test]
+    fn parse_numbers_needs_every_one_to_fit() {
+        let mut buf = [0u8; 2];
+        assert_eq!(parse_numbers("1 0xff".split_whitespace(), &mut buf), Some(&[1, 0xff][..]));
+        assert_eq!(parse_numbers("".split_whitespace(), &mut buf), Some(&[][..]));
+        assert_eq!(parse_numbers("1 256".split_whitespace(), &mut buf), None);
+        assert_eq!(parse_numbers("1 2 3".split_whitespace(), &mut buf), None);
+    }
+}
@@ -1,4 +1,9 @@
-use core::cell::UnsafeCell;
+use crate::{cpu, exception};
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+};
 
 pub mod interface {
     pub trait Mutex {
@@ -30,3 +35,361 @@ impl<T> interface::Mutex for &NullLock<T> {
         f(data)
     }
 }
+
+/// A spinlock for data shared between cores. The flag is taken with an
+/// acquire compare-exchange (an `ldaxr`/`stlxr` loop on AArch64), and waiting
+/// cores sleep in `wfe` until the holder releases it with `sev`.
+///
+/// Exclusive accesses need the MMU and data cache enabled, so cores must not
+/// contend on a `Spinlock` before then.
+pub struct Spinlock<T: ?Sized> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for Spinlock<T> {}
+unsafe impl<T: ?Sized + Send> Send for Spinlock<T> {}
+
+impl<T> Spinlock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T> interface::Mutex for &Spinlock<T> {
+    type Data = T;
+
+    fn lock<R>(&mut self, f: impl FnOnce(&mut Self::Data) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                cpu::wfe();
+            }
+        }
+
+        let data = unsafe { &mut *self.data.get() };
+        let ret = f(data);
+
+        self.locked.store(false, Ordering::Release);
+        cpu::sev();
+
+        ret
+    }
+}
+
+/// A `Spinlock` that also masks IRQs and FIQs on the holding core, so an
+/// interrupt handler taking it can't spin forever on the code it interrupted.
+///
+/// The DAIF bits are saved before the lock is taken and put back once the
+/// closure has returned and the lock is released, so nested locks leave
+/// interrupts masked until the outermost one is done. A panic inside never
+/// returns, and leaves them masked.
+pub struct IRQSafeSpinlock<T: ?Sized> {
+    inner: Spinlock<T>,
+}
+
+impl<T> IRQSafeSpinlock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            inner: Spinlock::new(data),
+        }
+    }
+}
+
+impl<T> interface::Mutex for &IRQSafeSpinlock<T> {
+    type Data = T;
+
+    fn lock<R>(&mut self, f: impl FnOnce(&mut Self::Data) -> R) -> R {
+        let daif = unsafe { exception::local_irq_save() };
+
+        let mut r = &self.inner;
+        let ret = r.lock(f);
+
+        unsafe { exception::local_irq_restore(daif) };
+
+        ret
+    }
+}
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+/// A value set up by the first `call_once`, from whichever core gets there
+/// first. Cores arriving while it runs wait in `wfe` for the result.
+///
+/// An initializer that panics leaves it running for good, so anyone else
+/// trying waits forever.
+pub struct Once<T> {
+    state: AtomicU8,
+    data: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(INCOMPLETE),
+            data: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Runs `f` if no call has yet, and returns the value it produced
+    pub fn call_once(&self, f: impl FnOnce() -> T) -> &T {
+        match self
+            .state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                unsafe { (*self.data.get()).as_mut_ptr().write(f()) };
+                self.state.store(COMPLETE, Ordering::Release);
+                cpu::sev();
+            }
+            Err(_) => {
+                while self.state.load(Ordering::Acquire) != COMPLETE {
+                    cpu::wfe();
+                }
+            }
+        }
+
+        unsafe { &*(*self.data.get()).as_ptr() }
+    }
+
+    /// The value, once a `call_once` has finished
+    pub fn get(&self) -> Option<&T> {
+        match self.state.load(Ordering::Acquire) {
+            COMPLETE => Some(unsafe { &*(*self.data.get()).as_ptr() }),
+            _ => None,
+        }
+    }
+}
+
+/// A lock-free queue for one producer and one consumer, e.g. an IRQ handler
+/// filling it and thread context draining it. Pushing from two places at
+/// once (or popping) is not safe without a lock around it.
+///
+/// The indices only ever increase and wrap around usize, which stays
+/// consistent modulo `N` as long as `N` is a power of two, as it must be.
+pub struct RingBuffer<T, const N: usize> {
+    data: UnsafeCell<MaybeUninit<[T; N]>>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+unsafe impl<T: Send, const N: usize> Sync for RingBuffer<T, N> {}
+
+impl<T, const N: usize> RingBuffer<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            data: UnsafeCell::new(MaybeUninit::uninit()),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+}
+
+// Only `Copy` items, so slots can be overwritten and read out without ever
+// having to drop what's in them
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    // Turns an index into a slot. The array is out of bounds, and so a
+    // build error, unless `N` is a power of two.
+    const MASK: usize = N.wrapping_sub(1) + [0][!N.is_power_of_two() as usize];
+
+    fn slot(&self, index: usize) -> *mut T {
+        unsafe { (*self.data.get()).as_mut_ptr().cast::<T>().add(index & Self::MASK) }
+    }
+
+    /// Returns false, dropping the item, when the buffer is full.
+    pub fn push(&self, item: T) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        // Acquire pairs with the consumer's release, so the slot is free
+        let tail = self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) == N {
+            return false;
+        }
+
+        unsafe { self.slot(head).write(item) };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+
+        true
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        // Acquire pairs with the producer's release, so the byte is visible
+        let head = self.head.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+
+        let item = unsafe { self.slot(tail).read() };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+
+        Some(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interface::Mutex;
+    use std::{sync::Arc, thread, vec::Vec};
+
+    #[test]
+    fn spinlock_returns_the_closures_value() {
+        let lock = Spinlock::new(41);
+        let mut r = &lock;
+
+        assert_eq!(r.lock(|data| {
+            *data += 1;
+            *data
+        }), 42);
+    }
+
+    #[test]
+    fn spinlock_serializes_threads() {
+        let lock = Arc::new(Spinlock::new(0usize));
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let lock = Arc::clone(&lock);
+                thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        let mut r = &*lock;
+                        r.lock(|count| *count += 1);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let mut r = &*lock;
+        assert_eq!(r.lock(|count| *count), 40_000);
+    }
+
+    #[test]
+    fn irq_safe_spinlock_masks_irqs_and_fiqs_while_held() {
+        use exception::{DAIF_A, DAIF_F, DAIF_I};
+
+        let lock = IRQSafeSpinlock::new(());
+        let mut r = &lock;
+
+        unsafe { exception::set_daif(DAIF_A) };
+        let inside = r.lock(|_| exception::daif());
+        assert_eq!(inside, DAIF_A | DAIF_I | DAIF_F);
+        assert_eq!(exception::daif(), DAIF_A);
+
+        // Nested, the inner lock leaves them masked for the outer one
+        let (inner, outer) = r.lock(|_| {
+            let inner = (&IRQSafeSpinlock::new(())).lock(|_| exception::daif());
+            (inner, exception::daif())
+        });
+        assert_eq!((inner, outer), (DAIF_A | DAIF_I | DAIF_F, DAIF_A | DAIF_I | DAIF_F));
+        assert_eq!(exception::daif(), DAIF_A);
+    }
+
+    #[test]
+    fn once_runs_the_initializer_exactly_once() {
+        let once = Once::new();
+        let mut runs = 0;
+
+        assert_eq!(once.get(), None);
+        for i in 0..3 {
+            assert_eq!(*once.call_once(|| {
+                runs += 1;
+                i
+            }), 0);
+        }
+        assert_eq!(runs, 1);
+        assert_eq!(once.get(), Some(&0));
+    }
+
+    #[test]
+    fn once_initializes_once_across_threads() {
+        let once = Arc::new(Once::new());
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let (once, runs) = (Arc::clone(&once), Arc::clone(&runs));
+                thread::spawn(move || {
+                    *once.call_once(|| {
+                        runs.fetch_add(1, Ordering::Relaxed);
+                        thread::sleep(std::time::Duration::from_millis(10));
+                        i
+                    })
+                })
+            })
+            .collect();
+        let seen: Vec<usize> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+        assert!(seen.iter().all(|&v| v == seen[0]));
+    }
+
+    #[test]
+    fn ring_buffer_is_fifo_and_drops_when_full() {
+        let ring = RingBuffer::<u8, 4>::new();
+
+        for b in 1..=4 {
+            assert!(ring.push(b));
+        }
+        assert!(!ring.push(5));
+        assert_eq!(ring.pop(), Some(1));
+        assert!(ring.push(6));
+        let rest: Vec<_> = core::iter::from_fn(|| ring.pop()).collect();
+        assert_eq!(rest, [2, 3, 4, 6]);
+    }
+
+    #[test]
+    fn ring_buffer_indices_wrap_around_usize() {
+        let ring = RingBuffer::<u8, 4>::new();
+        ring.head.store(usize::MAX - 1, Ordering::Relaxed);
+        ring.tail.store(usize::MAX - 1, Ordering::Relaxed);
+
+        for b in 0..4 {
+            assert!(ring.push(b));
+        }
+        assert!(!ring.push(4));
+        for b in 0..4 {
+            assert_eq!(ring.pop(), Some(b));
+        }
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn ring_buffer_hands_bytes_across_threads() {
+        let ring = Arc::new(RingBuffer::<u8, 8>::new());
+
+        let producer = {
+            let ring = Arc::clone(&ring);
+            thread::spawn(move || {
+                for b in 0..=255u8 {
+                    while !ring.push(b) {
+                        thread::yield_now();
+                    }
+                }
+            })
+        };
+        for b in 0..=255u8 {
+            let got = loop {
+                if let Some(got) = ring.pop() {
+                    break got;
+                }
+                thread::yield_now();
+            };
+            assert_eq!(got, b);
+        }
+        producer.join().unwrap();
+    }
+}
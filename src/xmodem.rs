@@ -0,0 +1,288 @@
+//! The receiving end of Xmodem: 128-byte blocks, each with a block number,
+//! its inverse and an 8-bit checksum, acknowledged one at a time.
+
+use crate::console::interface::All;
+use core::fmt;
+
+const SOH: u8 = 0x01;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+
+pub const BLOCK_SIZE: usize = 128;
+
+// The sender starts on the first NAK, which it may take a while to see
+const START_TIMEOUT_MS: u32 = 3_000;
+const BYTE_TIMEOUT_MS: u32 = 1_000;
+// In a row, for the start and for any one block
+const MAX_RETRIES: usize = 10;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum XmodemError {
+    /// The sender went quiet, or a block kept arriving damaged
+    Timeout,
+    /// The sender sent two CANs in a row
+    Cancelled,
+    /// A block number that is neither the next one nor a repeat
+    OutOfSequence,
+    /// More data than fits in the destination
+    Overflow,
+}
+
+impl fmt::Display for XmodemError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            XmodemError::Timeout => write!(f, "timed out"),
+            XmodemError::Cancelled => write!(f, "cancelled by sender"),
+            XmodemError::OutOfSequence => write!(f, "block out of sequence"),
+            XmodemError::Overflow => write!(f, "destination full"),
+        }
+    }
+}
+
+/// The sum of `data`, modulo 256
+pub const fn checksum(data: &[u8]) -> u8 {
+    let mut sum: u8 = 0;
+    let mut i = 0;
+    while i < data.len() {
+        sum = sum.wrapping_add(data[i]);
+        i += 1;
+    }
+
+    sum
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Block {
+    /// The block that was expected, intact
+    Next,
+    /// The previous block again, after the sender missed its ACK
+    Repeat,
+    /// Garbled in transit, worth asking for again
+    Damaged,
+    /// Some other block, the transfer can't recover
+    OutOfSequence,
+}
+
+/// Classifies a received block. `expected` is the block number wanted next,
+/// starting from 1 and wrapping after 255.
+pub const fn check_block(expected: u8, number: u8, inverse: u8, data: &[u8], sum: u8) -> Block {
+    if number != !inverse || data.len() != BLOCK_SIZE || checksum(data) != sum {
+        Block::Damaged
+    } else if number == expected {
+        Block::Next
+    } else if number == expected.wrapping_sub(1) {
+        Block::Repeat
+    } else {
+        Block::OutOfSequence
+    }
+}
+
+fn cancel(uart: &dyn All, err: XmodemError) -> Result<usize, XmodemError> {
+    uart.write_bytes(&[CAN, CAN]);
+    Err(err)
+}
+
+// Block number, inverse, data and checksum, or None on a timeout
+fn read_block(uart: &dyn All, buf: &mut [u8; BLOCK_SIZE + 3]) -> Option<()> {
+    for b in buf.iter_mut() {
+        *b = uart.read_byte_timeout(BYTE_TIMEOUT_MS)?;
+    }
+
+    Some(())
+}
+
+/// Receives a file into `dest` and returns its length, a whole number of
+/// blocks including whatever padding the sender added. `uart` must pass bytes
+/// through untranslated, like the PL011, and nothing else may read from it
+/// meanwhile.
+pub fn receive(uart: &dyn All, dest: &mut [u8]) -> Result<usize, XmodemError> {
+    let mut expected: u8 = 1;
+    let mut len = 0;
+    let mut retries = 0;
+    let mut block = [0u8; BLOCK_SIZE + 3];
+
+    // NAK until the first block starts
+    let mut header = loop {
+        uart.write_bytes(&[NAK]);
+        if let Some(b) = uart.read_byte_timeout(START_TIMEOUT_MS) {
+            break b;
+        }
+
+        retries += 1;
+        if retries == MAX_RETRIES {
+            return cancel(uart, XmodemError::Timeout);
+        }
+    };
+    retries = 0;
+
+    loop {
+        let reply = match header {
+            SOH if read_block(uart, &mut block).is_some() => {
+                let (number, inverse, sum) = (block[0], block[1], block[BLOCK_SIZE + 2]);
+                let data = &block[2..BLOCK_SIZE + 2];
+
+                match check_block(expected, number, inverse, data, sum) {
+                    Block::Next => {
+                        if len + BLOCK_SIZE > dest.len() {
+                            return cancel(uart, XmodemError::Overflow);
+                        }
+                        dest[len..len + BLOCK_SIZE].copy_from_slice(data);
+                        len += BLOCK_SIZE;
+                        expected = expected.wrapping_add(1);
+                        ACK
+                    }
+                    Block::Repeat => ACK,
+                    Block::Damaged => NAK,
+                    Block::OutOfSequence => return cancel(uart, XmodemError::OutOfSequence),
+                }
+            }
+            EOT => {
+                uart.write_bytes(&[ACK]);
+                return Ok(len);
+            }
+            // A lone CAN is line noise
+            CAN => match uart.read_byte_timeout(BYTE_TIMEOUT_MS) {
+                Some(CAN) => return Err(XmodemError::Cancelled),
+                _ => NAK,
+            },
+            // Line noise, or a block cut short
+            _ => NAK,
+        };
+
+        if reply == NAK {
+            retries += 1;
+            if retries == MAX_RETRIES {
+                return cancel(uart, XmodemError::Timeout);
+            }
+            // Let the rest of a garbled block go by first
+            while uart.read_byte_timeout(BYTE_TIMEOUT_MS).is_some() {}
+        } else {
+            retries = 0;
+        }
+        uart.write_bytes(&[reply]);
+
+        header = loop {
+            if let Some(b) = uart.read_byte_timeout(BYTE_TIMEOUT_MS * 10) {
+                break b;
+            }
+
+            retries += 1;
+            if retries == MAX_RETRIES {
+                return cancel(uart, XmodemError::Timeout);
+            }
+            uart.write_bytes(&[NAK]);
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::interface::{Read, Statistics, Write};
+    use core::{cell::RefCell, fmt};
+    use std::{collections::VecDeque, vec, vec::Vec};
+
+    /// Plays back bursts of input, with a timeout between them, and records
+    /// the replies
+    struct Sender {
+        input: RefCell<VecDeque<Option<u8>>>,
+        output: RefCell<Vec<u8>>,
+    }
+
+    impl Sender {
+        fn new(bursts: &[&[u8]]) -> Self {
+            let mut input = VecDeque::new();
+            for burst in bursts {
+                input.extend(burst.iter().map(|&b| Some(b)));
+                input.push_back(None);
+            }
+
+            Self { input: RefCell::new(input), output: RefCell::new(Vec::new()) }
+        }
+    }
+
+    impl Write for Sender {
+        fn write_char(&self, c: char) {
+            self.output.borrow_mut().push(c as u8);
+        }
+
+        fn write_fmt(&self, _args: fmt::Arguments) -> fmt::Result {
+            Ok(())
+        }
+    }
+
+    impl Read for Sender {
+        fn read_byte_timeout(&self, _ms: u32) -> Option<u8> {
+            self.input.borrow_mut().pop_front().flatten()
+        }
+    }
+
+    impl Statistics for Sender {}
+
+    fn block(number: u8, fill: u8) -> Vec<u8> {
+        let mut block = vec![SOH, number, !number];
+        block.extend_from_slice(&[fill; BLOCK_SIZE]);
+        block.push(checksum(&[fill; BLOCK_SIZE]));
+        block
+    }
+
+    #[test]
+    fn checksum_matches_known_vectors() {
+        assert_eq!(checksum(b""), 0);
+        assert_eq!(checksum(b"123456789"), 0xDD);
+        assert_eq!(checksum(&[0x1A; BLOCK_SIZE]), 0);
+        assert_eq!(checksum(&[0xFF; BLOCK_SIZE]), 0x80);
+    }
+
+    #[test]
+    fn check_block_classifies_blocks() {
+        let zeros = [0u8; BLOCK_SIZE];
+
+        assert_eq!(check_block(1, 1, 0xFE, &zeros, 0), Block::Next);
+        assert_eq!(check_block(1, 1, 0xFF, &zeros, 0), Block::Damaged);
+        assert_eq!(check_block(1, 1, 0xFE, &zeros, 1), Block::Damaged);
+        assert_eq!(check_block(1, 1, 0xFE, &zeros[1..], 0), Block::Damaged);
+        assert_eq!(check_block(0, 255, 0, &zeros, 0), Block::Repeat);
+        assert_eq!(check_block(3, 5, 0xFA, &zeros, 0), Block::OutOfSequence);
+    }
+
+    #[test]
+    fn receive_acks_each_block_and_the_end() {
+        let mut blocks = block(1, 0xAA);
+        blocks.extend(block(1, 0xAA));
+        blocks.extend(block(2, 0x55));
+        blocks.push(EOT);
+        let sender = Sender::new(&[&blocks]);
+        let mut dest = [0u8; 3 * BLOCK_SIZE];
+
+        assert_eq!(receive(&sender, &mut dest), Ok(2 * BLOCK_SIZE));
+        assert_eq!(*sender.output.borrow(), [NAK, ACK, ACK, ACK, ACK]);
+        assert!(dest[..BLOCK_SIZE].iter().all(|&b| b == 0xAA));
+        assert!(dest[BLOCK_SIZE..2 * BLOCK_SIZE].iter().all(|&b| b == 0x55));
+    }
+
+    #[test]
+    fn receive_cancels_on_two_cans_only() {
+        let sender = Sender::new(&[&[CAN, CAN]]);
+        assert_eq!(receive(&sender, &mut [0u8; BLOCK_SIZE]), Err(XmodemError::Cancelled));
+        assert_eq!(*sender.output.borrow(), [NAK]);
+
+        let mut rest = block(1, 0);
+        rest.push(EOT);
+        let sender = Sender::new(&[&[CAN, b'x'], &rest]);
+        assert_eq!(receive(&sender, &mut [0u8; BLOCK_SIZE]), Ok(BLOCK_SIZE));
+        assert_eq!(*sender.output.borrow(), [NAK, NAK, ACK, ACK]);
+    }
+
+    #[test]
+    fn receive_cancels_when_dest_is_full() {
+        let mut blocks = block(1, 0);
+        blocks.extend(block(2, 0));
+        let sender = Sender::new(&[&blocks]);
+
+        assert_eq!(receive(&sender, &mut [0u8; BLOCK_SIZE]), Err(XmodemError::Overflow));
+        assert_eq!(*sender.output.borrow(), [NAK, ACK, CAN, CAN]);
+    }
+}
@@ -0,0 +1,10 @@
+#![allow(dead_code)]
+
+#[path = "../../src/memory/address.rs"]
+mod address;
+
+use address::{PhysicalAddress, VirtualAddress};
+
+fn main() {
+    let _ = PhysicalAddress::new(0) == VirtualAddress::new(0);
+}
@@ -0,0 +1,12 @@
+#![allow(dead_code)]
+
+#[path = "../../src/memory/address.rs"]
+mod address;
+
+use address::{PhysicalAddress, VirtualAddress};
+
+fn map(_: VirtualAddress) {}
+
+fn main() {
+    map(PhysicalAddress::new(0x3F20_1000));
+}
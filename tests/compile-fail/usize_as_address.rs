@@ -0,0 +1,12 @@
+#![allow(dead_code)]
+
+#[path = "../../src/memory/address.rs"]
+mod address;
+
+use address::PhysicalAddress;
+
+fn dma_to(_: PhysicalAddress) {}
+
+fn main() {
+    dma_to(0x10_0000);
+}
@@ -0,0 +1,12 @@
+#![allow(dead_code)]
+
+#[path = "../../src/memory/address.rs"]
+mod address;
+
+use address::{PhysicalAddress, VirtualAddress};
+
+fn dma_to(_: PhysicalAddress) {}
+
+fn main() {
+    dma_to(VirtualAddress::new(0x10_0000));
+}
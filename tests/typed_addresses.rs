@@ -0,0 +1,6 @@
+// Physical and virtual addresses, and bare integers, must not mix
+#[test]
+fn typed_addresses() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/compile-fail/*.rs");
+}